@@ -0,0 +1,139 @@
+//! Frames per second through the data path against a synthetic bus, the
+//! baseline for performance work. Run with `cargo bench`.
+//!
+//! Each iteration passes [`FRAMES`] frames, criterion reports the throughput
+//! in frames per second.
+//!
+//! Baseline, median throughput on a Linux virtual machine with an Intel Xeon
+//! CPU, to compare against on the same machine:
+//!
+//! | Benchmark                    | Frames/s |
+//! |------------------------------|----------|
+//! | `to host/classic`            | 6.57 M   |
+//! | `to host/classic, busy host` | 5.16 M   |
+//! | `to host/FD`                 | 5.89 M   |
+//! | `to host/mixed`              | 6.72 M   |
+//! | `from host/classic`          | 5.31 M   |
+//! | `from host/FD`               | 4.36 M   |
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use embedded_can::{Frame as _, StandardId};
+use usb_device::bus::UsbBusAllocator;
+use usbd_gscan::{
+    host::{Feature, Frame, FrameFlag},
+    Channel,
+};
+use zerocopy::AsBytes;
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+use support::{Acceptance, SyntheticBus};
+
+/// Frames per iteration.
+const FRAMES: usize = 10_240;
+
+/// Frames queued by the firmware between polls.
+const BURST: usize = 32;
+
+fn classic() -> Frame {
+    Frame::new(StandardId::ZERO, &[0xAA; 8]).unwrap()
+}
+
+fn fd() -> Frame {
+    Frame::new(StandardId::ZERO, &[0xAA; 64]).unwrap()
+}
+
+/// Frames for the host queued with `transmit`, cycling through `frames`.
+fn to_host(c: &mut Criterion, name: &str, acceptance: Acceptance, frames: &[(Frame, bool)]) {
+    let alloc = UsbBusAllocator::new(SyntheticBus::new(acceptance));
+    let (mut class, mut device) = support::class(&alloc, Feature::FD);
+    support::start(&mut device, &mut class, Feature::FD);
+    let channel = Channel::new(0).unwrap();
+
+    let mut group = c.benchmark_group("to host");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            let mut frames = frames.iter().cycle();
+            for _ in 0..FRAMES / BURST {
+                for (frame, fd) in frames.by_ref().take(BURST) {
+                    if *fd {
+                        class.transmit_fd(channel, frame, FrameFlag::empty(), None);
+                    } else {
+                        class.transmit(channel, frame, FrameFlag::empty());
+                    }
+                }
+                support::run(&mut device, &mut class);
+            }
+        })
+    });
+    group.finish();
+    assert_eq!(class.queue_full_dropped(), 0, "frames for the host lost");
+}
+
+fn classic_to_host(c: &mut Criterion) {
+    to_host(c, "classic", Acceptance::All, &[(classic(), false)]);
+    to_host(
+        c,
+        "classic, busy host",
+        Acceptance::Burst(4),
+        &[(classic(), false)],
+    );
+}
+
+fn fd_to_host(c: &mut Criterion) {
+    to_host(c, "FD", Acceptance::All, &[(fd(), true)]);
+}
+
+fn mixed_to_host(c: &mut Criterion) {
+    to_host(
+        c,
+        "mixed",
+        Acceptance::All,
+        &[(classic(), false), (fd(), true), (classic(), false)],
+    );
+}
+
+/// Frames from the host on the OUT endpoint, each sent as `packets`.
+fn from_host_packets(c: &mut Criterion, name: &str, packets: &[&[u8]]) {
+    let alloc = UsbBusAllocator::new(SyntheticBus::default());
+    let (mut class, mut device) = support::class(&alloc, Feature::FD);
+    support::start(&mut device, &mut class, Feature::FD);
+
+    let mut group = c.benchmark_group("from host");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            // echoes are written as the frames are read.
+            device.bus().send_from_host(packets, FRAMES);
+            support::run(&mut device, &mut class);
+        })
+    });
+    group.finish();
+    assert_eq!(
+        class.device.received % FRAMES,
+        0,
+        "frames from the host lost"
+    );
+}
+
+fn from_host(c: &mut Criterion) {
+    let mut classic = classic();
+    classic.echo_id = 0;
+    from_host_packets(c, "classic", &[&classic.as_bytes()[..20]]);
+
+    let mut fd = fd();
+    fd.flags = FrameFlag::FD;
+    let bytes = &fd.as_bytes()[..76];
+    from_host_packets(c, "FD", &[&bytes[..64], &bytes[64..]]);
+}
+
+criterion_group!(
+    benches,
+    classic_to_host,
+    fd_to_host,
+    mixed_to_host,
+    from_host
+);
+criterion_main!(benches);
@@ -0,0 +1,264 @@
+//! gs_usb firmware for an STM32F405 with a single bxCAN channel.
+//!
+//! - USB OTG FS on PA11/PA12.
+//! - CAN1 on PB8 (RX) and PB9 (TX).
+//! - 8 MHz HSE, CAN clocked from the 42 MHz APB1 bus.
+//!
+//! Build with `cargo build --release` from this directory.
+
+#![no_std]
+#![no_main]
+
+use panic_halt as _;
+
+/// bxCAN peripheral clock (APB1).
+const FCLK_CAN: u32 = 42_000_000;
+
+mod can_device {
+    use bxcan::{Can, Interrupt};
+    use core::convert::Infallible;
+    use embedded_can::{ExtendedId, Frame as _, Id, StandardId};
+    use stm32f4xx_hal::{can::Can as HalCan, pac::CAN1};
+    use usbd_gscan::{
+        host::{
+            CanBitTimingConst, DeviceBitTiming, DeviceBitTimingConst, DeviceConfig, DeviceState,
+            Feature, Frame,
+        },
+        Channel, Device,
+    };
+
+    /// bxCAN timing limits.
+    const TIMING: CanBitTimingConst = CanBitTimingConst {
+        tseg1_min: 1,
+        tseg1_max: 16,
+        tseg2_min: 1,
+        tseg2_max: 8,
+        sjw_max: 4,
+        brp_min: 1,
+        brp_max: 1024,
+        brp_inc: 1,
+    };
+
+    const FEATURES: Feature = Feature::LISTEN_ONLY
+        .union(Feature::LOOP_BACK)
+        .union(Feature::ONE_SHOT)
+        .union(Feature::GET_STATE);
+
+    pub struct CanDevice {
+        pub can: Can<HalCan<CAN1>>,
+    }
+
+    impl CanDevice {
+        pub fn new(can: Can<HalCan<CAN1>>) -> Self {
+            Self { can }
+        }
+    }
+
+    /// Bit timing register value, fields are stored minus one.
+    fn btr(timing: &DeviceBitTiming) -> u32 {
+        let tseg1 = timing.prop_seg + timing.phase_seg1;
+        ((timing.sjw - 1) << 24)
+            | ((timing.phase_seg2 - 1) << 20)
+            | ((tseg1 - 1) << 16)
+            | (timing.brp - 1)
+    }
+
+    impl Device for CanDevice {
+        fn config(&self) -> DeviceConfig {
+            DeviceConfig::new(1)
+        }
+
+        fn bit_timing(&self) -> DeviceBitTimingConst {
+            DeviceBitTimingConst {
+                features: FEATURES,
+                fclk_can: super::FCLK_CAN,
+                timing: TIMING,
+            }
+        }
+
+        fn reset(&mut self, _channel: Channel) {
+            self.can.disable_interrupt(Interrupt::Fifo0MessagePending);
+            self.can.disable_interrupt(Interrupt::TransmitMailboxEmpty);
+            self.can.modify_config().leave_disabled();
+        }
+
+        fn start(
+            &mut self,
+            _channel: Channel,
+            features: Feature,
+            nominal: &DeviceBitTiming,
+            _data: Option<&DeviceBitTiming>,
+        ) {
+            self.can
+                .modify_config()
+                .set_bit_timing(btr(nominal))
+                .set_loopback(features.contains(Feature::LOOP_BACK))
+                .set_silent(features.contains(Feature::LISTEN_ONLY))
+                .set_automatic_retransmit(!features.contains(Feature::ONE_SHOT))
+                .enable();
+            self.can.enable_interrupt(Interrupt::Fifo0MessagePending);
+            self.can.enable_interrupt(Interrupt::TransmitMailboxEmpty);
+        }
+
+        fn state(&self, _channel: Channel) -> DeviceState {
+            // error counters could be read from the ESR register here.
+            DeviceState::active(0, 0)
+        }
+
+        fn receive(&mut self, _channel: Channel, frame: &Frame) -> nb::Result<(), Infallible> {
+            match to_bxcan(frame) {
+                // all mailboxes busy, the class holds the frame and retries.
+                Some(frame) => self.can.transmit(&frame).map(|_| ()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// Convert a frame from the host to a bxCAN frame.
+    fn to_bxcan(frame: &Frame) -> Option<bxcan::Frame> {
+        let id: bxcan::Id = match frame.id() {
+            Id::Standard(id) => bxcan::StandardId::new(id.as_raw())?.into(),
+            Id::Extended(id) => bxcan::ExtendedId::new(id.as_raw())?.into(),
+        };
+
+        if frame.is_remote_frame() {
+            Some(bxcan::Frame::new_remote(id, frame.dlc() as u8))
+        } else {
+            Some(bxcan::Frame::new_data(id, bxcan::Data::new(frame.data())?))
+        }
+    }
+
+    /// Convert a bxCAN frame to a frame for the host.
+    pub fn from_bxcan(frame: &bxcan::Frame) -> Option<Frame> {
+        let id: Id = match frame.id() {
+            bxcan::Id::Standard(id) => StandardId::new(id.as_raw())?.into(),
+            bxcan::Id::Extended(id) => ExtendedId::new(id.as_raw())?.into(),
+        };
+
+        match frame.data() {
+            Some(data) => Frame::new(id, data),
+            None => Frame::new_remote(id, frame.dlc() as usize),
+        }
+    }
+}
+
+#[rtic::app(device = stm32f4xx_hal::pac)]
+mod app {
+    use crate::can_device::{from_bxcan, CanDevice};
+    use bxcan::filter::Mask32;
+    use stm32f4xx_hal::{
+        otg_fs::{UsbBus, UsbBusType, USB},
+        pac::Interrupt,
+        prelude::*,
+    };
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
+    use usbd_gscan::{host::FrameFlag, identifier, Channel, GsCan};
+
+    /// The only CAN channel.
+    const CHANNEL: Channel = Channel::new(0).unwrap();
+
+    #[shared]
+    struct Shared {
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        gscan: GsCan<'static, UsbBusType, CanDevice>,
+    }
+
+    #[local]
+    struct Local {}
+
+    #[init(local = [
+        ep_memory: [u32; 1024] = [0; 1024],
+        usb_bus: Option<UsbBusAllocator<UsbBusType>> = None,
+    ])]
+    fn init(cx: init::Context) -> (Shared, Local) {
+        let dp = cx.device;
+
+        let rcc = dp.RCC.constrain();
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.MHz())
+            .sysclk(168.MHz())
+            .pclk1(crate::FCLK_CAN.Hz())
+            .require_pll48clk()
+            .freeze();
+
+        let gpioa = dp.GPIOA.split();
+        let gpiob = dp.GPIOB.split();
+
+        // CAN starts disabled, the host enables it with a mode request.
+        let can = dp.CAN1.can((gpiob.pb9, gpiob.pb8));
+        let mut can = bxcan::Can::builder(can).leave_disabled();
+        can.modify_filters()
+            .enable_bank(0, bxcan::Fifo::Fifo0, Mask32::accept_all());
+
+        let usb = USB::new(
+            (dp.OTG_FS_GLOBAL, dp.OTG_FS_DEVICE, dp.OTG_FS_PWRCLK),
+            (gpioa.pa11, gpioa.pa12),
+            &clocks,
+        );
+        let usb_bus: &'static _ = cx
+            .local
+            .usb_bus
+            .insert(UsbBus::new(usb, cx.local.ep_memory));
+
+        // the class must allocate its endpoints before the device is built.
+        let gscan = GsCan::new(usb_bus, CanDevice::new(can));
+
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, identifier::CANDLELIGHT)
+            .strings(&[StringDescriptors::default()
+                .manufacturer("usbd-gscan")
+                .product("STM32F4 bxCAN example")
+                .serial_number("0001")])
+            .unwrap()
+            .build();
+
+        (Shared { usb_dev, gscan }, Local {})
+    }
+
+    /// The only task touching the USB peripheral.
+    #[task(binds = OTG_FS, shared = [usb_dev, gscan])]
+    fn usb(cx: usb::Context) {
+        (cx.shared.usb_dev, cx.shared.gscan).lock(|usb_dev, gscan| {
+            usb_dev.poll(&mut [gscan]);
+
+            // work queued by the CAN tasks without a bus event.
+            gscan.kick();
+        });
+    }
+
+    #[task(binds = CAN1_TX, shared = [gscan])]
+    fn can_tx(mut cx: can_tx::Context) {
+        cx.shared.gscan.lock(|gscan| {
+            gscan.device.can.clear_tx_interrupt();
+
+            // a mailbox is free, deliver any frame held back from the host.
+            gscan.retry_receive();
+            if gscan.needs_poll() {
+                rtic::pend(Interrupt::OTG_FS);
+            }
+        });
+    }
+
+    #[task(binds = CAN1_RX0, shared = [gscan])]
+    fn can_rx(mut cx: can_rx::Context) {
+        cx.shared.gscan.lock(|gscan| {
+            loop {
+                match gscan.device.can.receive() {
+                    Ok(frame) => {
+                        if let Some(frame) = from_bxcan(&frame) {
+                            gscan.transmit(CHANNEL, &frame, FrameFlag::empty());
+                        }
+                    }
+                    // receive FIFO overrun, frames were lost.
+                    Err(nb::Error::Other(_)) => continue,
+                    Err(nb::Error::WouldBlock) => break,
+                }
+            }
+
+            // transmit() only queues, the USB task writes the frames.
+            if gscan.needs_poll() {
+                rtic::pend(Interrupt::OTG_FS);
+            }
+        });
+    }
+}
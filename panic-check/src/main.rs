@@ -0,0 +1,84 @@
+//! Fails to link if handling frames can panic.
+//!
+//! The panic handler calls a function that doesn't exist, so the build only
+//! links once the optimiser has removed every call to it. Built in release
+//! with the `panic-free` feature:
+//!
+//! ```text
+//! cargo build --release --target thumbv7em-none-eabihf
+//! ```
+//!
+//! Values the check can't know at compile time come from volatile reads, so
+//! checks on them aren't optimised away.
+//!
+//! Only the frames of `usbd-gscan-protocol` are checked. Every endpoint of
+//! usb-device 0.3 panics if used before the bus is frozen, which the compiler
+//! can't rule out, so the class itself relies on the `panic-free` lints.
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use core::ptr;
+use embedded_can::{Frame as _, StandardId};
+use usbd_gscan_protocol::{dlc, ErrorClass, Frame, FrameKind};
+use zerocopy::FromBytes;
+
+extern "C" {
+    /// Not defined anywhere, referenced only while a panic is possible.
+    fn usbd_gscan_can_panic() -> !;
+}
+
+#[panic_handler]
+fn panic(_: &PanicInfo) -> ! {
+    unsafe { usbd_gscan_can_panic() }
+}
+
+/// A value the compiler can't see through.
+fn opaque<T: Copy>(value: T) -> T {
+    let value = value;
+    unsafe { ptr::read_volatile(&value) }
+}
+
+/// Keep a value the compiler can't prove unused.
+fn sink<T: Copy>(value: T) {
+    let mut slot = value;
+    unsafe { ptr::write_volatile(&mut slot, value) }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    loop {
+        // a frame as read from the host.
+        let mut bytes = [0; core::mem::size_of::<Frame>()];
+        for byte in bytes.iter_mut() {
+            *byte = opaque(0);
+        }
+        let Some(mut frame) = Frame::read_from(&bytes[..]) else {
+            continue;
+        };
+
+        frame.sanitize(opaque(true));
+        sink(frame.id());
+        sink(frame.dlc());
+        sink(frame.data().len());
+        sink(frame.data_len());
+        sink(frame.kind());
+        sink(frame.set_fd(opaque(true)).is_ok());
+        sink(frame.set_brs(opaque(true)).is_ok());
+        sink(frame.set_esi(opaque(true)).is_ok());
+        frame.set_kind(opaque(FrameKind::Receive));
+
+        let data = [0; 64];
+        let len = opaque(64usize).min(data.len());
+        if let Some(data) = data.get(..len) {
+            sink(Frame::new_raw(opaque(0), data).is_ok());
+            sink(Frame::new(StandardId::ZERO, data).is_some());
+        }
+        sink(dlc::round_len_to_next_dlc(opaque(10)));
+        sink(Frame::new_remote(frame.id(), opaque(8)).is_some());
+
+        let mut error = Frame::new_error(opaque(ErrorClass::BUS_OFF), [opaque(0); 8]);
+        sink(error.copy_from(&frame).is_some());
+    }
+}
@@ -0,0 +1,59 @@
+//! Conversions between CAN FD data lengths and DLCs.
+//!
+//! These follow the CAN FD table whatever the `fd` feature, e.g. for sizing
+//! the buffers of a CAN controller. DLCs 0 to 8 are the classic lengths.
+
+/// Longest CAN FD payload.
+pub const MAX_FD_LEN: usize = 64;
+
+/// Data length of a DLC, `None` above 15.
+pub const fn fd_dlc_to_len(dlc: usize) -> Option<usize> {
+    match dlc {
+        0..=8 => Some(dlc),
+        9 => Some(12),
+        10 => Some(16),
+        11 => Some(20),
+        12 => Some(24),
+        13 => Some(32),
+        14 => Some(48),
+        15 => Some(64),
+        _ => None,
+    }
+}
+
+/// DLC of a data length, `None` unless [`is_valid_fd_len`].
+pub const fn fd_len_to_dlc(len: usize) -> Option<u8> {
+    match len {
+        0..=8 => Some(len as u8),
+        12 => Some(9),
+        16 => Some(10),
+        20 => Some(11),
+        24 => Some(12),
+        32 => Some(13),
+        48 => Some(14),
+        64 => Some(15),
+        _ => None,
+    }
+}
+
+/// Whether a CAN FD frame can carry exactly `len` bytes.
+pub const fn is_valid_fd_len(len: usize) -> bool {
+    fd_len_to_dlc(len).is_some()
+}
+
+/// DLC of the shortest CAN FD payload holding `len` bytes, with its length,
+/// e.g. `(9, 12)` for 10 bytes.
+///
+/// Lengths over [`MAX_FD_LEN`] give `(15, 64)`, the caller truncates.
+pub const fn round_len_to_next_dlc(len: usize) -> (u8, usize) {
+    let mut dlc = 0;
+    while dlc < 15 {
+        if let Some(padded) = fd_dlc_to_len(dlc as usize) {
+            if padded >= len {
+                return (dlc, padded);
+            }
+        }
+        dlc += 1;
+    }
+    (15, MAX_FD_LEN)
+}
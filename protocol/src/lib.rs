@@ -0,0 +1,1305 @@
+//! Wire definitions of the Geschwister Schneider USB/CAN protocol, shared by
+//! the `usbd-gscan` device class and host tools.
+//!
+//! The structs are laid out as in the Linux `gs_usb` driver and read and
+//! written with `zerocopy`. This crate doesn't depend on `usb-device`, so a
+//! host application can use it with any USB stack. The `fd` and `defmt-03`
+//! features are as those of `usbd-gscan`.
+
+#![no_std]
+#![cfg_attr(
+    feature = "panic-free",
+    deny(clippy::unwrap_used, clippy::expect_used, clippy::indexing_slicing)
+)]
+
+use bitflags::bitflags;
+use embedded_can::{ExtendedId, Id, StandardId};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+pub mod dlc;
+
+// `bRequest` of the vendor requests.
+pub const REQ_HOST_FORMAT: u8 = 0;
+pub const REQ_BIT_TIMING: u8 = 1;
+pub const REQ_MODE: u8 = 2;
+pub const REQ_BUS_ERROR: u8 = 3;
+pub const REQ_BIT_TIMING_CONST: u8 = 4;
+pub const REQ_DEVICE_CONFIG: u8 = 5;
+pub const REQ_TIMESTAMP: u8 = 6;
+pub const REQ_IDENTIFY: u8 = 7;
+pub const REQ_GET_USER_ID: u8 = 8;
+pub const REQ_SET_USER_ID: u8 = 9;
+pub const REQ_BIT_TIMING_DATA: u8 = 10;
+pub const REQ_BIT_TIMING_CONST_EXT: u8 = 11;
+pub const REQ_SET_TERMINATION: u8 = 12;
+pub const REQ_GET_TERMINATION: u8 = 13;
+pub const REQ_GET_STATE: u8 = 14;
+/// Request for the [`Capability`] list of a `usbd-gscan` device, one byte
+/// each. An extension outside the `gs_usb` requests, answered only if the
+/// firmware enables it.
+pub const REQ_CAPABILITIES: u8 = 0x80;
+
+/// A behaviour of the `usbd-gscan` firmware beyond the [`Feature`] bits, as
+/// listed by [`REQ_CAPABILITIES`].
+///
+/// Non-exhaustive, as later versions may list more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[non_exhaustive]
+#[repr(u8)]
+pub enum Capability {
+    /// Echoes may wait until the frame is sent on the bus.
+    DeviceEcho = 0,
+    /// Frames carry a timestamp with [`Feature::HW_TIMESTAMP`].
+    HwTimestamp = 1,
+    /// CAN FD frames.
+    Fd = 2,
+    /// Frames to the host may carry a sequence number in the reserved byte.
+    SequenceNumbers = 3,
+    /// Frames from the host one byte longer, with
+    /// [`Feature::REQ_USB_QUIRK_LPC546XX`].
+    Lpc546xxQuirk = 4,
+    /// The firmware can loop frames back to the host for a board test.
+    SelfTest = 5,
+    /// Channels may be shared by hosts on several USB ports.
+    SharedChannels = 6,
+    /// Frame handling is built without panics.
+    PanicFree = 7,
+}
+
+impl TryFrom<u8> for Capability {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::DeviceEcho,
+            1 => Self::HwTimestamp,
+            2 => Self::Fd,
+            3 => Self::SequenceNumbers,
+            4 => Self::Lpc546xxQuirk,
+            5 => Self::SelfTest,
+            6 => Self::SharedChannels,
+            7 => Self::PanicFree,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// [`HostConfig::byte_order`] of a little endian host.
+pub const HOST_LITTLE_ENDIAN: u32 = 0x0000beef;
+
+/// Maximum number of interfaces. Defined in the Linux driver.
+/// This may change in future.
+pub const MAX_INTF: usize = 3;
+
+/// Bytes of the [`Frame`] header, before the payload.
+pub const FRAME_HEADER_LEN: usize = core::mem::offset_of!(Frame, can_data);
+
+/// Bytes of the timestamp after the payload of frames to the host.
+pub const TIMESTAMP_LEN: usize = 4;
+
+/// Room for the payload in the frames exchanged with the host.
+///
+/// Non-exhaustive, as hosts may gain larger frames, e.g. for CAN XL.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Payload {
+    /// Up to 8 bytes.
+    #[default]
+    Classic,
+    /// Up to 64 bytes.
+    Fd,
+}
+
+impl Payload {
+    /// Longest payload, the bytes it takes on the wire.
+    pub const fn max_len(self) -> usize {
+        match self {
+            Self::Classic => 8,
+            Self::Fd => dlc::MAX_FD_LEN,
+        }
+    }
+}
+
+/// Size of the frames exchanged with the host on a channel, set by the flags
+/// the host last sent in a mode request.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WireFormat {
+    /// Room for the payload
+    pub payload: Payload,
+    /// Frames to the host end with a timestamp
+    pub timestamp: bool,
+}
+
+impl WireFormat {
+    /// The format of a channel started with `flags`.
+    pub fn new(flags: Feature) -> Self {
+        let payload = if cfg!(feature = "fd") && flags.contains(Feature::FD) {
+            Payload::Fd
+        } else {
+            Payload::Classic
+        };
+
+        Self {
+            payload,
+            timestamp: flags.contains(Feature::HW_TIMESTAMP),
+        }
+    }
+
+    /// Whether frames have room for a CAN FD payload.
+    pub fn is_fd(self) -> bool {
+        self.payload == Payload::Fd
+    }
+
+    /// Bytes of the payload.
+    pub fn data_len(self) -> usize {
+        self.payload.max_len()
+    }
+
+    /// Bytes of a frame to the host.
+    pub fn in_len(self) -> usize {
+        FRAME_HEADER_LEN + self.data_len() + if self.timestamp { TIMESTAMP_LEN } else { 0 }
+    }
+
+    /// Bytes of a frame from the host, which never carries a timestamp.
+    pub fn out_len(self) -> usize {
+        FRAME_HEADER_LEN + self.data_len()
+    }
+}
+
+/// Tells the device the byte order of the host.
+///
+/// `byte_order` will contain `0x0000beef` for little endian and `0xefbe0000`
+/// for big endian.
+#[derive(Debug, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct HostConfig {
+    pub byte_order: u32,
+}
+
+/// Device configuration.
+///
+/// `interface_count`
+#[derive(Debug, Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct DeviceConfig {
+    _reserved0: u8,
+    _reserved1: u8,
+    _reserved2: u8,
+    pub interface_count: u8,
+    pub software_version: u32,
+    pub hardware_version: u32,
+}
+
+/// Errors creating a device config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum ConfigError {
+    /// No interfaces.
+    NoInterfaces,
+    /// More interfaces than the channels supported, [`MAX_INTF`].
+    TooManyInterfaces,
+}
+
+impl DeviceConfig {
+    /// Creates a new device config.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of interfaces is 0 or more than the channels
+    /// supported, see [`DeviceConfig::try_new`]. In a const or static this is
+    /// a compile error.
+    pub const fn new(interfaces: u8) -> Self {
+        match Self::try_new(interfaces) {
+            Ok(config) => config,
+            Err(ConfigError::NoInterfaces) => panic!("device config without interfaces"),
+            Err(ConfigError::TooManyInterfaces) => panic!("more interfaces than supported"),
+        }
+    }
+
+    /// Creates a new device config, e.g. with a number of interfaces read
+    /// from configuration data.
+    pub const fn try_new(interfaces: u8) -> Result<Self, ConfigError> {
+        if interfaces == 0 {
+            return Err(ConfigError::NoInterfaces);
+        }
+        if interfaces as usize > MAX_INTF {
+            return Err(ConfigError::TooManyInterfaces);
+        }
+
+        // API useses N-1 to represent the interface count.
+        let interface_count = interfaces - 1;
+
+        Ok(Self {
+            _reserved0: 0,
+            _reserved1: 0,
+            _reserved2: 0,
+            interface_count,
+            software_version: 2, // to match candleLight firmware.
+            hardware_version: 0,
+        })
+    }
+}
+
+/// Device mode.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum Mode {
+    Reset = 0,
+    Start = 1,
+}
+
+impl TryFrom<u32> for Mode {
+    type Error = ();
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Self::Reset as u32 => Ok(Self::Reset),
+            x if x == Self::Start as u32 => Ok(Self::Start),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct DeviceMode {
+    pub mode: u32,
+    pub flags: Feature,
+}
+
+/// Same as Linux netlink can_state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(u32)]
+pub enum CanState {
+    /// RX/TX error count < 96
+    Active = 0,
+    /// RX/TX error count < 128
+    Warning = 1,
+    /// RX/TX error count < 256
+    Passive = 2,
+    /// RX/TX error count >= 256
+    BusOff = 3,
+    /// Device is stopped
+    Stopped = 4,
+    /// Device is sleeping
+    Sleeping = 5,
+}
+
+impl From<CanState> for u32 {
+    fn from(value: CanState) -> Self {
+        value as u32
+    }
+}
+
+impl TryFrom<u32> for CanState {
+    type Error = ();
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Self::Active as u32 => Ok(Self::Active),
+            x if x == Self::Warning as u32 => Ok(Self::Warning),
+            x if x == Self::Passive as u32 => Ok(Self::Passive),
+            x if x == Self::BusOff as u32 => Ok(Self::BusOff),
+            x if x == Self::Stopped as u32 => Ok(Self::Stopped),
+            x if x == Self::Sleeping as u32 => Ok(Self::Sleeping),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct DeviceState {
+    pub state: CanState,
+    pub rx_errors: u32,
+    pub tx_errors: u32,
+}
+
+/// [`DeviceState`] as read from the wire, with the state unchecked.
+///
+/// Converted to a [`DeviceState`] with [`TryFrom`], which fails if the state
+/// isn't a [`CanState`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct RawDeviceState {
+    pub state: u32,
+    pub rx_errors: u32,
+    pub tx_errors: u32,
+}
+
+impl From<DeviceState> for RawDeviceState {
+    fn from(value: DeviceState) -> Self {
+        Self {
+            state: value.state.into(),
+            rx_errors: value.rx_errors,
+            tx_errors: value.tx_errors,
+        }
+    }
+}
+
+impl TryFrom<RawDeviceState> for DeviceState {
+    type Error = ();
+
+    fn try_from(value: RawDeviceState) -> Result<Self, Self::Error> {
+        Ok(Self {
+            state: value.state.try_into()?,
+            rx_errors: value.rx_errors,
+            tx_errors: value.tx_errors,
+        })
+    }
+}
+
+impl DeviceState {
+    /// Creates an error active state, both counters below 96.
+    pub fn active(tx_errors: u8, rx_errors: u8) -> Self {
+        Self::new(CanState::Active, tx_errors.into(), rx_errors.into())
+    }
+
+    /// Creates an error warning state, a counter from 96 to 127.
+    pub fn warning(tx_errors: u8, rx_errors: u8) -> Self {
+        Self::new(CanState::Warning, tx_errors.into(), rx_errors.into())
+    }
+
+    /// Creates an error passive state, a counter from 128 to 255.
+    pub fn passive(tx_errors: u8, rx_errors: u8) -> Self {
+        Self::new(CanState::Passive, tx_errors.into(), rx_errors.into())
+    }
+
+    /// Creates a bus off state from the last counter values.
+    ///
+    /// Bus off is entered once the transmit error counter passes 255, which
+    /// 8-bit counters wrap or saturate at, so the transmit error count is
+    /// reported as 256 plus `tx_errors`.
+    pub fn bus_off(tx_errors: u8, rx_errors: u8) -> Self {
+        Self::new(
+            CanState::BusOff,
+            256 + u32::from(tx_errors),
+            rx_errors.into(),
+        )
+    }
+
+    fn new(state: CanState, tx_errors: u32, rx_errors: u32) -> Self {
+        let device_state = Self {
+            state,
+            rx_errors,
+            tx_errors,
+        };
+        debug_assert!(
+            device_state.is_consistent(),
+            "error counters disagree with the CAN state"
+        );
+
+        device_state
+    }
+
+    /// Returns `true` if the error counters are within the thresholds of the
+    /// state.
+    pub fn is_consistent(&self) -> bool {
+        let errors = self.tx_errors.max(self.rx_errors);
+        match self.state {
+            CanState::Active => errors < 96,
+            CanState::Warning => (96..128).contains(&errors),
+            CanState::Passive => (128..256).contains(&errors),
+            CanState::BusOff => self.tx_errors >= 256,
+            CanState::Stopped | CanState::Sleeping => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct DeviceBitTiming {
+    pub prop_seg: u32,
+    pub phase_seg1: u32,
+    pub phase_seg2: u32,
+    pub sjw: u32,
+    pub brp: u32,
+}
+
+impl DeviceBitTiming {
+    /// Converts to the SJA1000 bus timing registers `(BTR0, BTR1)`.
+    ///
+    /// As in the SJA1000 datasheet, BTR0 holds `SJW - 1` in bits 7:6 and
+    /// `BRP - 1` in bits 5:0, BTR1 holds `TSEG2 - 1` in bits 6:4 and
+    /// `TSEG1 - 1` in bits 3:0 where `TSEG1` is `prop_seg + phase_seg1`. The
+    /// sampling bit (BTR1 bit 7) is left clear.
+    ///
+    /// The SJA1000 divides its oscillator by two before the prescaler, so
+    /// advertise half the oscillator frequency as the CAN clock, like the
+    /// Linux sja1000 driver. Returns `None` if a value is out of the SJA1000's
+    /// range.
+    pub fn to_btr(&self) -> Option<(u8, u8)> {
+        let tseg1 = self.prop_seg.saturating_add(self.phase_seg1);
+        if !(1..=4).contains(&self.sjw)
+            || !(1..=64).contains(&self.brp)
+            || !(1..=16).contains(&tseg1)
+            || !(1..=8).contains(&self.phase_seg2)
+        {
+            return None;
+        }
+
+        let btr0 = ((self.sjw - 1) << 6) | (self.brp - 1);
+        let btr1 = ((self.phase_seg2 - 1) << 4) | (tseg1 - 1);
+
+        Some((btr0 as u8, btr1 as u8))
+    }
+
+    /// Bitrate in bit/s with the CAN clock `fclk_can`, rounded down.
+    ///
+    /// Returns `None` if `brp` is 0 or the bit is too long to calculate.
+    pub fn bitrate(&self, fclk_can: u32) -> Option<u32> {
+        let tq = 1_u32
+            .checked_add(self.prop_seg)?
+            .checked_add(self.phase_seg1)?
+            .checked_add(self.phase_seg2)?;
+        fclk_can.checked_div(self.brp.checked_mul(tq)?)
+    }
+
+    /// Converts from the SJA1000 bus timing registers, the inverse of
+    /// [`DeviceBitTiming::to_btr`].
+    ///
+    /// The registers only hold `TSEG1`, it is split between `prop_seg` and
+    /// `phase_seg1` as the Linux driver does. The sampling bit is ignored.
+    pub fn from_btr(btr0: u8, btr1: u8) -> Self {
+        let tseg1 = u32::from(btr1 & 0x0F) + 1;
+        let prop_seg = tseg1 / 2;
+
+        Self {
+            prop_seg,
+            phase_seg1: tseg1 - prop_seg,
+            phase_seg2: u32::from((btr1 >> 4) & 0x07) + 1,
+            sjw: u32::from(btr0 >> 6) + 1,
+            brp: u32::from(btr0 & 0x3F) + 1,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct CanBitTimingConst {
+    pub tseg1_min: u32,
+    pub tseg1_max: u32,
+    pub tseg2_min: u32,
+    pub tseg2_max: u32,
+    pub sjw_max: u32,
+    pub brp_min: u32,
+    pub brp_max: u32,
+    pub brp_inc: u32,
+}
+
+impl CanBitTimingConst {
+    /// Creates timing limits with minimums of 1 and a prescaler step of 1, as
+    /// most controllers have.
+    ///
+    /// Other limits can be set with struct update syntax, e.g.
+    /// `CanBitTimingConst { brp_inc: 2, ..CanBitTimingConst::new(16, 8, 4, 1024) }`.
+    pub const fn new(tseg1_max: u32, tseg2_max: u32, sjw_max: u32, brp_max: u32) -> Self {
+        Self {
+            tseg1_min: 1,
+            tseg1_max,
+            tseg2_min: 1,
+            tseg2_max,
+            sjw_max,
+            brp_min: 1,
+            brp_max,
+            brp_inc: 1,
+        }
+    }
+}
+
+/// Formats flags by name, with any unknown bits in hex, e.g.
+/// `FrameFlag(FD | 0x80)`.
+macro_rules! impl_flags_fmt {
+    ($name:ident) => {
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                write!(f, "{}(", stringify!($name))?;
+                if self.is_empty() {
+                    write!(f, "{:#x}", self.bits())?;
+                } else {
+                    bitflags::parser::to_writer(self, &mut *f)?;
+                }
+                f.write_str(")")
+            }
+        }
+
+        #[cfg(feature = "defmt-03")]
+        impl defmt::Format for $name {
+            fn format(&self, f: defmt::Formatter) {
+                defmt::write!(f, "{=str}(", stringify!($name));
+                let mut first = true;
+                for (name, _) in self.iter_names() {
+                    if !first {
+                        defmt::write!(f, " | ");
+                    }
+                    first = false;
+                    defmt::write!(f, "{=str}", name);
+                }
+                let unknown = self.bits() & !Self::all().bits();
+                if unknown != 0 || first {
+                    if !first {
+                        defmt::write!(f, " | ");
+                    }
+                    defmt::write!(f, "{:#x}", unknown);
+                }
+                defmt::write!(f, ")");
+            }
+        }
+    };
+}
+
+/// Features flags that can be advertised by the device.
+///
+/// Bits without a name are kept as they are on the wire, see
+/// [`Feature::known`] and [`Feature::raw_bits`].
+#[derive(Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Feature(u32);
+
+bitflags! {
+    impl Feature: u32 {
+        const LISTEN_ONLY = 1 << 0;
+        const LOOP_BACK = 1 << 1;
+        const TRIPLE_SAMPLE = 1 << 2;
+        const ONE_SHOT = 1 << 3;
+        const HW_TIMESTAMP = 1 << 4;
+        const IDENTIFY = 1 << 5;
+        const USER_ID = 1 << 6;
+        const PAD_PKTS_TO_MAX_PKT_SIZE = 1 << 7;
+        const FD = 1 << 8;
+        const REQ_USB_QUIRK_LPC546XX = 1 << 9;
+        const BT_CONST_EXT = 1 << 10;
+        const TERMINATION = 1 << 11;
+        const BUS_ERROR_REPORTING = 1 << 12;
+        const GET_STATE = 1 << 13;
+    }
+}
+
+impl_flags_fmt!(Feature);
+
+impl Feature {
+    /// The named flags, without any unknown bits.
+    pub const fn known(self) -> Self {
+        Self::from_bits_truncate(self.bits())
+    }
+
+    /// All bits as received from the host, including unknown ones.
+    pub const fn raw_bits(self) -> u32 {
+        self.0
+    }
+
+    /// The controller modes most CAN peripherals support: listen only,
+    /// loopback and one shot.
+    pub const GS_DEFAULT: Self = Self::LISTEN_ONLY
+        .union(Self::LOOP_BACK)
+        .union(Self::ONE_SHOT);
+
+    /// CAN FD with the extended timing request, which the Linux driver expects
+    /// of CAN FD devices.
+    pub const CAN_FD: Self = Self::FD.union(Self::BT_CONST_EXT);
+}
+
+/// Device bit timing and feature flags.
+#[derive(Debug, Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct DeviceBitTimingConst {
+    pub features: Feature,
+    pub fclk_can: u32,
+    pub timing: CanBitTimingConst,
+}
+
+impl DeviceBitTimingConst {
+    /// Creates the timing options of a device with a single set of timing
+    /// limits, e.g. in a static.
+    ///
+    /// CAN FD devices with other limits for the data phase advertise
+    /// [`Feature::BT_CONST_EXT`] and give them in a
+    /// [`DeviceBitTimingConstExtended`].
+    pub const fn classic(features: Feature, fclk_can: u32, timing: CanBitTimingConst) -> Self {
+        Self {
+            features,
+            fclk_can,
+            timing,
+        }
+    }
+}
+
+/// Device extended bit timing and feature flags for CAN FD devices.
+#[derive(Debug, Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct DeviceBitTimingConstExtended {
+    pub features: Feature,
+    pub fclk_can: u32,
+    pub timing_nominal: CanBitTimingConst,
+    pub timing_data: CanBitTimingConst,
+}
+
+impl DeviceBitTimingConstExtended {
+    /// Creates the timing options of a CAN FD device, e.g. in a static.
+    pub const fn new(
+        features: Feature,
+        fclk_can: u32,
+        timing_nominal: CanBitTimingConst,
+        timing_data: CanBitTimingConst,
+    ) -> Self {
+        Self {
+            features,
+            fclk_can,
+            timing_nominal,
+            timing_data,
+        }
+    }
+}
+
+/// [`IdentifyMode::mode`] turning identification off, any other value turns
+/// it on.
+pub const IDENTIFY_OFF: u32 = 0;
+/// [`IdentifyMode::mode`] turning identification on.
+pub const IDENTIFY_ON: u32 = 1;
+
+#[derive(Debug, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct IdentifyMode {
+    pub mode: u32,
+}
+
+/// [`DeviceTerminationState::state`] of a termination resistor that is off,
+/// any other value is on.
+pub const TERMINATION_STATE_OFF: u32 = 0;
+/// [`DeviceTerminationState::state`] of a termination resistor that is on.
+pub const TERMINATION_STATE_ON: u32 = 1;
+
+#[derive(Debug, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct DeviceTerminationState {
+    pub state: u32,
+}
+
+/// Size of [`CanData`], the largest payload and timestamp supported.
+#[cfg(feature = "fd")]
+const CAN_DATA_LEN: usize = 68;
+#[cfg(not(feature = "fd"))]
+const CAN_DATA_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct ClassicCan {
+    pub data: [u8; 8],
+    _padding: [u8; CAN_DATA_LEN - 8],
+}
+
+#[derive(Debug, Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct ClassicCanTimestamp {
+    pub data: [u8; 8],
+    pub timestamp_us: u32,
+    _padding: [u8; CAN_DATA_LEN - 12],
+}
+
+#[cfg(feature = "fd")]
+#[derive(Debug, Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct CanFd {
+    pub data: [u8; 64],
+    _padding: [u8; 4],
+}
+
+#[cfg(feature = "fd")]
+#[derive(Debug, Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(C)]
+pub struct CanFdTimestamp {
+    pub data: [u8; 64],
+    pub timestamp_us: u32,
+}
+
+#[derive(Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub union CanData {
+    pub classic_can: ClassicCan,
+    pub classic_can_timestamp: ClassicCanTimestamp,
+    #[cfg(feature = "fd")]
+    pub can_fd: CanFd,
+    #[cfg(feature = "fd")]
+    pub can_fd_timestamp: CanFdTimestamp,
+}
+
+/// Frame flags.
+#[derive(Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct FrameFlag(u8);
+
+bitflags! {
+    impl FrameFlag: u8 {
+        /// Receive/transmit overflow.
+        const OVERFLOW = 1 << 0;
+        /// FD type frame.
+        const FD = 1 << 1;
+        /// FD bit rate switching in use.
+        const BIT_RATE_SWITCH = 1 << 2;
+        /// FD error state indicator.
+        const ERROR_STATE_INDICATOR = 1 << 3;
+    }
+}
+
+impl_flags_fmt!(FrameFlag);
+
+/// A frame as exchanged with the host, `struct gs_host_frame`.
+///
+/// Plain data, so it is [`Copy`], [`Send`] and [`Sync`] and may be passed
+/// between an interrupt and a thread, e.g. through a `heapless` channel.
+#[derive(Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Frame {
+    pub echo_id: u32,
+    pub can_id: u32,
+    pub can_dlc: u8,
+    pub interface: u8,
+    pub flags: FrameFlag,
+    _reserved0: u8,
+    pub can_data: CanData,
+}
+
+/// Whether a frame to the host was received from the bus or echoes a frame
+/// from the host, as told by its `echo_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum FrameKind {
+    /// Received from the bus, including error frames, sent with an `echo_id`
+    /// of [`FrameKind::RECEIVE_ID`].
+    Receive,
+    /// The echo of the frame from the host with this `echo_id`.
+    Echo(u32),
+}
+
+impl FrameKind {
+    /// `echo_id` of receive frames.
+    pub const RECEIVE_ID: u32 = u32::MAX;
+
+    /// The kind of a frame with an `echo_id`.
+    pub const fn from_echo_id(echo_id: u32) -> Self {
+        if echo_id == Self::RECEIVE_ID {
+            Self::Receive
+        } else {
+            Self::Echo(echo_id)
+        }
+    }
+
+    /// The `echo_id` of a frame of this kind. An echo of
+    /// [`FrameKind::RECEIVE_ID`] is indistinguishable from a receive frame.
+    pub const fn echo_id(self) -> u32 {
+        match self {
+            Self::Receive => Self::RECEIVE_ID,
+            Self::Echo(echo_id) => echo_id,
+        }
+    }
+}
+
+/// Errors creating a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum FrameError {
+    /// Identifier is wider than 11 bits without [`IdFlag::EXTENDED`] set.
+    InvalidId,
+    /// Data length is not a valid CAN or CAN FD length.
+    InvalidLength,
+    /// Flag only valid for CAN FD frames set on a classic frame.
+    NotFd,
+    /// CAN FD frame without the `fd` feature.
+    FdUnsupported,
+}
+
+impl Frame {
+    /// Payload length implied by the DLC and frame type.
+    ///
+    /// Classic DLC values above 8 still carry 8 bytes, `None` if the DLC is
+    /// out of range.
+    #[doc(hidden)]
+    pub fn data_len(&self) -> Option<usize> {
+        let dlc = self.can_dlc as usize;
+        if self.is_fd() {
+            // FD frames can't be stored without the `fd` feature.
+            dlc::fd_dlc_to_len(dlc).filter(|_| cfg!(feature = "fd"))
+        } else if dlc <= 15 {
+            Some(dlc.min(8))
+        } else {
+            None
+        }
+    }
+
+    /// Creates a frame from an identifier word including [`IdFlag`] bits.
+    ///
+    /// Avoids going through [`Id`] when the identifier comes from a CAN
+    /// driver as a raw value.
+    pub fn new_raw(can_id: u32, data: &[u8]) -> Result<Self, FrameError> {
+        let id = can_id & 0x1FFFFFFF;
+        if can_id & IdFlag::EXTENDED.bits() == 0 && id > StandardId::MAX.as_raw() as u32 {
+            return Err(FrameError::InvalidId);
+        }
+
+        let mut frame = Frame::new_zeroed();
+        frame.can_dlc = len_to_dlc(data.len()).ok_or(FrameError::InvalidLength)?;
+        frame.can_id = can_id;
+
+        frame.set_payload(data).ok_or(FrameError::InvalidLength)?;
+
+        Ok(frame)
+    }
+
+    /// Returns the identifier word including [`IdFlag`] bits.
+    pub fn raw_id(&self) -> u32 {
+        self.can_id
+    }
+
+    /// Returns `true` for a CAN FD frame.
+    pub fn is_fd(&self) -> bool {
+        self.flags.contains(FrameFlag::FD)
+    }
+
+    /// Returns `true` if the data phase uses the faster bit rate.
+    ///
+    /// Only set for CAN FD frames.
+    pub fn brs(&self) -> bool {
+        self.flags.contains(FrameFlag::BIT_RATE_SWITCH)
+    }
+
+    /// Returns `true` if the transmitter is error passive.
+    ///
+    /// Only set for CAN FD frames.
+    pub fn esi(&self) -> bool {
+        self.flags.contains(FrameFlag::ERROR_STATE_INDICATOR)
+    }
+
+    /// Set whether the frame is a CAN FD frame.
+    ///
+    /// The DLC is kept, so the type can only change whilst it is 8 or below,
+    /// otherwise [`FrameError::InvalidLength`] is returned. Making the frame
+    /// classic also clears bit rate switching and the error state indicator.
+    pub fn set_fd(&mut self, fd: bool) -> Result<(), FrameError> {
+        if fd == self.is_fd() {
+            return Ok(());
+        }
+
+        if fd && !cfg!(feature = "fd") {
+            return Err(FrameError::FdUnsupported);
+        }
+
+        if self.can_dlc > 8 {
+            return Err(FrameError::InvalidLength);
+        }
+
+        if fd {
+            self.flags |= FrameFlag::FD;
+        } else {
+            self.flags -=
+                FrameFlag::FD | FrameFlag::BIT_RATE_SWITCH | FrameFlag::ERROR_STATE_INDICATOR;
+        }
+
+        Ok(())
+    }
+
+    /// Set whether the data phase uses the faster bit rate.
+    ///
+    /// Returns [`FrameError::NotFd`] when set on a classic frame.
+    pub fn set_brs(&mut self, brs: bool) -> Result<(), FrameError> {
+        self.set_fd_flag(FrameFlag::BIT_RATE_SWITCH, brs)
+    }
+
+    /// Set whether the transmitter is error passive.
+    ///
+    /// Returns [`FrameError::NotFd`] when set on a classic frame.
+    pub fn set_esi(&mut self, esi: bool) -> Result<(), FrameError> {
+        self.set_fd_flag(FrameFlag::ERROR_STATE_INDICATOR, esi)
+    }
+
+    /// Set a flag only valid for CAN FD frames.
+    fn set_fd_flag(&mut self, flag: FrameFlag, value: bool) -> Result<(), FrameError> {
+        if value && !self.is_fd() {
+            return Err(FrameError::NotFd);
+        }
+
+        self.flags.set(flag, value);
+
+        Ok(())
+    }
+
+    /// Creates an error frame for the host.
+    ///
+    /// `data` carries the details of each class, e.g. a [`ControllerError`] in
+    /// byte 1. Error frames always have a DLC of 8.
+    pub fn new_error(class: ErrorClass, data: [u8; 8]) -> Self {
+        let mut frame = Frame::new_zeroed();
+        frame.can_id = IdFlag::ERROR.bits() | class.bits();
+        frame.can_dlc = 8;
+        // every payload has room for 8 bytes.
+        frame.set_payload(&data);
+
+        frame
+    }
+
+    /// Returns `true` for an error frame.
+    pub fn is_error_frame(&self) -> bool {
+        self.can_id & IdFlag::ERROR.bits() != 0
+    }
+
+    /// Returns whether the frame was received from the bus or is an echo.
+    pub fn kind(&self) -> FrameKind {
+        FrameKind::from_echo_id(self.echo_id)
+    }
+
+    /// Set the `echo_id` of the frame from its kind.
+    pub fn set_kind(&mut self, kind: FrameKind) {
+        self.echo_id = kind.echo_id();
+    }
+
+    /// Returns the sequence number in the reserved byte.
+    ///
+    /// A nonstandard extension for finding frames lost on the way to the host,
+    /// which hosts ignore, see `GsCan::with_sequence_numbers` of `usbd-gscan`.
+    pub fn sequence(&self) -> u8 {
+        self._reserved0
+    }
+
+    /// Set the sequence number in the reserved byte, see [`Frame::sequence`].
+    pub fn set_sequence(&mut self, sequence: u8) {
+        self._reserved0 = sequence;
+    }
+
+    /// Zero the reserved byte and the data past the payload, so no stale bytes
+    /// are sent to the host.
+    ///
+    /// `wire_fd` is whether the frame is sent with room for a CAN FD payload,
+    /// zeroing up to 64 rather than 8 bytes of data. A timestamp is kept.
+    pub fn sanitize(&mut self, wire_fd: bool) {
+        self._reserved0 = 0;
+
+        let remote = self.can_id & IdFlag::REMOTE.bits() != 0;
+        let len = if remote {
+            0
+        } else {
+            self.data_len().unwrap_or(0)
+        };
+        let payload = self.payload_mut();
+        let end = payload.len().min(if wire_fd { 64 } else { 8 });
+        if let Some(stale) = payload.get_mut(len.min(end)..end) {
+            stale.fill(0);
+        }
+    }
+
+    /// Overwrite with the identifier and data of another frame, in place.
+    ///
+    /// Returns `None` if the data length is invalid.
+    #[doc(hidden)]
+    pub fn copy_from(&mut self, frame: &impl embedded_can::Frame) -> Option<()> {
+        if frame.is_remote_frame() {
+            self.zero();
+            self.set_id(frame.id());
+            self.can_id |= IdFlag::REMOTE.bits();
+            self.can_dlc = frame.dlc() as u8;
+            Some(())
+        } else {
+            self.copy_from_parts(frame.id(), frame.data(), false)
+        }
+    }
+
+    /// Overwrite with an identifier and data, in place.
+    ///
+    /// For a remote frame only the length of `data` is kept, as its DLC.
+    /// Returns `None` if the data length is invalid.
+    #[doc(hidden)]
+    pub fn copy_from_parts(&mut self, id: Id, data: &[u8], remote: bool) -> Option<()> {
+        self.zero();
+        self.set_id(id);
+        self.can_dlc = len_to_dlc(data.len())?;
+
+        if remote {
+            self.can_id |= IdFlag::REMOTE.bits();
+        } else {
+            self.set_payload(data)?;
+        }
+
+        Some(())
+    }
+
+    fn set_id(&mut self, id: Id) {
+        self.can_id = match id {
+            Id::Standard(id) => id.as_raw() as u32,
+            Id::Extended(id) => id.as_raw() | IdFlag::EXTENDED.bits(),
+        };
+    }
+
+    /// Copy `data` to the start of the payload.
+    ///
+    /// Returns `None` if it doesn't fit, e.g. a CAN FD payload without the
+    /// `fd` feature.
+    fn set_payload(&mut self, data: &[u8]) -> Option<()> {
+        self.payload_mut()
+            .get_mut(..data.len())?
+            .copy_from_slice(data);
+        Some(())
+    }
+
+    /// Storage for the largest payload supported.
+    fn payload_mut(&mut self) -> &mut [u8] {
+        // safety: every variant is plain bytes.
+        #[cfg(feature = "fd")]
+        unsafe {
+            &mut self.can_data.can_fd.data
+        }
+        #[cfg(not(feature = "fd"))]
+        unsafe {
+            &mut self.can_data.classic_can.data
+        }
+    }
+}
+
+impl embedded_can::Frame for Frame {
+    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+        let mut frame = Frame::new_zeroed();
+
+        frame.can_dlc = len_to_dlc(data.len())?;
+        frame.set_id(id.into());
+        frame.set_payload(data)?;
+
+        Some(frame)
+    }
+
+    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+        let mut frame = Frame::new_zeroed();
+
+        frame.set_id(id.into());
+        frame.can_id |= IdFlag::REMOTE.bits();
+        frame.can_dlc = dlc as u8;
+
+        Some(frame)
+    }
+
+    fn id(&self) -> Id {
+        let masked = self.can_id & 0x1FFFFFFF;
+        // identifier bits beyond the format are ignored.
+        if self.is_extended() {
+            Id::Extended(ExtendedId::new(masked).unwrap_or(ExtendedId::ZERO))
+        } else {
+            let raw = masked as u16 & StandardId::MAX.as_raw();
+            Id::Standard(StandardId::new(raw).unwrap_or(StandardId::ZERO))
+        }
+    }
+
+    fn is_extended(&self) -> bool {
+        (self.can_id & IdFlag::EXTENDED.bits()) != 0
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        (self.can_id & IdFlag::REMOTE.bits()) != 0
+    }
+
+    fn dlc(&self) -> usize {
+        self.can_dlc as usize
+    }
+
+    fn data(&self) -> &[u8] {
+        // no data for an invalid DLC.
+        let len = self.data_len().unwrap_or(0);
+        // safety: underlying type is initialised with zeros and length is given by dlc.
+        #[cfg(feature = "fd")]
+        if self.is_fd() {
+            return unsafe { self.can_data.can_fd.data.get(..len).unwrap_or(&[]) };
+        }
+        unsafe { self.can_data.classic_can.data.get(..len).unwrap_or(&[]) }
+    }
+}
+
+/// Identifier flags.
+#[derive(Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct IdFlag(u32);
+
+bitflags! {
+    impl IdFlag: u32 {
+        /// Extended frame.
+        const EXTENDED = 0x80000000;
+        /// Remote frame.
+        const REMOTE = 0x40000000;
+        /// Error frame.
+        const ERROR = 0x20000000;
+    }
+}
+
+impl_flags_fmt!(IdFlag);
+
+/// Error classes of an error frame, sent in the identifier. Defined in the
+/// Linux `can/error.h` as `CAN_ERR_*`.
+#[derive(Clone, Copy)]
+pub struct ErrorClass(u32);
+
+bitflags! {
+    impl ErrorClass: u32 {
+        /// Transmit timeout.
+        const TX_TIMEOUT = 0x00000001;
+        /// Lost arbitration, bit number in data byte 0.
+        const LOST_ARBITRATION = 0x00000002;
+        /// Controller problems, [`ControllerError`] in data byte 1.
+        const CONTROLLER = 0x00000004;
+        /// Protocol violations, details in data bytes 2 and 3.
+        const PROTOCOL = 0x00000008;
+        /// Transceiver status, details in data byte 4.
+        const TRANSCEIVER = 0x00000010;
+        /// No acknowledge on transmission.
+        const NO_ACK = 0x00000020;
+        /// Bus off.
+        const BUS_OFF = 0x00000040;
+        /// Bus error.
+        const BUS_ERROR = 0x00000080;
+        /// Controller restarted.
+        const RESTARTED = 0x00000100;
+        /// Error counters in data bytes 6 and 7.
+        const COUNTERS = 0x00000200;
+    }
+}
+
+impl_flags_fmt!(ErrorClass);
+
+/// Controller problems, sent in data byte 1 of an error frame with
+/// [`ErrorClass::CONTROLLER`]. Defined in the Linux `can/error.h` as
+/// `CAN_ERR_CRTL_*`.
+#[derive(Clone, Copy)]
+pub struct ControllerError(u8);
+
+bitflags! {
+    impl ControllerError: u8 {
+        /// Receive buffer overflow.
+        const RX_OVERFLOW = 0x01;
+        /// Transmit buffer overflow.
+        const TX_OVERFLOW = 0x02;
+        /// Reached warning level for receive errors.
+        const RX_WARNING = 0x04;
+        /// Reached warning level for transmit errors.
+        const TX_WARNING = 0x08;
+        /// Reached error passive level for receive errors.
+        const RX_PASSIVE = 0x10;
+        /// Reached error passive level for transmit errors.
+        const TX_PASSIVE = 0x20;
+        /// Recovered to error active state.
+        const ACTIVE = 0x40;
+    }
+}
+
+impl_flags_fmt!(ControllerError);
+
+/// DLC of a payload length.
+///
+/// Only classic lengths are valid without the `fd` feature.
+fn len_to_dlc(len: usize) -> Option<u8> {
+    dlc::fd_len_to_dlc(len).filter(|&dlc| dlc <= 8 || cfg!(feature = "fd"))
+}
+
+/// Checks the size and field offsets of a wire struct against the Linux
+/// driver definition.
+macro_rules! assert_layout {
+    ($name:ident, $size:expr, { $($field:ident: $offset:expr),* $(,)? }) => {
+        const _: () = {
+            assert!(
+                core::mem::size_of::<$name>() == $size,
+                concat!("size of ", stringify!($name), " differs from the Linux driver"),
+            );
+            $(
+                assert!(
+                    core::mem::offset_of!($name, $field) == $offset,
+                    concat!(
+                        "offset of ", stringify!($name), "::", stringify!($field),
+                        " differs from the Linux driver",
+                    ),
+                );
+            )*
+        };
+    };
+}
+
+// struct gs_host_config
+assert_layout!(HostConfig, 4, { byte_order: 0 });
+// struct gs_device_config
+assert_layout!(DeviceConfig, 12, {
+    interface_count: 3,
+    software_version: 4,
+    hardware_version: 8,
+});
+// struct gs_device_mode
+assert_layout!(DeviceMode, 8, { mode: 0, flags: 4 });
+// struct gs_device_state
+assert_layout!(DeviceState, 12, { state: 0, rx_errors: 4, tx_errors: 8 });
+assert_layout!(RawDeviceState, 12, { state: 0, rx_errors: 4, tx_errors: 8 });
+// struct gs_device_bittiming
+assert_layout!(DeviceBitTiming, 20, {
+    prop_seg: 0,
+    phase_seg1: 4,
+    phase_seg2: 8,
+    sjw: 12,
+    brp: 16,
+});
+// limits shared by struct gs_device_bt_const and gs_device_bt_const_extended
+assert_layout!(CanBitTimingConst, 32, {
+    tseg1_min: 0,
+    tseg1_max: 4,
+    tseg2_min: 8,
+    tseg2_max: 12,
+    sjw_max: 16,
+    brp_min: 20,
+    brp_max: 24,
+    brp_inc: 28,
+});
+// struct gs_device_bt_const
+assert_layout!(DeviceBitTimingConst, 40, {
+    features: 0,
+    fclk_can: 4,
+    timing: 8,
+});
+// struct gs_device_bt_const_extended
+assert_layout!(DeviceBitTimingConstExtended, 72, {
+    features: 0,
+    fclk_can: 4,
+    timing_nominal: 8,
+    timing_data: 40,
+});
+// struct gs_identify_mode
+assert_layout!(IdentifyMode, 4, { mode: 0 });
+// struct gs_device_termination_state
+assert_layout!(DeviceTerminationState, 4, { state: 0 });
+// struct gs_host_frame
+#[cfg(feature = "fd")]
+assert_layout!(Frame, 80, {
+    echo_id: 0,
+    can_id: 4,
+    can_dlc: 8,
+    interface: 9,
+    flags: 10,
+    can_data: 12,
+});
+// struct gs_host_frame with only struct classic_can_ts
+#[cfg(not(feature = "fd"))]
+assert_layout!(Frame, 24, {
+    echo_id: 0,
+    can_id: 4,
+    can_dlc: 8,
+    interface: 9,
+    flags: 10,
+    can_data: 12,
+});
+assert_layout!(ClassicCanTimestamp, CAN_DATA_LEN, { data: 0, timestamp_us: 8 });
+#[cfg(feature = "fd")]
+assert_layout!(CanFdTimestamp, 68, { data: 0, timestamp_us: 64 });
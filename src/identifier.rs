@@ -1,3 +1,4 @@
+use crate::host::Feature;
 use usb_device::device::UsbVidPid;
 
 pub const GS_USB_1: UsbVidPid = UsbVidPid(0x1d50, 0x606f);
@@ -5,3 +6,63 @@ pub const CANDLELIGHT: UsbVidPid = UsbVidPid(0x1209, 0x2323);
 pub const CES_CANEXT_FD: UsbVidPid = UsbVidPid(0x1cd2, 0x606f);
 pub const ABE_CANDEBUGGER_FD: UsbVidPid = UsbVidPid(0x16d0, 0x10b8);
 pub const XYLANTA_SAINT3: UsbVidPid = UsbVidPid(0x16d0, 0x0f30);
+
+/// What hosts expect from a device using a known identifier.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct KnownDevice {
+    pub vid: u16,
+    pub pid: u16,
+    /// Features to advertise.
+    pub features: Feature,
+    /// IN transfers are padded to the maximum packet size.
+    pub pad_packets: bool,
+    /// Most channels the host supports for the identifier.
+    pub max_channels: u8,
+}
+
+impl KnownDevice {
+    /// The identifier as passed to [`UsbDeviceBuilder::new`].
+    ///
+    /// [`UsbDeviceBuilder::new`]: usb_device::device::UsbDeviceBuilder::new
+    pub const fn vid_pid(&self) -> UsbVidPid {
+        UsbVidPid(self.vid, self.pid)
+    }
+
+    /// Features recommended for the identifier, including
+    /// [`Feature::PAD_PKTS_TO_MAX_PKT_SIZE`] if packets are padded.
+    pub fn recommended_features(&self) -> Feature {
+        if self.pad_packets {
+            self.features | Feature::PAD_PKTS_TO_MAX_PKT_SIZE
+        } else {
+            self.features
+        }
+    }
+}
+
+const fn known(id: UsbVidPid, features: Feature, max_channels: u8) -> KnownDevice {
+    KnownDevice {
+        vid: id.0,
+        pid: id.1,
+        features,
+        pad_packets: false,
+        max_channels,
+    }
+}
+
+/// Devices using the identifiers above.
+pub const KNOWN_DEVICES: [KnownDevice; 5] = [
+    known(GS_USB_1, Feature::empty(), 3),
+    known(CANDLELIGHT, Feature::empty(), 3),
+    // the Linux driver assumes the extended timing request is supported.
+    known(CES_CANEXT_FD, Feature::CAN_FD, 1),
+    known(ABE_CANDEBUGGER_FD, Feature::CAN_FD, 1),
+    known(XYLANTA_SAINT3, Feature::CAN_FD, 1),
+];
+
+/// Find the known device using an identifier.
+pub fn lookup(id: UsbVidPid) -> Option<&'static KnownDevice> {
+    KNOWN_DEVICES
+        .iter()
+        .find(|known| known.vid == id.0 && known.pid == id.1)
+}
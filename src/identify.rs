@@ -0,0 +1,112 @@
+//! Blink pattern for the host's identify request, e.g. `ip link set can0 up`
+//! followed by `ethtool -p can0`.
+//!
+//! [`GsCan`] keeps an [`IdentifyController`] per channel and turns it on and
+//! off from the identify requests of channels advertising
+//! [`Feature::IDENTIFY`]. The firmware polls it from its main loop and drives
+//! the channel's LED from the [`LedState`] returned:
+//!
+//! ```ignore
+//! match gs_can.identify(channel).poll(now_ms) {
+//!     LedState::Idle => led.show_activity(),
+//!     LedState::On => led.set_high(),
+//!     LedState::Off => led.set_low(),
+//! }
+//! ```
+//!
+//! [`GsCan`]: crate::GsCan
+//! [`Feature::IDENTIFY`]: crate::host::Feature::IDENTIFY
+
+/// Period of the blink pattern, 2 Hz.
+pub const BLINK_PERIOD_MS: u32 = 500;
+
+/// Time after which identification stops if the host doesn't turn it off.
+///
+/// The host only turns it off if the process asking for it exits cleanly.
+pub const DEFAULT_TIMEOUT_MS: u32 = 30_000;
+
+/// What to show on the LED of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum LedState {
+    /// Not identifying, the LED shows whatever it normally does.
+    Idle,
+    /// Identifying, LED on.
+    On,
+    /// Identifying, LED off.
+    Off,
+}
+
+/// State of the identify blink pattern of a channel.
+///
+/// The pattern starts at the first [`IdentifyController::poll`] after
+/// identification is turned on, with the LED on for the first half of each
+/// [`BLINK_PERIOD_MS`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct IdentifyController {
+    timeout_ms: Option<u32>,
+    active: bool,
+    /// Time of the first poll since identification was turned on.
+    started_ms: Option<u32>,
+}
+
+impl Default for IdentifyController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdentifyController {
+    /// An idle controller timing out after [`DEFAULT_TIMEOUT_MS`].
+    pub const fn new() -> Self {
+        Self {
+            timeout_ms: Some(DEFAULT_TIMEOUT_MS),
+            active: false,
+            started_ms: None,
+        }
+    }
+
+    /// Stop identifying `timeout_ms` after it started, or only when the host
+    /// says so if `None`.
+    pub const fn with_timeout(mut self, timeout_ms: Option<u32>) -> Self {
+        self.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Turn identification on or off.
+    ///
+    /// Called by [`GsCan`](crate::GsCan) for the host's identify requests.
+    /// Turning it on whilst on restarts the pattern and the timeout.
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+        self.started_ms = None;
+    }
+
+    /// Returns `true` if identifying.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// The LED state at `now_ms`, a wrapping millisecond time.
+    ///
+    /// Stops identifying once the timeout has passed.
+    pub fn poll(&mut self, now_ms: u32) -> LedState {
+        if !self.active {
+            return LedState::Idle;
+        }
+
+        let started_ms = *self.started_ms.get_or_insert(now_ms);
+        let elapsed = now_ms.wrapping_sub(started_ms);
+        if self.timeout_ms.is_some_and(|timeout| elapsed >= timeout) {
+            self.set_active(false);
+            return LedState::Idle;
+        }
+
+        if elapsed % BLINK_PERIOD_MS < BLINK_PERIOD_MS / 2 {
+            LedState::On
+        } else {
+            LedState::Off
+        }
+    }
+}
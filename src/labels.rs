@@ -0,0 +1,135 @@
+//! Reader for host tools fetching the channel labels of
+//! [`GsCan::with_channel_labels`](crate::GsCan::with_channel_labels) with
+//! GET_DESCRIPTOR control transfers.
+//!
+//! The transfers go through a [`ControlPipe`], e.g. implemented for a
+//! `rusb::DeviceHandle` with `read_control(0x80, 6, kind << 8 | index,
+//! lang_id, buf, timeout)`:
+//!
+//! ```ignore
+//! let mut buf = [0; 256];
+//! if let Some(first) = labels::first_label_index(&mut pipe, interface, &mut buf)? {
+//!     let channel = Channel::new(1).unwrap();
+//!     let label = labels::read_label(&mut pipe, first, channel, 0x409, &mut buf)?;
+//! }
+//! ```
+
+use crate::Channel;
+
+/// Descriptor types of GET_DESCRIPTOR.
+const DESCRIPTOR_CONFIGURATION: u8 = 2;
+const DESCRIPTOR_STRING: u8 = 3;
+const DESCRIPTOR_INTERFACE: u8 = 4;
+
+/// Longest descriptor, its length is a byte.
+const MAX_DESCRIPTOR_LEN: usize = 255;
+
+/// Default control pipe of the device.
+pub trait ControlPipe {
+    /// Error of a transfer, e.g. the device stalling the request.
+    type Error;
+
+    /// Read descriptor `index` of type `kind` in the language `lang_id` into
+    /// `buf` with a standard GET_DESCRIPTOR request, returning the bytes read.
+    fn get_descriptor(
+        &mut self,
+        kind: u8,
+        index: u8,
+        lang_id: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, Self::Error>;
+}
+
+/// Errors reading the labels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum LabelError<E> {
+    /// The transfer failed, e.g. the device has no label for the channel.
+    Pipe(E),
+    /// The configuration has no interface with the number.
+    NoInterface,
+    /// A descriptor is malformed, or cut short by the buffer.
+    Malformed,
+    /// The label doesn't fit the buffer.
+    BufferTooSmall,
+}
+
+/// String index of the label of channel 0 of the class on `interface`, the
+/// `iInterface` of its descriptor, read into `buf`.
+///
+/// Returns `None` if the channels have no labels. `buf` must hold the whole
+/// configuration descriptor.
+pub fn first_label_index<P: ControlPipe>(
+    pipe: &mut P,
+    interface: u8,
+    buf: &mut [u8],
+) -> Result<Option<u8>, LabelError<P::Error>> {
+    let len = pipe
+        .get_descriptor(DESCRIPTOR_CONFIGURATION, 0, 0, buf)
+        .map_err(LabelError::Pipe)?;
+    let mut descriptors = buf.get(..len).ok_or(LabelError::Malformed)?;
+
+    while let [length, kind, ..] = *descriptors {
+        let (descriptor, rest) = descriptors
+            .split_at_checked(usize::from(length))
+            .filter(|_| length >= 2)
+            .ok_or(LabelError::Malformed)?;
+        if kind == DESCRIPTOR_INTERFACE {
+            // the first alternate setting, the class has no others.
+            if let [_, _, number, 0, _, _, _, _, index, ..] = *descriptor {
+                if number == interface {
+                    return Ok((index != 0).then_some(index));
+                }
+            }
+        }
+        descriptors = rest;
+    }
+
+    Err(LabelError::NoInterface)
+}
+
+/// Label of `channel` in the language `lang_id`, e.g. 0x409 for English
+/// (United States), decoded into `buf`.
+///
+/// `first` is the index from [`first_label_index`]. A channel past the last
+/// label has none and the device rejects the request, an error of the pipe.
+pub fn read_label<'b, P: ControlPipe>(
+    pipe: &mut P,
+    first: u8,
+    channel: Channel,
+    lang_id: u16,
+    buf: &'b mut [u8],
+) -> Result<&'b str, LabelError<P::Error>> {
+    let index = first
+        .checked_add(channel.index())
+        .ok_or(LabelError::Malformed)?;
+    let mut descriptor = [0; MAX_DESCRIPTOR_LEN];
+    let len = pipe
+        .get_descriptor(DESCRIPTOR_STRING, index, lang_id, &mut descriptor)
+        .map_err(LabelError::Pipe)?;
+
+    let units = match descriptor.get(..len) {
+        Some([length, DESCRIPTOR_STRING, units @ ..])
+            if usize::from(*length) == len && len % 2 == 0 =>
+        {
+            units
+        }
+        _ => return Err(LabelError::Malformed),
+    };
+    // UTF-16LE, the length checked even.
+    let units = units
+        .chunks_exact(2)
+        .map(|unit| <[u8; 2]>::try_from(unit).map_or(0, u16::from_le_bytes));
+
+    let mut at = 0;
+    for c in char::decode_utf16(units) {
+        let c = c.map_err(|_| LabelError::Malformed)?;
+        let dest = buf
+            .get_mut(at..at + c.len_utf8())
+            .ok_or(LabelError::BufferTooSmall)?;
+        at += c.encode_utf8(dest).len();
+    }
+
+    let label = buf.get(..at).ok_or(LabelError::BufferTooSmall)?;
+    core::str::from_utf8(label).map_err(|_| LabelError::Malformed)
+}
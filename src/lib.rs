@@ -1,149 +1,3585 @@
 #![no_std]
+#![cfg_attr(
+    feature = "panic-free",
+    deny(clippy::unwrap_used, clippy::expect_used, clippy::indexing_slicing)
+)]
 
 pub mod host;
 pub mod identifier;
+pub mod identify;
+#[cfg(feature = "host-tools")]
+pub mod labels;
+mod queue;
+#[cfg(feature = "self-test")]
+pub mod self_test;
+#[cfg(feature = "host-tools")]
+pub mod session;
+#[cfg(feature = "shared")]
+pub mod shared;
 
+use core::convert::Infallible;
+use core::task::{Context, Poll, Waker};
 use embedded_can::Frame as _;
 use heapless::spsc::{self, Queue};
 use host::*;
+use identify::IdentifyController;
+use queue::FrameQueue;
 use usb_device::class_prelude::*;
+use usb_device::endpoint::{Endpoint, EndpointDirection};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 /// Interface class: vendor defined.
 pub const INTERFACE_CLASS: u8 = 0xFF;
 
-const REQ_HOST_FORMAT: u8 = 0;
-const REQ_BIT_TIMING: u8 = 1;
-const REQ_MODE: u8 = 2;
-#[allow(unused)]
-const REQ_BUS_ERROR: u8 = 3;
-const REQ_BIT_TIMING_CONST: u8 = 4;
-const REQ_DEVICE_CONFIG: u8 = 5;
-#[allow(unused)]
-const REQ_TIMESTAMP: u8 = 6;
-#[allow(unused)]
-const REQ_IDENTIFY: u8 = 7;
-#[allow(unused)]
-const REQ_GET_USER_ID: u8 = 8;
-#[allow(unused)]
-const REQ_SET_USER_ID: u8 = 9;
-const REQ_BIT_TIMING_DATA: u8 = 10;
-const REQ_BIT_TIMING_CONST_EXT: u8 = 11;
-#[allow(unused)]
-const REQ_SET_TERMINATION: u8 = 12;
-#[allow(unused)]
-const REQ_GET_TERMINATION: u8 = 13;
-const REQ_GET_STATE: u8 = 14;
-
-/// Maximum number of interfaces. Defined in the Linux driver.
-/// This may change in future.
-const MAX_INTF: usize = 3;
+/// `software_version` of the [`DeviceConfig`] sent to the host, unless set
+/// with [`GsCan::with_software_version`].
+///
+/// 2, as candleLight firmware and [`DeviceConfig::new`]. Version 3 tells the
+/// host frames carry hardware timestamps, which the class doesn't fill in, so
+/// it isn't reported even with [`Feature::HW_TIMESTAMP`] advertised.
+pub const SOFTWARE_VERSION: u32 = 2;
+
+/// Behaviours of this build of the class, answered to [`REQ_CAPABILITIES`]
+/// with [`GsCan::with_capabilities_request`].
+pub const CAPABILITIES: &[Capability] = &[
+    Capability::DeviceEcho,
+    #[cfg(feature = "fd")]
+    Capability::Fd,
+    Capability::SequenceNumbers,
+    Capability::Lpc546xxQuirk,
+    #[cfg(feature = "self-test")]
+    Capability::SelfTest,
+    #[cfg(feature = "shared")]
+    Capability::SharedChannels,
+    #[cfg(feature = "panic-free")]
+    Capability::PanicFree,
+];
+
+/// Maximum frames in flight from the host per channel. Defined in the Linux
+/// driver as `GS_MAX_TX_URBS`.
+const MAX_ECHO: usize = 10;
+
+/// Address of the bulk IN endpoint. Defined in the Linux driver as
+/// `GS_USB_ENDPOINT_IN`.
+const ENDPOINT_IN: u8 = 0x81;
+
+/// Address of the bulk OUT endpoint. Defined in the Linux driver as
+/// `GS_USB_ENDPOINT_OUT`.
+const ENDPOINT_OUT: u8 = 0x02;
+
+/// Maximum packet size of the bulk endpoints. Longer frames are split over
+/// two packets.
+const PACKET_LEN: usize = 64;
+
+/// Occurrences of a per-frame event between log messages, unless each one is
+/// logged with the `defmt-verbose` feature.
+#[cfg(all(feature = "defmt-03", not(feature = "defmt-verbose")))]
+const LOG_EVERY: u32 = 256;
+
+/// Events that can happen for every frame, counted rather than logged.
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+enum Event {
+    QueueFull,
+    InvalidHostFrame,
+    HostFrameDropped,
+    BridgeDropped,
+}
+
+/// Control transfer buffer of usb-device without its `control-buffer-256`
+/// feature, which every response must fit. The control pipe sends them in as
+/// many packets as the control endpoint's packet size needs.
+const CONTROL_BUF_LEN: usize = 128;
+
+// the extended timing constants are the longest response.
+const _: () = assert!(size_of::<DeviceBitTimingConstExtended>() <= CONTROL_BUF_LEN);
+
+/// CAN channel index.
+///
+/// Channels are numbered from zero and are unrelated to the USB interface
+/// number of the class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct Channel(u8);
+
+impl Channel {
+    /// Create a channel index.
+    ///
+    /// Returns `None` if the index is beyond the number of supported channels.
+    pub const fn new(index: u8) -> Option<Self> {
+        if (index as usize) < MAX_INTF {
+            Some(Self(index))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the channel index.
+    pub const fn index(self) -> u8 {
+        self.0
+    }
+}
+
+/// A channel index the class or the device doesn't have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct InvalidChannel;
+
+/// Only bounded by the channels the class supports, as [`Channel::new`]. Use
+/// [`GsCan::channel`] for a channel of the device.
+impl TryFrom<u16> for Channel {
+    type Error = InvalidChannel;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        u8::try_from(value)
+            .ok()
+            .and_then(Self::new)
+            .ok_or(InvalidChannel)
+    }
+}
+
+impl From<Channel> for u8 {
+    fn from(value: Channel) -> Self {
+        value.0
+    }
+}
+
+impl From<Channel> for usize {
+    fn from(value: Channel) -> Self {
+        value.0 as usize
+    }
+}
+
+impl Channel {
+    /// Every channel supported by the class.
+    fn all() -> impl Iterator<Item = Self> {
+        (0..MAX_INTF as u8).map(Self)
+    }
+}
+
+/// State kept for each channel, looked up without a bounds check.
+trait PerChannel<T> {
+    fn at(&self, channel: Channel) -> &T;
+
+    fn at_mut(&mut self, channel: Channel) -> &mut T;
+}
+
+// a channel is always below `MAX_INTF`, the remainder lets the compiler see
+// that too.
+#[allow(clippy::indexing_slicing)]
+impl<T> PerChannel<T> for [T; MAX_INTF] {
+    fn at(&self, channel: Channel) -> &T {
+        &self[usize::from(channel) % MAX_INTF]
+    }
+
+    fn at_mut(&mut self, channel: Channel) -> &mut T {
+        &mut self[usize::from(channel) % MAX_INTF]
+    }
+}
+
+/// How frames received from the host are delivered to the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum RxDelivery {
+    /// Frames are passed to [`Device::receive`] from the endpoint callback.
+    Direct,
+    /// Frames are stored in the class and drained with
+    /// [`GsCan::dequeue_host_frame`].
+    Queued,
+    /// Frames are stored in the class and passed to [`Device::receive`] from
+    /// [`GsCan::process`], outside of the endpoint callback.
+    Staged,
+}
+
+/// What to do with a frame from the host when the device cannot accept it.
+///
+/// Dropped frames are echoed to the host straight away with
+/// [`FrameFlag::OVERFLOW`] set, freeing the host's transmit slot and letting it
+/// report the overflow.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum HostTxPolicy {
+    /// Hold the frame and stop reading from the host until it is accepted.
+    #[default]
+    Nak,
+    /// Drop the frame that could not be accepted.
+    DropNewest,
+    /// Hold the frame, dropping it in favour of the next frame for the same
+    /// channel if it still cannot be accepted.
+    DropOldest,
+}
+
+/// What happens to frames for the host whilst the device isn't configured,
+/// e.g. when the CAN bus is live before the host enumerates the device.
+///
+/// Frames kept are sent once the host configures the device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum UnconfiguredPolicy {
+    /// Drop every frame.
+    #[default]
+    Drop,
+    /// Keep at most this many of the newest frames, limited by the queue
+    /// size, dropping older frames.
+    KeepNewest(usize),
+}
+
+impl UnconfiguredPolicy {
+    /// Most frames kept.
+    fn newest(self) -> usize {
+        match self {
+            Self::Drop => 0,
+            Self::KeepNewest(newest) => newest,
+        }
+    }
+}
+
+/// Why the device stopped a channel, see [`GsCan::channel_fault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum FaultReason {
+    /// The controller or transceiver overheated.
+    Thermal,
+    /// The transceiver reported a fault, e.g. a shorted bus.
+    Transceiver,
+    /// The controller failed, e.g. its clock was lost.
+    Controller,
+}
+
+/// Where the state of a channel read by the host comes from, see
+/// [`GsCan::report_state`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum StateSource {
+    /// The state last reported, or [`Device::state`] if none was reported
+    /// since the channel was reset.
+    #[default]
+    Reported,
+    /// Only the state last reported, error active without errors if none
+    /// was. [`Device::state`] is never called.
+    ReportedOnly,
+    /// Always [`Device::state`], reports are ignored.
+    Device,
+}
+
+/// When frames from the host are echoed back to it.
+///
+/// The host keeps a frame's transmit slot until the echo arrives, so every
+/// accepted frame must be echoed exactly once.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum EchoMode {
+    /// Echo as soon as the frame is accepted by the application.
+    #[default]
+    Immediate,
+    /// Echo when the application reports the frame is done with
+    /// [`GsCan::echo`] or [`GsCan::echo_aborted`], e.g. once it has been
+    /// sent on the bus.
+    Device,
+}
+
+/// Why a control transfer from the host was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum RejectReason {
+    /// The channel in `wValue` doesn't exist.
+    InvalidChannel,
+    /// The data stage has the wrong length.
+    InvalidLength,
+    /// The data stage holds a value the class doesn't support, e.g. an
+    /// unknown mode or a big endian host format.
+    InvalidValue,
+    /// Start requested with features that aren't advertised or not available
+    /// on the channel, see [`GsCan::with_channel_features`], a request for a
+    /// feature the channel doesn't have, e.g. termination without
+    /// [`Feature::TERMINATION`], or the extended bit timing requested without
+    /// [`Feature::BT_CONST_EXT`].
+    UnsupportedFeature,
+    /// Start requested with, or forced to have, features forced off on the
+    /// channel, see [`GsCan::with_forced_off_features`].
+    FeatureForcedOff,
+    /// Start refused by [`Device::validate_start`].
+    DeviceRejected,
+    /// Start requested before the timing was configured.
+    MissingTiming,
+    /// FD start requested before the data phase timing was configured.
+    MissingDataTiming,
+    /// The request isn't implemented, including the bus error request
+    /// superseded by error frames, see [`GsRequest::BusError`].
+    UnknownRequest,
+    /// Host format or timing change requested whilst a channel is started.
+    ChannelStarted,
+    /// Start requested whilst the last start is completing, see
+    /// [`GsCan::with_deferred_start`].
+    ChannelStarting,
+    /// Start requested whilst the last reset is completing, see
+    /// [`GsCan::with_deferred_stop`].
+    ChannelStopping,
+    /// The class is disabled, see [`GsCan::set_enabled`].
+    Disabled,
+}
+
+/// The last control transfer rejected by the class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct Rejection {
+    /// `bRequest` of the transfer.
+    pub request: u8,
+    /// `wValue` of the transfer, the channel for most requests.
+    pub value: u16,
+    pub reason: RejectReason,
+    /// Number of transfers rejected so far, including this one.
+    pub count: u32,
+}
+
+/// Requests the host has made whilst a channel was started, since the bus was
+/// reset.
+///
+/// Hosts that never read the state only notice bus-off from error frames, so
+/// firmware without bus error reporting may send them itself.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct HostCapabilities {
+    /// The host has read the state of a started channel.
+    pub polls_state: bool,
+    /// The host has read the timestamp whilst a channel was started.
+    pub reads_timestamp: bool,
+}
+
+/// Time between frames from the host being read and echoed on a channel, in
+/// microseconds of [`Device::timestamp_us`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct LatencyStats {
+    pub min_us: u32,
+    pub max_us: u32,
+    pub last_us: u32,
+    /// Number of frames echoed since the statistics were reset.
+    pub count: u32,
+}
+
+impl LatencyStats {
+    fn record(stats: &mut Option<Self>, latency_us: u32) {
+        let stats = stats.get_or_insert(Self {
+            min_us: latency_us,
+            max_us: latency_us,
+            last_us: latency_us,
+            count: 0,
+        });
+        stats.min_us = stats.min_us.min(latency_us);
+        stats.max_us = stats.max_us.max(latency_us);
+        stats.last_us = latency_us;
+        stats.count = stats.count.wrapping_add(1);
+    }
+}
+
+/// Milliseconds between updates of the load estimates.
+const LOAD_WINDOW_MS: u32 = 100;
+
+/// Weight of each window in the load estimates, as a power of two.
+const LOAD_WEIGHT_SHIFT: u32 = 3;
+
+/// Estimated load of a channel, see [`GsCan::load`].
+///
+/// Bytes are those of the frame payloads.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct LoadStats {
+    /// Frames per second sent to the host.
+    pub to_host_fps: u32,
+    /// Bytes per second sent to the host.
+    pub to_host_bps: u32,
+    /// Frames per second accepted from the host.
+    pub from_host_fps: u32,
+    /// Bytes per second accepted from the host.
+    pub from_host_bps: u32,
+}
+
+/// Exponentially weighted frame and byte rates in one direction.
+#[derive(Debug, Default, Clone, Copy)]
+struct LoadRate {
+    /// Counted since the last update
+    frames: u32,
+    bytes: u32,
+    /// Rates per second, scaled by the weight
+    fps_scaled: u32,
+    bps_scaled: u32,
+}
+
+impl LoadRate {
+    fn count(&mut self, bytes: usize) {
+        self.frames = self.frames.saturating_add(1);
+        self.bytes = self.bytes.saturating_add(bytes as u32);
+    }
+
+    fn update(&mut self) {
+        let per_second = 1000 / LOAD_WINDOW_MS;
+        let weigh = |scaled: u32, count: u32| {
+            (scaled - (scaled >> LOAD_WEIGHT_SHIFT))
+                .saturating_add(count.saturating_mul(per_second))
+        };
+        self.fps_scaled = weigh(self.fps_scaled, self.frames);
+        self.bps_scaled = weigh(self.bps_scaled, self.bytes);
+        self.frames = 0;
+        self.bytes = 0;
+    }
+
+    fn fps(&self) -> u32 {
+        self.fps_scaled >> LOAD_WEIGHT_SHIFT
+    }
+
+    fn bps(&self) -> u32 {
+        self.bps_scaled >> LOAD_WEIGHT_SHIFT
+    }
+}
+
+/// Frame rates of each channel, updated from [`GsCan::tick_1ms`].
+#[derive(Debug, Default, Clone, Copy)]
+struct LoadMonitor {
+    /// Milliseconds since the last update
+    ticks: u32,
+    to_host: [LoadRate; MAX_INTF],
+    from_host: [LoadRate; MAX_INTF],
+}
+
+/// Rewriting of the frames of a channel between the bus and the host's view
+/// of it, see [`GsCan::set_transform`].
+#[derive(Debug, Clone, Copy)]
+pub enum Transform {
+    /// Move a range of identifiers.
+    Remap(IdRemap),
+    /// Rewrite frames with functions of the application.
+    Custom {
+        /// Applied to frames from the bus
+        to_host: fn(&mut host::Frame),
+        /// Applied to frames from the host
+        from_host: fn(&mut host::Frame),
+    },
+}
+
+impl Transform {
+    /// Rewrite a frame from the bus as the host sees it.
+    pub fn to_host(&self, frame: &mut host::Frame) {
+        match self {
+            Self::Remap(remap) => remap.to_host(frame),
+            Self::Custom { to_host, .. } => to_host(frame),
+        }
+    }
+
+    /// Rewrite a frame from the host as it is sent on the bus.
+    pub fn from_host(&self, frame: &mut host::Frame) {
+        match self {
+            Self::Remap(remap) => remap.from_host(frame),
+            Self::Custom { from_host, .. } => from_host(frame),
+        }
+    }
+}
+
+/// Identifiers with the bits `bus` in `mask` on the bus, seen with the bits
+/// `host` by the host, e.g. `IdRemap { mask: 0x700, bus: 0x100, host: 0x600 }`
+/// shows 0x100 to 0x1FF on the bus as 0x600 to 0x6FF.
+///
+/// Other identifiers and error frames pass unchanged. Bits beyond the
+/// identifier format of a frame are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct IdRemap {
+    /// Identifier bits replaced
+    pub mask: u32,
+    /// Bits in `mask` on the bus
+    pub bus: u32,
+    /// Bits in `mask` on the host
+    pub host: u32,
+}
+
+impl IdRemap {
+    /// Rewrite a frame from the bus as the host sees it.
+    pub fn to_host(&self, frame: &mut host::Frame) {
+        remap_id(frame, self.mask, self.bus, self.host);
+    }
+
+    /// Rewrite a frame from the host as it is sent on the bus.
+    pub fn from_host(&self, frame: &mut host::Frame) {
+        remap_id(frame, self.mask, self.host, self.bus);
+    }
+}
+
+/// Suppression of repeated frames to the host on a channel, see
+/// [`GsCan::set_dedup`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct DedupConfig {
+    /// Time after a frame is queued during which repeats are suppressed.
+    pub window_us: u32,
+    /// Only frames with the same data are repeats, otherwise the same
+    /// identifier and flags are enough.
+    pub compare_data: bool,
+}
+
+/// Where a channel is between the host's start and reset requests, see
+/// [`GsCan::channel_mode`].
+///
+/// A start moves a channel from `Stopped` through `Starting` to `Started`, a
+/// reset back through `Stopping` to `Stopped`. `Starting` and `Stopping` are
+/// skipped unless the application completes them, see
+/// [`GsCan::with_deferred_start`] and [`GsCan::with_deferred_stop`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum ChannelMode {
+    /// Not started, or reset by the host.
+    Stopped,
+    /// Started by the host, waiting for [`GsCan::start_complete`].
+    Starting,
+    /// Started by the host.
+    Started,
+    /// Reset by the host, waiting for [`GsCan::stop_complete`].
+    Stopping,
+}
+
+impl ChannelMode {
+    /// Returns `true` if the host has started the channel, whether or not the
+    /// start has completed.
+    const fn is_started(self) -> bool {
+        matches!(self, Self::Starting | Self::Started)
+    }
+}
+
+/// Configuration of a channel negotiated with the host, see
+/// [`GsCan::channel_info`].
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct ChannelInfo {
+    /// The host has started the channel.
+    pub started: bool,
+    /// Features the channel was started with, empty unless started.
+    pub features: Feature,
+    /// Nominal timing last configured by the host.
+    pub nominal_timing: Option<DeviceBitTiming>,
+    /// Data phase timing last configured by the host, or the default of the
+    /// device once started in FD mode without one.
+    pub data_timing: Option<DeviceBitTiming>,
+    /// Bitrate of the nominal timing at the advertised CAN clock, in bit/s.
+    pub nominal_bitrate: Option<u32>,
+    /// Bitrate of the data phase timing at the advertised CAN clock, in bit/s.
+    pub data_bitrate: Option<u32>,
+}
+
+/// Reasons [`GsCan::transmit_raw`] and [`GsCan::transmit_parts`] refuse a
+/// frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum TransmitError {
+    /// The channel, or the frame's `interface`, isn't a channel of the device.
+    InvalidChannel,
+    /// The frame is an echo and echoes weren't allowed.
+    Echo,
+    /// The data length or flags are invalid for the frame.
+    InvalidFrame(FrameError),
+}
+
+/// A frame from the host with the time it was read.
+#[derive(Clone, Copy)]
+struct HostFrame {
+    frame: host::Frame,
+    received_us: Option<u32>,
+}
+
+/// Timing sent by the host for a channel, held until the channel starts.
+///
+/// The phases may be sent in any order, or only one of them, the other is
+/// kept from before. Changes whilst the channel is started are rejected, as
+/// they would only apply at the next start.
+#[derive(Debug, Default, Clone, Copy)]
+struct PendingTiming {
+    nominal: Option<DeviceBitTiming>,
+    #[cfg(feature = "fd")]
+    data: Option<DeviceBitTiming>,
+}
 
 /// Geschwister Schneider USB device.
-pub struct GsCan<'a, B: UsbBus, D: Device> {
+///
+/// When combined with other classes in a composite device, build the device
+/// with [`UsbDeviceBuilder::composite_with_iads`] so the device class is
+/// `0xEF/0x02/0x01` and an interface association descriptor is written for
+/// the gs_usb function.
+///
+/// `RX` is the depth of the host frame queue used with
+/// [`RxDelivery::Queued`] and [`RxDelivery::Staged`]. The queue holds
+/// `RX - 1` frames.
+///
+/// The lifetime is only that of the [`UsbBusAllocator`]. To keep the class in
+/// a static or an RTIC resource, create it from a `&'static` allocator (e.g.
+/// from a `StaticCell`) and a [`Device`] that owns its peripherals, giving a
+/// `GsCan<'static, B, D>`. This is [`Send`] whenever `D` is, for any bus `B`,
+/// as the endpoints only point to the bus in the allocator.
+///
+/// # Concurrency
+///
+/// Every method takes `&mut self`, so a class shared between the USB
+/// interrupt and CAN interrupts is accessed under a lock, e.g. as an RTIC
+/// resource. The endpoints are only accessed from the [`UsbClass`] callbacks
+/// run by [`UsbDevice::poll`], the methods used from CAN interrupts such as
+/// [`GsCan::transmit`], [`GsCan::echo`] and [`GsCan::retry_receive`] only
+/// change the queues. A frame is written out whole before the next one
+/// starts, whichever context queued it.
+///
+/// [`UsbDeviceBuilder::composite_with_iads`]: usb_device::device::UsbDeviceBuilder::composite_with_iads
+/// [`UsbDevice::poll`]: usb_device::device::UsbDevice::poll
+pub struct GsCan<'a, B: UsbBus, D: Device, const RX: usize = 2> {
     interface: InterfaceNumber,
     write_endpoint: EndpointIn<'a, B>,
     read_endpoint: EndpointOut<'a, B>,
     pub device: D,
-    interface_fd: [bool; MAX_INTF],
+    /// Frame size of each channel
+    wire_format: [WireFormat; MAX_INTF],
+    /// Timing configured by the host, applied when the channel starts
+    timing: [PendingTiming; MAX_INTF],
+    /// Features available on each channel, of those advertised
+    channel_features: [Feature; MAX_INTF],
+    /// Features each channel is started with whatever the host requests
+    forced_features: [Feature; MAX_INTF],
+    /// Features each channel is never started with
+    forced_off_features: [Feature; MAX_INTF],
+    /// Termination each channel is started with unless the host set it
+    default_termination: [Option<bool>; MAX_INTF],
+    /// Channels whose termination the host set since the bus reset
+    termination_set: [bool; MAX_INTF],
+    /// Where each channel is between the host's start and reset requests
+    mode: [ChannelMode; MAX_INTF],
+    /// Features each channel was last started with
+    started_features: [Feature; MAX_INTF],
+    /// Starts complete when the application says so
+    deferred_start: bool,
+    /// Resets complete when the application says so
+    deferred_stop: bool,
+    /// Channels stopped by the device, until the host starts them again
+    fault: [Option<FaultReason>; MAX_INTF],
     /// Frames waiting to be sent to the host
-    out_queue: spsc::Queue<host::Frame, 64>,
-    /// A frame half sent to the host
-    out_frame: Option<host::Frame>,
-    /// A frame half sent from the host
-    in_frame: Option<host::Frame>,
+    out_queue: FrameQueue<64>,
+    /// Length of the frame at the head of the out queue once its first packet
+    /// is sent
+    out_split: Option<usize>,
+    /// The host has configured the device
+    configured: bool,
+    /// The CAN function is available to the host
+    enabled: bool,
+    /// Frames to the host dropped whilst disabled
+    disabled_dropped: u32,
+    /// Bytes from the host discarded whilst disabled
+    disabled_discarded: u32,
+    unconfigured_policy: UnconfiguredPolicy,
+    /// Frames to the host dropped whilst the device wasn't configured
+    unconfigured_dropped: u32,
+    /// A frame partly read from the host, with the bytes read so far
+    in_frame: Option<(host::Frame, usize)>,
+    /// Woken when a frame leaves the out queue.
+    tx_waker: Option<Waker>,
+    rx_delivery: RxDelivery,
+    /// Frames received from the host waiting to be dequeued
+    rx_queue: spsc::Queue<(Channel, host::Frame), RX>,
+    /// A frame is waiting in the endpoint for space in the rx queue
+    rx_blocked: bool,
+    /// Woken when a frame is added to the rx queue.
+    rx_waker: Option<Waker>,
+    host_tx_policy: HostTxPolicy,
+    /// Frames from the host the device could not accept yet
+    rx_pending: [Option<HostFrame>; MAX_INTF],
+    /// Frames from the host dropped by the host tx policy
+    host_tx_dropped: [u32; MAX_INTF],
+    /// Frames from the host discarded as unreadable or malformed
+    invalid_host_frames: u32,
+    /// Invalid frames for the host dropped rather than panicking
+    #[cfg(feature = "panic-free")]
+    invalid_frames: u32,
+    /// Accept classic frames in FD sized transfers on classic channels
+    #[cfg(feature = "fd")]
+    fd_sized_classic: bool,
+    /// Classic frames accepted from FD sized transfers
+    normalized_frames: u32,
+    /// Frames from the host completed without the tail of their transfer
+    missing_tails: u32,
+    /// Transfers from the host too long for a frame
+    oversized_transfers: u32,
+    /// Dropping the packets of an oversized transfer until it ends
+    discard_transfer: bool,
+    /// Frames to the host dropped for a full queue
+    queue_full_dropped: u32,
+    /// Tell the host about frames from it that were discarded
+    drop_errors: bool,
+    /// Channels with a discard error frame waiting in the out queue
+    drop_error_queued: [bool; MAX_INTF],
+    /// Channels whose controller is error passive
+    error_passive: [bool; MAX_INTF],
+    /// Where the state read by the host comes from
+    state_source: StateSource,
+    /// State of each channel last reported by the application
+    reported_state: [Option<DeviceState>; MAX_INTF],
+    /// Answer the capabilities request
+    capabilities_request: bool,
+    echo_mode: EchoMode,
+    /// Echoes keep their place in the out queue
+    ordered_echoes: bool,
+    /// Frames accepted from the host waiting to be echoed
+    echo_pending: [heapless::Vec<HostFrame, MAX_ECHO>; MAX_INTF],
+    /// Time taken to echo frames from the host
+    echo_latency: [Option<LatencyStats>; MAX_INTF],
+    /// Partially transferred frames dropped to resynchronise
+    split_frames_dropped: u32,
+    /// Polls without a completed write before the host is taken to have
+    /// stopped reading
+    stall_threshold: Option<u32>,
+    /// Polls failing to write to the host since a write last completed
+    stall_polls: u32,
+    /// Polls the second half of a split frame may wait before it is dropped
+    split_retry_limit: Option<u32>,
+    /// Polls the second half of the split frame has waited
+    split_retries: u32,
+    /// A short packet must end the host's transfer holding the first half of
+    /// a dropped frame
+    terminate_transfer: bool,
+    /// The host has stopped reading, frames for it are dropped oldest first
+    stalled: bool,
+    /// Times the host stopped reading
+    stalls: u32,
+    /// Frames to the host dropped whilst it wasn't reading
+    stall_dropped: u32,
+    /// Microseconds a frame may wait for the host before it is dropped
+    max_frame_age: Option<u32>,
+    /// Frames to the host dropped for being queued too long
+    stale_dropped: u32,
+    /// The next frame to the host on each channel reports stale frames dropped
+    stale_overflow: [bool; MAX_INTF],
+    /// Microseconds between summaries of frames to the host dropped for a
+    /// full queue
+    drop_summary: Option<u32>,
+    /// Frames to the host dropped on each channel since the last summary
+    summary_dropped: [u32; MAX_INTF],
+    /// Time the last summary was queued for each channel
+    summary_sent_us: [Option<u32>; MAX_INTF],
+    /// Number the frames to the host in the reserved byte
+    sequence_numbers: bool,
+    /// Sequence number of the next frame to the host on each channel
+    sequence: [u8; MAX_INTF],
+    /// Picks the channel to forward frames from the CAN side to
+    bridge: Option<fn(Channel, &host::Frame) -> Option<Channel>>,
+    /// The last frame forwarded to each channel
+    bridged_last: [Option<host::Frame>; MAX_INTF],
+    /// Suppression of repeated frames to the host on each channel
+    dedup: [Option<DedupConfig>; MAX_INTF],
+    /// The last frame queued for the host on each channel and when, whilst
+    /// suppressing repeats
+    dedup_last: [Option<(host::Frame, u32)>; MAX_INTF],
+    /// Repeated frames suppressed on each channel
+    dedup_suppressed: [u32; MAX_INTF],
+    /// Frames to the host dropped by the device filter on each channel
+    filter_dropped: [u32; MAX_INTF],
+    /// Frame rates of each channel, if enabled
+    load: Option<LoadMonitor>,
+    /// Requests made by the host whilst a channel was started
+    host_capabilities: HostCapabilities,
+    /// Milliseconds of started channels without a state poll before warning
+    state_poll_warning_ms: Option<u32>,
+    /// Milliseconds with a started channel since the bus was reset
+    started_ms: u32,
+    /// Most frames written to the host per millisecond on each channel
+    frame_rate_limit: [Option<u16>; MAX_INTF],
+    /// Frames each channel may still write to the host this millisecond
+    frame_tokens: [u16; MAX_INTF],
+    /// Rewriting of each channel's frames between the bus and the host
+    transforms: [Option<Transform>; MAX_INTF],
+    /// Identify blink pattern of each channel
+    identify: [IdentifyController; MAX_INTF],
+    /// Label of each channel, from the first
+    channel_labels: &'a [&'a str],
+    /// String index of the first label
+    label_index: Option<StringIndex>,
+    /// Frames forwarded between each pair of channels
+    bridged: [[u32; MAX_INTF]; MAX_INTF],
+    /// Frames the device didn't accept between each pair of channels
+    bridge_dropped: [[u32; MAX_INTF]; MAX_INTF],
+    /// The last control transfer rejected
+    last_rejection: Option<Rejection>,
+    /// Device information sent to the host
+    config: DeviceConfig,
+    /// `software_version` set by the application
+    software_version: Option<u32>,
+    bit_timing: DeviceBitTimingConst,
+    #[cfg(feature = "fd")]
+    bit_timing_ext: DeviceBitTimingConstExtended,
+    /// The host has read the timing constants since the bus was reset
+    bit_timing_read: bool,
+    /// Byte order last set by the host, little endian until then
+    host_byte_order: u32,
+    /// Called with every packet written to the host
+    #[cfg(feature = "wire-dump")]
+    bulk_in_hook: Option<fn(&[u8])>,
+    /// Called with every packet read from the host
+    #[cfg(feature = "wire-dump")]
+    bulk_out_hook: Option<fn(&[u8])>,
+    /// Called with every vendor request to the class
+    #[cfg(feature = "wire-dump")]
+    control_hook: Option<fn(&control::Request, &[u8])>,
 }
 
-impl<'a, B: UsbBus, D: Device> GsCan<'a, B, D> {
-    /// Crate a new GsUsb device.
-    pub fn new(alloc: &'a UsbBusAllocator<B>, device: D) -> Self {
-        // hack to get the out endpoint number right.
-        let _: EndpointOut<'a, B> = alloc.bulk(0);
+impl<'a, B: UsbBus, D: Device, const RX: usize> GsCan<'a, B, D, RX> {
+    /// Crate a new GsUsb device.
+    ///
+    /// The bulk endpoints are allocated at `0x81` and `0x02`, the addresses
+    /// hard-coded in the host drivers. In a composite device, create the class
+    /// before any other class, so it also has interface 0 where the Linux
+    /// driver sends its requests. Vendor requests to other interfaces are left
+    /// to their classes.
+    ///
+    /// Responses to the host are sent over as many packets as needed, so any
+    /// control endpoint packet size works, down to 8 bytes.
+    ///
+    /// The device information and timing constants are read from `device`
+    /// here and the host byte order defaults to little endian, so hosts may
+    /// send the startup requests in any order or skip the host format request,
+    /// as python-can does.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bus can't allocate the endpoints at these addresses.
+    ///
+    /// Without the `fd` feature, panics if the device advertises
+    /// [`Feature::FD`] or [`Feature::BT_CONST_EXT`].
+    ///
+    /// In debug builds, panics if the [`DeviceConfig`] has more channels than
+    /// supported. Release builds only report the supported channels.
+    pub fn new(alloc: &'a UsbBusAllocator<B>, device: D) -> Self {
+        Self::new_with_endpoints(alloc, device, |alloc| {
+            (
+                alloc_bulk(alloc, ENDPOINT_IN),
+                alloc_bulk(alloc, ENDPOINT_OUT),
+            )
+        })
+    }
+
+    /// Create a new GsUsb device, allocating the bulk endpoints with
+    /// `alloc_endpoints`.
+    ///
+    /// For buses needing more than [`GsCan::new`] gives, e.g. a different
+    /// `bInterval` or a backend specific hint. `alloc_endpoints` returns the IN
+    /// and OUT endpoints, allocated with [`UsbBusAllocator::alloc`] at `0x81`
+    /// and `0x02` as bulk endpoints with 64 byte packets.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoints returned don't match the above, otherwise as
+    /// [`GsCan::new`].
+    pub fn new_with_endpoints<F>(
+        alloc: &'a UsbBusAllocator<B>,
+        device: D,
+        alloc_endpoints: F,
+    ) -> Self
+    where
+        F: FnOnce(&'a UsbBusAllocator<B>) -> (EndpointIn<'a, B>, EndpointOut<'a, B>),
+    {
+        let interface = alloc.interface();
+        let (write_endpoint, read_endpoint) = alloc_endpoints(alloc);
+        check_bulk(&write_endpoint, ENDPOINT_IN);
+        check_bulk(&read_endpoint, ENDPOINT_OUT);
+
+        let mut config = device.config();
+        // the host would address channels the class has no state for.
+        debug_assert!(
+            usize::from(config.interface_count) < MAX_INTF,
+            "more channels than supported",
+        );
+        clamp_channels(&mut config);
+
+        let bit_timing = device.bit_timing();
+        config.software_version = SOFTWARE_VERSION;
+        // only read from devices advertising it.
+        #[cfg(feature = "fd")]
+        let bit_timing_ext = if bit_timing.features.contains(Feature::BT_CONST_EXT) {
+            device.bit_timing_ext()
+        } else {
+            extend_bit_timing(&bit_timing)
+        };
+        #[cfg(not(feature = "fd"))]
+        assert!(
+            !bit_timing
+                .features
+                .intersects(Feature::FD | Feature::BT_CONST_EXT),
+            "CAN FD advertised without the `fd` feature",
+        );
+
+        Self {
+            interface,
+            write_endpoint,
+            read_endpoint,
+            device,
+            wire_format: [WireFormat::default(); MAX_INTF],
+            timing: [PendingTiming::default(); MAX_INTF],
+            channel_features: [Feature::all(); MAX_INTF],
+            forced_features: [Feature::empty(); MAX_INTF],
+            forced_off_features: [Feature::empty(); MAX_INTF],
+            default_termination: [None; MAX_INTF],
+            termination_set: [false; MAX_INTF],
+            mode: [ChannelMode::Stopped; MAX_INTF],
+            started_features: [Feature::empty(); MAX_INTF],
+            deferred_start: false,
+            deferred_stop: false,
+            fault: [None; MAX_INTF],
+            out_queue: FrameQueue::new(),
+            out_split: None,
+            configured: false,
+            enabled: true,
+            disabled_dropped: 0,
+            disabled_discarded: 0,
+            unconfigured_policy: UnconfiguredPolicy::Drop,
+            unconfigured_dropped: 0,
+            in_frame: None,
+            tx_waker: None,
+            rx_delivery: RxDelivery::Direct,
+            rx_queue: Queue::new(),
+            rx_blocked: false,
+            rx_waker: None,
+            host_tx_policy: HostTxPolicy::Nak,
+            rx_pending: [None; MAX_INTF],
+            host_tx_dropped: [0; MAX_INTF],
+            invalid_host_frames: 0,
+            #[cfg(feature = "panic-free")]
+            invalid_frames: 0,
+            #[cfg(feature = "fd")]
+            fd_sized_classic: false,
+            normalized_frames: 0,
+            missing_tails: 0,
+            oversized_transfers: 0,
+            discard_transfer: false,
+            queue_full_dropped: 0,
+            drop_errors: false,
+            drop_error_queued: [false; MAX_INTF],
+            error_passive: [false; MAX_INTF],
+            state_source: StateSource::Reported,
+            reported_state: [None; MAX_INTF],
+            capabilities_request: false,
+            echo_mode: EchoMode::Immediate,
+            ordered_echoes: false,
+            echo_pending: Default::default(),
+            echo_latency: [None; MAX_INTF],
+            split_frames_dropped: 0,
+            stall_threshold: None,
+            stall_polls: 0,
+            split_retry_limit: None,
+            split_retries: 0,
+            terminate_transfer: false,
+            stalled: false,
+            stalls: 0,
+            stall_dropped: 0,
+            max_frame_age: None,
+            stale_dropped: 0,
+            stale_overflow: [false; MAX_INTF],
+            drop_summary: None,
+            summary_dropped: [0; MAX_INTF],
+            summary_sent_us: [None; MAX_INTF],
+            sequence_numbers: false,
+            sequence: [0; MAX_INTF],
+            bridge: None,
+            bridged_last: [None; MAX_INTF],
+            dedup: [None; MAX_INTF],
+            dedup_last: [None; MAX_INTF],
+            dedup_suppressed: [0; MAX_INTF],
+            filter_dropped: [0; MAX_INTF],
+            load: None,
+            host_capabilities: HostCapabilities::default(),
+            state_poll_warning_ms: None,
+            started_ms: 0,
+            frame_rate_limit: [None; MAX_INTF],
+            frame_tokens: [0; MAX_INTF],
+            transforms: [None; MAX_INTF],
+            identify: [IdentifyController::new(); MAX_INTF],
+            channel_labels: &[],
+            label_index: None,
+            bridged: [[0; MAX_INTF]; MAX_INTF],
+            bridge_dropped: [[0; MAX_INTF]; MAX_INTF],
+            last_rejection: None,
+            config,
+            software_version: None,
+            bit_timing,
+            #[cfg(feature = "fd")]
+            bit_timing_ext,
+            bit_timing_read: false,
+            host_byte_order: HOST_LITTLE_ENDIAN,
+            #[cfg(feature = "wire-dump")]
+            bulk_in_hook: None,
+            #[cfg(feature = "wire-dump")]
+            bulk_out_hook: None,
+            #[cfg(feature = "wire-dump")]
+            control_hook: None,
+        }
+    }
+
+    /// Number of partially transferred frames dropped when the bus was reset,
+    /// when the host changed the mode of their channel, or when the host
+    /// didn't read a split frame, see [`GsCan::with_split_retry_limit`].
+    ///
+    /// Firmware that detects a disconnect (e.g. VBUS loss) should call
+    /// [`UsbClass::reset`] so both directions restart at a frame boundary.
+    pub fn split_frames_dropped(&self) -> u32 {
+        self.split_frames_dropped
+    }
+
+    /// The last control transfer rejected, for debugging interoperability
+    /// issues with the host.
+    pub fn last_rejection(&self) -> Option<Rejection> {
+        self.last_rejection
+    }
+
+    /// Channel `index` of the device, if the device has it.
+    ///
+    /// All 16 bits are checked, a channel number with the high byte set is
+    /// rejected rather than truncated to another channel.
+    pub fn channel(&self, index: u16) -> Result<Channel, InvalidChannel> {
+        Channel::try_from(index).and_then(|channel| {
+            if channel.0 <= self.config.interface_count {
+                Ok(channel)
+            } else {
+                Err(InvalidChannel)
+            }
+        })
+    }
+
+    /// Returns `true` if the host has started the channel.
+    pub fn is_started(&self, channel: Channel) -> bool {
+        self.mode.at(channel).is_started()
+    }
+
+    /// Where a channel is between the host's start and reset requests.
+    pub fn channel_mode(&self, channel: Channel) -> ChannelMode {
+        *self.mode.at(channel)
+    }
+
+    /// Leave started channels [`ChannelMode::Starting`] until the application
+    /// calls [`GsCan::start_complete`], for controllers that take a while to
+    /// start, e.g. waiting for a PLL to lock or a transceiver to wake.
+    ///
+    /// A start request repeated in the meantime, e.g. by a host retrying after
+    /// a timeout, is rejected with [`RejectReason::ChannelStarting`] rather
+    /// than starting the device again. The host reads the state as
+    /// [`CanState::Stopped`] until the start completes. A reset request
+    /// resets the device as usual. Disabled by default.
+    pub fn with_deferred_start(mut self, deferred: bool) -> Self {
+        self.deferred_start = deferred;
+        self
+    }
+
+    /// Complete the start of a channel, see [`GsCan::with_deferred_start`].
+    ///
+    /// Returns `false` if the channel wasn't starting.
+    pub fn start_complete(&mut self, channel: Channel) -> bool {
+        let mode = self.mode.at_mut(channel);
+        if *mode != ChannelMode::Starting {
+            return false;
+        }
+        *mode = ChannelMode::Started;
+        true
+    }
+
+    /// Leave reset channels [`ChannelMode::Stopping`] until the application
+    /// calls [`GsCan::stop_complete`], for controllers that take a while to
+    /// leave the bus, e.g. finishing a frame or waiting for bus idle.
+    ///
+    /// [`Device::reset`] is called as usual. A start request in the meantime
+    /// is rejected with [`RejectReason::ChannelStopping`], and the host reads
+    /// the state as [`CanState::Stopped`]. Disabled by default.
+    pub fn with_deferred_stop(mut self, deferred: bool) -> Self {
+        self.deferred_stop = deferred;
+        self
+    }
+
+    /// Complete the reset of a channel, see [`GsCan::with_deferred_stop`].
+    ///
+    /// Returns `false` if the channel wasn't stopping.
+    pub fn stop_complete(&mut self, channel: Channel) -> bool {
+        let mode = self.mode.at_mut(channel);
+        if *mode != ChannelMode::Stopping {
+            return false;
+        }
+        *mode = ChannelMode::Stopped;
+        true
+    }
+
+    /// Stop a started channel from the device side, e.g. on a transceiver
+    /// fault, telling the host the channel is bus-off.
+    ///
+    /// The application stops the controller itself, [`Device::reset`] isn't
+    /// called. Frames from the host waiting to be echoed are echoed as
+    /// aborted, followed by a [`ErrorClass::BUS_OFF`] error frame, and the
+    /// host reads the state as bus-off. Frames from the host for the channel
+    /// are dropped as by [`HostTxPolicy::DropNewest`] until the host starts
+    /// it again, which clears the fault.
+    pub fn channel_fault(&mut self, channel: Channel, reason: FaultReason) {
+        #[cfg(feature = "defmt-03")]
+        defmt::warn!("Channel {} stopped by the device: {}", channel, reason);
+
+        *self.fault.at_mut(channel) = Some(reason);
+        *self.mode.at_mut(channel) = ChannelMode::Stopped;
+        *self.reported_state.at_mut(channel) = Some(DeviceState::bus_off(0, 0));
+
+        if let Some(held) = self.rx_pending.at_mut(channel).take() {
+            self.drop_host_frame(channel, held.frame);
+        }
+        while let Some(echo_id) = self
+            .echo_pending
+            .at(channel)
+            .first()
+            .map(|pending| pending.frame.echo_id)
+        {
+            self.complete_echo(channel, echo_id, FrameFlag::OVERFLOW);
+        }
+
+        let mut frame = host::Frame::new_error(ErrorClass::BUS_OFF, [0; 8]);
+        frame.set_kind(FrameKind::Receive);
+        frame.interface = channel.into();
+        self.queue_report(channel, frame);
+    }
+
+    /// Why the device stopped a channel, until the host starts it again.
+    pub fn fault(&self, channel: Channel) -> Option<FaultReason> {
+        *self.fault.at(channel)
+    }
+
+    /// Configuration of a channel negotiated with the host, e.g. for a status
+    /// display.
+    pub fn channel_info(&self, channel: Channel) -> ChannelInfo {
+        let started = self.mode.at(channel).is_started();
+        let nominal_timing = self.timing.at(channel).nominal;
+        #[cfg(feature = "fd")]
+        let data_timing = self.timing.at(channel).data;
+        #[cfg(not(feature = "fd"))]
+        let data_timing = None;
+        let fclk_can = self.bit_timing.fclk_can;
+
+        ChannelInfo {
+            started,
+            features: if started {
+                *self.started_features.at(channel)
+            } else {
+                Feature::empty()
+            },
+            nominal_timing,
+            data_timing,
+            nominal_bitrate: nominal_timing.and_then(|timing| timing.bitrate(fclk_can)),
+            data_bitrate: data_timing.and_then(|timing| timing.bitrate(fclk_can)),
+        }
+    }
+
+    /// Returns `true` if nothing is pending in either direction.
+    ///
+    /// That is no frames or echoes waiting to be sent to the host, no frame
+    /// split across packets and no frames from the host held back or waiting
+    /// to be dequeued. Use to gate entering a low power mode.
+    ///
+    /// This doesn't consider the bus state, whilst suspended pending frames
+    /// stay queued until the host resumes the bus.
+    pub fn is_idle(&self) -> bool {
+        self.out_queue.is_empty()
+            && self.out_split.is_none()
+            && !self.terminate_transfer
+            && self.in_frame.is_none()
+            && !self.discard_transfer
+            && self.rx_queue.is_empty()
+            && self.rx_pending.iter().all(Option::is_none)
+            && self.echo_pending.iter().all(heapless::Vec::is_empty)
+    }
+
+    /// Feature flags advertised to the host, the union of those of the
+    /// channels, see [`GsCan::with_channel_features`].
+    pub fn advertised_features(&self) -> Feature {
+        self.bit_timing.features
+    }
+
+    /// Limit the features available on a channel to `features`, for devices
+    /// whose channels differ, e.g. a classic only channel beside a CAN FD one.
+    ///
+    /// The host only knows the features advertised for the whole device, so
+    /// the features of [`Device::bit_timing`] must be the union of those of
+    /// the channels. Starts requesting features not available on the channel
+    /// are rejected with [`RejectReason::UnsupportedFeature`], as are identify
+    /// requests for a channel without [`Feature::IDENTIFY`] and termination
+    /// requests for a channel without [`Feature::TERMINATION`]. Every channel
+    /// has all the advertised features by default.
+    pub fn with_channel_features(mut self, channel: Channel, features: Feature) -> Self {
+        *self.channel_features.at_mut(channel) = features;
+        self
+    }
+
+    /// Features available on a channel, those advertised limited by
+    /// [`GsCan::with_channel_features`].
+    pub fn channel_features(&self, channel: Channel) -> Feature {
+        self.bit_timing.features & *self.channel_features.at(channel)
+    }
+
+    /// Start a channel with `features` whatever the host requests, e.g.
+    /// [`Feature::LISTEN_ONLY`] for a bus the device must never drive.
+    ///
+    /// The features are added to those of each start passed to
+    /// [`Device::validate_start`] and [`Device::start`] and reported by
+    /// [`GsCan::channel_info`], they needn't be advertised. The host isn't
+    /// told, it only knows the features of the whole device. [`Feature::FD`]
+    /// and [`Feature::HW_TIMESTAMP`] are ignored, as they change the frames
+    /// the host expects. Features the host must not request are removed with
+    /// [`GsCan::with_forced_off_features`] instead, rejecting the start. None
+    /// by default.
+    pub fn with_forced_features(mut self, channel: Channel, features: Feature) -> Self {
+        *self.forced_features.at_mut(channel) =
+            features.difference(Feature::FD | Feature::HW_TIMESTAMP);
+        self
+    }
+
+    /// Features a channel is started with whatever the host requests, see
+    /// [`GsCan::with_forced_features`].
+    pub fn forced_features(&self, channel: Channel) -> Feature {
+        *self.forced_features.at(channel)
+    }
+
+    /// Never start a channel with `features`, e.g. [`Feature::LOOP_BACK`] on a
+    /// channel wired to a bus under test.
+    ///
+    /// Starts requesting the features are rejected with
+    /// [`RejectReason::FeatureForcedOff`], as is every start if they overlap
+    /// the features of [`GsCan::with_forced_features`]. They are masked from
+    /// the features of the bit timing constants read for the channel, which
+    /// the Linux driver reads per channel, so it doesn't offer them. A host
+    /// reading the constants once for the device sees those of channel 0.
+    /// None by default.
+    pub fn with_forced_off_features(mut self, channel: Channel, features: Feature) -> Self {
+        *self.forced_off_features.at_mut(channel) = features;
+        self
+    }
+
+    /// Features a channel is never started with, see
+    /// [`GsCan::with_forced_off_features`].
+    pub fn forced_off_features(&self, channel: Channel) -> Feature {
+        *self.forced_off_features.at(channel)
+    }
+
+    /// Switch the termination resistor of a channel to `enabled` with
+    /// [`Device::set_termination`] when the host starts it, unless the host set
+    /// the termination itself since the last bus reset.
+    ///
+    /// For devices whose resistor must be in a known state on the bus, as a
+    /// host needn't set it. Only applied to channels with
+    /// [`Feature::TERMINATION`]. The termination is left as it is by default.
+    pub fn with_default_termination(mut self, channel: Channel, enabled: bool) -> Self {
+        *self.default_termination.at_mut(channel) = Some(enabled);
+        self
+    }
+
+    /// CAN clock frequency advertised to the host.
+    pub fn can_clock(&self) -> u32 {
+        self.bit_timing.fclk_can
+    }
+
+    /// Set the CAN clock frequency advertised to the host, replacing that from
+    /// [`Device::bit_timing`] and [`Device::bit_timing_ext`].
+    ///
+    /// For devices whose clock is chosen at runtime, call before the device is
+    /// enumerated. The host calculates bit timings from the clock it read, so
+    /// once it has read the timing constants the change is refused until the
+    /// bus is reset. Returns `false` if refused.
+    pub fn set_can_clock(&mut self, fclk_can: u32) -> bool {
+        if self.bit_timing_read {
+            return false;
+        }
+
+        self.bit_timing.fclk_can = fclk_can;
+        #[cfg(feature = "fd")]
+        {
+            self.bit_timing_ext.fclk_can = fclk_can;
+        }
+        true
+    }
+
+    /// Device configuration sent to the host.
+    pub fn device_config(&self) -> &DeviceConfig {
+        &self.config
+    }
+
+    /// Report `version` as the `software_version` of the device config, e.g.
+    /// to match a fleet of older firmware, rather than [`SOFTWARE_VERSION`].
+    pub fn with_software_version(mut self, version: u32) -> Self {
+        self.software_version = Some(version);
+        self.config.software_version = version;
+        self
+    }
+
+    /// Set how frames from the host are delivered.
+    ///
+    /// Defaults to [`RxDelivery::Direct`].
+    pub fn with_rx_delivery(mut self, delivery: RxDelivery) -> Self {
+        self.rx_delivery = delivery;
+        self
+    }
+
+    /// Set what happens to frames from the host the device cannot accept.
+    ///
+    /// Defaults to [`HostTxPolicy::Nak`].
+    pub fn with_host_tx_policy(mut self, policy: HostTxPolicy) -> Self {
+        self.host_tx_policy = policy;
+        self
+    }
+
+    /// Set whether an error frame is sent when a frame from the host is
+    /// discarded, by the [`HostTxPolicy`] or for being malformed.
+    ///
+    /// The error frame reports a transmit overflow with
+    /// [`ControllerError::TX_OVERFLOW`], so the drop shows in the host's
+    /// statistics. Only sent for started channels, with at most one waiting to
+    /// be sent per channel. Defaults to `false`.
+    pub fn with_drop_errors(mut self, enabled: bool) -> Self {
+        self.drop_errors = enabled;
+        self
+    }
+
+    /// Report frames to the host dropped for a full queue, e.g. when the CAN
+    /// bus carries more than full speed USB can.
+    ///
+    /// Whilst frames are dropped, an error frame with
+    /// [`ControllerError::RX_OVERFLOW`] is queued for the channel at most every
+    /// `interval_us` microseconds as measured by [`Device::timestamp_us`],
+    /// with the number of frames dropped since the last one in data bytes 4 to
+    /// 7, little endian. Without a timer, one is queued as soon as the queue
+    /// has room. Disabled by default.
+    pub fn with_drop_summary(mut self, interval_us: u32) -> Self {
+        self.drop_summary = Some(interval_us);
+        self
+    }
+
+    /// Set whether frames sent to the host with [`GsCan::transmit`] carry a
+    /// sequence number, e.g. for a logger to find frames dropped in the device.
+    ///
+    /// The number counts every frame passed to [`GsCan::transmit`] on the
+    /// channel, wrapping, so frames dropped for a full queue or whilst the host
+    /// isn't there leave a gap. It is sent in the reserved byte of the frame
+    /// header, see [`host::Frame::sequence`]. This is a nonstandard extension,
+    /// hosts ignore the byte. Echoes and error frames from the class carry 0.
+    /// Defaults to `false`.
+    pub fn with_sequence_numbers(mut self, enabled: bool) -> Self {
+        self.sequence_numbers = enabled;
+        self
+    }
+
+    /// Set whether classic frames the host sends in CAN FD sized transfers on
+    /// a classic channel are accepted, as python-can's gs_usb backend and some
+    /// Android stacks do.
+    ///
+    /// Only the classic data area is used, the rest of the transfer is
+    /// ignored. Accepted frames are counted by [`GsCan::normalized_frames`].
+    /// Otherwise such transfers are discarded as malformed. Defaults to
+    /// `false`.
+    #[cfg(feature = "fd")]
+    pub fn with_fd_sized_classic(mut self, enabled: bool) -> Self {
+        self.fd_sized_classic = enabled;
+        self
+    }
+
+    /// Set a hook called with the bytes of every packet written to the bulk IN
+    /// endpoint, including the second half of split frames.
+    ///
+    /// For debugging the wire protocol, e.g. by streaming packets over RTT.
+    /// The hook runs in the USB context and must return quickly.
+    #[cfg(feature = "wire-dump")]
+    pub fn with_bulk_in_hook(mut self, hook: fn(&[u8])) -> Self {
+        self.bulk_in_hook = Some(hook);
+        self
+    }
+
+    /// Set a hook called with the bytes of every packet read from the bulk OUT
+    /// endpoint, including zero length packets and the second half of split
+    /// frames.
+    ///
+    /// The hook runs in the USB context and must return quickly.
+    #[cfg(feature = "wire-dump")]
+    pub fn with_bulk_out_hook(mut self, hook: fn(&[u8])) -> Self {
+        self.bulk_out_hook = Some(hook);
+        self
+    }
+
+    /// Set a hook called with every vendor request to the class and its data
+    /// stage, empty for requests reading from the device, before it is
+    /// handled.
+    ///
+    /// Along with the bulk hooks, records a session to replay off-target, see
+    /// `session` with the `host-tools` feature. The hook runs in the USB
+    /// context and must return quickly.
+    #[cfg(feature = "wire-dump")]
+    pub fn with_control_hook(mut self, hook: fn(&control::Request, &[u8])) -> Self {
+        self.control_hook = Some(hook);
+        self
+    }
+
+    /// Advertise the features recommended for a device using a known
+    /// identifier, on top of those from [`Device::bit_timing`].
+    ///
+    /// The [`Device`] must support the added features.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the device has more channels than the identifier supports,
+    /// or without the `fd` feature if CAN FD is recommended.
+    pub fn with_known_device(mut self, known: &identifier::KnownDevice) -> Self {
+        assert!(
+            self.config.interface_count < known.max_channels,
+            "more channels than the identifier supports",
+        );
+
+        let features = known.recommended_features();
+        #[cfg(not(feature = "fd"))]
+        assert!(
+            !features.intersects(Feature::FD | Feature::BT_CONST_EXT),
+            "CAN FD advertised without the `fd` feature",
+        );
+
+        #[cfg(feature = "fd")]
+        {
+            let advertised = self.bit_timing.features;
+            if features
+                .difference(advertised)
+                .contains(Feature::BT_CONST_EXT)
+            {
+                self.bit_timing_ext = self.device.bit_timing_ext();
+                self.bit_timing_ext.fclk_can = self.bit_timing.fclk_can;
+            }
+            self.bit_timing_ext.features |= features;
+        }
+        self.bit_timing.features |= features;
+        self.config.software_version = self.software_version.unwrap_or(SOFTWARE_VERSION);
+        self
+    }
+
+    /// Set what happens to frames for the host whilst the device isn't
+    /// configured.
+    ///
+    /// Defaults to [`UnconfiguredPolicy::Drop`].
+    pub fn with_unconfigured_policy(mut self, policy: UnconfiguredPolicy) -> Self {
+        self.unconfigured_policy = policy;
+        self
+    }
+
+    /// Drop the oldest frames for the host rather than the newest once it
+    /// stops reading, e.g. when the application on the host crashed with the
+    /// interface still claimed.
+    ///
+    /// The host is taken to have stopped reading when `polls` polls of the
+    /// class in a row find the bulk IN endpoint still busy, counting
+    /// [`GsCan::kick`]. Whilst stalled, [`GsCan::transmit`] makes room in a
+    /// full queue by dropping the oldest frame. The class recovers as soon as
+    /// a write completes again. Disabled by default.
+    pub fn with_stall_threshold(mut self, polls: u32) -> Self {
+        self.stall_threshold = Some(polls);
+        self
+    }
+
+    /// Drop a frame split across packets if the host doesn't read its first
+    /// packet within `polls` polls of the class, counting [`GsCan::kick`].
+    ///
+    /// The dropped frame is counted by [`GsCan::split_frames_dropped`]. The
+    /// host may still read the first packet, so a zero length packet is
+    /// written before the next frame to end that transfer short, where host
+    /// drivers discard it, rather than let the next frame complete it.
+    /// Disabled by default, the second half then waits for the host
+    /// indefinitely.
+    pub fn with_split_retry_limit(mut self, polls: u32) -> Self {
+        self.split_retry_limit = Some(polls);
+        self
+    }
+
+    /// Drop frames for the host that have waited longer than `max_age_us`
+    /// microseconds in the queue, as measured by [`Device::timestamp_us`],
+    /// rather than deliver stale data once the host reads again.
+    ///
+    /// Stale frames are dropped when the class is polled, counting
+    /// [`GsCan::kick`], and counted by [`GsCan::stale_dropped`]. The next frame
+    /// sent on the channel has [`FrameFlag::OVERFLOW`] set, so the host
+    /// counts an overflow. Echoes and error frames are never dropped. Does
+    /// nothing without a timer. Disabled by default.
+    pub fn with_max_frame_age(mut self, max_age_us: u32) -> Self {
+        self.max_frame_age = Some(max_age_us);
+        self
+    }
+
+    /// Set when frames from the host are echoed back.
+    ///
+    /// Defaults to [`EchoMode::Immediate`].
+    pub fn with_echo_mode(mut self, mode: EchoMode) -> Self {
+        self.echo_mode = mode;
+        self
+    }
+
+    /// Keep echoes in the order the frames were accepted from the host,
+    /// relative to the frames sent to the host afterwards.
+    ///
+    /// Only used with [`EchoMode::Device`]. A place in the out queue is held
+    /// for the echo when the frame is accepted, and filled by
+    /// [`GsCan::echo`] or [`GsCan::echo_aborted`]. Frames to the host queued
+    /// after it wait until then, so a frame received on the bus after one
+    /// sent from the host reaches the host after its echo. Every accepted
+    /// frame must be echoed, or the host stops receiving until the channel
+    /// is reset. Disabled by default.
+    pub fn with_ordered_echoes(mut self, ordered: bool) -> Self {
+        self.ordered_echoes = ordered;
+        self
+    }
+
+    /// Echo a frame from the host once it has been sent on the bus.
+    ///
+    /// Only used with [`EchoMode::Device`]. `echo_id` is the
+    /// [`echo_id`](host::Frame::echo_id) of the frame passed to the
+    /// application. Returns `false` if no frame with that echo ID is waiting.
+    pub fn echo(&mut self, channel: Channel, echo_id: u32) -> bool {
+        self.complete_echo(channel, echo_id, FrameFlag::empty())
+    }
+
+    /// Echo a frame from the host that will never be sent on the bus, e.g. a
+    /// one-shot frame that lost arbitration or hit an error.
+    ///
+    /// Only used with [`EchoMode::Device`]. The echo is sent with
+    /// [`FrameFlag::OVERFLOW`] set, like frames dropped by the
+    /// [`HostTxPolicy`], so the host frees the transmit slot and counts an
+    /// error. Otherwise the same as [`GsCan::echo`].
+    pub fn echo_aborted(&mut self, channel: Channel, echo_id: u32) -> bool {
+        self.complete_echo(channel, echo_id, FrameFlag::OVERFLOW)
+    }
+
+    /// Returns `true` if the host has configured the device.
+    pub fn is_configured(&self) -> bool {
+        self.configured
+    }
+
+    /// Set whether the device is configured.
+    ///
+    /// The class follows the host's `SET_CONFIGURATION` requests and bus
+    /// resets itself, this is only needed when the configuration is tracked
+    /// elsewhere, e.g. from [`UsbDevice::state`].
+    ///
+    /// [`UsbDevice::state`]: usb_device::device::UsbDevice::state
+    pub fn set_configured(&mut self, configured: bool) {
+        if configured == self.configured {
+            return;
+        }
+
+        self.configured = configured;
+        if !configured {
+            // the endpoints start over once configured again.
+            self.resync();
+            self.trim_unconfigured(self.unconfigured_policy.newest());
+        }
+    }
+
+    /// Enable or disable the CAN function without leaving the bus, e.g. whilst
+    /// a firmware update runs over another interface.
+    ///
+    /// Disabling resets the started channels, calling [`Device::reset`], and
+    /// flushes the frames waiting for the host and those held from it. Whilst
+    /// disabled, vendor requests other than reading the device config are
+    /// rejected with [`RejectReason::Disabled`], data from the host is
+    /// discarded and frames for the host are dropped. Once enabled again, the
+    /// channels stay reset until the host starts them.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled == self.enabled {
+            return;
+        }
+
+        self.enabled = enabled;
+        if enabled {
+            return;
+        }
+
+        for channel in Channel::all() {
+            if self.mode.at(channel).is_started() {
+                self.device.reset(channel);
+            }
+            *self.mode.at_mut(channel) = ChannelMode::Stopped;
+            *self.wire_format.at_mut(channel) = WireFormat::default();
+            self.clear_echoes(channel);
+            *self.error_passive.at_mut(channel) = false;
+            *self.reported_state.at_mut(channel) = None;
+        }
+
+        self.resync();
+        while !self.out_queue.is_empty() {
+            self.pop_out_frame();
+        }
+        self.rx_blocked = false;
+        self.rx_pending = [None; MAX_INTF];
+        self.drop_error_queued = [false; MAX_INTF];
+        self.summary_dropped = [0; MAX_INTF];
+    }
+
+    /// Returns `true` unless the CAN function is disabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Number of frames to the host dropped whilst disabled.
+    pub fn disabled_dropped(&self) -> u32 {
+        self.disabled_dropped
+    }
+
+    /// Number of bytes from the host discarded whilst disabled.
+    pub fn disabled_discarded(&self) -> u32 {
+        self.disabled_discarded
+    }
+
+    /// Sequence number of the next frame to the host on a channel, see
+    /// [`GsCan::with_sequence_numbers`].
+    pub fn sequence(&self, channel: Channel) -> u8 {
+        *self.sequence.at(channel)
+    }
+
+    /// Number of frames to the host dropped by the [`UnconfiguredPolicy`].
+    pub fn unconfigured_dropped(&self) -> u32 {
+        self.unconfigured_dropped
+    }
+
+    /// Returns `true` whilst the host isn't reading, see
+    /// [`GsCan::with_stall_threshold`].
+    pub fn is_stalled(&self) -> bool {
+        self.stalled
+    }
+
+    /// Number of times the host stopped reading.
+    pub fn stalls(&self) -> u32 {
+        self.stalls
+    }
+
+    /// Number of frames to the host dropped whilst it wasn't reading.
+    pub fn stall_dropped(&self) -> u32 {
+        self.stall_dropped
+    }
+
+    /// Number of frames to the host dropped for waiting longer than
+    /// allowed, see [`GsCan::with_max_frame_age`].
+    pub fn stale_dropped(&self) -> u32 {
+        self.stale_dropped
+    }
+
+    /// Set or clear the bridge forwarding frames between channels, e.g. for a
+    /// gateway mode.
+    ///
+    /// Frames sent to the host with [`GsCan::transmit`] or
+    /// [`GsCan::transmit_fd`] are passed to `bridge` with their channel. If it
+    /// returns another channel, the frame is also passed to
+    /// [`Device::receive`] for that channel, as a receive frame with the same
+    /// flags. This happens in the context calling `transmit`, whether or not
+    /// the host is there, and a frame the device doesn't accept is dropped.
+    ///
+    /// A frame that matches the last frame forwarded to its channel, e.g.
+    /// received back in loopback or from a bridge the other way, is sent to
+    /// the host but not forwarded again.
+    pub fn set_bridge(&mut self, bridge: Option<fn(Channel, &host::Frame) -> Option<Channel>>) {
+        self.bridge = bridge;
+        self.bridged_last = [None; MAX_INTF];
+    }
+
+    /// Set or clear the suppression of repeated frames to the host on a
+    /// channel, e.g. for a sensor sending the same frame at a high rate.
+    ///
+    /// A frame passed to [`GsCan::transmit`] or [`GsCan::transmit_fd`] is
+    /// dropped if it repeats the last frame queued on the channel within
+    /// `window_us`, as measured by [`Device::timestamp_us`]. Repeats have the
+    /// same identifier, flags and, with `compare_data`, data. The first repeat
+    /// after the window is sent, so a steady stream is reduced to one frame
+    /// per window. Error frames are always sent, echoes aren't affected and
+    /// nothing is suppressed without a timer.
+    pub fn set_dedup(&mut self, channel: Channel, config: Option<DedupConfig>) {
+        *self.dedup.at_mut(channel) = config;
+        *self.dedup_last.at_mut(channel) = None;
+    }
+
+    /// Number of repeated frames suppressed on a channel, see
+    /// [`GsCan::set_dedup`].
+    pub fn dedup_suppressed(&self, channel: Channel) -> u32 {
+        *self.dedup_suppressed.at(channel)
+    }
+
+    /// Estimate the load of each channel, see [`GsCan::load`].
+    ///
+    /// Call [`GsCan::tick_1ms`] every millisecond, e.g. from a timer
+    /// interrupt.
+    pub fn with_load_monitor(mut self, enabled: bool) -> Self {
+        self.load = enabled.then(LoadMonitor::default);
+        self
+    }
+
+    /// Requests the host has made whilst a channel was started, e.g. to send
+    /// error frames for bus-off to a host that doesn't read the state.
+    pub fn host_capabilities(&self) -> HostCapabilities {
+        self.host_capabilities
+    }
+
+    /// Log a warning once channels have been started for `after_ms` without
+    /// the host reading their state, for hosts too old to notice bus-off
+    /// otherwise. Only logs with the `defmt-03` feature.
+    ///
+    /// Call [`GsCan::tick_1ms`] every millisecond, e.g. from a timer
+    /// interrupt.
+    pub fn with_state_poll_warning(mut self, after_ms: u32) -> Self {
+        self.state_poll_warning_ms = Some(after_ms);
+        self
+    }
+
+    /// Write at most `per_ms` frames a millisecond to the host on a channel,
+    /// for host applications that can't keep up with a busy bus.
+    ///
+    /// Frames beyond the budget stay queued, and with them the frames of
+    /// other channels behind them, so the order is kept. They are dropped as
+    /// usual once the queue is full. A limit of 0 is taken as 1.
+    ///
+    /// Call [`GsCan::tick_1ms`] every millisecond, e.g. from a timer
+    /// interrupt, and [`GsCan::kick`] after it.
+    pub fn with_frame_rate_limit(mut self, channel: Channel, per_ms: u16) -> Self {
+        self.set_frame_rate_limit(channel, Some(per_ms));
+        self
+    }
+
+    /// Change or remove the frame rate limit of a channel, see
+    /// [`GsCan::with_frame_rate_limit`].
+    pub fn set_frame_rate_limit(&mut self, channel: Channel, per_ms: Option<u16>) {
+        let per_ms = per_ms.map(|per_ms| per_ms.max(1));
+        *self.frame_rate_limit.at_mut(channel) = per_ms;
+        *self.frame_tokens.at_mut(channel) = per_ms.unwrap_or(0);
+    }
+
+    /// Frames per millisecond a channel may write to the host, `None` if not
+    /// limited.
+    pub fn frame_rate_limit(&self, channel: Channel) -> Option<u16> {
+        *self.frame_rate_limit.at(channel)
+    }
+
+    /// Rewrite the frames of a channel between the bus and the host, e.g. for
+    /// a gateway showing the host other identifiers than those on the bus.
+    ///
+    /// Frames passed to [`GsCan::transmit`] and [`GsCan::transmit_fd`] are
+    /// rewritten with [`Transform::to_host`] before [`Device::filter_to_host`],
+    /// and frames from the host with [`Transform::from_host`] before they
+    /// reach the device. Echoes carry the frame as the host sent it, and the
+    /// bridge and [`GsCan::transmit_raw`] see frames unchanged.
+    pub fn with_transform(mut self, channel: Channel, transform: Transform) -> Self {
+        self.set_transform(channel, Some(transform));
+        self
+    }
+
+    /// Change or remove the rewriting of a channel's frames, see
+    /// [`GsCan::with_transform`].
+    pub fn set_transform(&mut self, channel: Channel, transform: Option<Transform>) {
+        *self.transforms.at_mut(channel) = transform;
+    }
+
+    /// Rewriting of a channel's frames, if any.
+    pub fn transform(&self, channel: Channel) -> Option<Transform> {
+        *self.transforms.at(channel)
+    }
+
+    /// Advance the load estimates, the state poll warning and the frame rate
+    /// limits by a millisecond.
+    ///
+    /// The estimates are updated every 100 ms, each update weighing an eighth,
+    /// so they settle to within a few percent about three seconds after the
+    /// load changes.
+    pub fn tick_1ms(&mut self) {
+        // a millisecond's budget, unused frames aren't carried over.
+        for channel in Channel::all() {
+            if let Some(per_ms) = *self.frame_rate_limit.at(channel) {
+                *self.frame_tokens.at_mut(channel) = per_ms;
+            }
+        }
+
+        if let Some(after_ms) = self.state_poll_warning_ms {
+            if self.any_started()
+                && !self.host_capabilities.polls_state
+                && self.started_ms < after_ms
+            {
+                self.started_ms += 1;
+                if self.started_ms == after_ms {
+                    #[cfg(feature = "defmt-03")]
+                    defmt::warn!(
+                        "Host hasn't read the state after {} ms, bus-off goes unnoticed",
+                        after_ms
+                    );
+                }
+            }
+        }
+
+        let Some(load) = &mut self.load else {
+            return;
+        };
+
+        load.ticks += 1;
+        if load.ticks < LOAD_WINDOW_MS {
+            return;
+        }
+        load.ticks = 0;
+        for rate in load.to_host.iter_mut().chain(load.from_host.iter_mut()) {
+            rate.update();
+        }
+    }
+
+    /// Estimated frames and bytes per second on a channel in each direction,
+    /// `None` without [`GsCan::with_load_monitor`].
+    ///
+    /// Counts the frames passed to [`GsCan::transmit`] that are queued for the
+    /// host and the valid frames read from the host.
+    pub fn load(&self, channel: Channel) -> Option<LoadStats> {
+        let load = self.load.as_ref()?;
+        let (to_host, from_host) = (load.to_host.at(channel), load.from_host.at(channel));
+
+        Some(LoadStats {
+            to_host_fps: to_host.fps(),
+            to_host_bps: to_host.bps(),
+            from_host_fps: from_host.fps(),
+            from_host_bps: from_host.bps(),
+        })
+    }
+
+    /// Name the channels for the host, e.g. "Chassis bus", with a string
+    /// descriptor for each label.
+    ///
+    /// `labels[n]` labels channel `n`. `iInterface` of the interface
+    /// descriptor is the string index of the first label and the label of
+    /// channel `n` is at `iInterface + n`, so a host tool reads them with
+    /// GET_DESCRIPTOR requests for those indices. Channels past the last label
+    /// have none. A label is at most 126 UTF-16 code units.
+    ///
+    /// The indices are allocated from `alloc`, call before building the
+    /// `UsbDevice`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more labels than channels.
+    pub fn with_channel_labels(
+        mut self,
+        alloc: &'a UsbBusAllocator<B>,
+        labels: &'a [&'a str],
+    ) -> Self {
+        assert!(
+            labels.len() <= usize::from(self.config.interface_count) + 1,
+            "more labels than channels",
+        );
+        // allocated in a row, the labels have consecutive indices.
+        self.label_index = None;
+        for _ in labels {
+            let index = alloc.string();
+            self.label_index.get_or_insert(index);
+        }
+        self.channel_labels = labels;
+        self
+    }
+
+    /// Use `controller` for the identify pattern of every channel, e.g. to
+    /// change the timeout.
+    pub fn with_identify(mut self, controller: IdentifyController) -> Self {
+        self.identify = [controller; MAX_INTF];
+        self
+    }
+
+    /// The identify pattern of a channel, turned on and off by the host.
+    ///
+    /// Poll it from the main loop to drive the channel's LED, see
+    /// [`identify`].
+    pub fn identify(&mut self, channel: Channel) -> &mut IdentifyController {
+        self.identify.at_mut(channel)
+    }
+
+    /// Number of frames to the host dropped on a channel by
+    /// [`Device::filter_to_host`].
+    pub fn filter_dropped(&self, channel: Channel) -> u32 {
+        *self.filter_dropped.at(channel)
+    }
+
+    /// Number of frames forwarded from `source` to `target` by the bridge.
+    pub fn bridged(&self, source: Channel, target: Channel) -> u32 {
+        *self.bridged.at(source).at(target)
+    }
+
+    /// Number of frames from `source` to `target` the device didn't accept
+    /// from the bridge.
+    pub fn bridge_dropped(&self, source: Channel, target: Channel) -> u32 {
+        *self.bridge_dropped.at(source).at(target)
+    }
+
+    /// Time taken to echo frames from the host on a channel, from reading them
+    /// from the endpoint to [`GsCan::echo`] with [`EchoMode::Device`].
+    ///
+    /// `None` until a frame is echoed, or if the device has no
+    /// [`Device::timestamp_us`].
+    pub fn echo_latency(&self, channel: Channel) -> Option<LatencyStats> {
+        *self.echo_latency.at(channel)
+    }
+
+    /// Clear the echo latency statistics of a channel.
+    pub fn reset_echo_latency(&mut self, channel: Channel) {
+        *self.echo_latency.at_mut(channel) = None;
+    }
+
+    /// Number of frames from the host dropped on a channel by the
+    /// [`HostTxPolicy`].
+    pub fn host_tx_dropped(&self, channel: Channel) -> u32 {
+        *self.host_tx_dropped.at(channel)
+    }
+
+    /// Number of frames from the host discarded as unreadable, for an invalid
+    /// channel or with a length that doesn't match.
+    pub fn invalid_host_frames(&self) -> u32 {
+        self.invalid_host_frames
+    }
+
+    /// Number of frames passed to [`GsCan::transmit`] or [`GsCan::transmit_raw`]
+    /// dropped as invalid, where they would panic without the `panic-free`
+    /// feature.
+    #[cfg(feature = "panic-free")]
+    pub fn invalid_frames(&self) -> u32 {
+        self.invalid_frames
+    }
+
+    /// Number of classic frames from the host accepted from CAN FD sized
+    /// transfers, see [`GsCan::with_fd_sized_classic`].
+    pub fn normalized_frames(&self) -> u32 {
+        self.normalized_frames
+    }
+
+    /// Number of frames from the host accepted without the tail of their
+    /// transfer, all their data having been in the first packet.
+    pub fn missing_tails(&self) -> u32 {
+        self.missing_tails
+    }
+
+    /// Number of transfers from the host too long for any frame the class
+    /// knows of, e.g. CAN XL frames. They are dropped whole.
+    pub fn oversized_transfers(&self) -> u32 {
+        self.oversized_transfers
+    }
+
+    /// Number of frames to the host dropped for a full queue, including echoes
+    /// and error frames.
+    pub fn queue_full_dropped(&self) -> u32 {
+        self.queue_full_dropped
+    }
+
+    /// Retry delivering frames from the host the device could not accept.
+    ///
+    /// Call when the device has space again, e.g. from the CAN transmit
+    /// interrupt. Reading from the host resumes on the next
+    /// [`UsbDevice::poll`] once all held frames are accepted.
+    ///
+    /// [`UsbDevice::poll`]: usb_device::device::UsbDevice::poll
+    pub fn retry_receive(&mut self) {
+        for channel in Channel::all() {
+            let Some(held) = *self.rx_pending.at(channel) else {
+                continue;
+            };
+
+            if self.deliver(channel, held).is_ok() {
+                *self.rx_pending.at_mut(channel) = None;
+            }
+        }
+    }
+
+    /// Register a waker to be woken when a frame from the host is queued.
+    ///
+    /// Only used with [`RxDelivery::Queued`] and [`RxDelivery::Staged`].
+    /// Registering a new waker replaces the previous one.
+    pub fn register_rx_waker(&mut self, waker: Waker) {
+        self.rx_waker = Some(waker);
+    }
+
+    /// Take the next frame received from the host along with its channel.
+    ///
+    /// Only used with [`RxDelivery::Queued`]. Whilst the queue is full the
+    /// endpoint is left unread so the host is NAKed.
+    pub fn dequeue_host_frame(&mut self) -> Option<(Channel, host::Frame)> {
+        let item = self.rx_queue.dequeue();
+
+        if item.is_some() {
+            // space available, move held frames into the queue.
+            self.retry_receive();
+        }
+
+        item
+    }
+
+    /// Pass staged frames from the host to [`Device::receive`] in order.
+    ///
+    /// Only used with [`RxDelivery::Staged`]. Call from the application's
+    /// context, e.g. a task woken by [`GsCan::register_rx_waker`]. Returns
+    /// once the queue is empty or the device would block, leaving the frame it
+    /// refused at the front of the queue. Whilst the queue is full the endpoint
+    /// is left unread so the host is NAKed.
+    pub fn process(&mut self) {
+        while let Some(&(channel, frame)) = self.rx_queue.peek() {
+            if self.device.receive(channel, &frame).is_err() {
+                break;
+            }
+
+            self.rx_queue.dequeue();
+
+            // space available, move held frames into the queue.
+            self.retry_receive();
+        }
+    }
+
+    /// Number of frames that can be passed to [`GsCan::transmit`] before the
+    /// host-bound queue is full.
+    pub fn tx_free(&self) -> usize {
+        self.out_queue.capacity() - self.out_queue.len()
+    }
+
+    /// Register a waker to be woken when a frame leaves the host-bound queue.
+    ///
+    /// Only one waker is stored, registering a new waker replaces the previous
+    /// one. The waker is consumed when woken.
+    pub fn register_tx_waker(&mut self, waker: Waker) {
+        self.tx_waker = Some(waker);
+    }
+
+    /// Poll for space in the host-bound queue.
+    ///
+    /// Returns [`Poll::Ready`] when [`GsCan::transmit`] will not drop the
+    /// frame, or whilst the host isn't reading and older frames are dropped
+    /// instead, otherwise registers the context waker.
+    pub fn poll_tx_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.tx_free() > 0 || self.stalled {
+            Poll::Ready(())
+        } else {
+            self.register_tx_waker(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    /// Send a CAN frame to the host, waiting for space in the queue.
+    ///
+    /// The future holds a mutable borrow of the class, so the USB device must
+    /// be polled from elsewhere (e.g. the USB interrupt) for it to complete.
+    #[cfg(feature = "async")]
+    pub async fn transmit_async(
+        &mut self,
+        channel: Channel,
+        frame: &impl embedded_can::Frame,
+        flags: FrameFlag,
+    ) {
+        core::future::poll_fn(|cx| self.poll_tx_ready(cx)).await;
+        self.transmit(channel, frame, flags);
+    }
+
+    /// Send a CAN frame to the host.
+    ///
+    /// The frame is only queued, it is written to the endpoint from the USB
+    /// context, see [`GsCan::kick`]. The frame is dropped if the queue is
+    /// full, whilst the class is disabled, by [`Device::filter_to_host`], or
+    /// according to the [`UnconfiguredPolicy`] until the host configures the
+    /// device.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the data length is invalid for the frame type, if
+    /// [`FrameFlag::FD`] is given without the `fd` feature, or if the flags are
+    /// invalid for the frame as checked by [`host::Frame::set_brs`] and
+    /// [`host::Frame::set_esi`]. With the `panic-free` feature the frame is
+    /// dropped instead, counted by `GsCan::invalid_frames`.
+    // Whilst embedded_can::Frame doesn't support FD, we pass the flags separately.
+    pub fn transmit(
+        &mut self,
+        channel: Channel,
+        frame: &impl embedded_can::Frame,
+        flags: FrameFlag,
+    ) {
+        self.queue_frame(channel, frame, flags);
+    }
+
+    /// Queue a CAN frame for the host as by [`GsCan::transmit`], returning its
+    /// position in the out queue if it was queued.
+    fn queue_frame(
+        &mut self,
+        channel: Channel,
+        frame: &impl embedded_can::Frame,
+        flags: FrameFlag,
+    ) -> Option<usize> {
+        let fill = |slot: &mut host::Frame| slot.copy_from(frame);
+        self.queue_to_host(channel, fill, flags, FrameKind::Receive, true)
+            .unwrap_or_else(|error| {
+                self.invalid_frame(error);
+                None
+            })
+    }
+
+    /// Send a CAN frame to the host in place of a queued frame with the same
+    /// ID on the channel, e.g. an obsolete sample of a periodic signal,
+    /// returning `true` if one was replaced.
+    ///
+    /// The frame takes the place, and with [`GsCan::with_sequence_numbers`]
+    /// the sequence number, of the oldest such frame not yet written to the
+    /// endpoint. Without one it is queued as by [`GsCan::transmit`], which
+    /// also applies to the frame given in every other respect.
+    pub fn transmit_replacing(
+        &mut self,
+        channel: Channel,
+        frame: &impl embedded_can::Frame,
+        flags: FrameFlag,
+    ) -> bool {
+        // dropped, filtered or suppressed. Drop summaries may be queued ahead
+        // of it.
+        let Some(len) = self.queue_frame(channel, frame, flags) else {
+            return false;
+        };
+        let Some(&new) = self.out_queue.get(len) else {
+            return false;
+        };
+
+        // the first half of the head may be in the endpoint.
+        let first = usize::from(self.out_split.is_some());
+        let Some(index) = (first..len).find(|&index| {
+            self.out_queue.get(index).is_some_and(|queued| {
+                queued.interface == new.interface
+                    && queued.can_id == new.can_id
+                    && queued.kind() == FrameKind::Receive
+            })
+        }) else {
+            return false;
+        };
+
+        if self.sequence_numbers {
+            let sequence = self.out_queue.get(index).map(host::Frame::sequence);
+            if let Some((queued, sequence)) = self.out_queue.get_mut(len).zip(sequence) {
+                queued.set_sequence(sequence);
+                let next = self.sequence.at_mut(channel);
+                *next = next.wrapping_sub(1);
+            }
+        }
+        self.out_queue.replace_with_newest(index);
+        true
+    }
+
+    /// Send a frame in the host format to the host, on the channel in its
+    /// `interface` and with its flags.
+    ///
+    /// Frames are sent as received from the bus. Echoes are sent by the class,
+    /// see [`GsCan::echo`], so a frame whose `echo_id` isn't
+    /// [`FrameKind::RECEIVE_ID`] is refused unless `allow_echo` is set, e.g.
+    /// for replaying a capture. Otherwise the same as [`GsCan::transmit`],
+    /// including its panics.
+    pub fn transmit_raw(
+        &mut self,
+        frame: &host::Frame,
+        allow_echo: bool,
+    ) -> Result<(), TransmitError> {
+        let channel = self
+            .channel(u16::from(frame.interface))
+            .map_err(|_| TransmitError::InvalidChannel)?;
+        let kind = frame.kind();
+        if kind != FrameKind::Receive && !allow_echo {
+            return Err(TransmitError::Echo);
+        }
+
+        let fill = |slot: &mut host::Frame| slot.copy_from(frame);
+        if let Err(error) = self.queue_to_host(channel, fill, frame.flags, kind, false) {
+            self.invalid_frame(error);
+        }
+        Ok(())
+    }
+
+    /// Send a CAN frame to the host from its parts, e.g. as given by a CAN
+    /// driver, without building an [`embedded_can::Frame`] first.
+    ///
+    /// `data` is written straight to the queue. For a remote frame only its
+    /// length is used, as the requested length. Refuses a channel the device
+    /// doesn't have, and a data length or flags that [`GsCan::transmit`]
+    /// would panic on. Otherwise the same as [`GsCan::transmit`].
+    pub fn transmit_parts(
+        &mut self,
+        channel: Channel,
+        id: embedded_can::Id,
+        data: &[u8],
+        remote: bool,
+        flags: FrameFlag,
+    ) -> Result<(), TransmitError> {
+        if channel.0 > self.config.interface_count {
+            return Err(TransmitError::InvalidChannel);
+        }
+
+        let fill = |slot: &mut host::Frame| slot.copy_from_parts(id, data, remote);
+        self.queue_to_host(channel, fill, flags, FrameKind::Receive, true)
+            .map(|_| ())
+            .map_err(TransmitError::InvalidFrame)
+    }
+
+    /// Handle a frame for the host refused by [`GsCan::queue_to_host`].
+    fn invalid_frame(&mut self, _error: FrameError) {
+        #[cfg(not(feature = "panic-free"))]
+        panic!("invalid frame for the host: {:?}", _error);
+
+        #[cfg(feature = "panic-free")]
+        {
+            #[cfg(feature = "defmt-verbose")]
+            defmt::warn!("Dropped invalid frame for the host: {}", _error);
+
+            self.invalid_frames = self.invalid_frames.wrapping_add(1);
+        }
+    }
+
+    /// Queue a frame for the host, the body of [`GsCan::transmit`].
+    ///
+    /// `fill` writes the identifier and data to the queued frame, returning
+    /// `None` for an invalid data length. Returns the position of the frame in
+    /// the out queue if it was queued. An invalid frame is dropped and its
+    /// error returned.
+    fn queue_to_host(
+        &mut self,
+        channel: Channel,
+        fill: impl FnOnce(&mut host::Frame) -> Option<()>,
+        flags: FrameFlag,
+        kind: FrameKind,
+        transform: bool,
+    ) -> Result<Option<usize>, FrameError> {
+        let keep = self.enabled
+            && (self.configured || {
+                // room for the frame amongst the newest.
+                let newest = self.unconfigured_policy.newest();
+                self.trim_unconfigured(newest.saturating_sub(1));
+                newest > 0
+            });
+
+        if keep {
+            // ahead of the frame, which is dropped if it took the last slot.
+            self.send_drop_summaries();
+        }
+        if keep && self.stalled && self.tx_free() == 0 {
+            self.drop_stalled();
+        }
+        // echoes are neither suppressed nor filtered.
+        let receive = kind == FrameKind::Receive;
+        let now = self
+            .dedup
+            .at(channel)
+            .filter(|_| receive)
+            .and_then(|_| self.device.timestamp_us());
+        let queued_us = self
+            .max_frame_age
+            .filter(|_| receive)
+            .and_then(|_| self.device.timestamp_us());
+
+        // built in the queue, or checked and dropped when it is full.
+        let mut dropped;
+        let slot = match self.out_queue.grant().filter(|_| keep) {
+            Some(slot) => slot,
+            None => {
+                dropped = host::Frame::new_zeroed();
+                &mut dropped
+            }
+        };
+
+        fill_frame(slot, fill, flags)?;
+        slot.set_kind(kind);
+        slot.interface = channel.into();
+        // bridged as given, whatever the host sees.
+        let bridged = self.bridge.is_some().then_some(*slot);
+        if let Some(transform) = self.transforms.at(channel).filter(|_| transform) {
+            transform.to_host(slot);
+        }
+        let pass = self.enabled && (!receive || self.device.filter_to_host(channel, slot));
+        let repeat = pass
+            && now.is_some_and(|now| {
+                is_repeat(
+                    *self.dedup.at(channel),
+                    self.dedup_last.at(channel),
+                    slot,
+                    now,
+                )
+            });
+        // suppressed frames leave no gap.
+        if self.sequence_numbers && receive && pass && !repeat {
+            let sequence = self.sequence.at_mut(channel);
+            slot.set_sequence(*sequence);
+            *sequence = sequence.wrapping_add(1);
+        }
+        let queued = now.map(|now| (*slot, now));
+        let data_len = slot.data().len();
+        let mut position = None;
+
+        if !self.enabled {
+            self.disabled_dropped = self.disabled_dropped.wrapping_add(1);
+        } else if !pass {
+            *self.filter_dropped.at_mut(channel) = self.filter_dropped.at(channel).wrapping_add(1);
+        } else if repeat {
+            *self.dedup_suppressed.at_mut(channel) =
+                self.dedup_suppressed.at(channel).wrapping_add(1);
+        } else if !keep {
+            self.unconfigured_dropped = self.unconfigured_dropped.wrapping_add(1);
+        } else if self.out_queue.len() < self.out_queue.capacity() {
+            self.out_queue.commit_stamped(queued_us.unwrap_or(0));
+            position = self.out_queue.len().checked_sub(1);
+            if queued.is_some() {
+                *self.dedup_last.at_mut(channel) = queued;
+            }
+            if let Some(load) = &mut self.load {
+                load.to_host.at_mut(channel).count(data_len);
+            }
+        } else {
+            #[cfg(feature = "defmt-verbose")]
+            defmt::error!("Transmit queue full");
+
+            self.count_queue_full();
+            let dropped = self.summary_dropped.at_mut(channel);
+            *dropped = dropped.wrapping_add(1);
+        }
+
+        if let Some(frame) = bridged {
+            self.bridge_frame(channel, frame);
+        }
+
+        Ok(position)
+    }
+
+    /// Send a CAN FD frame to the host.
+    ///
+    /// [`FrameFlag::FD`] is added to `flags`. The error state indicator is
+    /// `esi` if given, otherwise it is set whilst the channel is error passive,
+    /// see [`GsCan::set_error_state`]. Otherwise the same as
+    /// [`GsCan::transmit`].
+    #[cfg(feature = "fd")]
+    pub fn transmit_fd(
+        &mut self,
+        channel: Channel,
+        frame: &impl embedded_can::Frame,
+        flags: FrameFlag,
+        esi: Option<bool>,
+    ) {
+        let mut flags = flags | FrameFlag::FD;
+        let esi = esi.unwrap_or(*self.error_passive.at(channel));
+        flags.set(FrameFlag::ERROR_STATE_INDICATOR, esi);
+
+        self.transmit(channel, frame, flags);
+    }
+
+    /// Set the error state of a channel's controller, e.g. from its error
+    /// interrupt.
+    ///
+    /// Whilst [`CanState::Passive`], CAN FD frames echoed to the host and those
+    /// sent with [`GsCan::transmit_fd`] carry the error state indicator. The
+    /// state returns to active when the host resets or starts the channel.
+    pub fn set_error_state(&mut self, channel: Channel, state: CanState) {
+        *self.error_passive.at_mut(channel) = matches!(state, CanState::Passive);
+    }
+
+    /// Report the state and error counters of a channel's controller, e.g.
+    /// along with the error frames sent for a change of state.
+    ///
+    /// The host is answered with the state last reported, so it agrees with
+    /// the error frames, see [`StateSource`]. Also sets the error state as by
+    /// [`GsCan::set_error_state`]. The report is forgotten when the host
+    /// resets or starts the channel.
+    pub fn report_state(&mut self, channel: Channel, state: DeviceState) {
+        debug_assert!(
+            state.is_consistent(),
+            "error counters disagree with the CAN state"
+        );
+        self.set_error_state(channel, state.state);
+        *self.reported_state.at_mut(channel) = Some(state);
+    }
+
+    /// Set where the state read by the host comes from.
+    ///
+    /// Defaults to [`StateSource::Reported`].
+    pub fn with_state_source(mut self, source: StateSource) -> Self {
+        self.state_source = source;
+        self
+    }
+
+    /// Answer [`REQ_CAPABILITIES`] with [`CAPABILITIES`], for host tools to
+    /// learn what this build of the class does.
+    ///
+    /// The request is an extension of this crate, hosts without it never
+    /// send it. Disabled by default, the request is then stalled as unknown.
+    pub fn with_capabilities_request(mut self, enabled: bool) -> Self {
+        self.capabilities_request = enabled;
+        self
+    }
+
+    /// Features masked from the bit timing constants read with `wValue`, those
+    /// forced off on the channel, as the Linux driver reads them per channel.
+    fn masked_features(&self, value: u16) -> Feature {
+        self.channel(value).map_or(Feature::empty(), |channel| {
+            *self.forced_off_features.at(channel)
+        })
+    }
+
+    /// Termination to switch a starting channel to, see
+    /// [`GsCan::with_default_termination`].
+    fn default_termination(&self, channel: Channel) -> Option<bool> {
+        self.default_termination
+            .at(channel)
+            .filter(|_| !self.termination_set.at(channel))
+            .filter(|_| {
+                self.channel_features(channel)
+                    .contains(Feature::TERMINATION)
+            })
+    }
+
+    /// Returns `true` if the host has started any channel.
+    fn any_started(&self) -> bool {
+        self.mode.iter().any(|mode| mode.is_started())
+    }
+
+    /// State of a channel as read by the host.
+    fn channel_state(&self, channel: Channel) -> DeviceState {
+        if matches!(
+            self.mode.at(channel),
+            ChannelMode::Starting | ChannelMode::Stopping
+        ) {
+            return DeviceState {
+                state: CanState::Stopped,
+                rx_errors: 0,
+                tx_errors: 0,
+            };
+        }
+
+        let reported = *self.reported_state.at(channel);
+        match self.state_source {
+            StateSource::Reported => reported.unwrap_or_else(|| self.device.state(channel)),
+            StateSource::ReportedOnly => reported.unwrap_or_else(|| DeviceState::active(0, 0)),
+            StateSource::Device => self.device.state(channel),
+        }
+    }
+
+    /// Returns `true` if nothing will be written to or read from the host until
+    /// the class is polled, e.g. frames are queued whilst the endpoint is free.
+    ///
+    /// [`UsbDevice::poll`] only polls classes when the bus has an event, so
+    /// call [`GsCan::kick`] from the USB context when this returns `true`,
+    /// e.g. after pending the USB interrupt from a CAN interrupt.
+    ///
+    /// [`UsbDevice::poll`]: usb_device::device::UsbDevice::poll
+    pub fn needs_poll(&self) -> bool {
+        (self.configured
+            && self.out_split.is_none()
+            && ((!self.out_queue.is_empty() && !self.out_queue.is_held(0) && !self.head_paced())
+                || self.terminate_transfer))
+            || self.read_unblocked()
+    }
+
+    /// Write the next frame to the host and read a frame left in the endpoint,
+    /// without waiting for a bus event.
+    ///
+    /// [`UsbDevice::poll`] only polls classes on bus events, so call this from
+    /// the USB context after a burst of [`GsCan::transmit`] calls, under the
+    /// same lock as [`UsbDevice::poll`]. The endpoint holds a single packet,
+    /// the following frames are written as the host reads them. Does nothing
+    /// unless [`GsCan::needs_poll`] returns `true`.
+    ///
+    /// [`UsbDevice::poll`]: usb_device::device::UsbDevice::poll
+    pub fn kick(&mut self) {
+        if self.needs_poll() {
+            UsbClass::<B>::poll(self);
+        } else if self.configured && self.out_split.is_some() {
+            // the host hasn't read the first half of a frame yet.
+            self.count_stall();
+            self.count_split_retry();
+            self.drop_stale_frames();
+        }
+    }
+
+    /// Returns `true` if a packet was left in the endpoint whilst frames were
+    /// held and all of them have since been accepted.
+    fn read_unblocked(&self) -> bool {
+        self.rx_blocked && self.rx_pending.iter().all(Option::is_none)
+    }
+
+    /// Queue a frame for the host, written by [`UsbClass::poll`].
+    fn send_to_host(&mut self, frame: host::Frame) {
+        if self.out_queue.enqueue(frame).is_err() {
+            #[cfg(feature = "defmt-verbose")]
+            defmt::error!("Transmit queue full");
+
+            self.count_queue_full();
+        }
+    }
+
+    fn count_queue_full(&mut self) {
+        self.queue_full_dropped = self.queue_full_dropped.wrapping_add(1);
+        log_event(Event::QueueFull, self.queue_full_dropped);
+    }
+
+    fn count_invalid_host_frame(&mut self) {
+        self.invalid_host_frames = self.invalid_host_frames.wrapping_add(1);
+        log_event(Event::InvalidHostFrame, self.invalid_host_frames);
+    }
+
+    /// Write a packet to the host.
+    fn write_packet(&self, bytes: &[u8]) -> usb_device::Result<usize> {
+        let len = self.write_endpoint.write(bytes)?;
+
+        #[cfg(feature = "wire-dump")]
+        if let Some(hook) = self.bulk_in_hook {
+            hook(bytes.get(..len).unwrap_or(bytes));
+        }
+
+        Ok(len)
+    }
+
+    /// Read a packet from the host.
+    fn read_packet(&self, bytes: &mut [u8]) -> usb_device::Result<usize> {
+        let len = self.read_endpoint.read(bytes)?;
+
+        #[cfg(feature = "wire-dump")]
+        if let Some(hook) = self.bulk_out_hook {
+            hook(bytes.get(..len).unwrap_or(bytes));
+        }
+
+        Ok(len)
+    }
+
+    /// Write the next packet of the frame at the head of the out queue,
+    /// removing the frame once it is sent.
+    fn write_next_packet(&mut self) {
+        // frames wait for the host to configure the device.
+        if !self.configured {
+            return;
+        }
+
+        if self.terminate_transfer {
+            if self.write_packet(&[]).is_err() {
+                self.count_stall();
+                return;
+            }
+            self.terminate_transfer = false;
+        }
+
+        let len = match self.out_split {
+            Some(len) => len,
+            None => {
+                let Some(frame) = self.out_queue.peek() else {
+                    return;
+                };
+                // waiting for the application to echo it.
+                if self.out_queue.is_held(0) || self.head_paced() {
+                    return;
+                }
+
+                let mut format = self.wire_format(frame.interface);
+                // FD frames keep their payload on a channel that isn't in FD mode.
+                if frame.is_fd() {
+                    format.payload = Payload::Fd;
+                }
+
+                let Some(frame) = self.out_queue.peek_mut() else {
+                    return;
+                };
+                let sequence = frame.sequence();
+                frame.sanitize(format.is_fd());
+                if frame.kind() == FrameKind::Receive {
+                    if let Some(overflow) =
+                        self.stale_overflow.get_mut(usize::from(frame.interface))
+                    {
+                        if core::mem::take(overflow) {
+                            frame.flags |= FrameFlag::OVERFLOW;
+                        }
+                    }
+                }
+                // only frames from `transmit` are numbered.
+                if self.sequence_numbers && frame.kind() == FrameKind::Receive {
+                    frame.set_sequence(sequence);
+                }
+                format.in_len()
+            }
+        };
+
+        let Some(frame) = self.out_queue.peek() else {
+            return;
+        };
+        let interface = frame.interface;
+
+        let bytes = frame.as_bytes();
+        let packet = if self.out_split.is_some() {
+            bytes.get(PACKET_LEN..len)
+        } else {
+            bytes.get(..len.min(PACKET_LEN))
+        };
+        let Some(packet) = packet else {
+            // longer than the frame, never sent.
+            self.out_split = None;
+            self.pop_out_frame();
+            return;
+        };
+        if let Err(error) = self.write_packet(packet) {
+            self.count_stall();
+            match error {
+                UsbError::WouldBlock if self.out_split.is_some() => self.count_split_retry(),
+                UsbError::WouldBlock => {}
+                // the endpoint was reset and lost the first half.
+                _ => self.drop_split_frame(false),
+            }
+            return;
+        }
+        self.stall_polls = 0;
+        self.stalled = false;
+        self.split_retries = 0;
+
+        if self.out_split.is_none() {
+            if let Some(tokens) = self.frame_tokens.get_mut(usize::from(interface)) {
+                *tokens = tokens.saturating_sub(1);
+            }
+        }
+        // frames longer than a packet are sent in two.
+        if self.out_split.is_none() && len > PACKET_LEN {
+            self.out_split = Some(len);
+            return;
+        }
+        self.out_split = None;
+        self.pop_out_frame();
+        self.send_drop_summaries();
+    }
+
+    /// Returns `true` if the frame at the head of the queue to the host has
+    /// to wait for its channel's frame rate budget.
+    fn head_paced(&self) -> bool {
+        self.out_queue.peek().is_some_and(|frame| {
+            let channel = usize::from(frame.interface);
+            self.frame_rate_limit
+                .get(channel)
+                .is_some_and(Option::is_some)
+                && self.frame_tokens.get(channel) == Some(&0)
+        })
+    }
+
+    /// Read and discard everything waiting in the endpoint whilst disabled.
+    fn discard_host_data(&mut self) {
+        // long enough for backends delivering a frame in a single read.
+        let mut scratch = host::Frame::new_zeroed();
+        while let Ok(read) = self.read_packet(scratch.as_bytes_mut()) {
+            if read == 0 {
+                break;
+            }
+            self.disabled_discarded = self.disabled_discarded.wrapping_add(read as u32);
+        }
+    }
+
+    /// Count a poll finding the endpoint busy, noting when the host has
+    /// stopped reading.
+    fn count_stall(&mut self) {
+        let Some(threshold) = self.stall_threshold else {
+            return;
+        };
+
+        self.stall_polls = self.stall_polls.saturating_add(1);
+        if !self.stalled && self.stall_polls >= threshold {
+            #[cfg(feature = "defmt-03")]
+            defmt::warn!("Host stopped reading");
+
+            self.stalled = true;
+            self.stalls = self.stalls.wrapping_add(1);
+        }
+    }
+
+    /// Count a poll finding the first half of a split frame still unread,
+    /// dropping the frame once the retry limit is reached.
+    fn count_split_retry(&mut self) {
+        let Some(limit) = self.split_retry_limit else {
+            return;
+        };
+
+        self.split_retries = self.split_retries.saturating_add(1);
+        if self.split_retries >= limit {
+            #[cfg(feature = "defmt-03")]
+            defmt::warn!("Second half of a frame not read, dropping it");
+
+            self.drop_split_frame(true);
+        }
+    }
+
+    /// Drop the frame split across packets, ending the host's transfer of the
+    /// first half short if `terminate`.
+    fn drop_split_frame(&mut self, terminate: bool) {
+        if self.out_split.take().is_none() {
+            return;
+        }
+
+        self.pop_out_frame();
+        self.split_frames_dropped = self.split_frames_dropped.wrapping_add(1);
+        self.split_retries = 0;
+        self.terminate_transfer = terminate;
+    }
+
+    /// Drop the frames for the host that have waited too long, if enabled.
+    fn drop_stale_frames(&mut self) {
+        let Some(max_age) = self.max_frame_age else {
+            return;
+        };
+        let Some(now) = self.device.timestamp_us() else {
+            return;
+        };
+
+        // the frame with a packet in the endpoint must be finished.
+        let mut index = usize::from(self.out_split.is_some());
+        while let Some(frame) = self.out_queue.get(index) {
+            if frame.kind() != FrameKind::Receive || frame.is_error_frame() {
+                index += 1;
+                continue;
+            }
+            // queued in order, the following frames are newer.
+            let queued_us = self.out_queue.stamp(index).unwrap_or(now);
+            if now.wrapping_sub(queued_us) <= max_age {
+                break;
+            }
+
+            let channel = usize::from(frame.interface);
+            self.remove_out_frame(index);
+            self.stale_dropped = self.stale_dropped.wrapping_add(1);
+            if let Some(overflow) = self.stale_overflow.get_mut(channel) {
+                *overflow = true;
+            }
+        }
+    }
+
+    /// Drop the oldest frame for the host whilst it isn't reading.
+    fn drop_stalled(&mut self) {
+        // the frame with a packet in the endpoint must be finished.
+        let index = usize::from(self.out_split.is_some());
+        if index < self.out_queue.len() {
+            self.remove_out_frame(index);
+            self.stall_dropped = self.stall_dropped.wrapping_add(1);
+        }
+    }
+
+    /// Remove the frame at the head of the out queue.
+    fn pop_out_frame(&mut self) {
+        self.remove_out_frame(0);
+    }
+
+    /// Remove the frame `index` places from the head of the out queue.
+    fn remove_out_frame(&mut self, index: usize) {
+        let Some(frame) = self.out_queue.get(index) else {
+            return;
+        };
+
+        let tx_overflow = ControllerError::TX_OVERFLOW.bits();
+        if frame.is_error_frame()
+            && frame.kind() == FrameKind::Receive
+            && frame.data().get(1) == Some(&tx_overflow)
+        {
+            // further drops are reported again.
+            if let Ok(channel) = Channel::try_from(u16::from(frame.interface)) {
+                *self.drop_error_queued.at_mut(channel) = false;
+            }
+        }
+        self.out_queue.remove(index);
+
+        if let Some(waker) = self.tx_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Drop the oldest frames for the host until at most `keep` are left,
+    /// whilst the device isn't configured.
+    fn trim_unconfigured(&mut self, keep: usize) {
+        while self.out_queue.len() > keep {
+            self.pop_out_frame();
+            self.unconfigured_dropped = self.unconfigured_dropped.wrapping_add(1);
+        }
+    }
+
+    /// Read frames from the host, carrying on with the next frame after one
+    /// missing its tail.
+    fn read_host_frame(&mut self) {
+        loop {
+            let missing_tails = self.missing_tails;
+            self.read_one_host_frame();
+            if self.missing_tails == missing_tails {
+                return;
+            }
+        }
+    }
+
+    /// Read a frame, or half of one, from the host.
+    fn read_one_host_frame(&mut self) {
+        if !self.enabled {
+            self.discard_host_data();
+            return;
+        }
+
+        if !self.discard_oversized() {
+            return;
+        }
+
+        let (mut frame, mut len) = match self.in_frame.take() {
+            Some(partial) => partial,
+            None => {
+                let holding = self.rx_pending.iter().any(Option::is_some);
+                if self.host_tx_policy == HostTxPolicy::Nak && holding {
+                    // leave the packet in the endpoint so the host is NAKed.
+                    self.rx_blocked = true;
+                    return;
+                }
+                self.rx_blocked = false;
+
+                (host::Frame::new_zeroed(), 0)
+            }
+        };
+
+        // backends deliver a frame longer than a packet whole, or a packet per
+        // read over one or more calls.
+        loop {
+            // some hosts leave out the tail of a frame whose data fits in the
+            // first packet, a full packet in its place starts the next frame.
+            let tail_optional = len > 0
+                && frame
+                    .data_len()
+                    .is_some_and(|data_len| FRAME_HEADER_LEN + data_len <= len);
+            // a packet that may not fit is read whole, in case the host
+            // sends a longer frame than the class knows of.
+            let room = size_of::<host::Frame>().saturating_sub(len);
+            let mut packet = [0; PACKET_LEN];
+            let result = if tail_optional || room < PACKET_LEN {
+                self.read_packet(&mut packet)
+            } else {
+                match frame.as_bytes_mut().get_mut(len..) {
+                    Some(rest) => self.read_packet(rest),
+                    None => Err(UsbError::BufferOverflow),
+                }
+            };
+            let read = match result {
+                Ok(read) => read,
+                Err(UsbError::WouldBlock) => {
+                    if len > 0 {
+                        self.in_frame = Some((frame, len));
+                    }
+                    return;
+                }
+                Err(_error) => {
+                    #[cfg(feature = "defmt-verbose")]
+                    defmt::warn!("Frame from host unreadable: {}", _error);
+
+                    self.count_invalid_host_frame();
+                    return;
+                }
+            };
+
+            if tail_optional && read == PACKET_LEN {
+                #[cfg(feature = "defmt-verbose")]
+                defmt::warn!("Frame from host missing its tail");
+
+                let mut next = host::Frame::new_zeroed();
+                let Some(head) = next.as_bytes_mut().get_mut(..PACKET_LEN) else {
+                    self.count_invalid_host_frame();
+                    return;
+                };
+                head.copy_from_slice(&packet);
+                self.in_frame = Some((next, PACKET_LEN));
+                self.missing_tails = self.missing_tails.wrapping_add(1);
+
+                // the rest of the frame is zero.
+                len = self.host_frame_len(&frame, len);
+                break;
+            }
+            if tail_optional || room < PACKET_LEN {
+                if read > room {
+                    #[cfg(feature = "defmt-verbose")]
+                    defmt::warn!("Transfer from host too long for a frame");
+
+                    self.oversized_transfers = self.oversized_transfers.wrapping_add(1);
+                    // the transfer goes on until a short packet.
+                    self.discard_transfer = read == PACKET_LEN;
+                    self.discard_oversized();
+                    return;
+                }
+
+                let tail = frame.as_bytes_mut().get_mut(len..len + read);
+                let Some((tail, bytes)) = tail.zip(packet.get(..read)) else {
+                    self.count_invalid_host_frame();
+                    return;
+                };
+                tail.copy_from_slice(bytes);
+            }
+            len += read;
+
+            // a short packet ends the transfer.
+            if read % PACKET_LEN != 0 || read == 0 || len >= self.host_frame_len(&frame, len) {
+                break;
+            }
+        }
+
+        let Ok(channel) = self.channel(u16::from(frame.interface)) else {
+            #[cfg(feature = "defmt-verbose")]
+            defmt::warn!("Frame for invalid channel: {}", frame.interface);
+
+            self.count_invalid_host_frame();
+            return;
+        };
+
+        if self.echo_mode == EchoMode::Immediate {
+            frame.echo_id = 0; // tx complete
+        }
+
+        let format = self.wire_format.at(channel);
+
+        // hosts send whole frames, one byte longer with the LPC546xx quirk.
+        let host_len = format.out_len();
+        let valid_len = if self
+            .bit_timing
+            .features
+            .contains(Feature::REQ_USB_QUIRK_LPC546XX)
+        {
+            len == host_len + 1
+        } else {
+            len <= host_len
+        };
+        #[cfg(feature = "fd")]
+        let normalized = !valid_len && self.is_fd_sized_classic(*format, &frame, len);
+        #[cfg(not(feature = "fd"))]
+        let normalized = false;
+        if !valid_len && !normalized {
+            #[cfg(feature = "defmt-verbose")]
+            defmt::warn!("Frame length {} invalid, expected {}", len, host_len);
+
+            self.count_invalid_host_frame();
+            self.report_drop(channel);
+            return;
+        }
+
+        let data_len = match frame.data_len() {
+            // FD frames are only valid once the channel is in FD mode.
+            Some(_) if frame.is_fd() && !format.is_fd() => None,
+            // bit rate switching and the error state indicator need FD.
+            Some(_) if !frame.is_fd() && (frame.brs() || frame.esi()) => None,
+            Some(_) if frame.is_remote_frame() => Some(0),
+            data_len => data_len,
+        };
+        let Some(data_len) = data_len.filter(|data_len| FRAME_HEADER_LEN + data_len <= len) else {
+            #[cfg(feature = "defmt-verbose")]
+            defmt::warn!("Frame length {} disagrees with DLC: {}", len, frame.can_dlc);
+
+            self.count_invalid_host_frame();
+            self.report_drop(channel);
+            return;
+        };
+
+        if normalized {
+            self.normalized_frames = self.normalized_frames.wrapping_add(1);
+        }
+
+        if let Some(load) = &mut self.load {
+            load.from_host.at_mut(channel).count(data_len);
+        }
+
+        // clear anything past the payload.
+        if let Some(rest) = frame.as_bytes_mut().get_mut(FRAME_HEADER_LEN + data_len..) {
+            rest.fill(0);
+        }
+        let frame = HostFrame {
+            frame,
+            received_us: self.device.timestamp_us(),
+        };
+
+        // the device has stopped the channel.
+        if self.fault.at(channel).is_some() {
+            self.drop_host_frame(channel, frame.frame);
+            return;
+        }
+
+        if let Some(held) = *self.rx_pending.at(channel) {
+            // held frames go first.
+            if self.deliver(channel, held).is_ok() {
+                *self.rx_pending.at_mut(channel) = None;
+            } else {
+                // only DropOldest reads whilst holding a frame.
+                *self.rx_pending.at_mut(channel) = None;
+                self.drop_host_frame(channel, held.frame);
+            }
+        }
+
+        if self.deliver(channel, frame).is_ok() {
+            return;
+        }
+
+        match self.host_tx_policy {
+            HostTxPolicy::Nak | HostTxPolicy::DropOldest => {
+                *self.rx_pending.at_mut(channel) = Some(frame)
+            }
+            HostTxPolicy::DropNewest => self.drop_host_frame(channel, frame.frame),
+        }
+    }
+
+    /// Drop the rest of an oversized transfer, returning `true` once it has
+    /// ended.
+    fn discard_oversized(&mut self) -> bool {
+        let mut packet = [0; PACKET_LEN];
+        while self.discard_transfer {
+            match self.read_packet(&mut packet) {
+                Ok(PACKET_LEN) => {}
+                Err(UsbError::WouldBlock) => return false,
+                _ => self.discard_transfer = false,
+            }
+        }
+
+        true
+    }
+
+    /// Bytes the host sends for a frame, from the channel in its header and
+    /// the bytes read so far.
+    fn host_frame_len(&self, frame: &host::Frame, read: usize) -> usize {
+        let Ok(channel) = self.channel(u16::from(frame.interface)) else {
+            // nothing more to wait for.
+            return 0;
+        };
+
+        let len = self.wire_format.at(channel).out_len();
+        if read > len {
+            // an FD sized transfer on a classic channel, read to its end.
+            return size_of::<host::Frame>();
+        }
+        if self
+            .bit_timing
+            .features
+            .contains(Feature::REQ_USB_QUIRK_LPC546XX)
+        {
+            len + 1
+        } else {
+            len
+        }
+    }
+
+    /// Returns `true` for a classic frame in an FD sized transfer on a classic
+    /// channel, accepted with [`GsCan::with_fd_sized_classic`].
+    #[cfg(feature = "fd")]
+    fn is_fd_sized_classic(&self, format: WireFormat, frame: &host::Frame, len: usize) -> bool {
+        // with or without room for a timestamp.
+        let fd_len = WireFormat::new(Feature::FD).out_len();
+        self.fd_sized_classic
+            && !format.is_fd()
+            && !frame.is_fd()
+            && (len == fd_len || len == fd_len + TIMESTAMP_LEN)
+    }
+
+    /// Check a start request, returning the timing to start the channel with.
+    fn check_start(
+        &mut self,
+        channel: Channel,
+        features: Feature,
+    ) -> Result<(DeviceBitTiming, Option<DeviceBitTiming>), RejectReason> {
+        // only features advertised to the host and available on the channel
+        // may be requested, unknown bits are left to the device.
+        if !features
+            .known()
+            .difference(self.channel_features(channel))
+            .is_empty()
+        {
+            return Err(RejectReason::UnsupportedFeature);
+        }
+
+        let features = features | *self.forced_features.at(channel);
+        if features.intersects(*self.forced_off_features.at(channel)) {
+            return Err(RejectReason::FeatureForcedOff);
+        }
+        if self.device.validate_start(channel, features).is_err() {
+            return Err(RejectReason::DeviceRejected);
+        }
+
+        let nominal = self
+            .timing
+            .at(channel)
+            .nominal
+            .ok_or(RejectReason::MissingTiming)?;
+
+        #[cfg(feature = "fd")]
+        if features.intersects(Feature::FD) {
+            // the data phase must not run at whatever rate was left.
+            let data = match self.timing.at(channel).data {
+                Some(data) => data,
+                None => {
+                    let data = self
+                        .device
+                        .default_data_timing(channel)
+                        .ok_or(RejectReason::MissingDataTiming)?;
+                    self.timing.at_mut(channel).data = Some(data);
+                    data
+                }
+            };
+
+            return Ok((nominal, Some(data)));
+        }
+
+        Ok((nominal, None))
+    }
+
+    /// Record why a control transfer is being rejected.
+    fn record_rejection(&mut self, req: &control::Request, reason: RejectReason) {
+        let count = self
+            .last_rejection
+            .map_or(0, |rejection| rejection.count)
+            .wrapping_add(1);
+        let rejection = Rejection {
+            request: req.request,
+            value: req.value,
+            reason,
+            count,
+        };
+
+        #[cfg(feature = "defmt-03")]
+        defmt::warn!("Rejected control transfer: {}", rejection);
+
+        self.last_rejection = Some(rejection);
+    }
+
+    /// Returns `true` if a request is for the class, sent to the device or to
+    /// the interface of the class.
+    ///
+    /// Requests to other interfaces are left to their classes. The Linux driver
+    /// sends the channel requests to interface 0 whatever the interface number.
+    /// The whole index is compared, a request with the high byte set isn't for
+    /// the interface of the low byte.
+    fn is_addressed(&self, req: &control::Request) -> bool {
+        match req.recipient {
+            control::Recipient::Device => true,
+            control::Recipient::Interface => req.index == u16::from(u8::from(self.interface)),
+            _ => false,
+        }
+    }
+
+    /// Drop partially transferred frames so both directions restart at a frame
+    /// boundary.
+    ///
+    /// The bus reset clears the endpoint buffers, anything written after this
+    /// starts a new frame.
+    fn resync(&mut self) {
+        // the endpoint is free again.
+        self.stall_polls = 0;
+        self.stalled = false;
+        self.terminate_transfer = false;
+        self.discard_transfer = false;
+
+        // the host lost the first half.
+        self.drop_split_frame(false);
 
-        Self {
-            interface: alloc.interface(),
-            write_endpoint: alloc.bulk(64),
-            read_endpoint: alloc.bulk(64),
-            device,
-            interface_fd: [false; MAX_INTF],
-            out_queue: Queue::new(),
-            out_frame: None,
-            in_frame: None,
+        if self.in_frame.take().is_some() {
+            self.split_frames_dropped = self.split_frames_dropped.wrapping_add(1);
         }
     }
 
-    /// Send a CAN frame to the host.
-    ///
-    /// [`UsbDevice::poll()`] should be called immediately after to ensure the
-    /// frame is sent correctly.
-    // Whilst embedded_can::Frame doesn't support FD, we pass the flags separately.
-    pub fn transmit(&mut self, interface: u16, frame: &impl embedded_can::Frame, flags: FrameFlag) {
-        let mut frame = if frame.is_remote_frame() {
-            host::Frame::new_remote(frame.id(), frame.dlc()).unwrap()
-        } else {
-            host::Frame::new(frame.id(), frame.data()).unwrap()
+    /// Drop the partially transferred frames of a channel, their remaining
+    /// halves being read or written in a mode about to change.
+    fn flush_split_frames(&mut self, channel: Channel) {
+        let interface = u8::from(channel);
+        if self
+            .out_queue
+            .peek()
+            .is_some_and(|frame| frame.interface == interface)
+        {
+            // end the host's transfer of the first half.
+            self.drop_split_frame(true);
+        }
+
+        if self
+            .in_frame
+            .take_if(|(frame, _)| frame.interface == interface)
+            .is_some()
+        {
+            self.split_frames_dropped = self.split_frames_dropped.wrapping_add(1);
+        }
+    }
+
+    /// Pass a frame from the host to the application, echoing it or waiting
+    /// for the application to echo it according to the [`EchoMode`].
+    fn deliver(&mut self, channel: Channel, frame: HostFrame) -> nb::Result<(), Infallible> {
+        let echo_pending = self.echo_pending.at(channel);
+        if self.echo_mode == EchoMode::Device && echo_pending.is_full() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.pass_to_application(channel, frame.frame)?;
+
+        match self.echo_mode {
+            EchoMode::Immediate => {
+                self.record_latency(channel, frame.received_us);
+                self.echo_to_host(channel, frame.frame);
+            }
+            EchoMode::Device => {
+                if self.ordered_echoes {
+                    self.hold_echo(frame.frame);
+                }
+                // space checked above.
+                self.echo_pending.at_mut(channel).push(frame).ok();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pass a frame from the host to the application.
+    fn pass_to_application(
+        &mut self,
+        channel: Channel,
+        mut frame: host::Frame,
+    ) -> nb::Result<(), Infallible> {
+        // echoed as the host sent it.
+        if let Some(transform) = self.transforms.at(channel) {
+            transform.from_host(&mut frame);
+        }
+
+        match self.rx_delivery {
+            RxDelivery::Direct => self.device.receive(channel, &frame),
+            RxDelivery::Queued | RxDelivery::Staged => {
+                self.rx_queue
+                    .enqueue((channel, frame))
+                    .map_err(|_| nb::Error::WouldBlock)?;
+
+                if let Some(waker) = self.rx_waker.take() {
+                    waker.wake();
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Echo a frame waiting for the application with extra flags.
+    fn complete_echo(&mut self, channel: Channel, echo_id: u32, flags: FrameFlag) -> bool {
+        let echo_pending = self.echo_pending.at_mut(channel);
+        let Some(position) = echo_pending
+            .iter()
+            .position(|pending| pending.frame.echo_id == echo_id)
+        else {
+            #[cfg(feature = "defmt-03")]
+            defmt::warn!(
+                "No frame waiting for echo {} on channel {}",
+                echo_id,
+                channel
+            );
+            return false;
         };
 
-        frame.echo_id = u32::MAX; // set as receive frame
-        frame.interface = interface as u8;
-        frame.flags = flags;
+        let HostFrame {
+            mut frame,
+            received_us,
+        } = echo_pending.remove(position);
+        frame.flags |= flags;
+        self.record_latency(channel, received_us);
+        match self.held_echo(channel, echo_id) {
+            Some(index) => {
+                if frame.is_fd() && *self.error_passive.at(channel) {
+                    frame.flags |= FrameFlag::ERROR_STATE_INDICATOR;
+                }
+                if let Some(held) = self.out_queue.get_mut(index) {
+                    *held = frame;
+                }
+                self.out_queue.set_held(index, false);
+            }
+            // no place held, or it was dropped.
+            None => self.echo_to_host(channel, frame),
+        }
+
+        // space for another frame from the host.
+        self.retry_receive();
+
+        true
+    }
+
+    /// Hold a place in the out queue for the echo of a frame from the host.
+    fn hold_echo(&mut self, frame: host::Frame) {
+        // echoed at the back of the queue once there's space.
+        if self.out_queue.enqueue(frame).is_ok() {
+            self.out_queue.set_held(self.out_queue.len() - 1, true);
+        }
+    }
 
-        if self.out_frame.is_none() {
-            if self.write_endpoint.write(&frame.as_bytes()[..64]).is_ok() {
-                // first half write complete.
-                // defer second half of frame.
-                self.out_frame = Some(frame);
+    /// Position in the out queue of the place held for an echo.
+    fn held_echo(&self, channel: Channel, echo_id: u32) -> Option<usize> {
+        (0..self.out_queue.len()).find(|&index| {
+            self.out_queue.is_held(index)
+                && self.out_queue.get(index).is_some_and(|frame| {
+                    frame.interface == u8::from(channel) && frame.echo_id == echo_id
+                })
+        })
+    }
+
+    /// Forget the frames of a channel waiting to be echoed.
+    fn clear_echoes(&mut self, channel: Channel) {
+        self.echo_pending.at_mut(channel).clear();
+
+        let mut index = 0;
+        while index < self.out_queue.len() {
+            let for_channel = self
+                .out_queue
+                .get(index)
+                .is_some_and(|frame| frame.interface == u8::from(channel));
+            if self.out_queue.is_held(index) && for_channel {
+                self.out_queue.remove(index);
             } else {
-                if self.out_queue.enqueue(frame).is_err() {
-                    #[cfg(feature = "defmt-03")]
-                    defmt::error!("Transmit queue full");
-                }
+                index += 1;
             }
+        }
+    }
+
+    /// Record the time a frame from the host took to be echoed.
+    fn record_latency(&mut self, channel: Channel, received_us: Option<u32>) {
+        let Some((received_us, now_us)) = received_us.zip(self.device.timestamp_us()) else {
+            return;
+        };
+
+        let stats = self.echo_latency.at_mut(channel);
+        LatencyStats::record(stats, now_us.wrapping_sub(received_us));
+    }
+
+    /// Forward a frame from the CAN side to the channel picked by the bridge.
+    fn bridge_frame(&mut self, source: Channel, mut frame: host::Frame) {
+        let Some(bridge) = self.bridge else {
+            return;
+        };
+
+        // our own frame coming back.
+        let last = self.bridged_last.at_mut(source);
+        if last.is_some_and(|last| same_frame(&last, &frame)) {
+            *last = None;
+            return;
+        }
+
+        let Some(target) = bridge(source, &frame).filter(|&target| target != source) else {
+            return;
+        };
+        frame.interface = target.into();
+
+        if self.device.receive(target, &frame).is_ok() {
+            *self.bridged_last.at_mut(target) = Some(frame);
+            let bridged = self.bridged.at_mut(source).at_mut(target);
+            *bridged = bridged.wrapping_add(1);
         } else {
-            if self.out_queue.enqueue(frame).is_err() {
-                #[cfg(feature = "defmt-03")]
-                defmt::error!("Transmit queue full");
+            #[cfg(feature = "defmt-verbose")]
+            defmt::warn!("Dropped bridged frame from {} to {}", source, target);
+
+            let dropped = self.bridge_dropped.at_mut(source).at_mut(target);
+            *dropped = dropped.wrapping_add(1);
+            log_event(Event::BridgeDropped, *dropped);
+        }
+    }
+
+    /// Frame size of the channel a frame to the host is for.
+    fn wire_format(&self, interface: u8) -> WireFormat {
+        self.wire_format
+            .get(usize::from(interface))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Echo a frame sent on the bus, with the error state indicator of the
+    /// channel.
+    fn echo_to_host(&mut self, channel: Channel, mut frame: host::Frame) {
+        if frame.is_fd() && *self.error_passive.at(channel) {
+            frame.flags |= FrameFlag::ERROR_STATE_INDICATOR;
+        }
+
+        self.send_to_host(frame);
+    }
+
+    /// Drop a frame from the host, echoing it with the overflow flag set.
+    fn drop_host_frame(&mut self, channel: Channel, mut frame: host::Frame) {
+        #[cfg(feature = "defmt-verbose")]
+        defmt::warn!("Dropped frame from host on channel {}", channel);
+
+        let dropped = self.host_tx_dropped.at_mut(channel);
+        *dropped = dropped.wrapping_add(1);
+        log_event(Event::HostFrameDropped, *dropped);
+
+        frame.flags |= FrameFlag::OVERFLOW;
+        self.send_to_host(frame);
+        self.report_drop(channel);
+    }
+
+    /// Queue the summaries of frames to the host dropped that are due, if
+    /// enabled.
+    fn send_drop_summaries(&mut self) {
+        if self.drop_summary.is_none() || !self.configured {
+            return;
+        }
+
+        let now = self.device.timestamp_us();
+        for channel in Channel::all() {
+            let dropped = self.summary_dropped.at(channel);
+            let due = match (now, self.summary_sent_us.at(channel), self.drop_summary) {
+                (Some(now), Some(sent), Some(interval)) => now.wrapping_sub(*sent) >= interval,
+                _ => true,
+            };
+            if *dropped == 0 || !due {
+                continue;
             }
+
+            let mut data = [0; 8];
+            data[1] = ControllerError::RX_OVERFLOW.bits();
+            data[4..].copy_from_slice(&dropped.to_le_bytes());
+            let mut frame = host::Frame::new_error(ErrorClass::CONTROLLER, data);
+            frame.set_kind(FrameKind::Receive);
+            frame.interface = channel.into();
+
+            if self.queue_report(channel, frame) {
+                *self.summary_dropped.at_mut(channel) = 0;
+                *self.summary_sent_us.at_mut(channel) = now;
+            }
+        }
+    }
+
+    /// Send an error frame for a frame from the host that was discarded, if
+    /// enabled.
+    fn report_drop(&mut self, channel: Channel) {
+        // one report stands for any drops until it is sent.
+        if !self.drop_errors
+            || !self.mode.at(channel).is_started()
+            || *self.drop_error_queued.at(channel)
+        {
+            return;
+        }
+
+        let mut data = [0; 8];
+        data[1] = ControllerError::TX_OVERFLOW.bits();
+        let mut frame = host::Frame::new_error(ErrorClass::CONTROLLER, data);
+        frame.set_kind(FrameKind::Receive);
+        frame.interface = channel.into();
+
+        if self.queue_report(channel, frame) {
+            *self.drop_error_queued.at_mut(channel) = true;
+        }
+    }
+
+    /// Queue an error frame the class reports itself, passing it to
+    /// [`Device::notify`] if queued.
+    fn queue_report(&mut self, channel: Channel, frame: host::Frame) -> bool {
+        if self.out_queue.enqueue(frame).is_err() {
+            return false;
         }
+
+        self.device.notify(channel, &frame);
+        true
     }
 }
 
-impl<B: UsbBus, D: Device> UsbClass<B> for GsCan<'_, B, D> {
+impl<B: UsbBus, D: Device, const RX: usize> UsbClass<B> for GsCan<'_, B, D, RX> {
     fn get_configuration_descriptors(
         &self,
         writer: &mut DescriptorWriter,
     ) -> usb_device::Result<()> {
-        writer.interface(self.interface, INTERFACE_CLASS, 0xFF, 0xFF)?;
+        // only written for composite devices.
+        writer.iad(self.interface, 1, INTERFACE_CLASS, 0xFF, 0xFF, None)?;
+        writer.interface_alt(
+            self.interface,
+            usb_device::device::DEFAULT_ALTERNATE_SETTING,
+            INTERFACE_CLASS,
+            0xFF,
+            0xFF,
+            self.label_index,
+        )?;
         writer.endpoint(&self.write_endpoint)?;
         writer.endpoint(&self.read_endpoint)?;
 
         Ok(())
     }
 
+    fn get_string(&self, index: StringIndex, _lang_id: LangID) -> Option<&str> {
+        let first = u8::from(self.label_index?);
+        let channel = u8::from(index).checked_sub(first)?;
+        self.channel_labels.get(usize::from(channel)).copied()
+    }
+
     // Handle control requests to the host.
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = *xfer.request();
 
-        if req.request_type != control::RequestType::Vendor {
+        if req.request_type != control::RequestType::Vendor || !self.is_addressed(&req) {
+            return;
+        }
+
+        #[cfg(feature = "wire-dump")]
+        if let Some(hook) = self.control_hook {
+            hook(&req, &[]);
+        }
+
+        let request = GsRequest::parse(&req);
+        // the device config still answers, e.g. for enumeration tools.
+        if !self.enabled && request != Some(GsRequest::DeviceConfig) {
+            self.record_rejection(&req, RejectReason::Disabled);
+            xfer.reject().ok();
             return;
         }
 
-        match req.request {
-            REQ_BIT_TIMING_CONST => {
-                xfer.accept_with(self.device.bit_timing().as_bytes())
-                    .unwrap();
+        match request {
+            Some(GsRequest::BitTimingConst) => {
+                self.bit_timing_read = true;
+                let mut bit_timing = self.bit_timing;
+                bit_timing.features -= self.masked_features(req.value);
+                accept_in(xfer, bit_timing.as_bytes());
+            }
+            Some(GsRequest::DeviceConfig) => {
+                accept_in(xfer, self.config.as_bytes());
+            }
+            #[cfg(feature = "fd")]
+            Some(GsRequest::BitTimingConstExt) => {
+                // probed by some hosts regardless.
+                if !self.bit_timing.features.contains(Feature::BT_CONST_EXT) {
+                    self.record_rejection(&req, RejectReason::UnsupportedFeature);
+                    xfer.reject().ok();
+                    return;
+                }
+                self.bit_timing_read = true;
+                let mut bit_timing_ext = self.bit_timing_ext;
+                bit_timing_ext.features -= self.masked_features(req.value);
+                accept_in(xfer, bit_timing_ext.as_bytes());
+            }
+            Some(GsRequest::GetState { channel }) => {
+                let Ok(channel) = self.channel(channel) else {
+                    self.record_rejection(&req, RejectReason::InvalidChannel);
+                    xfer.reject().ok();
+                    return;
+                };
+                if self.mode.at(channel).is_started() {
+                    self.host_capabilities.polls_state = true;
+                }
+                let state = self.channel_state(channel);
+                debug_assert!(
+                    state.is_consistent(),
+                    "error counters disagree with the CAN state"
+                );
+                accept_in(xfer, state.as_bytes());
+            }
+            Some(GsRequest::GetTermination { channel }) => {
+                let Ok(channel) = self.channel(channel) else {
+                    self.record_rejection(&req, RejectReason::InvalidChannel);
+                    xfer.reject().ok();
+                    return;
+                };
+                if !self
+                    .channel_features(channel)
+                    .contains(Feature::TERMINATION)
+                {
+                    self.record_rejection(&req, RejectReason::UnsupportedFeature);
+                    xfer.reject().ok();
+                    return;
+                }
+                let state = DeviceTerminationState {
+                    state: if self.device.termination(channel) {
+                        TERMINATION_STATE_ON
+                    } else {
+                        TERMINATION_STATE_OFF
+                    },
+                };
+                accept_in(xfer, state.as_bytes());
             }
-            REQ_DEVICE_CONFIG => {
-                xfer.accept_with(self.device.config().as_bytes()).unwrap();
+            Some(GsRequest::BusError { .. }) => {
+                // bus errors are reported with error frames, a stall tells
+                // probing hosts not to retry.
+                self.record_rejection(&req, RejectReason::UnknownRequest);
+                xfer.reject().ok();
             }
-            REQ_BIT_TIMING_CONST_EXT => {
-                xfer.accept_with(self.device.bit_timing_ext().as_bytes())
-                    .unwrap();
+            None if self.capabilities_request && req.request == REQ_CAPABILITIES => {
+                let mut codes = [0; CAPABILITIES.len()];
+                for (code, &capability) in codes.iter_mut().zip(CAPABILITIES) {
+                    *code = capability as u8;
+                }
+                accept_in(xfer, &codes);
             }
-            REQ_GET_STATE => {
-                let interface = req.value as u8;
-                xfer.accept_with(self.device.state(interface).as_bytes())
-                    .unwrap();
+            Some(GsRequest::Timestamp) if self.any_started() => {
+                // not answered, but the host is using it.
+                self.host_capabilities.reads_timestamp = true;
+
+                #[cfg(feature = "defmt-03")]
+                defmt::warn!("Unimplemented request kind: {}", req.request);
             }
             _ => {
                 #[cfg(feature = "defmt-03")]
@@ -156,170 +3592,649 @@ impl<B: UsbBus, D: Device> UsbClass<B> for GsCan<'_, B, D> {
     fn control_out(&mut self, xfer: ControlOut<B>) {
         let req = *xfer.request();
 
-        if req.request_type != control::RequestType::Vendor {
+        // observed only, the device answers standard requests.
+        if req.request_type == control::RequestType::Standard
+            && req.recipient == control::Recipient::Device
+            && req.request == control::Request::SET_CONFIGURATION
+        {
+            match req.value {
+                0 => self.set_configured(false),
+                // the only configuration of the device.
+                1 => self.set_configured(true),
+                _ => {}
+            }
+        }
+
+        if req.request_type != control::RequestType::Vendor || !self.is_addressed(&req) {
+            return;
+        }
+
+        #[cfg(feature = "wire-dump")]
+        if let Some(hook) = self.control_hook {
+            hook(&req, xfer.data());
+        }
+
+        let request = GsRequest::parse(&req);
+        if !self.enabled {
+            self.record_rejection(&req, RejectReason::Disabled);
+            xfer.reject().ok();
             return;
         }
 
-        match req.request {
-            REQ_HOST_FORMAT => {
+        let channel = request
+            .and_then(GsRequest::channel)
+            .map_or(Err(InvalidChannel), |channel| self.channel(channel));
+
+        match request {
+            Some(GsRequest::HostFormat) => {
                 if xfer.data().len() != 4 {
                     #[cfg(feature = "defmt-03")]
                     defmt::error!(
                         "Host format request length incorrect. Expected 4, got {}",
                         xfer.data().len()
                     );
-                    xfer.reject().unwrap();
+                    self.record_rejection(&req, RejectReason::InvalidLength);
+                    xfer.reject().ok();
                     return;
                 }
 
-                let config = HostConfig::ref_from(xfer.data()).unwrap();
-                assert_eq!(
-                    config.byte_order, 0x0000beef,
-                    "Byte order check mismatch. Big endian not currently supported.",
-                );
-                xfer.accept().unwrap();
+                let Some(config) = HostConfig::ref_from(xfer.data()) else {
+                    self.record_rejection(&req, RejectReason::InvalidLength);
+                    xfer.reject().ok();
+                    return;
+                };
+                // repeated by some drivers, a change would corrupt frames in
+                // flight.
+                if config.byte_order != self.host_byte_order && self.any_started() {
+                    self.record_rejection(&req, RejectReason::ChannelStarted);
+                    xfer.reject().ok();
+                    return;
+                }
+                // big endian hosts aren't supported.
+                if config.byte_order != HOST_LITTLE_ENDIAN {
+                    self.record_rejection(&req, RejectReason::InvalidValue);
+                    xfer.reject().ok();
+                    return;
+                }
+                self.host_byte_order = config.byte_order;
+                xfer.accept().ok();
             }
-            REQ_BIT_TIMING => {
-                let timing = DeviceBitTiming::read_from(xfer.data()).unwrap();
-                let interface = req.value as u8;
-                self.device.configure_bit_timing(interface, timing);
-                xfer.accept().unwrap();
+            Some(GsRequest::BitTiming { .. }) => {
+                let Ok(channel) = channel else {
+                    self.record_rejection(&req, RejectReason::InvalidChannel);
+                    xfer.reject().ok();
+                    return;
+                };
+                let Some(timing) = DeviceBitTiming::read_from(xfer.data()) else {
+                    self.record_rejection(&req, RejectReason::InvalidLength);
+                    xfer.reject().ok();
+                    return;
+                };
+                let pending = &mut self.timing.at_mut(channel).nominal;
+                if self.mode.at(channel).is_started() && *pending != Some(timing) {
+                    self.record_rejection(&req, RejectReason::ChannelStarted);
+                    xfer.reject().ok();
+                    return;
+                }
+                *pending = Some(timing);
+                self.device.configure_bit_timing(channel, timing);
+                xfer.accept().ok();
             }
-            REQ_MODE => {
-                let device_mode = DeviceMode::ref_from(xfer.data()).unwrap();
-                let interface = req.value as u8;
+            Some(GsRequest::Mode { .. }) => {
+                let Ok(channel) = channel else {
+                    self.record_rejection(&req, RejectReason::InvalidChannel);
+                    xfer.reject().ok();
+                    return;
+                };
+                let Some(device_mode) = DeviceMode::ref_from(xfer.data()) else {
+                    self.record_rejection(&req, RejectReason::InvalidLength);
+                    xfer.reject().ok();
+                    return;
+                };
+                let Ok(mode) = host::Mode::try_from(device_mode.mode) else {
+                    self.record_rejection(&req, RejectReason::InvalidValue);
+                    xfer.reject().ok();
+                    return;
+                };
+                let start = match mode {
+                    host::Mode::Reset => None,
+                    // left alone, the device is still starting.
+                    host::Mode::Start if *self.mode.at(channel) == ChannelMode::Starting => {
+                        self.record_rejection(&req, RejectReason::ChannelStarting);
+                        xfer.reject().ok();
+                        return;
+                    }
+                    host::Mode::Start if *self.mode.at(channel) == ChannelMode::Stopping => {
+                        self.record_rejection(&req, RejectReason::ChannelStopping);
+                        xfer.reject().ok();
+                        return;
+                    }
+                    host::Mode::Start => match self.check_start(channel, device_mode.flags) {
+                        Ok(timing) => Some(timing),
+                        Err(reason) => {
+                            self.record_rejection(&req, reason);
+                            xfer.reject().ok();
+                            return;
+                        }
+                    },
+                };
                 // store interface configuration.
-                self.interface_fd[interface as usize] = device_mode.flags.intersects(Feature::FD);
-                let mode = host::Mode::try_from(device_mode.mode).unwrap();
-                match mode {
-                    host::Mode::Reset => self.device.reset(interface),
-                    host::Mode::Start => self.device.start(interface, device_mode.flags),
+                self.flush_split_frames(channel);
+                *self.wire_format.at_mut(channel) = WireFormat::new(device_mode.flags);
+                // the host forgets frames in flight when the channel is reset.
+                self.clear_echoes(channel);
+                *self.error_passive.at_mut(channel) = false;
+                *self.reported_state.at_mut(channel) = None;
+                if start.is_some() {
+                    *self.fault.at_mut(channel) = None;
                 }
-                xfer.accept().unwrap();
+                let channel_mode = self.mode.at_mut(channel);
+                match start {
+                    // nothing to do for a channel that isn't running.
+                    None if !channel_mode.is_started() => {}
+                    None => {
+                        *channel_mode = if self.deferred_stop {
+                            ChannelMode::Stopping
+                        } else {
+                            ChannelMode::Stopped
+                        };
+                        self.device.reset(channel);
+                    }
+                    Some((nominal, data)) => {
+                        // restart with the new mode rather than starting twice.
+                        if channel_mode.is_started() {
+                            self.device.reset(channel);
+                        }
+                        *channel_mode = if self.deferred_start {
+                            ChannelMode::Starting
+                        } else {
+                            ChannelMode::Started
+                        };
+                        let features = device_mode.flags | *self.forced_features.at(channel);
+                        *self.started_features.at_mut(channel) = features;
+                        if let Some(enabled) = self.default_termination(channel) {
+                            self.device.set_termination(channel, enabled);
+                        }
+                        self.device
+                            .start(channel, features, &nominal, data.as_ref());
+                    }
+                }
+                xfer.accept().ok();
+            }
+            Some(GsRequest::Identify { .. })
+                if self.bit_timing.features.contains(Feature::IDENTIFY) =>
+            {
+                let Ok(channel) = channel else {
+                    self.record_rejection(&req, RejectReason::InvalidChannel);
+                    xfer.reject().ok();
+                    return;
+                };
+                if !self.channel_features(channel).contains(Feature::IDENTIFY) {
+                    self.record_rejection(&req, RejectReason::UnsupportedFeature);
+                    xfer.reject().ok();
+                    return;
+                }
+                let Some(mode) = IdentifyMode::ref_from(xfer.data()) else {
+                    self.record_rejection(&req, RejectReason::InvalidLength);
+                    xfer.reject().ok();
+                    return;
+                };
+                let active = mode.mode != host::IDENTIFY_OFF;
+                self.identify.at_mut(channel).set_active(active);
+                self.device.identify(channel, active);
+                xfer.accept().ok();
+            }
+            #[cfg(feature = "fd")]
+            Some(GsRequest::BitTimingData { .. }) => {
+                let Ok(channel) = channel else {
+                    self.record_rejection(&req, RejectReason::InvalidChannel);
+                    xfer.reject().ok();
+                    return;
+                };
+                let Some(timing) = DeviceBitTiming::read_from(xfer.data()) else {
+                    self.record_rejection(&req, RejectReason::InvalidLength);
+                    xfer.reject().ok();
+                    return;
+                };
+                let pending = &mut self.timing.at_mut(channel).data;
+                if self.mode.at(channel).is_started() && *pending != Some(timing) {
+                    self.record_rejection(&req, RejectReason::ChannelStarted);
+                    xfer.reject().ok();
+                    return;
+                }
+                *pending = Some(timing);
+                self.device.configure_bit_timing_data(channel, timing);
+                xfer.accept().ok();
+            }
+            Some(GsRequest::SetTermination { .. }) => {
+                let Ok(channel) = channel else {
+                    self.record_rejection(&req, RejectReason::InvalidChannel);
+                    xfer.reject().ok();
+                    return;
+                };
+                if !self
+                    .channel_features(channel)
+                    .contains(Feature::TERMINATION)
+                {
+                    self.record_rejection(&req, RejectReason::UnsupportedFeature);
+                    xfer.reject().ok();
+                    return;
+                }
+                let Some(state) = DeviceTerminationState::ref_from(xfer.data()) else {
+                    self.record_rejection(&req, RejectReason::InvalidLength);
+                    xfer.reject().ok();
+                    return;
+                };
+                let enabled = state.state != TERMINATION_STATE_OFF;
+                *self.termination_set.at_mut(channel) = true;
+                self.device.set_termination(channel, enabled);
+                xfer.accept().ok();
             }
-            REQ_BIT_TIMING_DATA => {
-                let timing = DeviceBitTiming::read_from(xfer.data()).unwrap();
-                let interface = req.value as u8;
-                self.device.configure_bit_timing_data(interface, timing);
-                xfer.accept().unwrap();
+            Some(GsRequest::BusError { .. }) => {
+                // as for reading it, bus errors are reported with error frames.
+                self.record_rejection(&req, RejectReason::UnknownRequest);
+                xfer.reject().ok();
             }
             _ => {
                 #[cfg(feature = "defmt-03")]
                 defmt::warn!("Unimplemented request kind: {}", req.request);
+                self.record_rejection(&req, RejectReason::UnknownRequest);
                 xfer.reject().ok();
             }
         }
     }
 
+    // the only place the write endpoint is written.
     fn poll(&mut self) {
-        if self.out_frame.is_none() {
-            // attempt sending new frame.
-            if let Some(frame) = self.out_queue.peek() {
-                if self.write_endpoint.write(&frame.as_bytes()[..64]).is_ok() {
-                    let frame = self.out_queue.dequeue().unwrap(); // remove from queue
-                    self.out_frame = Some(frame);
-                }
-            }
-        } else {
-            // attempt sending second frame half.
-            self.out_frame
-                .take_if(|frame| self.write_endpoint.write(&frame.as_bytes()[64..76]).is_ok());
+        self.retry_receive();
+
+        if self.read_unblocked() {
+            self.read_host_frame();
         }
+
+        self.drop_stale_frames();
+        self.write_next_packet();
     }
 
-    fn endpoint_in_complete(&mut self, _addr: EndpointAddress) {
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        // completions for other endpoints must not advance the frame split.
+        if addr != self.write_endpoint.address() {
+            return;
+        }
+
         self.poll();
     }
 
     fn endpoint_out(&mut self, addr: EndpointAddress) {
         // filter endpoint address.
-        if addr.index() != 2 {
+        if addr != self.read_endpoint.address() {
             return;
         }
 
-        let mut frame = match self.in_frame {
-            None => {
-                let mut frame = host::Frame::new_zeroed();
-                self.read_endpoint
-                    .read(&mut frame.as_bytes_mut()[..64])
-                    .unwrap();
-
-                if self.interface_fd[frame.interface as usize] {
-                    self.in_frame = Some(frame);
-                    return;
-                }
+        self.read_host_frame();
+    }
 
-                frame
+    fn reset(&mut self) {
+        // host is gone, stop running channels.
+        for channel in Channel::all() {
+            if self.mode.at(channel).is_started() {
+                self.device.reset(channel);
             }
-            Some(mut frame) => {
-                self.read_endpoint
-                    .read(&mut frame.as_bytes_mut()[64..])
-                    .unwrap();
-                self.in_frame = None;
+        }
 
-                frame
+        // reset internal state
+        self.wire_format = [WireFormat::default(); MAX_INTF];
+        self.timing = [PendingTiming::default(); MAX_INTF];
+        self.mode = [ChannelMode::Stopped; MAX_INTF];
+        self.fault = [None; MAX_INTF];
+        self.termination_set = [false; MAX_INTF];
+        self.resync();
+        // frames to the host are kept by the unconfigured policy.
+        self.set_configured(false);
+        self.rx_queue = Queue::new();
+        self.rx_blocked = false;
+        self.rx_pending = [None; MAX_INTF];
+        for channel in Channel::all() {
+            self.clear_echoes(channel);
+        }
+        self.drop_error_queued = [false; MAX_INTF];
+        self.summary_dropped = [0; MAX_INTF];
+        self.summary_sent_us = [None; MAX_INTF];
+        self.stale_overflow = [false; MAX_INTF];
+        self.sequence = [0; MAX_INTF];
+        self.dedup_last = [None; MAX_INTF];
+        self.error_passive = [false; MAX_INTF];
+        self.reported_state = [None; MAX_INTF];
+        self.bit_timing_read = false;
+        self.host_byte_order = HOST_LITTLE_ENDIAN;
+        self.host_capabilities = HostCapabilities::default();
+        self.started_ms = 0;
+
+        // channels may have been fitted or removed whilst the host was away.
+        let mut config = self.device.config();
+        clamp_channels(&mut config);
+        config.software_version = self.config.software_version;
+        self.config = config;
+        for (index, identify) in self.identify.iter_mut().enumerate() {
+            if index > usize::from(self.config.interface_count) {
+                identify.set_active(false);
             }
-        };
+        }
 
-        frame.echo_id = 0; // tx complete
+        // queue trimmed, pending transmitters can proceed.
+        if let Some(waker) = self.tx_waker.take() {
+            waker.wake();
+        }
+    }
+}
 
-        self.device.receive(frame.interface, &frame);
+/// Limit the channels of `config` to those the class has state for.
+fn clamp_channels(config: &mut DeviceConfig) {
+    config.interface_count = config.interface_count.min(MAX_INTF as u8 - 1);
+}
 
-        if self.out_frame.is_none() {
-            if self.write_endpoint.write(&frame.as_bytes()[..64]).is_ok() {
-                // first half write complete.
-                // defer second half of frame.
-                self.out_frame = Some(frame);
-            } else {
-                if self.out_queue.enqueue(frame).is_err() {
-                    #[cfg(feature = "defmt-03")]
-                    defmt::error!("Transmit queue full");
-                }
-            }
-        } else {
-            if self.out_queue.enqueue(frame).is_err() {
-                #[cfg(feature = "defmt-03")]
-                defmt::error!("Transmit queue full");
-            }
+/// Log a per-frame event the first time and every `LOG_EVERY` times after,
+/// from its counter. Each event is logged where it happens with
+/// `defmt-verbose` instead.
+fn log_event(_event: Event, _count: u32) {
+    #[cfg(all(feature = "defmt-03", not(feature = "defmt-verbose")))]
+    if _count % LOG_EVERY == 1 {
+        defmt::warn!("{} x{}", _event, _count);
+    }
+}
+
+/// Fill a frame for the host with `fill`, writing the identifier and data of
+/// the CAN side, and its flags.
+fn fill_frame(
+    slot: &mut host::Frame,
+    fill: impl FnOnce(&mut host::Frame) -> Option<()>,
+    flags: FrameFlag,
+) -> Result<(), FrameError> {
+    fill(slot).ok_or(FrameError::InvalidLength)?;
+    slot.flags = flags.difference(FrameFlag::BIT_RATE_SWITCH | FrameFlag::ERROR_STATE_INDICATOR);
+    if !slot.is_fd() {
+        if !slot.is_remote_frame() && slot.can_dlc > 8 {
+            return Err(FrameError::InvalidLength);
         }
+    } else if cfg!(not(feature = "fd")) {
+        return Err(FrameError::FdUnsupported);
     }
+    slot.set_brs(flags.contains(FrameFlag::BIT_RATE_SWITCH))?;
+    slot.set_esi(flags.contains(FrameFlag::ERROR_STATE_INDICATOR))
+}
 
-    fn reset(&mut self) {
-        // reset internal state
-        self.interface_fd = [false; 3];
-        self.out_queue = Queue::new();
-        self.out_frame = None;
-        self.in_frame = None;
+/// Returns `true` if a frame to the host repeats the last one queued within the
+/// dedup window.
+fn is_repeat(
+    config: Option<DedupConfig>,
+    last: &Option<(host::Frame, u32)>,
+    frame: &host::Frame,
+    now: u32,
+) -> bool {
+    let (Some(config), Some((last, queued_us))) = (config, last) else {
+        return false;
+    };
+
+    !frame.is_error_frame()
+        && now.wrapping_sub(*queued_us) < config.window_us
+        && frame.can_id == last.can_id
+        && frame.flags.bits() == last.flags.bits()
+        && (!config.compare_data || (frame.can_dlc == last.can_dlc && frame.data() == last.data()))
+}
+
+/// Replace the bits `from` in `mask` of a frame's identifier with `to`.
+fn remap_id(frame: &mut host::Frame, mask: u32, from: u32, to: u32) {
+    if frame.is_error_frame() {
+        return;
+    }
+
+    let format = if frame.is_extended() {
+        embedded_can::ExtendedId::MAX.as_raw()
+    } else {
+        u32::from(embedded_can::StandardId::MAX.as_raw())
+    };
+    let mask = mask & format;
+    let id = frame.can_id & format;
+    if id & mask == from & mask {
+        frame.can_id = (frame.can_id & !mask) | (to & mask);
+    }
+}
+
+/// Returns `true` if two frames have the same identifier, type and data.
+fn same_frame(a: &host::Frame, b: &host::Frame) -> bool {
+    let kind = FrameFlag::FD | FrameFlag::BIT_RATE_SWITCH;
+    a.can_id == b.can_id
+        && a.can_dlc == b.can_dlc
+        && a.flags.intersection(kind).bits() == b.flags.intersection(kind).bits()
+        && a.data() == b.data()
+}
+
+/// Extended bit timing options with the same options for both phases.
+#[cfg(feature = "fd")]
+fn extend_bit_timing(bit_timing: &DeviceBitTimingConst) -> DeviceBitTimingConstExtended {
+    DeviceBitTimingConstExtended {
+        features: bit_timing.features,
+        fclk_can: bit_timing.fclk_can,
+        timing_nominal: bit_timing.timing,
+        timing_data: bit_timing.timing,
+    }
+}
+
+/// Allocate a bulk endpoint at the address the host expects.
+///
+/// The host doesn't look the endpoints up from the descriptors, so a bus that
+/// can't allocate the address is of no use.
+// only panics while the class is built, see `GsCan::new`.
+#[allow(clippy::expect_used)]
+fn alloc_bulk<B: UsbBus, D: EndpointDirection>(
+    alloc: &UsbBusAllocator<B>,
+    address: u8,
+) -> Endpoint<'_, B, D> {
+    alloc
+        .alloc(
+            Some(EndpointAddress::from(address)),
+            EndpointType::Bulk,
+            PACKET_LEN as u16,
+            0,
+        )
+        .expect("endpoint address unavailable")
+}
+
+/// Check an endpoint is what the host expects.
+fn check_bulk<B: UsbBus, D: EndpointDirection>(endpoint: &Endpoint<'_, B, D>, address: u8) {
+    assert_eq!(
+        endpoint.address(),
+        EndpointAddress::from(address),
+        "endpoint allocated at another address",
+    );
+    assert!(
+        endpoint.ep_type() == EndpointType::Bulk
+            && usize::from(endpoint.max_packet_size()) == PACKET_LEN,
+        "endpoint isn't bulk with 64 byte packets",
+    );
+}
+
+/// Respond to a control in transfer, truncated to the length the host asked
+/// for.
+///
+/// If the response cannot be sent the transfer is left unanswered and
+/// usb-device rejects it.
+fn accept_in<B: UsbBus>(xfer: ControlIn<B>, data: &[u8]) {
+    let len = data.len().min(xfer.request().length as usize);
+
+    if xfer.accept_with(data.get(..len).unwrap_or(data)).is_err() {
+        #[cfg(feature = "defmt-03")]
+        defmt::error!("Failed to respond to control request");
     }
 }
 
 pub trait Device {
     /// Returns the device configuration.
     ///
-    /// Requested after reset by the host.
+    /// Read when the class is created and again at every USB reset, so the
+    /// number of channels may change across a re-enumeration. The
+    /// `software_version` is set by the class.
     fn config(&self) -> DeviceConfig;
 
     /// Returns the bit timing options.
+    ///
+    /// Read once when the class is created.
     fn bit_timing(&self) -> DeviceBitTimingConst;
 
     /// Returns the extended bit timing options.
-    fn bit_timing_ext(&self) -> DeviceBitTimingConstExtended;
+    ///
+    /// Read once when the class is created, only if
+    /// [`Feature::BT_CONST_EXT`] is advertised. Defaults to the options of
+    /// [`Device::bit_timing`] for both phases.
+    #[cfg(feature = "fd")]
+    fn bit_timing_ext(&self) -> DeviceBitTimingConstExtended {
+        extend_bit_timing(&self.bit_timing())
+    }
+
+    /// Called when the host configures the timing of the CAN channel.
+    ///
+    /// Only a notification, the timing is passed to [`Device::start`]. Hosts
+    /// may send it before or after the data phase timing, or not at all to
+    /// keep the last one. Whilst the channel is started it is only called
+    /// with the timing in use, other timings are rejected.
+    fn configure_bit_timing(&mut self, channel: Channel, timing: DeviceBitTiming) {
+        let _ = (channel, timing);
+    }
+
+    /// Called when the host configures the data phase timing of the CAN
+    /// channel.
+    ///
+    /// Only a notification, the timing is passed to [`Device::start`]. As for
+    /// [`Device::configure_bit_timing`], it may come in any order and only
+    /// the timing in use is accepted whilst the channel is started.
+    #[cfg(feature = "fd")]
+    fn configure_bit_timing_data(&mut self, channel: Channel, timing: DeviceBitTiming) {
+        let _ = (channel, timing);
+    }
+
+    /// Returns the data phase timing to use if the host starts a channel in FD
+    /// mode without configuring it.
+    ///
+    /// Hosts that only read [`Device::bit_timing`], e.g. when
+    /// [`Feature::BT_CONST_EXT`] isn't advertised, may never send it. Return
+    /// `None` to reject the start instead.
+    #[cfg(feature = "fd")]
+    fn default_data_timing(&mut self, channel: Channel) -> Option<DeviceBitTiming> {
+        let _ = channel;
+        None
+    }
+
+    /// Called when the host requests a channel is reset.
+    ///
+    /// Only called for started channels. Also called for started channels
+    /// when the USB bus is reset.
+    fn reset(&mut self, channel: Channel);
+
+    /// Called before a channel is started to check the requested features.
+    ///
+    /// The named features have already been checked against those advertised,
+    /// bits without a name in [`Feature`] are passed through as sent. Return
+    /// an error for combinations the device cannot support, the host's request
+    /// is then rejected and the channel left as it was.
+    #[allow(clippy::result_unit_err)]
+    fn validate_start(&mut self, channel: Channel, features: Feature) -> Result<(), ()> {
+        let _ = (channel, features);
+        Ok(())
+    }
+
+    /// Called when the host requests a channel is started.
+    ///
+    /// `nominal` is the last timing configured by the host. `data` is the data
+    /// phase timing, only given when starting in FD mode. Both are kept over
+    /// channel resets as the host doesn't send them again.
+    ///
+    /// `features` keeps any bits unknown to this crate, they are opaque and
+    /// may be ignored, see [`Feature::known`].
+    ///
+    /// If the channel is already started, [`Device::reset`] is called first.
+    fn start(
+        &mut self,
+        channel: Channel,
+        features: Feature,
+        nominal: &DeviceBitTiming,
+        data: Option<&DeviceBitTiming>,
+    );
+
+    /// Returns the current time in microseconds, wrapping, if the device has a
+    /// timer.
+    ///
+    /// Used for [`GsCan::echo_latency`]. Defaults to `None`.
+    fn timestamp_us(&self) -> Option<u32> {
+        None
+    }
+
+    /// Called with each frame passed to [`GsCan::transmit`] or
+    /// [`GsCan::transmit_fd`] before it is queued for the host.
+    ///
+    /// The frame may be modified in place, e.g. to tag it, or dropped by
+    /// returning `false`, e.g. to hide it from the host. Dropped frames are
+    /// counted by [`GsCan::filter_dropped`] and still passed to the bridge,
+    /// which gets the frame as it was before filtering. Echoes and the error
+    /// frames the class reports itself aren't filtered. Defaults to passing
+    /// every frame unchanged.
+    fn filter_to_host(&mut self, channel: Channel, frame: &mut host::Frame) -> bool {
+        let _ = (channel, frame);
+        true
+    }
 
-    /// Called to configure the timing of the CAN interface.
-    fn configure_bit_timing(&mut self, interface: u8, timing: DeviceBitTiming);
+    /// Called with each error frame the class queues for the host itself, e.g.
+    /// for [`GsCan::with_drop_errors`] and [`GsCan::with_drop_summary`], so a
+    /// log on the device can match the host's.
+    ///
+    /// The frame is as queued. Frames passed to [`GsCan::transmit`] and echoes
+    /// aren't passed. Defaults to doing nothing.
+    fn notify(&mut self, channel: Channel, frame: &host::Frame) {
+        let _ = (channel, frame);
+    }
 
-    /// Called to configure the timing of the CAN interface.
-    fn configure_bit_timing_data(&mut self, interface: u8, timing: DeviceBitTiming);
+    /// Called when the host turns identification of a channel on or off.
+    ///
+    /// Only called if [`Feature::IDENTIFY`] is advertised. The blink pattern
+    /// is kept by [`GsCan::identify`], this is only a notification.
+    fn identify(&mut self, channel: Channel, active: bool) {
+        let _ = (channel, active);
+    }
 
-    /// Called when the host requests an interface is reset.
-    fn reset(&mut self, interface: u8);
+    /// Called when the host switches the termination resistor of a channel on
+    /// or off.
+    ///
+    /// Only called for channels with [`Feature::TERMINATION`], see
+    /// [`GsCan::with_channel_features`].
+    fn set_termination(&mut self, channel: Channel, enabled: bool) {
+        let _ = (channel, enabled);
+    }
 
-    /// Called when the host requests an interface is started.
-    fn start(&mut self, interface: u8, features: Feature);
+    /// Returns `true` if the termination resistor of a channel is on.
+    ///
+    /// Only called for channels with [`Feature::TERMINATION`]. Defaults to
+    /// `false`.
+    fn termination(&self, channel: Channel) -> bool {
+        let _ = channel;
+        false
+    }
 
     /// Returns the device state including TX and RX error counters.
-    fn state(&self, interface: u8) -> DeviceState;
+    ///
+    /// The [`DeviceState`] constructors keep the counters consistent with the
+    /// state. Not called whilst the state reported with
+    /// [`GsCan::report_state`] is used, see [`StateSource`]. Defaults to error
+    /// active without errors.
+    fn state(&self, channel: Channel) -> DeviceState {
+        let _ = channel;
+        DeviceState::active(0, 0)
+    }
 
     /// Called when a frame is received from the host.
-    fn receive(&mut self, interface: u8, frame: &host::Frame);
+    ///
+    /// With [`EchoMode::Device`], call [`GsCan::echo`] with the frame's
+    /// `echo_id` once the frame has been sent.
+    ///
+    /// Return [`nb::Error::WouldBlock`] if the frame cannot be accepted right
+    /// now, it is then handled according to the [`HostTxPolicy`].
+    fn receive(&mut self, channel: Channel, frame: &host::Frame) -> nb::Result<(), Infallible>;
 }
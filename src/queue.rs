@@ -0,0 +1,163 @@
+use crate::host::Frame;
+use zerocopy::FromZeroes;
+
+/// Ring of frames waiting to be sent to the host.
+///
+/// Frames are built in place with [`FrameQueue::grant`] and written to the
+/// endpoint straight from [`FrameQueue::peek`], so a frame isn't copied on its
+/// way through. Holds `N` frames.
+pub(crate) struct FrameQueue<const N: usize> {
+    frames: [Frame; N],
+    /// Stamp of each frame, e.g. the time it was queued
+    stamps: [u32; N],
+    /// Frames not ready to be written, see [`FrameQueue::set_held`]
+    held: [bool; N],
+    /// Index of the oldest frame
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> FrameQueue<N> {
+    pub(crate) fn new() -> Self {
+        Self {
+            frames: [Frame::new_zeroed(); N],
+            stamps: [0; N],
+            held: [false; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        N
+    }
+
+    /// The next free slot, holding a stale frame.
+    ///
+    /// The frame is only queued once [`FrameQueue::commit`] is called.
+    pub(crate) fn grant(&mut self) -> Option<&mut Frame> {
+        if self.len == N {
+            return None;
+        }
+
+        self.frames.get_mut((self.head + self.len) % N)
+    }
+
+    /// Queue the frame in the slot last returned by [`FrameQueue::grant`].
+    pub(crate) fn commit(&mut self) {
+        self.commit_stamped(0);
+    }
+
+    /// Queue the frame in the slot last returned by [`FrameQueue::grant`]
+    /// with a stamp, see [`FrameQueue::stamp`].
+    pub(crate) fn commit_stamped(&mut self, stamp: u32) {
+        debug_assert!(self.len < N);
+        let slot = (self.head + self.len) % N;
+        if let Some(queued) = self.stamps.get_mut(slot) {
+            *queued = stamp;
+        }
+        if let Some(held) = self.held.get_mut(slot) {
+            *held = false;
+        }
+        self.len += 1;
+    }
+
+    pub(crate) fn enqueue(&mut self, frame: Frame) -> Result<(), Frame> {
+        let Some(slot) = self.grant() else {
+            return Err(frame);
+        };
+
+        *slot = frame;
+        self.commit();
+
+        Ok(())
+    }
+
+    pub(crate) fn peek(&self) -> Option<&Frame> {
+        self.get(0)
+    }
+
+    pub(crate) fn peek_mut(&mut self) -> Option<&mut Frame> {
+        self.get_mut(0)
+    }
+
+    /// The frame `index` places from the oldest.
+    pub(crate) fn get(&self, index: usize) -> Option<&Frame> {
+        self.frames.get(self.slot(index)?)
+    }
+
+    /// The frame `index` places from the oldest, to be modified.
+    pub(crate) fn get_mut(&mut self, index: usize) -> Option<&mut Frame> {
+        let slot = self.slot(index)?;
+        self.frames.get_mut(slot)
+    }
+
+    /// Returns `true` if the frame `index` places from the oldest is held.
+    pub(crate) fn is_held(&self, index: usize) -> bool {
+        self.slot(index)
+            .and_then(|slot| self.held.get(slot))
+            .is_some_and(|&held| held)
+    }
+
+    /// Hold the frame `index` places from the oldest in the queue, keeping
+    /// its place whilst it isn't ready to be written.
+    pub(crate) fn set_held(&mut self, index: usize, held: bool) {
+        let slot = self.slot(index);
+        if let Some(slot) = slot.and_then(|slot| self.held.get_mut(slot)) {
+            *slot = held;
+        }
+    }
+
+    /// The stamp of the frame `index` places from the oldest, 0 unless it was
+    /// queued with [`FrameQueue::commit_stamped`].
+    pub(crate) fn stamp(&self, index: usize) -> Option<u32> {
+        self.stamps.get(self.slot(index)?).copied()
+    }
+
+    /// Remove the frame `index` places from the oldest, keeping the order of
+    /// the others.
+    pub(crate) fn remove(&mut self, index: usize) {
+        if index >= self.len {
+            return;
+        }
+
+        // older frames move up into the gap, the removed one ends at the head.
+        for i in (0..index).rev() {
+            let (from, to) = ((self.head + i) % N, (self.head + i + 1) % N);
+            self.frames.swap(from, to);
+            self.stamps.swap(from, to);
+            self.held.swap(from, to);
+        }
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+    }
+
+    /// Move the newest frame into the place of the frame `index` places from
+    /// the oldest, which is dropped.
+    pub(crate) fn replace_with_newest(&mut self, index: usize) {
+        let (Some(to), Some(from)) = (self.slot(index), self.len.checked_sub(1)) else {
+            return;
+        };
+        let Some(from) = self.slot(from) else {
+            return;
+        };
+
+        self.frames.swap(from, to);
+        self.stamps.swap(from, to);
+        self.held.swap(from, to);
+        self.len -= 1;
+    }
+
+    /// Position in the ring of the frame `index` places from the oldest.
+    fn slot(&self, index: usize) -> Option<usize> {
+        (index < self.len).then_some((self.head + index) % N)
+    }
+}
@@ -0,0 +1,203 @@
+//! A device for testing the USB path without CAN hardware, e.g. in
+//! manufacturing before the transceivers are fitted.
+//!
+//! [`SelfTestDevice`] has a classic channel 0 and a CAN FD channel 1, accepts
+//! any timing and loops every frame from the host back to it as a received
+//! frame, see [`GsCan::loop_back`].
+//!
+//! # Host sequence
+//!
+//! Once a channel is started, the host sends the frames of [`pattern`] in
+//! order, starting from 0. Frame `n` has standard ID `n % 0x800` and data
+//! bytes `n + i`, truncated to a byte. On channel 0 it is a classic frame of
+//! `n % 9` bytes, on channel 1 a CAN FD frame with the `n % 16`th CAN FD
+//! length, from 0 to 64 bytes. The board passes if each frame is echoed and
+//! received back unchanged and [`SelfTestDevice::mismatches`] stays 0.
+//!
+//! With `python-can`, start both channels (channel 1 with `fd=True`) and
+//! compare each message from `recv()` against the one sent.
+
+use crate::host::{
+    CanBitTimingConst, CanState, DeviceBitTiming, DeviceBitTimingConst, DeviceConfig, DeviceState,
+    Feature, Frame, FrameFlag,
+};
+use crate::{Channel, Device, GsCan};
+use core::convert::Infallible;
+use embedded_can::{Frame as _, StandardId};
+use usb_device::bus::UsbBus;
+use zerocopy::FromZeroes;
+
+/// The classic channel.
+pub const CLASSIC: Channel = Channel(0);
+/// The CAN FD channel.
+pub const FD: Channel = Channel(1);
+
+/// Frames from the host waiting to be looped back.
+const LOOP_LEN: usize = 16;
+
+/// Lengths of the CAN FD frames of the pattern.
+const FD_LENS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+const TIMING: CanBitTimingConst = CanBitTimingConst {
+    tseg1_min: 1,
+    tseg1_max: 256,
+    tseg2_min: 1,
+    tseg2_max: 128,
+    sjw_max: 128,
+    brp_min: 1,
+    brp_max: 1024,
+    brp_inc: 1,
+};
+
+/// Frame `n` of the host sequence on a channel.
+pub fn pattern(channel: Channel, n: u32) -> Frame {
+    let id = StandardId::new((n % 0x800) as u16).unwrap_or(StandardId::ZERO);
+    let len = if channel == FD {
+        FD_LENS
+            .get(n as usize % FD_LENS.len())
+            .copied()
+            .unwrap_or(0)
+    } else {
+        n as usize % 9
+    };
+
+    let mut data = [0; 64];
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = (n as u8).wrapping_add(i as u8);
+    }
+
+    let mut frame = data
+        .get(..len)
+        .and_then(|data| Frame::new(id, data))
+        .unwrap_or_else(Frame::new_zeroed);
+    if channel == FD {
+        frame.flags = FrameFlag::FD;
+    }
+    frame
+}
+
+/// Device looping frames from the host back to it.
+#[derive(Default)]
+pub struct SelfTestDevice {
+    /// Frames from the host waiting to be looped back
+    looped: heapless::Vec<(Channel, Frame), LOOP_LEN>,
+    /// Index of the next frame of the pattern on each channel
+    next: [u32; 2],
+    matched: u32,
+    mismatches: u32,
+}
+
+impl SelfTestDevice {
+    /// Create a device at the start of the pattern.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of frames from the host that followed the [`pattern`].
+    pub fn matched(&self) -> u32 {
+        self.matched
+    }
+
+    /// Number of frames from the host that didn't follow the [`pattern`].
+    pub fn mismatches(&self) -> u32 {
+        self.mismatches
+    }
+
+    fn check(&mut self, channel: Channel, frame: &Frame) {
+        let Some(next) = self.next.get_mut(usize::from(channel)) else {
+            self.mismatches = self.mismatches.wrapping_add(1);
+            return;
+        };
+        let expected = pattern(channel, *next);
+        *next = next.wrapping_add(1);
+
+        let fd = frame.flags.contains(FrameFlag::FD);
+        if frame.can_id == expected.can_id
+            && frame.can_dlc == expected.can_dlc
+            && fd == (channel == FD)
+            && frame.data() == expected.data()
+        {
+            self.matched = self.matched.wrapping_add(1);
+        } else {
+            self.mismatches = self.mismatches.wrapping_add(1);
+        }
+    }
+}
+
+impl Device for SelfTestDevice {
+    fn config(&self) -> DeviceConfig {
+        DeviceConfig::new(2)
+    }
+
+    fn bit_timing(&self) -> DeviceBitTimingConst {
+        DeviceBitTimingConst {
+            features: Feature::FD | Feature::BT_CONST_EXT | Feature::LOOP_BACK,
+            fclk_can: 80_000_000,
+            timing: TIMING,
+        }
+    }
+
+    fn reset(&mut self, channel: Channel) {
+        self.looped.retain(|(looped, _)| *looped != channel);
+    }
+
+    fn validate_start(&mut self, channel: Channel, features: Feature) -> Result<(), ()> {
+        if channel == CLASSIC && features.contains(Feature::FD) {
+            return Err(());
+        }
+
+        Ok(())
+    }
+
+    fn start(
+        &mut self,
+        channel: Channel,
+        _features: Feature,
+        _nominal: &DeviceBitTiming,
+        _data: Option<&DeviceBitTiming>,
+    ) {
+        // the host starts the sequence over.
+        if let Some(next) = self.next.get_mut(usize::from(channel)) {
+            *next = 0;
+        }
+    }
+
+    fn state(&self, _channel: Channel) -> DeviceState {
+        DeviceState {
+            state: CanState::Active,
+            rx_errors: 0,
+            tx_errors: 0,
+        }
+    }
+
+    fn receive(&mut self, channel: Channel, frame: &Frame) -> nb::Result<(), Infallible> {
+        if self.looped.is_full() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.check(channel, frame);
+        self.looped.push((channel, *frame)).ok();
+        Ok(())
+    }
+}
+
+impl<B: UsbBus, const RX: usize> GsCan<'_, B, SelfTestDevice, RX> {
+    /// Send the frames from the host back to it, as far as the queue to the
+    /// host has room.
+    ///
+    /// Call from the same context as [`GsCan::transmit`], e.g. the main loop,
+    /// then [`GsCan::kick`] from the USB context.
+    pub fn loop_back(&mut self) {
+        while self.tx_free() > 0 {
+            if self.device.looped.is_empty() {
+                break;
+            }
+            let (channel, frame) = self.device.looped.remove(0);
+            let flags = frame.flags & (FrameFlag::FD | FrameFlag::BIT_RATE_SWITCH);
+            self.transmit(channel, &frame, flags);
+        }
+
+        // frames held back by a full loop.
+        self.retry_receive();
+    }
+}
@@ -0,0 +1,368 @@
+//! Sessions of USB transfers recorded with the trace hooks, for replaying a
+//! field issue against the class off-target.
+//!
+//! A session is a sequence of records, each a header followed by `len`
+//! bytes, all little endian:
+//!
+//! | Offset | Size  | Field          |
+//! |--------|-------|----------------|
+//! | 0      | 1     | [`RecordKind`] |
+//! | 1      | 2     | `len`          |
+//! | 3      | 4     | `time_us`      |
+//! | 7      | `len` | bytes          |
+//!
+//! The firmware writes a record from each of the hooks, e.g. to a buffer
+//! read out over RTT, with whatever clock it has:
+//!
+//! ```ignore
+//! let gs_can = GsCan::new(&usb_bus, device)
+//!     .with_control_hook(|req, data| {
+//!         let mut bytes = [0; 8 + 64];
+//!         let len = session::control_bytes(req, data, &mut bytes).unwrap();
+//!         log(RecordKind::Control, &bytes[..len]);
+//!     })
+//!     .with_bulk_in_hook(|bytes| log(RecordKind::BulkIn, bytes))
+//!     .with_bulk_out_hook(|bytes| log(RecordKind::BulkOut, bytes));
+//!
+//! fn log(kind: RecordKind, bytes: &[u8]) {
+//!     let record = Record { kind, time_us: now_us(), bytes };
+//!     let len = record.write_to(&mut buf).unwrap();
+//!     rtt.write(&buf[..len]);
+//! }
+//! ```
+
+use usb_device::control;
+use usb_device::UsbDirection;
+
+/// Bytes of the header before the bytes of a record.
+pub const RECORD_HEADER_LEN: usize = 7;
+
+/// What a record holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(u8)]
+pub enum RecordKind {
+    /// A vendor request to the class: the 8 bytes of the setup packet,
+    /// followed by the data stage of an OUT request
+    Control = 0,
+    /// A packet read from the bulk OUT endpoint
+    BulkOut = 1,
+    /// A packet written to the bulk IN endpoint
+    BulkIn = 2,
+}
+
+impl TryFrom<u8> for RecordKind {
+    type Error = SessionError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Control),
+            1 => Ok(Self::BulkOut),
+            2 => Ok(Self::BulkIn),
+            _ => Err(SessionError::UnknownKind(value)),
+        }
+    }
+}
+
+/// Errors reading a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum SessionError {
+    /// The session ends part way through a record.
+    Truncated,
+    /// A record of a kind this crate doesn't know of.
+    UnknownKind(u8),
+}
+
+/// A transfer of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct Record<'a> {
+    /// What the bytes are
+    pub kind: RecordKind,
+    /// Time of the transfer, from the clock of the recorder
+    pub time_us: u32,
+    /// Bytes of the transfer
+    pub bytes: &'a [u8],
+}
+
+impl Record<'_> {
+    /// Bytes of the record in a session.
+    pub fn len(&self) -> usize {
+        RECORD_HEADER_LEN + self.bytes.len()
+    }
+
+    /// Returns `true` for a record without bytes, e.g. a zero length packet.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Write the record to the start of `buf`, returning its length.
+    ///
+    /// Returns `None` if it doesn't fit, or has more bytes than a record can.
+    pub fn write_to(&self, buf: &mut [u8]) -> Option<usize> {
+        let len = u16::try_from(self.bytes.len()).ok()?;
+        let record = buf.get_mut(..self.len())?;
+        let (header, bytes) = record.split_at_mut_checked(RECORD_HEADER_LEN)?;
+        let [l0, l1] = len.to_le_bytes();
+        let [t0, t1, t2, t3] = self.time_us.to_le_bytes();
+        header.copy_from_slice(&[self.kind as u8, l0, l1, t0, t1, t2, t3]);
+        bytes.copy_from_slice(self.bytes);
+
+        Some(record.len())
+    }
+}
+
+/// Reads the records of a session in turn, stopping after the first error.
+#[derive(Debug, Clone)]
+pub struct Records<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Records<'a> {
+    /// Records of the session in `bytes`.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record<'a>, SessionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+
+        let record = read_record(self.bytes);
+        self.bytes = match record {
+            Ok(record) => self.bytes.get(record.len()..).unwrap_or(&[]),
+            Err(_) => &[],
+        };
+
+        Some(record)
+    }
+}
+
+/// Read the record at the start of `bytes`.
+fn read_record(bytes: &[u8]) -> Result<Record<'_>, SessionError> {
+    let (header, rest) = bytes
+        .split_first_chunk::<RECORD_HEADER_LEN>()
+        .ok_or(SessionError::Truncated)?;
+    let [kind, l0, l1, t0, t1, t2, t3] = *header;
+    let len = usize::from(u16::from_le_bytes([l0, l1]));
+
+    Ok(Record {
+        kind: RecordKind::try_from(kind)?,
+        time_us: u32::from_le_bytes([t0, t1, t2, t3]),
+        bytes: rest.get(..len).ok_or(SessionError::Truncated)?,
+    })
+}
+
+/// The setup packet of a control request.
+pub fn setup_packet(req: &control::Request) -> [u8; 8] {
+    let direction = match req.direction {
+        UsbDirection::Out => 0x00,
+        UsbDirection::In => 0x80,
+    };
+    let request_type = direction | (req.request_type as u8) << 5 | req.recipient as u8;
+    let [v0, v1] = req.value.to_le_bytes();
+    let [i0, i1] = req.index.to_le_bytes();
+    let [l0, l1] = req.length.to_le_bytes();
+
+    [request_type, req.request, v0, v1, i0, i1, l0, l1]
+}
+
+/// Write the bytes of a [`RecordKind::Control`] record for a request and its
+/// data stage to the start of `buf`, returning their length.
+///
+/// Returns `None` if they don't fit.
+pub fn control_bytes(req: &control::Request, data: &[u8], buf: &mut [u8]) -> Option<usize> {
+    let setup = setup_packet(req);
+    let len = setup.len() + data.len();
+    let (head, tail) = buf.get_mut(..len)?.split_at_mut_checked(setup.len())?;
+    head.copy_from_slice(&setup);
+    tail.copy_from_slice(data);
+
+    Some(len)
+}
+
+/// Errors converting a usbmon capture, with the number of the line from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum UsbmonError {
+    /// The line isn't an event of the usbmon text format.
+    Malformed(usize),
+    /// The data of the transfer was cut short by usbmon, which keeps 32 bytes
+    /// of it unless told otherwise.
+    Truncated(usize),
+}
+
+/// Longest data stage of a control request converted from a capture.
+const USBMON_MAX_DATA: usize = 1024;
+
+/// Convert a capture of the usbmon text format of Linux, e.g. read from
+/// `/sys/kernel/debug/usb/usbmon/1u` whilst a host drives the device, to the
+/// records of a session, passing each to `record`.
+///
+/// Only the transfers of the device with address `address` are converted: the
+/// vendor requests when submitted, the bulk OUT transfers when submitted and
+/// the bulk IN transfers when completed, split into packets of `packet_size`
+/// bytes as the hooks record them. Times are those of the capture, wrapping
+/// at `u32::MAX`.
+pub fn from_usbmon(
+    text: &str,
+    address: u8,
+    packet_size: usize,
+    mut record: impl FnMut(Record<'_>),
+) -> Result<(), UsbmonError> {
+    let packet_size = packet_size.max(1);
+    for (number, line) in text.lines().enumerate() {
+        let line_number = number + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut buf = [0; 8 + USBMON_MAX_DATA];
+        let Some((kind, time_us, len)) =
+            usbmon_event(line, address, &mut buf).map_err(|error| error.at(line_number))?
+        else {
+            continue;
+        };
+
+        let bytes = buf.get(..len).unwrap_or(&[]);
+        if kind == RecordKind::Control || bytes.is_empty() {
+            record(Record {
+                kind,
+                time_us,
+                bytes,
+            });
+            continue;
+        }
+        for packet in bytes.chunks(packet_size) {
+            record(Record {
+                kind,
+                time_us,
+                bytes: packet,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Error of a usbmon line before its number is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineError {
+    Malformed,
+    Truncated,
+}
+
+impl LineError {
+    fn at(self, line: usize) -> UsbmonError {
+        match self {
+            Self::Malformed => UsbmonError::Malformed(line),
+            Self::Truncated => UsbmonError::Truncated(line),
+        }
+    }
+}
+
+/// Kind, time and length of the bytes written to `buf` of the record of a
+/// usbmon event, `None` for an event not recorded.
+///
+/// An event is `tag time event type:bus:device:endpoint`, then a setup packet
+/// `s bmRequestType bRequest wValue wIndex wLength` or a status, the length
+/// and the data as `= 01020304 05`.
+fn usbmon_event(
+    line: &str,
+    address: u8,
+    buf: &mut [u8],
+) -> Result<Option<(RecordKind, u32, usize)>, LineError> {
+    let mut words = line.split_ascii_whitespace();
+    let _tag = words.next();
+    let time_us = words
+        .next()
+        .and_then(|time| time.parse::<u64>().ok())
+        .ok_or(LineError::Malformed)?;
+    // the clock of the recorder wraps.
+    let time_us = time_us as u32;
+    let event = words.next().ok_or(LineError::Malformed)?;
+    let mut endpoint = words.next().ok_or(LineError::Malformed)?.split(':');
+    let transfer = endpoint.next().ok_or(LineError::Malformed)?;
+    let _bus = endpoint.next();
+    let device = endpoint
+        .next()
+        .and_then(|device| device.parse::<u8>().ok())
+        .ok_or(LineError::Malformed)?;
+    if device != address {
+        return Ok(None);
+    }
+
+    let kind = match (transfer, event) {
+        ("Ci" | "Co", "S") => RecordKind::Control,
+        ("Bo", "S") => RecordKind::BulkOut,
+        ("Bi", "C") => RecordKind::BulkIn,
+        _ => return Ok(None),
+    };
+
+    let mut at = 0;
+    if kind == RecordKind::Control {
+        if words.next() != Some("s") {
+            return Err(LineError::Malformed);
+        }
+        let request_type = hex_field::<u8>(words.next())?;
+        // the hooks only see the vendor requests to the class.
+        if request_type & 0x60 != 0x40 {
+            return Ok(None);
+        }
+        let request = hex_field::<u8>(words.next())?;
+        let [v0, v1] = hex_field::<u16>(words.next())?.to_le_bytes();
+        let [i0, i1] = hex_field::<u16>(words.next())?.to_le_bytes();
+        let [l0, l1] = hex_field::<u16>(words.next())?.to_le_bytes();
+        let setup = [request_type, request, v0, v1, i0, i1, l0, l1];
+        buf.get_mut(..setup.len())
+            .ok_or(LineError::Truncated)?
+            .copy_from_slice(&setup);
+        at = setup.len();
+        // the data stage of an IN request completes later.
+        if request_type & 0x80 != 0 {
+            return Ok(Some((kind, time_us, at)));
+        }
+    } else {
+        let _status = words.next();
+    }
+
+    let len = words
+        .next()
+        .and_then(|len| len.parse::<usize>().ok())
+        .ok_or(LineError::Malformed)?;
+    match words.next() {
+        Some("=") => {}
+        // no data: none captured, `<` or `>`, or none at all.
+        _ if len == 0 => return Ok(Some((kind, time_us, at))),
+        _ => return Err(LineError::Truncated),
+    }
+    let start = at;
+    for word in words {
+        if word.len() % 2 != 0 {
+            return Err(LineError::Malformed);
+        }
+        for i in (0..word.len()).step_by(2) {
+            let byte = hex_field::<u8>(word.get(i..i + 2))?;
+            *buf.get_mut(at).ok_or(LineError::Truncated)? = byte;
+            at += 1;
+        }
+    }
+    if at - start != len {
+        return Err(LineError::Truncated);
+    }
+
+    Ok(Some((kind, time_us, at)))
+}
+
+/// A field of a usbmon line in hexadecimal.
+fn hex_field<T: TryFrom<u32>>(field: Option<&str>) -> Result<T, LineError> {
+    field
+        .and_then(|field| u32::from_str_radix(field, 16).ok())
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or(LineError::Malformed)
+}
@@ -0,0 +1,218 @@
+//! One set of CAN channels served to hosts on separate USB ports, e.g. two
+//! ports for electrical isolation between two hosts.
+//!
+//! [`Shared`] holds the [`Device`] and the port owning each channel. Each
+//! port's [`GsCan`] is created with a [`SharedDevice`] from [`Shared::port`].
+//! The first host to start a channel owns it until it resets the channel or
+//! its bus is reset. Until then the other hosts' start requests are rejected
+//! with [`RejectReason::DeviceRejected`] and their frames for the channel
+//! dropped. Frames from the CAN bus are passed to the class of the owner,
+//! see [`Shared::owner`].
+//!
+//! The device is behind a [`critical_section::Mutex`], so the classes may be
+//! polled from different interrupts. Wiring two ports, each with its own
+//! `UsbBus`:
+//!
+//! ```ignore
+//! static SHARED: StaticCell<Shared<CanDevice>> = StaticCell::new();
+//! let shared: &'static _ = SHARED.init(Shared::new(CanDevice::new(can)));
+//!
+//! // each class allocates its endpoints before its device is built.
+//! let mut gs_can0 = GsCan::new(usb_bus0, shared.port(0));
+//! let mut usb_dev0 = UsbDeviceBuilder::new(usb_bus0, identifier::CANDLELIGHT).build();
+//! let mut gs_can1 = GsCan::new(usb_bus1, shared.port(1));
+//! let mut usb_dev1 = UsbDeviceBuilder::new(usb_bus1, identifier::CANDLELIGHT).build();
+//!
+//! // from each port's USB interrupt.
+//! usb_dev0.poll(&mut [&mut gs_can0]);
+//! usb_dev1.poll(&mut [&mut gs_can1]);
+//!
+//! // from the CAN interrupt, with both classes locked.
+//! if let Ok(frame) = shared.with_device(|device| device.can.receive()) {
+//!     match shared.owner(channel) {
+//!         Some(0) => gs_can0.transmit(channel, &frame, FrameFlag::empty()),
+//!         Some(1) => gs_can1.transmit(channel, &frame, FrameFlag::empty()),
+//!         _ => {}
+//!     }
+//! }
+//! ```
+//!
+//! [`GsCan`]: crate::GsCan
+//! [`RejectReason::DeviceRejected`]: crate::RejectReason::DeviceRejected
+
+#[cfg(feature = "fd")]
+use crate::host::DeviceBitTimingConstExtended;
+use crate::host::{
+    DeviceBitTiming, DeviceBitTimingConst, DeviceConfig, DeviceState, Feature, Frame, MAX_INTF,
+};
+use crate::{Channel, Device, PerChannel};
+use core::cell::RefCell;
+use core::convert::Infallible;
+use critical_section::Mutex;
+
+struct Inner<D> {
+    device: D,
+    /// Port owning each channel
+    owners: [Option<u8>; MAX_INTF],
+}
+
+/// A [`Device`] shared by the classes of several USB ports.
+pub struct Shared<D> {
+    inner: Mutex<RefCell<Inner<D>>>,
+}
+
+impl<D> Shared<D> {
+    /// Share `device`, with no channel owned.
+    pub const fn new(device: D) -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(Inner {
+                device,
+                owners: [None; MAX_INTF],
+            })),
+        }
+    }
+
+    /// The device for the class of `port`, a number of the application's
+    /// choosing.
+    pub fn port(&self, port: u8) -> SharedDevice<'_, D> {
+        SharedDevice { shared: self, port }
+    }
+
+    /// Port owning a channel, if started by a host.
+    pub fn owner(&self, channel: Channel) -> Option<u8> {
+        self.with(|inner| *inner.owners.at(channel))
+    }
+
+    /// Run `f` with the device, e.g. from a CAN interrupt.
+    pub fn with_device<R>(&self, f: impl FnOnce(&mut D) -> R) -> R {
+        self.with(|inner| f(&mut inner.device))
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Inner<D>) -> R) -> R {
+        critical_section::with(|cs| f(&mut self.inner.borrow_ref_mut(cs)))
+    }
+}
+
+/// The [`Device`] of a [`GsCan`](crate::GsCan) on one port, see [`Shared`].
+pub struct SharedDevice<'a, D> {
+    shared: &'a Shared<D>,
+    port: u8,
+}
+
+impl<D> SharedDevice<'_, D> {
+    /// The port of the class.
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    /// Run `f` with the device unless another port owns the channel.
+    fn if_available(&self, channel: Channel, f: impl FnOnce(&mut D)) {
+        self.shared.with(|inner| {
+            if inner
+                .owners
+                .at(channel)
+                .is_none_or(|owner| owner == self.port)
+            {
+                f(&mut inner.device);
+            }
+        });
+    }
+}
+
+impl<D: Device> Device for SharedDevice<'_, D> {
+    fn config(&self) -> DeviceConfig {
+        self.shared.with(|inner| inner.device.config())
+    }
+
+    fn bit_timing(&self) -> DeviceBitTimingConst {
+        self.shared.with(|inner| inner.device.bit_timing())
+    }
+
+    #[cfg(feature = "fd")]
+    fn bit_timing_ext(&self) -> DeviceBitTimingConstExtended {
+        self.shared.with(|inner| inner.device.bit_timing_ext())
+    }
+
+    fn configure_bit_timing(&mut self, channel: Channel, timing: DeviceBitTiming) {
+        self.if_available(channel, |device| {
+            device.configure_bit_timing(channel, timing);
+        });
+    }
+
+    #[cfg(feature = "fd")]
+    fn configure_bit_timing_data(&mut self, channel: Channel, timing: DeviceBitTiming) {
+        self.if_available(channel, |device| {
+            device.configure_bit_timing_data(channel, timing);
+        });
+    }
+
+    #[cfg(feature = "fd")]
+    fn default_data_timing(&mut self, channel: Channel) -> Option<DeviceBitTiming> {
+        self.shared
+            .with(|inner| inner.device.default_data_timing(channel))
+    }
+
+    fn reset(&mut self, channel: Channel) {
+        self.shared.with(|inner| {
+            // only the owner has started the channel.
+            let owner = inner.owners.at_mut(channel);
+            if *owner == Some(self.port) {
+                *owner = None;
+                inner.device.reset(channel);
+            }
+        });
+    }
+
+    fn validate_start(&mut self, channel: Channel, features: Feature) -> Result<(), ()> {
+        self.shared.with(|inner| match *inner.owners.at(channel) {
+            Some(owner) if owner != self.port => Err(()),
+            _ => inner.device.validate_start(channel, features),
+        })
+    }
+
+    fn start(
+        &mut self,
+        channel: Channel,
+        features: Feature,
+        nominal: &DeviceBitTiming,
+        data: Option<&DeviceBitTiming>,
+    ) {
+        self.shared.with(|inner| {
+            *inner.owners.at_mut(channel) = Some(self.port);
+            inner.device.start(channel, features, nominal, data);
+        });
+    }
+
+    fn timestamp_us(&self) -> Option<u32> {
+        self.shared.with(|inner| inner.device.timestamp_us())
+    }
+
+    fn filter_to_host(&mut self, channel: Channel, frame: &mut Frame) -> bool {
+        self.shared
+            .with(|inner| inner.device.filter_to_host(channel, frame))
+    }
+
+    fn notify(&mut self, channel: Channel, frame: &Frame) {
+        self.shared
+            .with(|inner| inner.device.notify(channel, frame));
+    }
+
+    fn identify(&mut self, channel: Channel, active: bool) {
+        self.if_available(channel, |device| device.identify(channel, active));
+    }
+
+    fn state(&self, channel: Channel) -> DeviceState {
+        self.shared.with(|inner| inner.device.state(channel))
+    }
+
+    fn receive(&mut self, channel: Channel, frame: &Frame) -> nb::Result<(), Infallible> {
+        self.shared.with(|inner| {
+            // only the owner's frames reach the bus.
+            if *inner.owners.at(channel) == Some(self.port) {
+                inner.device.receive(channel, frame)
+            } else {
+                Ok(())
+            }
+        })
+    }
+}
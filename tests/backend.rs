@@ -0,0 +1,785 @@
+//! Frames from the host as different `UsbBus` backends deliver them, a host
+//! that stops reading, and control requests alongside another class.
+#![cfg(feature = "fd")]
+
+use core::convert::Infallible;
+use embedded_can::{Frame as _, StandardId};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use usb_device::bus::InterfaceNumber;
+use usb_device::bus::{PollResult, UsbBus, UsbBusAllocator};
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::control::{Recipient, RequestType};
+use usb_device::descriptor::DescriptorWriter;
+use usb_device::device::{UsbDevice, UsbDeviceBuilder};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{UsbDirection, UsbError};
+use usbd_gscan::{
+    host::{
+        CanBitTimingConst, CanState, DeviceBitTiming, DeviceBitTimingConst, DeviceConfig,
+        DeviceState, Feature, Frame, FrameFlag, FrameKind,
+    },
+    identifier, Channel, Device, GsCan, RejectReason,
+};
+use zerocopy::AsBytes;
+
+/// Bus returning scripted chunks, one per read, and scripted poll results.
+#[derive(Default)]
+struct ScriptBus {
+    /// Chunks waiting to be read from each OUT endpoint
+    reads: Mutex<[VecDeque<Vec<u8>>; 3]>,
+    /// `(ep_out, ep_setup)` of the next polls
+    polls: Mutex<VecDeque<(u16, u16)>>,
+    /// Writes to the bulk IN endpoint return `WouldBlock`
+    stalled: AtomicBool,
+    /// Packets written to the bulk IN endpoint
+    written: Mutex<Vec<Vec<u8>>>,
+}
+
+impl ScriptBus {
+    fn push(&self, index: usize, chunks: &[&[u8]], setup: bool) {
+        let mut reads = self.reads.lock().unwrap();
+        reads[index].extend(chunks.iter().map(|chunk| chunk.to_vec()));
+        let bit = 1 << index;
+        let poll = if setup { (0, bit) } else { (bit, 0) };
+        self.polls.lock().unwrap().push_back(poll);
+    }
+}
+
+impl UsbBus for ScriptBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> usb_device::Result<EndpointAddress> {
+        Ok(ep_addr.unwrap_or(EndpointAddress::from_parts(0, ep_dir)))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+        if ep_addr.index() == 1 {
+            if self.stalled.load(Ordering::Relaxed) {
+                return Err(UsbError::WouldBlock);
+            }
+            self.written.lock().unwrap().push(buf.to_vec());
+        }
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+        let mut reads = self.reads.lock().unwrap();
+        let Some(chunk) = reads[ep_addr.index()].pop_front() else {
+            return Err(UsbError::WouldBlock);
+        };
+        if chunk.len() > buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        buf[..chunk.len()].copy_from_slice(&chunk);
+        Ok(chunk.len())
+    }
+
+    fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        match self.polls.lock().unwrap().pop_front() {
+            Some((ep_out, ep_setup)) => PollResult::Data {
+                ep_out,
+                ep_in_complete: 0,
+                ep_setup,
+            },
+            None => PollResult::None,
+        }
+    }
+}
+
+const TIMING: CanBitTimingConst = CanBitTimingConst {
+    tseg1_min: 1,
+    tseg1_max: 255,
+    tseg2_min: 1,
+    tseg2_max: 127,
+    sjw_max: 127,
+    brp_min: 1,
+    brp_max: 511,
+    brp_inc: 1,
+};
+
+const BIT_TIMING: DeviceBitTiming = DeviceBitTiming {
+    prop_seg: 15,
+    phase_seg1: 16,
+    phase_seg2: 8,
+    sjw: 1,
+    brp: 1,
+};
+
+#[derive(Default)]
+struct RecordingDevice {
+    received: Vec<Frame>,
+    /// Current time, no timer if `None`.
+    now_us: Option<u32>,
+    /// Two channels rather than one.
+    two_channels: bool,
+}
+
+impl Device for RecordingDevice {
+    fn config(&self) -> DeviceConfig {
+        DeviceConfig::new(if self.two_channels { 2 } else { 1 })
+    }
+
+    fn bit_timing(&self) -> DeviceBitTimingConst {
+        DeviceBitTimingConst {
+            features: Feature::FD,
+            fclk_can: 80_000_000,
+            timing: TIMING,
+        }
+    }
+
+    fn reset(&mut self, _channel: Channel) {}
+
+    fn start(
+        &mut self,
+        _channel: Channel,
+        _features: Feature,
+        _nominal: &DeviceBitTiming,
+        _data: Option<&DeviceBitTiming>,
+    ) {
+    }
+
+    fn timestamp_us(&self) -> Option<u32> {
+        self.now_us
+    }
+
+    fn state(&self, _channel: Channel) -> DeviceState {
+        DeviceState {
+            state: CanState::Active,
+            rx_errors: 0,
+            tx_errors: 0,
+        }
+    }
+
+    fn receive(&mut self, _channel: Channel, frame: &Frame) -> nb::Result<(), Infallible> {
+        self.received.push(*frame);
+        Ok(())
+    }
+}
+
+type Class<'a> = GsCan<'a, ScriptBus, RecordingDevice>;
+
+fn poll(device: &mut UsbDevice<'_, ScriptBus>, class: &mut Class<'_>) {
+    while !device.bus().polls.lock().unwrap().is_empty() {
+        device.poll(&mut [class]);
+    }
+}
+
+/// Send a vendor request with a data stage to `channel`.
+fn vendor_out(
+    device: &mut UsbDevice<'_, ScriptBus>,
+    class: &mut Class<'_>,
+    request: u8,
+    channel: u8,
+    data: &[u8],
+) {
+    let len = (data.len() as u16).to_le_bytes();
+    let setup = [0x40, request, channel, 0, 0, 0, len[0], len[1]];
+    device.bus().push(0, &[&setup], true);
+    device.bus().push(0, &[data], false);
+    poll(device, class);
+}
+
+/// Start channel 0 in FD mode.
+fn start_fd(device: &mut UsbDevice<'_, ScriptBus>, class: &mut Class<'_>) {
+    start_fd_on(device, class, 0);
+}
+
+/// Start `channel` in FD mode.
+fn start_fd_on(device: &mut UsbDevice<'_, ScriptBus>, class: &mut Class<'_>, channel: u8) {
+    vendor_out(device, class, 1, channel, BIT_TIMING.as_bytes());
+    vendor_out(device, class, 10, channel, BIT_TIMING.as_bytes());
+    let mut mode = 1_u32.to_le_bytes().to_vec();
+    mode.extend_from_slice(&Feature::FD.bits().to_le_bytes());
+    vendor_out(device, class, 2, channel, &mode);
+    assert!(class.is_started(Channel::new(channel).unwrap()));
+}
+
+/// Reset `channel`.
+fn reset_channel(device: &mut UsbDevice<'_, ScriptBus>, class: &mut Class<'_>, channel: u8) {
+    vendor_out(device, class, 2, channel, &[0; 8]);
+    assert!(!class.is_started(Channel::new(channel).unwrap()));
+}
+
+fn fd_frame() -> Vec<u8> {
+    let mut frame = Frame::new_raw(0x123, &[0xAA; 64]).unwrap();
+    frame.flags = FrameFlag::FD;
+    frame.as_bytes()[..76].to_vec()
+}
+
+/// Deliver a CAN FD frame from the host in `calls` endpoint callbacks of
+/// chunks, one chunk per read.
+fn deliver(calls: &[&[&[u8]]]) {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let mut class: Class = GsCan::new(&alloc, RecordingDevice::default());
+    let mut device = UsbDeviceBuilder::new(&alloc, identifier::CANDLELIGHT).build();
+    start_fd(&mut device, &mut class);
+
+    for (call, chunks) in calls.iter().enumerate() {
+        assert!(class.device.received.is_empty(), "dispatched after {call}");
+        device.bus().push(2, chunks, false);
+        poll(&mut device, &mut class);
+    }
+
+    assert_eq!(class.device.received.len(), 1);
+    let received = class.device.received[0];
+    assert_eq!(received.can_id, 0x123);
+    assert_eq!(received.as_bytes()[12..76], [0xAA; 64]);
+}
+
+#[test]
+fn test_whole_frame_read() {
+    let frame = fd_frame();
+    deliver(&[&[&frame]]);
+}
+
+#[test]
+fn test_packets_one_call() {
+    let frame = fd_frame();
+    deliver(&[&[&frame[..64], &frame[64..]]]);
+}
+
+#[test]
+fn test_packets_two_calls() {
+    let frame = fd_frame();
+    deliver(&[&[&frame[..64]], &[&frame[64..]]]);
+}
+
+/// First packet of a CAN FD frame with ID 0x100 and 8 bytes of data, as
+/// captured from a host leaving out the rest of the transfer.
+const CAPTURED_FIRST_PACKET: [u8; 64] = {
+    let mut packet = [0; 64];
+    // echo_id, can_id
+    packet[0] = 0x00;
+    packet[4] = 0x00;
+    packet[5] = 0x01;
+    // can_dlc, channel, flags
+    packet[8] = 8;
+    packet[10] = 0x02;
+    let mut i = 12;
+    while i < 20 {
+        packet[i] = 0x11;
+        i += 1;
+    }
+    packet
+};
+
+/// Feed captured host packets in `calls` endpoint callbacks of chunks,
+/// returning the class.
+fn replay<'a>(
+    alloc: &'a UsbBusAllocator<ScriptBus>,
+    calls: &[&[&[u8]]],
+) -> (Class<'a>, UsbDevice<'a, ScriptBus>) {
+    let mut class: Class = GsCan::new(alloc, RecordingDevice::default());
+    let mut device = UsbDeviceBuilder::new(alloc, identifier::CANDLELIGHT).build();
+    start_fd(&mut device, &mut class);
+
+    for chunks in calls {
+        device.bus().push(2, chunks, false);
+        poll(&mut device, &mut class);
+    }
+
+    (class, device)
+}
+
+#[test]
+fn test_missing_tail() {
+    let frame = fd_frame();
+    for calls in [
+        &[&[&CAPTURED_FIRST_PACKET[..], &frame[..64], &frame[64..]][..]][..],
+        &[
+            &[&CAPTURED_FIRST_PACKET[..]],
+            &[&frame[..64]],
+            &[&frame[64..]],
+        ],
+    ] {
+        let alloc = UsbBusAllocator::new(ScriptBus::default());
+        let (class, _device) = replay(&alloc, calls);
+
+        assert_eq!(class.missing_tails(), 1);
+        assert_eq!(class.invalid_host_frames(), 0);
+        let received = &class.device.received;
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].can_id, 0x100);
+        assert_eq!(received[0].as_bytes()[12..20], [0x11; 8]);
+        assert_eq!(received[0].as_bytes()[20..76], [0; 56]);
+        assert_eq!(received[1].can_id, 0x123);
+        assert_eq!(received[1].as_bytes()[12..76], [0xAA; 64]);
+    }
+}
+
+#[test]
+fn test_missing_tail_consecutive() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let (class, _device) = replay(
+        &alloc,
+        &[
+            &[&CAPTURED_FIRST_PACKET],
+            &[&CAPTURED_FIRST_PACKET],
+            &[&[0; 12]],
+        ],
+    );
+
+    // the second frame's tail arrives.
+    assert_eq!(class.missing_tails(), 1);
+    assert_eq!(class.device.received.len(), 2);
+}
+
+#[test]
+fn test_tail_present() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let (class, _device) = replay(&alloc, &[&[&CAPTURED_FIRST_PACKET, &[0; 12]]]);
+
+    assert_eq!(class.missing_tails(), 0);
+    assert_eq!(class.device.received.len(), 1);
+    assert_eq!(class.device.received[0].can_id, 0x100);
+}
+
+/// Send a classic frame with ID `id` to the host.
+fn transmit(class: &mut Class<'_>, id: u16) {
+    let frame = Frame::new(StandardId::new(id).unwrap(), &[]).unwrap();
+    class.transmit(Channel::new(0).unwrap(), &frame, FrameFlag::empty());
+}
+
+/// IDs of the frames written to the host, classic frames are a packet each.
+fn written_ids(bus: &ScriptBus) -> Vec<u32> {
+    bus.written
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|packet| u32::from_le_bytes(packet[4..8].try_into().unwrap()))
+        .collect()
+}
+
+#[test]
+fn test_stalled_drop_oldest() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let mut class: Class = GsCan::new(&alloc, RecordingDevice::default()).with_stall_threshold(3);
+    let device = UsbDeviceBuilder::new(&alloc, identifier::CANDLELIGHT).build();
+    // the scripted bus is never enumerated.
+    class.set_configured(true);
+    device.bus().stalled.store(true, Ordering::Relaxed);
+
+    for id in 0..64 {
+        transmit(&mut class, id);
+    }
+    assert_eq!(class.tx_free(), 0);
+
+    // dropped as the newest until the host is taken to have stopped reading.
+    transmit(&mut class, 64);
+    for _ in 0..2 {
+        class.kick();
+    }
+    assert!(!class.is_stalled());
+    class.kick();
+    assert!(class.is_stalled());
+    assert_eq!(class.stalls(), 1);
+
+    transmit(&mut class, 65);
+    transmit(&mut class, 66);
+    assert_eq!(class.stall_dropped(), 2);
+    assert_eq!(class.tx_free(), 0);
+
+    // the host reads again.
+    device.bus().stalled.store(false, Ordering::Relaxed);
+    while !class.is_idle() {
+        class.kick();
+    }
+    assert!(!class.is_stalled());
+    let expected: Vec<u32> = (2..64).chain(65..67).collect();
+    assert_eq!(written_ids(device.bus()), expected);
+
+    // the newest is dropped again once full.
+    device.bus().stalled.store(true, Ordering::Relaxed);
+    for id in 0..65 {
+        transmit(&mut class, id);
+    }
+    assert_eq!(class.stall_dropped(), 2);
+    assert_eq!(class.stalls(), 1);
+}
+
+#[test]
+fn test_stalled_keeps_split_frame() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let mut class: Class = GsCan::new(&alloc, RecordingDevice::default()).with_stall_threshold(1);
+    let mut device = UsbDeviceBuilder::new(&alloc, identifier::CANDLELIGHT).build();
+    class.set_configured(true);
+    start_fd(&mut device, &mut class);
+
+    let channel = Channel::new(0).unwrap();
+    let frame = Frame::new(StandardId::ZERO, &[0xAA; 64]).unwrap();
+    class.transmit_fd(channel, &frame, FrameFlag::empty(), None);
+    // the first packet of the frame is written, the host stops reading.
+    class.kick();
+    device.bus().stalled.store(true, Ordering::Relaxed);
+    for id in 1..64 {
+        transmit(&mut class, id);
+    }
+    class.kick();
+    assert!(class.is_stalled());
+
+    transmit(&mut class, 64);
+    assert_eq!(class.stall_dropped(), 1);
+
+    // the host reads the first packet, the second follows it.
+    device.bus().stalled.store(false, Ordering::Relaxed);
+    UsbClass::<ScriptBus>::poll(&mut class);
+    let written = device.bus().written.lock().unwrap();
+    assert_eq!(written.len(), 2);
+    assert_eq!(written[1].len(), 12);
+}
+
+#[test]
+fn test_max_frame_age() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let mut class: Class =
+        GsCan::new(&alloc, RecordingDevice::default()).with_max_frame_age(50_000);
+    let device = UsbDeviceBuilder::new(&alloc, identifier::CANDLELIGHT).build();
+    class.set_configured(true);
+    device.bus().stalled.store(true, Ordering::Relaxed);
+
+    class.device.now_us = Some(0);
+    transmit(&mut class, 1);
+    class.device.now_us = Some(10_000);
+    transmit(&mut class, 2);
+    // echoes don't go stale.
+    let mut echo = Frame::new(StandardId::new(3).unwrap(), &[]).unwrap();
+    echo.set_kind(FrameKind::Echo(7));
+    class.transmit_raw(&echo, true).unwrap();
+
+    // expires once older than the maximum age.
+    class.device.now_us = Some(50_000);
+    class.kick();
+    assert_eq!(class.stale_dropped(), 0);
+    class.device.now_us = Some(50_001);
+    class.kick();
+    assert_eq!(class.stale_dropped(), 1);
+    class.device.now_us = Some(60_001);
+    class.kick();
+    assert_eq!(class.stale_dropped(), 2);
+
+    // fresh frames still flow, the first frame received after the drop
+    // reports the overflow.
+    transmit(&mut class, 4);
+    device.bus().stalled.store(false, Ordering::Relaxed);
+    while !class.is_idle() {
+        class.kick();
+    }
+    assert_eq!(written_ids(device.bus()), [3, 4]);
+    let flags: Vec<bool> = device
+        .bus()
+        .written
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|packet| packet[10] & FrameFlag::OVERFLOW.bits() != 0)
+        .collect();
+    assert_eq!(flags, [false, true]);
+}
+
+/// A class with an FD frame split across packets, the host having stopped
+/// reading after the first.
+fn split_class(alloc: &UsbBusAllocator<ScriptBus>) -> (Class<'_>, UsbDevice<'_, ScriptBus>) {
+    let mut class: Class = GsCan::new(alloc, RecordingDevice::default()).with_split_retry_limit(3);
+    let mut device = UsbDeviceBuilder::new(alloc, identifier::CANDLELIGHT).build();
+    class.set_configured(true);
+    start_fd(&mut device, &mut class);
+
+    let channel = Channel::new(0).unwrap();
+    let frame = Frame::new(StandardId::ZERO, &[0xAA; 64]).unwrap();
+    class.transmit_fd(channel, &frame, FrameFlag::empty(), None);
+    class.kick();
+    device.bus().stalled.store(true, Ordering::Relaxed);
+    transmit(&mut class, 1);
+
+    for _ in 0..2 {
+        class.kick();
+    }
+    assert_eq!(class.split_frames_dropped(), 0);
+    class.kick();
+    assert_eq!(class.split_frames_dropped(), 1);
+
+    (class, device)
+}
+
+#[test]
+fn test_split_retry_limit() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let (mut class, device) = split_class(&alloc);
+
+    // the zero length packet waits for the host too.
+    class.kick();
+    assert!(!class.is_idle());
+
+    // the host reads again, its transfer of the first half ends short and
+    // the next frame starts a new one.
+    device.bus().stalled.store(false, Ordering::Relaxed);
+    while !class.is_idle() {
+        UsbClass::<ScriptBus>::poll(&mut class);
+    }
+    let written = device.bus().written.lock().unwrap();
+    let lens: Vec<usize> = written.iter().map(Vec::len).collect();
+    assert_eq!(lens, [64, 0, 64, 12]);
+    assert_eq!(written[2][4..8], 1_u32.to_le_bytes());
+}
+
+#[test]
+fn test_split_retry_limit_reset() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let (mut class, device) = split_class(&alloc);
+
+    // the bus reset flushes the first half, no transfer is left to end.
+    UsbClass::<ScriptBus>::reset(&mut class);
+    class.set_configured(true);
+    device.bus().stalled.store(false, Ordering::Relaxed);
+    transmit(&mut class, 2);
+    while !class.is_idle() {
+        class.kick();
+    }
+    let written = device.bus().written.lock().unwrap();
+    let lens: Vec<usize> = written.iter().map(Vec::len).collect();
+    assert_eq!(lens, [64, 20]);
+}
+
+/// A class with both channels started in FD mode.
+fn two_channel_class(alloc: &UsbBusAllocator<ScriptBus>) -> (Class<'_>, UsbDevice<'_, ScriptBus>) {
+    let device = RecordingDevice {
+        two_channels: true,
+        ..Default::default()
+    };
+    let mut class: Class = GsCan::new(alloc, device);
+    let mut device = UsbDeviceBuilder::new(alloc, identifier::CANDLELIGHT).build();
+    class.set_configured(true);
+    start_fd_on(&mut device, &mut class, 0);
+    start_fd_on(&mut device, &mut class, 1);
+    (class, device)
+}
+
+#[test]
+fn test_reset_between_halves_from_host() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let (mut class, mut device) = two_channel_class(&alloc);
+    let frame = fd_frame();
+
+    device.bus().push(2, &[&frame[..64]], false);
+    poll(&mut device, &mut class);
+    reset_channel(&mut device, &mut class, 0);
+    assert_eq!(class.split_frames_dropped(), 1);
+
+    // the second half alone is too short for a frame.
+    device.bus().push(2, &[&frame[64..]], false);
+    poll(&mut device, &mut class);
+    assert!(class.device.received.is_empty());
+    assert_eq!(class.invalid_host_frames(), 1);
+
+    // another channel's reset leaves the frame alone.
+    let mut frame = frame;
+    frame[9] = 1;
+    device.bus().push(2, &[&frame[..64]], false);
+    poll(&mut device, &mut class);
+    reset_channel(&mut device, &mut class, 0);
+    device.bus().push(2, &[&frame[64..]], false);
+    poll(&mut device, &mut class);
+    assert_eq!(class.split_frames_dropped(), 1);
+    assert_eq!(class.device.received.len(), 1);
+    assert_eq!(class.device.received[0].as_bytes()[12..76], [0xAA; 64]);
+}
+
+#[test]
+fn test_reset_between_halves_to_host() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let (mut class, mut device) = two_channel_class(&alloc);
+    let frame = Frame::new(StandardId::ZERO, &[0xAA; 64]).unwrap();
+
+    // a split frame for channel 0 whilst channel 1 is reset, then one for
+    // channel 1.
+    for (channel, reset) in [(0, 1), (1, 1)] {
+        class.transmit_fd(
+            Channel::new(channel).unwrap(),
+            &frame,
+            FrameFlag::empty(),
+            None,
+        );
+        class.kick();
+        // the host stops reading after the first half.
+        device.bus().stalled.store(true, Ordering::Relaxed);
+        class.kick();
+
+        reset_channel(&mut device, &mut class, reset);
+        device.bus().stalled.store(false, Ordering::Relaxed);
+        while !class.is_idle() {
+            UsbClass::<ScriptBus>::poll(&mut class);
+        }
+    }
+
+    // the frame for channel 1 is dropped, its transfer ending short.
+    let written = device.bus().written.lock().unwrap();
+    let lens: Vec<usize> = written.iter().map(Vec::len).collect();
+    assert_eq!(lens, [64, 12, 64, 0]);
+    assert_eq!(written[0][9], 0);
+    assert_eq!(written[2][9], 1);
+    assert_eq!(class.split_frames_dropped(), 1);
+}
+
+#[test]
+fn test_channel_count_change() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let mut class: Class = GsCan::new(&alloc, RecordingDevice::default());
+    let mut device = UsbDeviceBuilder::new(&alloc, identifier::CANDLELIGHT).build();
+    let software_version = class.device_config().software_version;
+
+    // a second board is fitted, the host enumerates the device again.
+    vendor_out(&mut device, &mut class, 1, 1, BIT_TIMING.as_bytes());
+    assert_eq!(
+        class.last_rejection().map(|rejection| rejection.reason),
+        Some(RejectReason::InvalidChannel),
+    );
+    class.device.two_channels = true;
+    UsbClass::<ScriptBus>::reset(&mut class);
+    assert_eq!(class.device_config().interface_count, 1);
+    assert_eq!(class.device_config().software_version, software_version);
+    start_fd_on(&mut device, &mut class, 1);
+
+    // and removed again.
+    class.device.two_channels = false;
+    UsbClass::<ScriptBus>::reset(&mut class);
+    assert_eq!(class.device_config().interface_count, 0);
+    vendor_out(&mut device, &mut class, 1, 1, BIT_TIMING.as_bytes());
+    let rejection = class.last_rejection().unwrap();
+    assert_eq!(rejection.reason, RejectReason::InvalidChannel);
+    assert_eq!(rejection.count, 2);
+    start_fd(&mut device, &mut class);
+}
+
+/// Another vendor class of a composite device, with requests by number.
+struct VendorClass {
+    interface: InterfaceNumber,
+    /// `(bRequest, wValue)` of the requests handled
+    requests: Vec<(u8, u16)>,
+}
+
+impl VendorClass {
+    fn new(alloc: &UsbBusAllocator<ScriptBus>) -> Self {
+        Self {
+            interface: alloc.interface(),
+            requests: Vec::new(),
+        }
+    }
+
+    /// Record a request if it is to the interface of the class.
+    fn handle(&mut self, req: &usb_device::control::Request) -> bool {
+        let addressed = req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Interface
+            && req.index as u8 == u8::from(self.interface);
+        if addressed {
+            self.requests.push((req.request, req.value));
+        }
+        addressed
+    }
+}
+
+impl UsbClass<ScriptBus> for VendorClass {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.interface, 0xFF, 0, 0)
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<ScriptBus>) {
+        if self.handle(xfer.request()) {
+            xfer.accept().unwrap();
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<ScriptBus>) {
+        if self.handle(xfer.request()) {
+            xfer.accept_with(&[0xAA; 4]).unwrap();
+        }
+    }
+}
+
+/// Send a control request, with the data stage of a write.
+fn control(
+    device: &mut UsbDevice<'_, ScriptBus>,
+    classes: &mut [&mut dyn UsbClass<ScriptBus>],
+    setup: [u8; 8],
+    data: &[u8],
+) {
+    device.bus().push(0, &[&setup], true);
+    if !data.is_empty() {
+        device.bus().push(0, &[data], false);
+    }
+    while !device.bus().polls.lock().unwrap().is_empty() {
+        device.poll(classes);
+    }
+}
+
+#[test]
+fn test_composite_vendor_requests() {
+    let alloc = UsbBusAllocator::new(ScriptBus::default());
+    let mut class: Class = GsCan::new(&alloc, RecordingDevice::default());
+    let mut other = VendorClass::new(&alloc);
+    let mut device = UsbDeviceBuilder::new(&alloc, identifier::CANDLELIGHT).build();
+    let channel = Channel::new(0).unwrap();
+    let mut start = 1_u32.to_le_bytes().to_vec();
+    start.extend_from_slice(&0_u32.to_le_bytes());
+
+    // to the device, as by tools with the channel in wValue.
+    control(
+        &mut device,
+        &mut [&mut class, &mut other],
+        [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00],
+        BIT_TIMING.as_bytes(),
+    );
+
+    // a start request to the other interface is left to its class.
+    control(
+        &mut device,
+        &mut [&mut class, &mut other],
+        [0x41, 0x02, 0x00, 0x00, 0x01, 0x00, 0x08, 0x00],
+        &start,
+    );
+    control(
+        &mut device,
+        &mut [&mut class, &mut other],
+        [0xc1, 0x0e, 0x00, 0x00, 0x01, 0x00, 0x0c, 0x00],
+        &[],
+    );
+    assert!(!class.is_started(channel));
+    assert_eq!(class.last_rejection(), None);
+    assert_eq!(other.requests, [(2, 0), (14, 0)]);
+
+    // and the other way around, with the other class polled first.
+    control(
+        &mut device,
+        &mut [&mut other, &mut class],
+        [0x41, 0x02, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00],
+        &start,
+    );
+    assert!(class.is_started(channel));
+    assert_eq!(other.requests.len(), 2);
+}
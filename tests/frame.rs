@@ -0,0 +1,134 @@
+use embedded_can::{ExtendedId, Frame as _, Id, StandardId};
+#[cfg(feature = "fd")]
+use usbd_gscan::host::FrameFlag;
+use usbd_gscan::host::{Frame, FrameError, IdFlag};
+use zerocopy::AsBytes;
+
+#[test]
+fn test_new_raw_standard() {
+    let frame = Frame::new_raw(0x7FF, &[1, 2, 3]).unwrap();
+    assert_eq!(frame.raw_id(), 0x7FF);
+    assert_eq!(frame.id(), Id::Standard(StandardId::MAX));
+    assert_eq!(frame.data(), [1, 2, 3]);
+}
+
+#[test]
+fn test_new_raw_extended() {
+    let raw = 0x1FFFFFFF | IdFlag::EXTENDED.bits();
+    let frame = Frame::new_raw(raw, &[]).unwrap();
+    assert_eq!(frame.raw_id(), raw);
+    assert_eq!(frame.id(), Id::Extended(ExtendedId::MAX));
+
+    // small identifiers may still be extended.
+    let frame = Frame::new_raw(0x10 | IdFlag::EXTENDED.bits(), &[]).unwrap();
+    assert!(frame.is_extended());
+}
+
+#[test]
+fn test_new_raw_invalid_id() {
+    // 29 bit identifier without the extended flag.
+    assert_eq!(
+        Frame::new_raw(0x1FFFFFFF, &[]).err(),
+        Some(FrameError::InvalidId)
+    );
+    assert_eq!(
+        Frame::new_raw(0x800, &[]).err(),
+        Some(FrameError::InvalidId)
+    );
+}
+
+#[test]
+fn test_new_raw_invalid_length() {
+    assert_eq!(
+        Frame::new_raw(0x100, &[0; 9]).err(),
+        Some(FrameError::InvalidLength)
+    );
+}
+
+#[test]
+fn test_raw_id_round_trip() {
+    for raw in [0, 0x123, 0x7FF, 0x1234567 | IdFlag::EXTENDED.bits()] {
+        let frame = Frame::new_raw(raw, &[0xAA; 8]).unwrap();
+        let copy = Frame::new_raw(frame.raw_id(), frame.data()).unwrap();
+        assert_eq!(copy.raw_id(), raw);
+        assert_eq!(copy.data(), [0xAA; 8]);
+    }
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_fd_flags() {
+    let mut frame = Frame::new_raw(0x100, &[0xAA; 8]).unwrap();
+    assert!(!frame.is_fd());
+
+    // only FD frames switch bit rate or indicate the error state.
+    assert_eq!(frame.set_brs(true), Err(FrameError::NotFd));
+    assert_eq!(frame.set_esi(true), Err(FrameError::NotFd));
+    assert!(frame.set_brs(false).is_ok());
+    assert!(frame.flags.is_empty());
+
+    frame.set_fd(true).unwrap();
+    frame.set_brs(true).unwrap();
+    frame.set_esi(true).unwrap();
+    assert!(frame.is_fd() && frame.brs() && frame.esi());
+
+    // classic frames drop the FD only flags.
+    frame.set_fd(false).unwrap();
+    assert!(!frame.is_fd() && !frame.brs() && !frame.esi());
+    assert_eq!(frame.data(), [0xAA; 8]);
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_set_fd_keeps_length() {
+    // 12 bytes would become 8 as a classic frame.
+    let mut frame = Frame::new_raw(0x100, &[0xAA; 12]).unwrap();
+    frame.flags = FrameFlag::FD;
+    assert_eq!(frame.set_fd(false), Err(FrameError::InvalidLength));
+    assert!(frame.is_fd());
+
+    // classic DLC 9 carries 8 bytes, not 12.
+    let mut frame = Frame::new_raw(0x100, &[0xAA; 8]).unwrap();
+    frame.can_dlc = 9;
+    assert_eq!(frame.set_fd(true), Err(FrameError::InvalidLength));
+    assert!(!frame.is_fd());
+}
+
+#[cfg(not(feature = "fd"))]
+#[test]
+fn test_set_fd_unsupported() {
+    let mut frame = Frame::new_raw(0x100, &[0xAA; 8]).unwrap();
+    assert_eq!(frame.set_fd(true), Err(FrameError::FdUnsupported));
+    assert!(frame.set_fd(false).is_ok());
+    assert_eq!(frame.set_brs(true), Err(FrameError::NotFd));
+    assert!(frame.flags.is_empty());
+}
+
+#[test]
+fn test_sanitize() {
+    let mut frame = Frame::new_raw(0x100, &[0xAA; 8]).unwrap();
+    frame.can_dlc = 2;
+    frame.as_bytes_mut()[11] = 0xFF; // reserved
+    frame.sanitize(false);
+
+    let bytes = frame.as_bytes();
+    assert_eq!(bytes[11], 0);
+    assert_eq!(bytes[12..14], [0xAA; 2]);
+    assert!(bytes[14..20].iter().all(|&b| b == 0));
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_sanitize_wire_fd() {
+    // a classic frame left over in FD sized storage.
+    let mut frame = Frame::new_raw(0x100, &[0xAA; 64]).unwrap();
+    frame.can_dlc = 1;
+
+    frame.sanitize(false);
+    assert!(frame.as_bytes()[13..20].iter().all(|&b| b == 0));
+    assert!(frame.as_bytes()[20..76].iter().all(|&b| b == 0xAA));
+
+    frame.sanitize(true);
+    assert_eq!(frame.as_bytes()[12], 0xAA);
+    assert!(frame.as_bytes()[13..76].iter().all(|&b| b == 0));
+}
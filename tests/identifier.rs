@@ -0,0 +1,60 @@
+use usb_device::device::UsbVidPid;
+use usbd_gscan::host::Feature;
+use usbd_gscan::identifier::{self, KnownDevice};
+
+fn lookup(id: UsbVidPid) -> &'static KnownDevice {
+    let (vid, pid) = (id.0, id.1);
+    let known = identifier::lookup(id).unwrap();
+    assert_eq!((known.vid, known.pid), (vid, pid));
+    known
+}
+
+#[test]
+fn test_gs_usb_1() {
+    let known = lookup(identifier::GS_USB_1);
+    assert_eq!(known.features.bits(), 0);
+    assert!(!known.pad_packets);
+    assert_eq!(known.max_channels, 3);
+}
+
+#[test]
+fn test_candlelight() {
+    let known = lookup(identifier::CANDLELIGHT);
+    assert_eq!(known.features.bits(), 0);
+    assert!(!known.pad_packets);
+    assert_eq!(known.max_channels, 3);
+}
+
+#[test]
+fn test_can_fd_devices() {
+    for id in [
+        identifier::CES_CANEXT_FD,
+        identifier::ABE_CANDEBUGGER_FD,
+        identifier::XYLANTA_SAINT3,
+    ] {
+        let known = lookup(id);
+        assert_eq!(
+            known.features.bits(),
+            (Feature::FD | Feature::BT_CONST_EXT).bits()
+        );
+        assert!(!known.pad_packets);
+        assert_eq!(known.max_channels, 1);
+    }
+}
+
+#[test]
+fn test_lookup_unknown() {
+    assert!(identifier::lookup(UsbVidPid(0x1234, 0x5678)).is_none());
+}
+
+#[test]
+fn test_recommended_features() {
+    let mut known = *lookup(identifier::CANDLELIGHT);
+    assert_eq!(known.recommended_features().bits(), 0);
+
+    known.pad_packets = true;
+    assert_eq!(
+        known.recommended_features().bits(),
+        Feature::PAD_PKTS_TO_MAX_PKT_SIZE.bits()
+    );
+}
@@ -0,0 +1,81 @@
+use usbd_gscan::identify::{IdentifyController, LedState, BLINK_PERIOD_MS, DEFAULT_TIMEOUT_MS};
+
+#[test]
+fn test_idle() {
+    let mut identify = IdentifyController::new();
+    assert!(!identify.is_active());
+    assert_eq!(identify.poll(0), LedState::Idle);
+    assert_eq!(identify.poll(250), LedState::Idle);
+}
+
+#[test]
+fn test_pattern() {
+    let mut identify = IdentifyController::new();
+    identify.set_active(true);
+
+    // the pattern starts at the first poll, on for half of each period.
+    let start = 1000;
+    for elapsed in (0..2000).step_by(10) {
+        let expected = if elapsed % BLINK_PERIOD_MS < 250 {
+            LedState::On
+        } else {
+            LedState::Off
+        };
+        assert_eq!(identify.poll(start + elapsed), expected, "{elapsed} ms");
+    }
+
+    identify.set_active(false);
+    assert_eq!(identify.poll(start + 2000), LedState::Idle);
+}
+
+#[test]
+fn test_pattern_wrapping() {
+    let mut identify = IdentifyController::new();
+    identify.set_active(true);
+
+    let start = u32::MAX - 100;
+    assert_eq!(identify.poll(start), LedState::On);
+    assert_eq!(identify.poll(start.wrapping_add(300)), LedState::Off);
+    assert_eq!(identify.poll(start.wrapping_add(500)), LedState::On);
+}
+
+#[test]
+fn test_restart() {
+    let mut identify = IdentifyController::new();
+    identify.set_active(true);
+    assert_eq!(identify.poll(0), LedState::On);
+    assert_eq!(identify.poll(300), LedState::Off);
+
+    // turning it on again restarts the pattern from the next poll.
+    identify.set_active(true);
+    assert_eq!(identify.poll(300), LedState::On);
+    assert_eq!(identify.poll(600), LedState::Off);
+}
+
+#[test]
+fn test_timeout() {
+    let mut identify = IdentifyController::new();
+    identify.set_active(true);
+    assert_eq!(identify.poll(0), LedState::On);
+    assert_eq!(identify.poll(DEFAULT_TIMEOUT_MS - 1), LedState::Off);
+    assert!(identify.is_active());
+
+    assert_eq!(identify.poll(DEFAULT_TIMEOUT_MS), LedState::Idle);
+    assert!(!identify.is_active());
+    assert_eq!(identify.poll(DEFAULT_TIMEOUT_MS + 250), LedState::Idle);
+}
+
+#[test]
+fn test_custom_timeout() {
+    let mut identify = IdentifyController::new().with_timeout(Some(1000));
+    identify.set_active(true);
+    assert_eq!(identify.poll(0), LedState::On);
+    assert_eq!(identify.poll(999), LedState::Off);
+    assert_eq!(identify.poll(1000), LedState::Idle);
+
+    let mut identify = IdentifyController::new().with_timeout(None);
+    identify.set_active(true);
+    assert_eq!(identify.poll(0), LedState::On);
+    assert_eq!(identify.poll(10 * DEFAULT_TIMEOUT_MS), LedState::On);
+    assert!(identify.is_active());
+}
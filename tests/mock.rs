@@ -1,78 +1,281 @@
+use core::convert::Infallible;
+use embedded_can::{ExtendedId, Frame as _, Id, StandardId};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::task::{Wake, Waker};
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usb_device::device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbVidPid};
+use usb_device::endpoint::{EndpointAddress, EndpointType, In};
+use usb_device::UsbDirection;
+#[cfg(feature = "fd")]
+use usbd_gscan::host::DeviceBitTimingConstExtended;
 use usbd_gscan::{
     host::{
-        CanBitTimingConst, CanState, DeviceBitTiming, DeviceBitTimingConst,
-        DeviceBitTimingConstExtended, DeviceConfig, DeviceState, Feature,
+        CanBitTimingConst, CanState, Capability, ControllerError, DeviceBitTiming,
+        DeviceBitTimingConst, DeviceConfig, DeviceState, ErrorClass, Feature, Frame, FrameError,
+        FrameFlag, FrameKind, IdFlag, RawDeviceState, REQ_CAPABILITIES,
     },
-    Device, GsCan,
+    identifier::{self, KnownDevice},
+    identify::{IdentifyController, LedState},
+    Channel, ChannelMode, DedupConfig, Device, EchoMode, FaultReason, GsCan, HostCapabilities,
+    HostTxPolicy, IdRemap, InvalidChannel, LoadStats, RejectReason, Rejection, RxDelivery,
+    StateSource, Transform, TransmitError, UnconfiguredPolicy, CAPABILITIES, SOFTWARE_VERSION,
 };
 
 const TIMING_NOMINAL: CanBitTimingConst = CanBitTimingConst {
     tseg1_min: 1,
     tseg1_max: 255,
     tseg2_min: 1,
-    tset2_max: 127,
+    tseg2_max: 127,
     sjw_max: 127,
     brp_min: 1,
     brp_max: 511,
     brp_inc: 1,
 };
+#[cfg(feature = "fd")]
 const TIMING_DATA: CanBitTimingConst = CanBitTimingConst {
     tseg1_min: 1,
     tseg1_max: 31,
     tseg2_min: 1,
-    tset2_max: 15,
+    tseg2_max: 15,
     sjw_max: 15,
     brp_min: 1,
     brp_max: 31,
     brp_inc: 1,
 };
 
-pub struct MockCanDevice {}
+const CHANNEL0: Channel = Channel::new(0).unwrap();
+
+/// Bytes of a frame written by the device on a classic channel.
+const FRAME_LEN: usize = 20;
+
+/// Bytes of a frame written by the device on a CAN FD channel.
+#[cfg(feature = "fd")]
+const FD_FRAME_LEN: usize = 76;
+
+/// Features advertised when a test doesn't choose.
+#[cfg(feature = "fd")]
+const ALL_FEATURES: Feature = Feature::all().difference(Feature::REQ_USB_QUIRK_LPC546XX);
+#[cfg(not(feature = "fd"))]
+const ALL_FEATURES: Feature = Feature::all()
+    .difference(Feature::REQ_USB_QUIRK_LPC546XX)
+    .difference(Feature::FD)
+    .difference(Feature::BT_CONST_EXT);
+
+/// 500 kbit/s at 80 MHz.
+const NOMINAL_TIMING: DeviceBitTiming = DeviceBitTiming {
+    prop_seg: 69,
+    phase_seg1: 70,
+    phase_seg2: 20,
+    sjw: 1,
+    brp: 1,
+};
+/// 2 Mbit/s at 80 MHz.
+#[cfg(feature = "fd")]
+const DATA_TIMING: DeviceBitTiming = DeviceBitTiming {
+    prop_seg: 15,
+    phase_seg1: 16,
+    phase_seg2: 8,
+    sjw: 1,
+    brp: 1,
+};
+#[cfg(feature = "fd")]
+const DEFAULT_DATA_TIMING: DeviceBitTiming = DeviceBitTiming {
+    prop_seg: 7,
+    phase_seg1: 8,
+    phase_seg2: 4,
+    sjw: 1,
+    brp: 2,
+};
+
+#[derive(Default)]
+pub struct MockCanDevice {
+    received: Vec<Frame>,
+    /// Refuse frames from the host.
+    busy: bool,
+    /// Mode changes requested of the device.
+    modes: Vec<(&'static str, Channel)>,
+    /// Advertised features, all features if `None`.
+    features: Option<Feature>,
+    /// Number of channels, 2 if `None`.
+    channels: Option<u8>,
+    /// Timing each start was given.
+    start_timing: Vec<(DeviceBitTiming, Option<DeviceBitTiming>)>,
+    /// Features each start was given.
+    start_features: Vec<Feature>,
+    /// Provide default data phase timing.
+    #[cfg(feature = "fd")]
+    default_data_timing: bool,
+    /// Current time, no timer if `None`.
+    now_us: Option<u32>,
+    /// Filter for frames to the host, passing all if `None`.
+    filter: Option<fn(Channel, &mut Frame) -> bool>,
+    /// Error frames the class queued itself.
+    notified: Vec<(Channel, Frame)>,
+    /// Termination switched by the host.
+    terminations: Vec<(Channel, bool)>,
+}
 
 impl Device for MockCanDevice {
     fn config(&self) -> DeviceConfig {
-        DeviceConfig::new(2)
+        // more channels than supported can only be set directly.
+        let mut config = DeviceConfig::new(1);
+        config.interface_count = self.channels.unwrap_or(2) - 1;
+        config
     }
 
     fn bit_timing(&self) -> DeviceBitTimingConst {
         DeviceBitTimingConst {
-            features: Feature::all(),
+            features: self.features.unwrap_or(ALL_FEATURES),
             fclk_can: 80_000_000,
             timing: TIMING_NOMINAL,
         }
     }
 
+    #[cfg(feature = "fd")]
     fn bit_timing_ext(&self) -> DeviceBitTimingConstExtended {
+        // as a classic device leaving it unimplemented would.
+        assert!(
+            self.bit_timing().features.contains(Feature::BT_CONST_EXT),
+            "extended bit timing read without BT_CONST_EXT",
+        );
         DeviceBitTimingConstExtended {
-            features: Feature::all(),
+            features: self.features.unwrap_or(ALL_FEATURES),
             fclk_can: 80_000_000,
             timing_nominal: TIMING_NOMINAL,
             timing_data: TIMING_DATA,
         }
     }
 
-    fn configure_bit_timing(&mut self, _interface: u8, _timing: DeviceBitTiming) {}
+    #[cfg(feature = "fd")]
+    fn default_data_timing(&mut self, _channel: Channel) -> Option<DeviceBitTiming> {
+        self.default_data_timing.then_some(DEFAULT_DATA_TIMING)
+    }
+
+    fn reset(&mut self, channel: Channel) {
+        self.modes.push(("reset", channel));
+    }
+
+    fn validate_start(&mut self, _channel: Channel, features: Feature) -> Result<(), ()> {
+        if features.contains(Feature::LISTEN_ONLY | Feature::LOOP_BACK) {
+            return Err(());
+        }
+
+        Ok(())
+    }
+
+    fn start(
+        &mut self,
+        channel: Channel,
+        features: Feature,
+        nominal: &DeviceBitTiming,
+        data: Option<&DeviceBitTiming>,
+    ) {
+        self.modes.push(("start", channel));
+        self.start_timing.push((*nominal, data.copied()));
+        self.start_features.push(features);
+    }
+
+    fn timestamp_us(&self) -> Option<u32> {
+        self.now_us
+    }
+
+    fn filter_to_host(&mut self, channel: Channel, frame: &mut Frame) -> bool {
+        self.filter.is_none_or(|filter| filter(channel, frame))
+    }
 
-    fn configure_bit_timing_data(&mut self, _interface: u8, _timing: DeviceBitTiming) {}
+    fn notify(&mut self, channel: Channel, frame: &Frame) {
+        self.notified.push((channel, *frame));
+    }
 
-    fn reset(&mut self, _interface: u8) {}
+    fn set_termination(&mut self, channel: Channel, enabled: bool) {
+        self.terminations.push((channel, enabled));
+    }
 
-    fn start(&mut self, _interface: u8, _features: Feature) {}
+    fn termination(&self, channel: Channel) -> bool {
+        self.terminations
+            .iter()
+            .rev()
+            .find(|(c, _)| *c == channel)
+            .is_some_and(|&(_, enabled)| enabled)
+    }
 
-    fn state(&self, _interface: u8) -> DeviceState {
+    fn state(&self, channel: Channel) -> DeviceState {
         DeviceState {
             state: CanState::Active,
-            rx_errors: 0,
+            // tells the channels apart.
+            rx_errors: channel.index().into(),
             tx_errors: 0,
         }
     }
 
-    fn receive(&mut self, _interface: u8, _frame: &usbd_gscan::host::Frame) {}
+    fn receive(&mut self, _channel: Channel, frame: &Frame) -> nb::Result<(), Infallible> {
+        if self.busy {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.received.push(*frame);
+        Ok(())
+    }
 }
 
 use usbd_class_tester::prelude::*;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+#[derive(Default)]
+struct TestCtx {
+    host_tx_policy: HostTxPolicy,
+    /// Build as a composite device with interface association descriptors.
+    composite: bool,
+    /// Features advertised by the device, all features if `None`.
+    features: Option<Feature>,
+    /// Channels of the device, 2 if `None`.
+    channels: Option<u8>,
+    echo_mode: EchoMode,
+    ordered_echoes: bool,
+    drop_errors: bool,
+    known_device: Option<KnownDevice>,
+    unconfigured_policy: UnconfiguredPolicy,
+    /// Microseconds between drop summaries, disabled if `None`.
+    drop_summary: Option<u32>,
+    sequence_numbers: bool,
+    #[cfg(feature = "fd")]
+    fd_sized_classic: bool,
+    /// Packet size of the control endpoint, 8 bytes if `None`.
+    ep0_size: Option<u8>,
+    load_monitor: bool,
+    channel_labels: &'static [&'static str],
+    /// Leave the device unconfigured until the test enumerates it.
+    skip_setup: bool,
+    state_source: StateSource,
+    capabilities_request: bool,
+    deferred_start: bool,
+    deferred_stop: bool,
+}
 
-struct TestCtx {}
+/// Create the class on the emulated bus.
+///
+/// The emulated bus reads back from the IN endpoint with the index of the OUT
+/// endpoint written to, so an unused IN endpoint is allocated alongside
+/// [`READ_EP`].
+fn new_class<const RX: usize>(
+    alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    device: MockCanDevice,
+) -> GsCan<'_, EmulatedUsbBus, MockCanDevice, RX> {
+    let class = GsCan::new(alloc, device);
+    alloc
+        .alloc::<In>(
+            Some(EndpointAddress::from_parts(READ_EP, UsbDirection::In)),
+            EndpointType::Bulk,
+            64,
+            0,
+        )
+        .unwrap();
+    class
+}
 
 impl UsbDeviceCtx for TestCtx {
     type C<'c> = GsCan<'c, EmulatedUsbBus, MockCanDevice>;
@@ -81,27 +284,4568 @@ impl UsbDeviceCtx for TestCtx {
         &mut self,
         alloc: &'a usb_device::bus::UsbBusAllocator<EmulatedUsbBus>,
     ) -> AnyResult<Self::C<'a>> {
-        Ok(GsCan::new(&alloc, MockCanDevice {}))
+        let device = MockCanDevice {
+            features: self.features,
+            channels: self.channels,
+            ..Default::default()
+        };
+
+        let mut class = new_class(alloc, device)
+            .with_host_tx_policy(self.host_tx_policy)
+            .with_echo_mode(self.echo_mode)
+            .with_ordered_echoes(self.ordered_echoes)
+            .with_drop_errors(self.drop_errors)
+            .with_unconfigured_policy(self.unconfigured_policy)
+            .with_sequence_numbers(self.sequence_numbers)
+            .with_load_monitor(self.load_monitor)
+            .with_state_source(self.state_source)
+            .with_capabilities_request(self.capabilities_request)
+            .with_deferred_start(self.deferred_start)
+            .with_deferred_stop(self.deferred_stop);
+        if !self.channel_labels.is_empty() {
+            class = class.with_channel_labels(alloc, self.channel_labels);
+        }
+        if let Some(known) = &self.known_device {
+            class = class.with_known_device(known);
+        }
+        if let Some(interval) = self.drop_summary {
+            class = class.with_drop_summary(interval);
+        }
+        #[cfg(feature = "fd")]
+        {
+            class = class.with_fd_sized_classic(self.fd_sized_classic);
+        }
+
+        Ok(class)
+    }
+
+    fn skip_setup(&mut self) -> bool {
+        self.skip_setup
+    }
+
+    fn build_usb_device<'a>(
+        &mut self,
+        alloc: &'a usb_device::bus::UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<UsbDevice<'a, EmulatedUsbBus>> {
+        let mut builder = UsbDeviceBuilder::new(alloc, UsbVidPid(0x1234, 0x5678))
+            .strings(&[StringDescriptors::default().product("TestProduct")])
+            .map_err(AnyUsbError::UsbDeviceBuilder)?;
+        if self.composite {
+            builder = builder.composite_with_iads();
+        }
+        if let Some(size) = self.ep0_size {
+            builder = builder
+                .max_packet_size_0(size)
+                .map_err(AnyUsbError::UsbDeviceBuilder)?;
+        }
+
+        Ok(builder.build())
+    }
+}
+
+struct QueuedTestCtx {}
+
+impl UsbDeviceCtx for QueuedTestCtx {
+    type C<'c> = GsCan<'c, EmulatedUsbBus, MockCanDevice, 4>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a usb_device::bus::UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<Self::C<'a>> {
+        Ok(new_class(alloc, MockCanDevice::default()).with_rx_delivery(RxDelivery::Queued))
+    }
+}
+
+struct StagedTestCtx {}
+
+impl UsbDeviceCtx for StagedTestCtx {
+    type C<'c> = GsCan<'c, EmulatedUsbBus, MockCanDevice, 4>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a usb_device::bus::UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<Self::C<'a>> {
+        Ok(new_class(alloc, MockCanDevice::default()).with_rx_delivery(RxDelivery::Staged))
     }
 }
 
+/// Send a host format request with `byte_order` as the host would write it.
+fn set_host_format<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    byte_order: u32,
+) -> AnyResult<Vec<u8>>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    dev.control_write(
+        cls,
+        CtrRequestType::to_device().class().vendor(),
+        0,
+        0,
+        0,
+        4,
+        &byte_order.to_le_bytes(),
+    )
+}
+
 #[test]
 fn test_host_format() {
-    TestCtx {}
+    TestCtx::default()
         .with_usb(|mut cls, mut dev| {
-            dev.control_write(
-                &mut cls,
-                CtrRequestType::to_device().class().vendor(),
-                0,
-                0,
-                0,
-                4,
-                &0x0000beef_u32.to_le_bytes(),
-            )
-            .unwrap();
+            set_host_format(&mut dev, &mut cls, 0x0000beef).unwrap();
 
             let st = dev.interface_get_status(&mut cls, 0).expect("Status");
             assert_eq!(st, 0);
+
+            // repeated whilst running.
+            set_mode(&mut dev, &mut cls, 0, 1);
+            set_host_format(&mut dev, &mut cls, 0x0000beef).unwrap();
+            assert!(cls.last_rejection().is_none());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_host_format_change_started() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_host_format(&mut dev, &mut cls, 0x0000beef).unwrap();
+            set_mode(&mut dev, &mut cls, 0, 1);
+
+            // as sent by a big endian host.
+            assert!(set_host_format(&mut dev, &mut cls, 0xefbe0000).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason)),
+                Some((0, RejectReason::ChannelStarted))
+            );
+            assert!(cls.is_started(CHANNEL0));
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_host_format_big_endian() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            assert!(set_host_format(&mut dev, &mut cls, 0xefbe0000).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason)),
+                Some((0, RejectReason::InvalidValue))
+            );
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_mode_unknown() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_timing(&mut dev, &mut cls, 1, 0, &NOMINAL_TIMING).unwrap();
+            assert!(try_set_mode(&mut dev, &mut cls, 0, 7, Feature::empty()).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason)),
+                Some((2, RejectReason::InvalidValue))
+            );
+            assert!(!cls.is_started(CHANNEL0));
+        })
+        .expect("with_usb")
+}
+
+struct FlagWaker(AtomicBool);
+
+impl Wake for FlagWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+fn classic_frame(id: u16) -> Frame {
+    Frame::new(StandardId::new(id).unwrap(), &[0x01, 0x02, 0x03, 0x04]).unwrap()
+}
+
+#[test]
+fn test_kick() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+            assert!(cls.needs_poll());
+
+            // written straight away, the host reads it before polling.
+            cls.kick();
+            assert!(!cls.needs_poll());
+            let written = dev.ep_read(&mut cls, 1, 256).unwrap();
+            assert_eq!(parse_frame(&written).can_id, 1);
+            assert!(cls.is_idle());
+
+            // nothing to do.
+            cls.kick();
+            assert!(dev.ep_read(&mut cls, 1, 256).unwrap().is_empty());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_frame_rate_limit() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.set_frame_rate_limit(CHANNEL0, Some(2));
+            assert_eq!(cls.frame_rate_limit(CHANNEL0), Some(2));
+            for id in 0..5 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            cls.transmit(CHANNEL1, &classic_frame(5), FrameFlag::empty());
+
+            // two a millisecond, the other channel waiting its turn.
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [0, 1]);
+            assert!(!cls.needs_poll());
+            cls.tick_1ms();
+            assert!(cls.needs_poll());
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [2, 3]);
+            cls.tick_1ms();
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [4, 5]);
+            assert!(cls.is_idle());
+
+            // removed, frames flow again.
+            cls.set_frame_rate_limit(CHANNEL0, None);
+            for id in 0..5 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [0, 1, 2, 3, 4]);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_frame_rate_limit_queue_full() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.set_frame_rate_limit(CHANNEL0, Some(0));
+            assert_eq!(cls.frame_rate_limit(CHANNEL0), Some(1));
+            for id in 0..70 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            assert_eq!(cls.queue_full_dropped(), 6);
+
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [0]);
+            cls.tick_1ms();
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [1]);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_unconfigured_drop() {
+    TestCtx {
+        skip_setup: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert!(!cls.is_configured());
+        cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+        cls.transmit(CHANNEL0, &classic_frame(2), FrameFlag::empty());
+        assert_eq!(cls.unconfigured_dropped(), 2);
+        assert!(cls.is_idle());
+
+        dev.setup(&mut cls).unwrap();
+        assert!(cls.is_configured());
+        cls.transmit(CHANNEL0, &classic_frame(3), FrameFlag::empty());
+        cls.kick();
+        assert_eq!(read_frame_ids(&mut dev, &mut cls), [3]);
+
+        // deconfigured by the host.
+        cls.transmit(CHANNEL0, &classic_frame(4), FrameFlag::empty());
+        dev.device_set_configuration(&mut cls, 0).unwrap();
+        assert!(!cls.is_configured());
+        cls.transmit(CHANNEL0, &classic_frame(5), FrameFlag::empty());
+        assert_eq!(cls.unconfigured_dropped(), 4);
+        assert!(cls.is_idle());
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_unconfigured_keep_newest() {
+    TestCtx {
+        unconfigured_policy: UnconfiguredPolicy::KeepNewest(2),
+        skip_setup: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        for id in 1..=4 {
+            cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+        }
+        assert_eq!(cls.unconfigured_dropped(), 2);
+
+        // held until configured.
+        assert!(!cls.needs_poll());
+        cls.kick();
+        assert!(dev.ep_read(&mut cls, 1, 256).unwrap().is_empty());
+
+        dev.setup(&mut cls).unwrap();
+        assert_eq!(read_frame_ids(&mut dev, &mut cls), [3, 4]);
+
+        // a bus reset starts enumeration over.
+        for id in 5..=7 {
+            cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+        }
+        UsbClass::<EmulatedUsbBus>::reset(&mut cls);
+        assert_eq!(cls.unconfigured_dropped(), 3);
+
+        dev.setup(&mut cls).unwrap();
+        assert_eq!(read_frame_ids(&mut dev, &mut cls), [6, 7]);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_tx_waker() {
+    TestCtx::default()
+        .with_usb(|mut cls, _dev| {
+            // frames are only queued until polled.
+            for id in 0..64 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            assert_eq!(cls.tx_free(), 0);
+
+            let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+            cls.register_tx_waker(Waker::from(flag.clone()));
+            assert!(!flag.0.load(Ordering::SeqCst));
+
+            // first frame leaves the queue once written.
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            assert!(flag.0.load(Ordering::SeqCst));
+            assert_eq!(cls.tx_free(), 1);
+        })
+        .expect("with_usb")
+}
+
+/// Every classic frame written to the host, polling until done.
+fn read_frames<'a, C, X>(dev: &mut usbd_class_tester::Device<'a, C, X>, cls: &mut C) -> Vec<Frame>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    let mut written = Vec::new();
+    loop {
+        let read = dev.ep_read(cls, 1, u16::MAX).unwrap();
+        if read.is_empty() {
+            break;
+        }
+        written.extend(read);
+    }
+
+    written.chunks(FRAME_LEN).map(parse_frame).collect()
+}
+
+/// Identifiers of every frame written to the host, polling until done.
+fn read_frame_ids<'a, C, X>(dev: &mut usbd_class_tester::Device<'a, C, X>, cls: &mut C) -> Vec<u32>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    read_frames(dev, cls)
+        .iter()
+        .map(|frame| frame.can_id)
+        .collect()
+}
+
+const CHANNEL1: Channel = Channel::new(1).unwrap();
+
+/// Forwards frames from channel 0 to channel 1 and back.
+fn swap_channels(channel: Channel, _frame: &Frame) -> Option<Channel> {
+    Some(if channel == CHANNEL0 {
+        CHANNEL1
+    } else {
+        CHANNEL0
+    })
+}
+
+#[test]
+fn test_bridge() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.set_bridge(Some(|channel, _frame| {
+                (channel == CHANNEL0).then_some(CHANNEL1)
+            }));
+            cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+            cls.transmit(CHANNEL1, &classic_frame(2), FrameFlag::empty());
+            cls.kick();
+
+            // both reach the host, only the first is forwarded.
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [1, 2]);
+            assert_eq!(cls.device.received.len(), 1);
+            let bridged = cls.device.received[0];
+            assert_eq!(bridged.can_id, 1);
+            assert_eq!(bridged.interface, 1);
+            assert_eq!(bridged.echo_id, u32::MAX);
+            assert_eq!(bridged.data(), [0x01, 0x02, 0x03, 0x04]);
+            assert_eq!(cls.bridged(CHANNEL0, CHANNEL1), 1);
+            assert_eq!(cls.bridged(CHANNEL1, CHANNEL0), 0);
+
+            cls.set_bridge(None);
+            cls.transmit(CHANNEL0, &classic_frame(3), FrameFlag::empty());
+            assert_eq!(cls.device.received.len(), 1);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_bridge_loop() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.set_bridge(Some(swap_channels));
+            cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+            // the forwarded frame received back.
+            cls.transmit(CHANNEL1, &classic_frame(1), FrameFlag::empty());
+            assert_eq!(cls.device.received.len(), 1);
+            assert_eq!(cls.bridged(CHANNEL1, CHANNEL0), 0);
+
+            // later frames are forwarded again.
+            cls.transmit(CHANNEL1, &classic_frame(1), FrameFlag::empty());
+            assert_eq!(cls.device.received.len(), 2);
+            assert_eq!(cls.device.received[1].interface, 0);
+            assert_eq!(cls.bridged(CHANNEL0, CHANNEL1), 1);
+            assert_eq!(cls.bridged(CHANNEL1, CHANNEL0), 1);
+
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [1, 1, 1]);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_bridge_dropped() {
+    TestCtx::default()
+        .with_usb(|mut cls, _dev| {
+            cls.set_bridge(Some(swap_channels));
+            cls.device.busy = true;
+            cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+            assert_eq!(cls.bridged(CHANNEL0, CHANNEL1), 0);
+            assert_eq!(cls.bridge_dropped(CHANNEL0, CHANNEL1), 1);
+
+            // not forwarded, so forwarded the other way.
+            cls.device.busy = false;
+            cls.transmit(CHANNEL1, &classic_frame(1), FrameFlag::empty());
+            assert_eq!(cls.bridged(CHANNEL1, CHANNEL0), 1);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_transmit_queue_wraps() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            for id in 0..40 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            cls.kick();
+            assert_eq!(
+                read_frame_ids(&mut dev, &mut cls),
+                (0..40).collect::<Vec<_>>()
+            );
+
+            // a full queue wraps around the end of the storage.
+            for id in 40..104 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            assert_eq!(cls.tx_free(), 0);
+            cls.kick();
+            assert_eq!(
+                read_frame_ids(&mut dev, &mut cls),
+                (40..104).collect::<Vec<_>>()
+            );
+            assert!(cls.is_idle());
+        })
+        .expect("with_usb")
+}
+
+/// Returns the number of frames dropped if `frame` is a drop summary.
+fn drop_summary(frame: &Frame) -> Option<u32> {
+    let summary = frame.is_error_frame()
+        && frame.raw_id() == IdFlag::ERROR.bits() | ErrorClass::CONTROLLER.bits()
+        && frame.data()[..4] == [0, ControllerError::RX_OVERFLOW.bits(), 0, 0];
+    summary.then(|| u32::from_le_bytes(frame.data()[4..].try_into().unwrap()))
+}
+
+#[test]
+fn test_drop_summary() {
+    TestCtx {
+        drop_summary: Some(1000),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        cls.device.now_us = Some(0);
+        for id in 0..67 {
+            cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+        }
+
+        // queued once the first frame is written.
+        cls.kick();
+        let frames = read_frames(&mut dev, &mut cls);
+        assert_eq!(frames.len(), 65);
+        assert!(frames[..64].iter().all(|frame| !frame.is_error_frame()));
+        assert_eq!(drop_summary(&frames[64]), Some(3));
+        assert_eq!(frames[64].interface, 0);
+        assert_eq!(frames[64].echo_id, u32::MAX);
+
+        // drops continue within the interval.
+        cls.device.now_us = Some(500);
+        for id in 0..66 {
+            cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+        }
+        cls.kick();
+        assert_eq!(read_frames(&mut dev, &mut cls).len(), 64);
+
+        // ahead of the next frame once due.
+        cls.device.now_us = Some(1000);
+        cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+        cls.kick();
+        let frames = read_frames(&mut dev, &mut cls);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(drop_summary(&frames[0]), Some(2));
+        assert_eq!(frames[1].can_id, 1);
+
+        // nothing more to report.
+        cls.device.now_us = Some(5000);
+        cls.transmit(CHANNEL0, &classic_frame(2), FrameFlag::empty());
+        cls.kick();
+        assert_eq!(read_frame_ids(&mut dev, &mut cls), [2]);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_transmit_raw() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let mut frame = classic_frame(1);
+            frame.echo_id = FrameKind::RECEIVE_ID;
+            frame.interface = 1;
+            assert_eq!(cls.transmit_raw(&frame, false), Ok(()));
+
+            // echoes only when allowed.
+            frame.set_kind(FrameKind::Echo(5));
+            assert_eq!(cls.transmit_raw(&frame, false), Err(TransmitError::Echo));
+            cls.kick();
+            let frames = read_frames(&mut dev, &mut cls);
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].kind(), FrameKind::Receive);
+            assert_eq!(frames[0].interface, 1);
+
+            assert_eq!(cls.transmit_raw(&frame, true), Ok(()));
+            cls.kick();
+            let frames = read_frames(&mut dev, &mut cls);
+            assert_eq!(frames[0].kind(), FrameKind::Echo(5));
+
+            frame.interface = 7;
+            assert_eq!(
+                cls.transmit_raw(&frame, true),
+                Err(TransmitError::InvalidChannel)
+            );
+
+            // `transmit` always sends receive frames.
+            cls.transmit(CHANNEL0, &frame, FrameFlag::empty());
+            cls.kick();
+            let frames = read_frames(&mut dev, &mut cls);
+            assert_eq!(frames[0].kind(), FrameKind::Receive);
+            assert_eq!(frames[0].interface, 0);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_transmit_parts() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let extended = Id::Extended(ExtendedId::new(0x1234567).unwrap());
+            let standard = Id::Standard(StandardId::new(0x123).unwrap());
+
+            // the same bytes as from a frame.
+            #[cfg_attr(not(feature = "fd"), allow(unused_mut))]
+            let mut cases = vec![
+                (standard, vec![1, 2, 3], false, FrameFlag::empty()),
+                (extended, vec![], false, FrameFlag::empty()),
+                (extended, vec![0; 4], true, FrameFlag::empty()),
+            ];
+            #[cfg(feature = "fd")]
+            {
+                start_fd(&mut dev, &mut cls);
+                cases.push((
+                    standard,
+                    (0..48).collect(),
+                    false,
+                    FrameFlag::FD | FrameFlag::BIT_RATE_SWITCH,
+                ));
+            }
+            for (id, data, remote, flags) in cases {
+                let mut frame = if remote {
+                    Frame::new_remote(id, data.len()).unwrap()
+                } else {
+                    Frame::new(id, &data).unwrap()
+                };
+                frame.flags = flags;
+                cls.transmit(CHANNEL0, &frame, flags);
+                assert_eq!(
+                    cls.transmit_parts(CHANNEL0, id, &data, remote, flags),
+                    Ok(())
+                );
+                cls.kick();
+                let mut written = Vec::new();
+                loop {
+                    let read = dev.ep_read(&mut cls, 1, u16::MAX).unwrap();
+                    if read.is_empty() {
+                        break;
+                    }
+                    written.extend(read);
+                }
+                let (from_frame, from_parts) = written.split_at(written.len() / 2);
+                assert_eq!(from_frame, from_parts);
+                let frame = parse_frame(from_parts);
+                assert_eq!(frame.is_remote_frame(), remote);
+                assert_eq!(frame.data_len(), Some(data.len()));
+            }
+
+            assert_eq!(
+                cls.transmit_parts(
+                    Channel::new(2).unwrap(),
+                    standard,
+                    &[],
+                    false,
+                    FrameFlag::empty()
+                ),
+                Err(TransmitError::InvalidChannel)
+            );
+            assert_eq!(
+                cls.transmit_parts(CHANNEL0, standard, &[0; 9], false, FrameFlag::empty()),
+                Err(TransmitError::InvalidFrame(FrameError::InvalidLength))
+            );
+            // FD lengths and flags need an FD frame.
+            assert_eq!(
+                cls.transmit_parts(CHANNEL0, standard, &[0; 12], false, FrameFlag::empty()),
+                Err(TransmitError::InvalidFrame(FrameError::InvalidLength))
+            );
+            assert_eq!(
+                cls.transmit_parts(CHANNEL0, standard, &[], false, FrameFlag::BIT_RATE_SWITCH),
+                Err(TransmitError::InvalidFrame(FrameError::NotFd))
+            );
+            #[cfg(not(feature = "fd"))]
+            assert_eq!(
+                cls.transmit_parts(CHANNEL0, standard, &[], false, FrameFlag::FD),
+                Err(TransmitError::InvalidFrame(FrameError::FdUnsupported))
+            );
+
+            // refused frames are neither sent nor counted as dropped.
+            cls.kick();
+            assert!(read_frames(&mut dev, &mut cls).is_empty());
+            #[cfg(feature = "panic-free")]
+            assert_eq!(cls.invalid_frames(), 0);
+        })
+        .expect("with_usb")
+}
+
+/// Hides 0x100 and tags 0x200 on channel 0.
+fn redact(channel: Channel, frame: &mut Frame) -> bool {
+    if channel != CHANNEL0 {
+        return true;
+    }
+    match frame.can_id {
+        0x100 => false,
+        0x200 => {
+            // the first data byte.
+            frame.as_bytes_mut()[12] = 0xEE;
+            true
+        }
+        _ => true,
+    }
+}
+
+#[test]
+fn test_identify() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(cls.identify(CHANNEL0).poll(0), LedState::Idle);
+
+            identify(&mut dev, &mut cls, 0, 1).unwrap();
+            assert_eq!(cls.identify(CHANNEL0).poll(0), LedState::On);
+            assert_eq!(cls.identify(CHANNEL0).poll(300), LedState::Off);
+
+            identify(&mut dev, &mut cls, 0, 0).unwrap();
+            assert_eq!(cls.identify(CHANNEL0).poll(500), LedState::Idle);
+
+            // unknown channels and short data are refused.
+            assert!(identify(&mut dev, &mut cls, 5, 1).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason)),
+                Some((7, RejectReason::InvalidChannel))
+            );
+            assert!(dev
+                .control_write(
+                    &mut cls,
+                    CtrRequestType::to_device().vendor(),
+                    7,
+                    0,
+                    0,
+                    2,
+                    &[1, 0],
+                )
+                .is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason)),
+                Some((7, RejectReason::InvalidLength))
+            );
+            assert!(!cls.identify(CHANNEL0).is_active());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_identify_timeout() {
+    TestCtx::default()
+        .with_usb(|cls, mut dev| {
+            let mut cls = cls.with_identify(IdentifyController::new().with_timeout(Some(1000)));
+            identify(&mut dev, &mut cls, 0, 1).unwrap();
+            assert_eq!(cls.identify(CHANNEL0).poll(10), LedState::On);
+            assert_eq!(cls.identify(CHANNEL0).poll(1010), LedState::Idle);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_bus_error_request() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            // stalled at once in either direction, not left to time out.
+            assert!(dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 3, 0, 0, 4)
+                .is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason, r.count)),
+                Some((3, RejectReason::UnknownRequest, 1))
+            );
+            assert!(dev
+                .control_write(
+                    &mut cls,
+                    CtrRequestType::to_device().vendor(),
+                    3,
+                    0,
+                    0,
+                    4,
+                    &[1, 0, 0, 0],
+                )
+                .is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason, r.count)),
+                Some((3, RejectReason::UnknownRequest, 2))
+            );
+
+            // the channel still comes up.
+            set_mode(&mut dev, &mut cls, 0, 1);
+            assert!(cls.is_started(CHANNEL0));
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_identify_unadvertised() {
+    TestCtx {
+        features: Some(Feature::ONE_SHOT),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert!(identify(&mut dev, &mut cls, 0, 1).is_err());
+        assert_eq!(
+            cls.last_rejection().map(|r| (r.request, r.reason)),
+            Some((7, RejectReason::UnknownRequest))
+        );
+        assert!(!cls.identify(CHANNEL0).is_active());
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_filter_to_host() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.device.filter = Some(redact);
+            for id in [0x100, 0x200, 0x300, 0x100] {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            cls.transmit(CHANNEL1, &classic_frame(0x100), FrameFlag::empty());
+            cls.kick();
+
+            let frames = read_frames(&mut dev, &mut cls);
+            let ids: Vec<_> = frames
+                .iter()
+                .map(|frame| (frame.interface, frame.can_id))
+                .collect();
+            assert_eq!(ids, [(0, 0x200), (0, 0x300), (1, 0x100)]);
+            assert_eq!(frames[0].data(), [0xEE, 0x02, 0x03, 0x04]);
+            assert_eq!(frames[1].data(), [0x01, 0x02, 0x03, 0x04]);
+            assert_eq!(cls.filter_dropped(CHANNEL0), 2);
+            assert_eq!(cls.filter_dropped(CHANNEL1), 0);
+
+            // echoes aren't filtered.
+            let mut frame = classic_frame(0x100);
+            frame.echo_id = 7;
+            let echo = parse_frame(&host_write(&mut dev, &mut cls, &frame.as_bytes()[..20]));
+            assert_eq!(echo.can_id, 0x100);
+            assert_ne!(echo.echo_id, u32::MAX);
+            assert_eq!(cls.filter_dropped(CHANNEL0), 2);
         })
         .expect("with_usb")
 }
+
+#[test]
+fn test_load() {
+    TestCtx {
+        load_monitor: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert_eq!(cls.load(CHANNEL0), Some(LoadStats::default()));
+
+        let mut from_host = classic_frame(1);
+        from_host.interface = 1;
+        for ms in 0..5000 {
+            // 2000 frames and 8000 bytes per second to the host on channel 0.
+            for id in 0..2 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            cls.kick();
+            read_frames(&mut dev, &mut cls);
+
+            // 500 frames and 2000 bytes per second from the host on channel 1.
+            if ms % 2 == 0 {
+                host_write(&mut dev, &mut cls, &from_host.as_bytes()[..20]);
+            }
+            cls.tick_1ms();
+        }
+
+        let within = |estimate: u32, rate: u32| estimate.abs_diff(rate) <= rate / 50;
+        let load = cls.load(CHANNEL0).unwrap();
+        assert!(within(load.to_host_fps, 2000), "{:?}", load);
+        assert!(within(load.to_host_bps, 8000), "{:?}", load);
+        assert_eq!((load.from_host_fps, load.from_host_bps), (0, 0));
+
+        let load = cls.load(CHANNEL1).unwrap();
+        assert_eq!((load.to_host_fps, load.to_host_bps), (0, 0));
+        assert!(within(load.from_host_fps, 500), "{:?}", load);
+        assert!(within(load.from_host_bps, 2000), "{:?}", load);
+
+        // decays once the frames stop.
+        for _ in 0..5000 {
+            cls.tick_1ms();
+        }
+        assert!(cls.load(CHANNEL0).unwrap().to_host_fps < 20);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_load_disabled() {
+    TestCtx::default()
+        .with_usb(|mut cls, _dev| {
+            cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+            cls.tick_1ms();
+            assert_eq!(cls.load(CHANNEL0), None);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_sequence_numbers() {
+    TestCtx {
+        sequence_numbers: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        // six dropped for a full queue.
+        for id in 0..70 {
+            cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+        }
+        cls.kick();
+        let sequences: Vec<_> = read_frames(&mut dev, &mut cls)
+            .iter()
+            .map(|frame| (frame.interface, frame.sequence()))
+            .collect();
+        let expected: Vec<_> = (0..64).map(|n| (0, n)).collect();
+        assert_eq!(sequences, expected);
+
+        // counted per channel.
+        cls.transmit(CHANNEL1, &classic_frame(70), FrameFlag::empty());
+        for id in 0..3 {
+            cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+        }
+        cls.kick();
+        let sequences: Vec<_> = read_frames(&mut dev, &mut cls)
+            .iter()
+            .map(Frame::sequence)
+            .collect();
+        // the gap where frames were dropped.
+        assert_eq!(sequences, [0, 70, 71, 72]);
+        assert_eq!(cls.sequence(CHANNEL0), 73);
+        assert_eq!(cls.sequence(CHANNEL1), 1);
+
+        // echoes aren't numbered.
+        let mut bytes = host_frame_bytes(1);
+        bytes[11] = 0x55;
+        let echo = host_write(&mut dev, &mut cls, &bytes);
+        assert_eq!(parse_frame(&echo).sequence(), 0);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_sequence_numbers_wrap() {
+    TestCtx {
+        sequence_numbers: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        for _ in 0..4 {
+            for id in 0..64 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            cls.kick();
+            read_frames(&mut dev, &mut cls);
+        }
+        cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+        cls.kick();
+        assert_eq!(read_frames(&mut dev, &mut cls)[0].sequence(), 0);
+    })
+    .expect("with_usb")
+}
+
+/// Send a classic frame with the given data to the host on channel 0.
+fn transmit_data<'a>(cls: &mut GsCan<'a, EmulatedUsbBus, MockCanDevice>, id: u16, data: &[u8]) {
+    let frame = Frame::new(StandardId::new(id).unwrap(), data).unwrap();
+    cls.transmit(CHANNEL0, &frame, FrameFlag::empty());
+}
+
+/// The host sees 0x100 to 0x1FF on channel 0 as 0x600 to 0x6FF.
+const REMAP: Transform = Transform::Remap(IdRemap {
+    mask: 0x700,
+    bus: 0x100,
+    host: 0x600,
+});
+
+#[test]
+fn test_transform_to_host() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.set_transform(CHANNEL0, Some(REMAP));
+            for id in [0x105, 0x205, 0x605] {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            cls.transmit(CHANNEL1, &classic_frame(0x105), FrameFlag::empty());
+
+            cls.kick();
+            let frames = read_frames(&mut dev, &mut cls);
+            let ids: Vec<_> = frames.iter().map(|frame| frame.raw_id()).collect();
+            assert_eq!(
+                ids,
+                [0x605, 0x205, 0x605, 0x105],
+                "only the range on channel 0 is moved"
+            );
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_transform_from_host() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.set_transform(
+                CHANNEL0,
+                Some(Transform::Custom {
+                    to_host: |_| {},
+                    from_host: |frame| frame.can_id |= IdFlag::EXTENDED.bits(),
+                }),
+            );
+            cls.set_transform(CHANNEL1, Some(REMAP));
+            set_mode(&mut dev, &mut cls, 0, 1);
+            set_mode(&mut dev, &mut cls, 1, 1);
+
+            let written = host_exchange(&mut dev, &mut cls, &host_frame_bytes(0x605));
+            let mut frame = classic_frame(0x605);
+            frame.interface = 1;
+            let written = [
+                written,
+                host_exchange(&mut dev, &mut cls, &frame.as_bytes()[..20]),
+            ]
+            .concat();
+
+            // the device gets the bus identifiers.
+            let received: Vec<_> = cls
+                .device
+                .received
+                .iter()
+                .map(|frame| frame.raw_id())
+                .collect();
+            assert_eq!(received, [IdFlag::EXTENDED.bits() | 0x605, 0x105]);
+
+            // the echoes those the host sent.
+            let echoes: Vec<_> = written
+                .chunks(FRAME_LEN)
+                .map(|bytes| {
+                    let echo = parse_frame(bytes);
+                    (echo.interface, echo.raw_id())
+                })
+                .collect();
+            assert_eq!(echoes, [(0, 0x605), (1, 0x605)]);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_id_remap_extended() {
+    let remap = IdRemap {
+        mask: 0x1F00_0000,
+        bus: 0x0100_0000,
+        host: 0x1F00_0000,
+    };
+    let id = embedded_can::ExtendedId::new(0x0123_4567).unwrap();
+    let mut frame = Frame::new(id, &[]).unwrap();
+    remap.to_host(&mut frame);
+    assert_eq!(frame.raw_id(), IdFlag::EXTENDED.bits() | 0x1F23_4567);
+    remap.from_host(&mut frame);
+    assert_eq!(frame.id(), id.into());
+
+    // out of the standard format.
+    let mut frame = classic_frame(0x123);
+    remap.to_host(&mut frame);
+    assert_eq!(frame.raw_id(), 0x123);
+
+    // error classes aren't identifiers.
+    let mut error = Frame::new_error(ErrorClass::BUS_OFF, [0; 8]);
+    error.can_id |= 0x0100_0000;
+    let raw_id = error.raw_id();
+    remap.to_host(&mut error);
+    assert_eq!(error.raw_id(), raw_id);
+}
+
+#[test]
+fn test_dedup() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let config = DedupConfig {
+                window_us: 1000,
+                compare_data: true,
+            };
+            cls.set_dedup(CHANNEL0, Some(config));
+
+            cls.device.now_us = Some(0);
+            transmit_data(&mut cls, 1, &[1]);
+            cls.device.now_us = Some(100);
+            transmit_data(&mut cls, 1, &[1]);
+            // a change is sent straight away.
+            transmit_data(&mut cls, 1, &[2]);
+            cls.device.now_us = Some(1099);
+            transmit_data(&mut cls, 1, &[2]);
+            // channels are filtered separately.
+            cls.transmit(CHANNEL1, &classic_frame(1), FrameFlag::empty());
+            cls.transmit(CHANNEL1, &classic_frame(1), FrameFlag::empty());
+            cls.kick();
+            let frames = read_frames(&mut dev, &mut cls);
+            let sent: Vec<_> = frames
+                .iter()
+                .map(|frame| (frame.interface, frame.data()[0]))
+                .collect();
+            assert_eq!(sent, [(0, 1), (0, 2), (1, 1), (1, 1)]);
+            assert_eq!(cls.dedup_suppressed(CHANNEL0), 2);
+            assert_eq!(cls.dedup_suppressed(CHANNEL1), 0);
+
+            // the heartbeat once the window has passed.
+            cls.device.now_us = Some(1100);
+            transmit_data(&mut cls, 1, &[2]);
+            transmit_data(&mut cls, 1, &[2]);
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [1]);
+            assert_eq!(cls.dedup_suppressed(CHANNEL0), 3);
+
+            // echoes aren't filtered.
+            for _ in 0..2 {
+                let echo = host_write(&mut dev, &mut cls, &host_frame_bytes(1));
+                assert_eq!(parse_frame(&echo).can_id, 1);
+            }
+
+            cls.set_dedup(CHANNEL0, None);
+            transmit_data(&mut cls, 1, &[2]);
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [1]);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_dedup_identifier_only() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let config = DedupConfig {
+                window_us: 1000,
+                compare_data: false,
+            };
+            cls.set_dedup(CHANNEL0, Some(config));
+
+            // nothing is suppressed without a timer.
+            transmit_data(&mut cls, 1, &[1]);
+            transmit_data(&mut cls, 1, &[1]);
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [1, 1]);
+
+            cls.device.now_us = Some(u32::MAX - 10);
+            transmit_data(&mut cls, 1, &[1]);
+            transmit_data(&mut cls, 1, &[2, 3]);
+            // across the timer wrapping.
+            cls.device.now_us = Some(10);
+            transmit_data(&mut cls, 1, &[4]);
+            transmit_data(&mut cls, 2, &[4]);
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [1, 2]);
+            assert_eq!(cls.dedup_suppressed(CHANNEL0), 2);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_drop_summary_without_timer() {
+    TestCtx {
+        drop_summary: Some(1000),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        // sustained overflow, a summary whenever there is room.
+        for round in 1..4 {
+            for id in 0..70 {
+                cls.transmit(CHANNEL1, &classic_frame(id), FrameFlag::empty());
+            }
+            cls.kick();
+            let frames = read_frames(&mut dev, &mut cls);
+            let summaries: Vec<_> = frames.iter().filter_map(drop_summary).collect();
+            assert_eq!(summaries, [6], "round {}", round);
+            assert_eq!(frames.last().unwrap().interface, 1);
+        }
+    })
+    .expect("with_usb")
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_transmit_async() {
+    TestCtx::default()
+        .with_usb(|mut cls, _dev| {
+            use std::future::Future as _;
+
+            for id in 0..=64 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            let frame = classic_frame(0x100);
+
+            let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+            let waker = Waker::from(flag.clone());
+            let mut cx = std::task::Context::from_waker(&waker);
+
+            {
+                let fut = cls.transmit_async(CHANNEL0, &frame, FrameFlag::empty());
+                let mut fut = std::pin::pin!(fut);
+                assert!(fut.as_mut().poll(&mut cx).is_pending());
+            }
+
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            assert!(flag.0.load(Ordering::SeqCst));
+
+            let fut = cls.transmit_async(CHANNEL0, &frame, FrameFlag::empty());
+            let mut fut = std::pin::pin!(fut);
+            assert!(fut.as_mut().poll(&mut cx).is_ready());
+        })
+        .expect("with_usb")
+}
+
+/// Host to device endpoint index.
+const READ_EP: usize = 2;
+
+/// Write data from the host, returning anything the device wrote back.
+fn host_write<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    data: &[u8],
+) -> Vec<u8>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    dev.ep_write(cls, READ_EP, data).unwrap();
+    dev.ep_read(cls, 1, 1024).unwrap()
+}
+
+/// Write data from the host, returning everything the device wrote back
+/// including frames written after the response to the data.
+fn host_exchange<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    data: &[u8],
+) -> Vec<u8>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    let mut written = host_write(dev, cls, data);
+    written.extend(dev.ep_read(cls, 1, 256).unwrap());
+    written
+}
+
+/// Classic frame as sent by the host.
+fn host_frame_bytes(id: u16) -> Vec<u8> {
+    let mut frame = classic_frame(id);
+    frame.echo_id = 7;
+    frame.as_bytes()[..20].to_vec()
+}
+
+#[test]
+fn test_receive_direct() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let echo = host_write(&mut dev, &mut cls, &host_frame_bytes(0x123));
+            assert_eq!(echo.len(), FRAME_LEN);
+
+            assert_eq!(cls.device.received.len(), 1);
+            assert_eq!(cls.device.received[0].can_id, 0x123);
+            assert!(cls.dequeue_host_frame().is_none());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_disabled() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_mode(&mut dev, &mut cls, 0, 1);
+            host_write(&mut dev, &mut cls, &host_frame_bytes(1));
+            cls.transmit(CHANNEL0, &classic_frame(2), FrameFlag::empty());
+
+            // the running channel is reset and the queue flushed.
+            cls.set_enabled(false);
+            assert!(!cls.is_enabled());
+            assert!(!cls.is_started(CHANNEL0));
+            assert_eq!(cls.device.modes.last(), Some(&("reset", CHANNEL0)));
+            assert!(cls.is_idle());
+
+            // data from the host is discarded, frames for it dropped.
+            assert!(host_write(&mut dev, &mut cls, &host_frame_bytes(3)).is_empty());
+            assert_eq!(cls.device.received.len(), 1);
+            assert_eq!(cls.disabled_discarded(), 20);
+            cls.transmit(CHANNEL0, &classic_frame(4), FrameFlag::empty());
+            cls.kick();
+            assert!(read_frame_ids(&mut dev, &mut cls).is_empty());
+            assert_eq!(cls.disabled_dropped(), 1);
+
+            // only the device config answers.
+            assert!(try_set_mode(&mut dev, &mut cls, 0, 1, Feature::empty()).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|rejection| rejection.reason),
+                Some(RejectReason::Disabled)
+            );
+            assert!(dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 14, 0, 0, 12)
+                .is_err());
+            let config = dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 5, 0, 0, 12)
+                .unwrap();
+            assert_eq!(config.len(), 12);
+
+            // enabled again with the channel reset.
+            cls.set_enabled(true);
+            assert!(!cls.is_started(CHANNEL0));
+            set_mode(&mut dev, &mut cls, 0, 1);
+            let echo = host_write(&mut dev, &mut cls, &host_frame_bytes(5));
+            assert_eq!(parse_frame(&echo).can_id, 5);
+            assert_eq!(cls.device.received.len(), 2);
+            cls.transmit(CHANNEL0, &classic_frame(6), FrameFlag::empty());
+            cls.kick();
+            assert_eq!(read_frame_ids(&mut dev, &mut cls), [6]);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_receive_queued() {
+    QueuedTestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+            cls.register_rx_waker(Waker::from(flag.clone()));
+
+            for id in 0..4 {
+                host_write(&mut dev, &mut cls, &host_frame_bytes(id));
+            }
+
+            assert!(flag.0.load(Ordering::SeqCst));
+            assert!(cls.device.received.is_empty());
+
+            // queue holds 3 frames, the 4th is NAKed until space frees up.
+            for id in 0..4 {
+                let (channel, frame) = cls.dequeue_host_frame().unwrap();
+                assert_eq!(channel, CHANNEL0);
+                assert_eq!(frame.can_id, id);
+            }
+            assert!(cls.dequeue_host_frame().is_none());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_receive_staged() {
+    StagedTestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            for id in 0..3 {
+                host_write(&mut dev, &mut cls, &host_frame_bytes(id));
+            }
+
+            // nothing reaches the device from the endpoint callback.
+            assert!(cls.device.received.is_empty());
+
+            cls.process();
+            let ids: Vec<u32> = cls.device.received.iter().map(|f| f.can_id).collect();
+            assert_eq!(ids, [0, 1, 2]);
+            assert!(cls.is_idle());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_receive_staged_backpressure() {
+    StagedTestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            // queue holds 3 frames, the 4th is held and the 5th is NAKed.
+            for id in 0..5 {
+                host_write(&mut dev, &mut cls, &host_frame_bytes(id));
+            }
+
+            cls.device.busy = true;
+            cls.process();
+            assert!(cls.device.received.is_empty());
+
+            cls.device.busy = false;
+            cls.process();
+            let ids: Vec<u32> = cls.device.received.iter().map(|f| f.can_id).collect();
+            assert_eq!(ids, [0, 1, 2, 3]);
+
+            // the frame left in the endpoint is read when polled.
+            assert!(cls.needs_poll());
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            cls.process();
+            let ids: Vec<u32> = cls.device.received.iter().map(|f| f.can_id).collect();
+            assert_eq!(ids, [0, 1, 2, 3, 4]);
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "wire-dump")]
+std::thread_local! {
+    static BULK_IN: std::cell::RefCell<Vec<Vec<u8>>> = const { std::cell::RefCell::new(Vec::new()) };
+    static BULK_OUT: std::cell::RefCell<Vec<Vec<u8>>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(feature = "wire-dump")]
+struct WireDumpTestCtx {}
+
+#[cfg(feature = "wire-dump")]
+impl UsbDeviceCtx for WireDumpTestCtx {
+    type C<'c> = GsCan<'c, EmulatedUsbBus, MockCanDevice>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a usb_device::bus::UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<Self::C<'a>> {
+        Ok(new_class(alloc, MockCanDevice::default())
+            .with_bulk_in_hook(|bytes| BULK_IN.with_borrow_mut(|p| p.push(bytes.to_vec())))
+            .with_bulk_out_hook(|bytes| BULK_OUT.with_borrow_mut(|p| p.push(bytes.to_vec()))))
+    }
+}
+
+#[cfg(feature = "wire-dump")]
+#[test]
+fn test_wire_dump() {
+    WireDumpTestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let written = host_exchange(&mut dev, &mut cls, &host_frame_bytes(1));
+            assert_eq!(BULK_OUT.take(), [host_frame_bytes(1)]);
+
+            let packets = BULK_IN.take();
+            let lens: Vec<usize> = packets.iter().map(Vec::len).collect();
+            assert_eq!(lens, [FRAME_LEN]);
+            assert_eq!(packets.concat(), written);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_channel_try_from() {
+    assert_eq!(Channel::try_from(0_u16), Ok(CHANNEL0));
+    assert_eq!(Channel::try_from(2_u16).map(u8::from), Ok(2));
+    assert_eq!(Channel::try_from(3_u16), Err(InvalidChannel));
+    assert_eq!(Channel::try_from(0x100_u16), Err(InvalidChannel));
+}
+
+#[test]
+fn test_channel_of_device() {
+    TestCtx::default()
+        .with_usb(|cls, _| {
+            // two channels configured, of the three the class supports.
+            assert_eq!(cls.channel(1).map(u8::from), Ok(1));
+            assert_eq!(cls.channel(2), Err(InvalidChannel));
+            assert_eq!(cls.channel(0x100), Err(InvalidChannel));
+        })
+        .expect("with_usb");
+}
+
+/// Parse a frame written by the device.
+fn parse_frame(bytes: &[u8]) -> Frame {
+    let mut frame = Frame::new_zeroed();
+    frame.as_bytes_mut()[..bytes.len()].copy_from_slice(bytes);
+    frame
+}
+
+#[test]
+fn test_transmit_replacing() {
+    TestCtx {
+        sequence_numbers: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        let sample = |value| Frame::new(StandardId::new(1).unwrap(), &[value]).unwrap();
+        cls.transmit(CHANNEL0, &sample(1), FrameFlag::empty());
+        cls.transmit(CHANNEL0, &classic_frame(2), FrameFlag::empty());
+        cls.transmit(CHANNEL1, &sample(1), FrameFlag::empty());
+        assert!(cls.transmit_replacing(CHANNEL0, &sample(2), FrameFlag::empty()));
+        assert!(cls.transmit_replacing(CHANNEL0, &sample(3), FrameFlag::empty()));
+
+        // in place of the first, with its sequence number.
+        cls.kick();
+        let frames: Vec<_> = read_frames(&mut dev, &mut cls)
+            .iter()
+            .map(|frame| {
+                (
+                    frame.interface,
+                    frame.can_id,
+                    frame.sequence(),
+                    frame.data()[0],
+                )
+            })
+            .collect();
+        assert_eq!(frames, [(0, 1, 0, 3), (0, 2, 1, 1), (1, 1, 0, 1)]);
+
+        // nothing to replace, queued as usual.
+        assert!(!cls.transmit_replacing(CHANNEL0, &sample(4), FrameFlag::empty()));
+        cls.kick();
+        let frames: Vec<_> = read_frames(&mut dev, &mut cls)
+            .iter()
+            .map(|frame| (frame.can_id, frame.sequence(), frame.data()[0]))
+            .collect();
+        assert_eq!(frames, [(1, 2, 4)]);
+    })
+    .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_transmit_replacing_split() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+
+            // the first half of the frame is in the endpoint.
+            cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            assert!(!cls.transmit_replacing(CHANNEL0, &classic_frame(1), FrameFlag::empty()));
+
+            // both are written whole.
+            let mut written = Vec::new();
+            loop {
+                let read = dev.ep_read(&mut cls, 1, 1024).unwrap();
+                if read.is_empty() {
+                    break;
+                }
+                written.extend(read);
+            }
+            assert_eq!(written.len(), 2 * FD_FRAME_LEN);
+            assert!(cls.is_idle());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_transmit_replacing_drop_summary() {
+    TestCtx {
+        drop_summary: Some(1000),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        let sample = |value| Frame::new(StandardId::new(1).unwrap(), &[value]).unwrap();
+        cls.device.now_us = Some(0);
+        for id in 0..66 {
+            cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+        }
+        cls.kick();
+        read_frames(&mut dev, &mut cls);
+
+        // dropped within the interval, the summary is pending.
+        cls.device.now_us = Some(500);
+        for id in 0..65 {
+            cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+        }
+        cls.kick();
+        assert_eq!(read_frames(&mut dev, &mut cls).len(), 64);
+        cls.transmit(CHANNEL0, &sample(1), FrameFlag::empty());
+
+        // the summary is queued ahead of the replacement.
+        cls.device.now_us = Some(1000);
+        assert!(cls.transmit_replacing(CHANNEL0, &sample(2), FrameFlag::empty()));
+        cls.kick();
+        let frames = read_frames(&mut dev, &mut cls);
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].can_id, frames[0].data()), (1, &[2][..]));
+        assert_eq!(drop_summary(&frames[1]), Some(1));
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_host_tx_policy_nak() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.device.busy = true;
+
+            // first frame is held, the second is left in the endpoint.
+            assert!(host_write(&mut dev, &mut cls, &host_frame_bytes(1)).is_empty());
+            assert!(host_write(&mut dev, &mut cls, &host_frame_bytes(2)).is_empty());
+            assert!(cls.device.received.is_empty());
+
+            cls.device.busy = false;
+            cls.retry_receive();
+            assert!(cls.needs_poll());
+
+            // the frame left in the endpoint is read when polled.
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            let ids: Vec<u32> = cls.device.received.iter().map(|f| f.can_id).collect();
+            assert_eq!(ids, [1, 2]);
+            assert_eq!(cls.host_tx_dropped(CHANNEL0), 0);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_host_tx_policy_drop_newest() {
+    TestCtx {
+        host_tx_policy: HostTxPolicy::DropNewest,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        cls.device.busy = true;
+
+        let echo = parse_frame(&host_write(&mut dev, &mut cls, &host_frame_bytes(1)));
+        assert_eq!(echo.can_id, 1);
+        assert!(echo.flags.contains(FrameFlag::OVERFLOW));
+        assert_eq!(cls.host_tx_dropped(CHANNEL0), 1);
+
+        cls.device.busy = false;
+        cls.retry_receive();
+        assert!(cls.device.received.is_empty());
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_host_tx_policy_drop_oldest() {
+    TestCtx {
+        host_tx_policy: HostTxPolicy::DropOldest,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        cls.device.busy = true;
+
+        assert!(host_write(&mut dev, &mut cls, &host_frame_bytes(1)).is_empty());
+
+        // second frame replaces the first.
+        let echo = parse_frame(&host_write(&mut dev, &mut cls, &host_frame_bytes(2)));
+        assert_eq!(echo.can_id, 1);
+        assert!(echo.flags.contains(FrameFlag::OVERFLOW));
+        assert_eq!(cls.host_tx_dropped(CHANNEL0), 1);
+
+        cls.device.busy = false;
+        cls.retry_receive();
+
+        let ids: Vec<u32> = cls.device.received.iter().map(|f| f.can_id).collect();
+        assert_eq!(ids, [2]);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_drop_errors() {
+    TestCtx {
+        host_tx_policy: HostTxPolicy::DropNewest,
+        drop_errors: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        cls.device.busy = true;
+
+        // not reported for a stopped channel.
+        let written = host_exchange(&mut dev, &mut cls, &host_frame_bytes(1));
+        assert_eq!(written.len(), FRAME_LEN);
+        assert!(parse_frame(&written).flags.contains(FrameFlag::OVERFLOW));
+
+        set_mode(&mut dev, &mut cls, 0, 1);
+        let written = host_exchange(&mut dev, &mut cls, &host_frame_bytes(2));
+        assert_eq!(written.len(), 2 * FRAME_LEN);
+        let (echo, error) = written.split_at(FRAME_LEN);
+        assert_eq!(parse_frame(echo).can_id, 2);
+
+        let error = parse_frame(error);
+        assert_eq!(error.echo_id, u32::MAX);
+        assert_eq!(error.interface, 0);
+        assert!(error.is_error_frame());
+        assert_eq!(
+            error.raw_id(),
+            IdFlag::ERROR.bits() | ErrorClass::CONTROLLER.bits()
+        );
+        assert_eq!(
+            error.data(),
+            [0, ControllerError::TX_OVERFLOW.bits(), 0, 0, 0, 0, 0, 0]
+        );
+
+        // malformed frames are reported too.
+        let mut short = classic_frame(3);
+        short.can_dlc = 8;
+        let written = host_exchange(&mut dev, &mut cls, &short.as_bytes()[..16]);
+        assert_eq!(written.len(), FRAME_LEN);
+        assert!(parse_frame(&written).is_error_frame());
+        assert_eq!(cls.host_tx_dropped(CHANNEL0), 2);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_notify() {
+    TestCtx {
+        host_tx_policy: HostTxPolicy::DropNewest,
+        drop_errors: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        set_mode(&mut dev, &mut cls, 0, 1);
+
+        // frames from the bus aren't passed.
+        cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+        cls.kick();
+        assert_eq!(read_frame_ids(&mut dev, &mut cls), [1]);
+        assert!(cls.device.notified.is_empty());
+
+        // the error frame is passed as the host gets it.
+        cls.device.busy = true;
+        let written = host_exchange(&mut dev, &mut cls, &host_frame_bytes(2));
+        let (_echo, error) = written.split_at(FRAME_LEN);
+        assert_eq!(cls.device.notified.len(), 1);
+        let (channel, notified) = cls.device.notified[0];
+        assert_eq!(channel, CHANNEL0);
+        assert!(notified.is_error_frame());
+        assert_eq!(&notified.as_bytes()[..FRAME_LEN], error);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_flags_debug() {
+    assert_eq!(
+        format!("{:?}", FrameFlag::FD | FrameFlag::BIT_RATE_SWITCH),
+        "FrameFlag(FD | BIT_RATE_SWITCH)"
+    );
+    assert_eq!(
+        format!("{:?}", FrameFlag::from_bits_retain(0x82)),
+        "FrameFlag(FD | 0x80)"
+    );
+    assert_eq!(format!("{:?}", FrameFlag::empty()), "FrameFlag(0x0)");
+    assert_eq!(
+        format!("{:?}", Feature::FD | Feature::GET_STATE),
+        "Feature(FD | GET_STATE)"
+    );
+    assert_eq!(
+        format!("{:?}", Feature::from_bits_retain(1 << 20)),
+        "Feature(0x100000)"
+    );
+    assert_eq!(
+        format!("{:?}", IdFlag::EXTENDED | IdFlag::REMOTE),
+        "IdFlag(EXTENDED | REMOTE)"
+    );
+}
+
+/// Split a configuration descriptor into its descriptors.
+fn split_descriptors(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut descriptors = Vec::new();
+    while !data.is_empty() {
+        let (descriptor, rest) = data.split_at(data[0] as usize);
+        descriptors.push(descriptor);
+        data = rest;
+    }
+    descriptors
+}
+
+#[test]
+fn test_configuration_descriptor() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let data = dev.device_get_descriptor(&mut cls, 2, 0, 0, 255).unwrap();
+            let kinds: Vec<u8> = split_descriptors(&data).iter().map(|d| d[1]).collect();
+            assert_eq!(kinds, [2, 4, 5, 5]);
+            assert_eq!(
+                data[9..],
+                [
+                    9, 4, 0, 0, 2, 0xff, 0xff, 0xff, 0, // interface
+                    7, 5, 0x81, 2, 64, 0, 0, // write endpoint
+                    7, 5, 0x02, 2, 64, 0, 0, // read endpoint
+                ]
+            );
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_channel_labels() {
+    TestCtx {
+        channel_labels: &["Chassis bus", "Körper bus"],
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        let data = dev.device_get_descriptor(&mut cls, 2, 0, 0, 255).unwrap();
+        let interface = split_descriptors(&data)[1];
+        let first = interface[8];
+        // after the manufacturer, product and serial number strings.
+        assert_eq!(first, 4);
+
+        assert_eq!(
+            dev.device_get_string(&mut cls, first, 0x409).unwrap(),
+            "Chassis bus"
+        );
+        let label = dev
+            .device_get_descriptor(&mut cls, 3, first + 1, 0x409, 255)
+            .unwrap();
+        let mut expected = vec![22, 3];
+        expected.extend("Körper bus".encode_utf16().flat_map(u16::to_le_bytes));
+        assert_eq!(label, expected);
+
+        assert!(dev.device_get_string(&mut cls, first + 2, 0x409).is_err());
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_channel_labels_partial() {
+    TestCtx {
+        channel_labels: &["Chassis bus"],
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        let data = dev.device_get_descriptor(&mut cls, 2, 0, 0, 255).unwrap();
+        let first = split_descriptors(&data)[1][8];
+        assert_eq!(
+            dev.device_get_string(&mut cls, first, 0x409).unwrap(),
+            "Chassis bus"
+        );
+        assert!(dev.device_get_string(&mut cls, first + 1, 0x409).is_err());
+    })
+    .expect("with_usb")
+}
+
+/// Default control pipe of the device on the emulated bus.
+#[cfg(feature = "host-tools")]
+struct TesterPipe<'t, 'a, X: UsbDeviceCtx<C<'a> = GsCan<'a, EmulatedUsbBus, MockCanDevice>>> {
+    dev: &'t mut usbd_class_tester::Device<'a, GsCan<'a, EmulatedUsbBus, MockCanDevice>, X>,
+    cls: &'t mut GsCan<'a, EmulatedUsbBus, MockCanDevice>,
+}
+
+#[cfg(feature = "host-tools")]
+impl<'a, X: UsbDeviceCtx<C<'a> = GsCan<'a, EmulatedUsbBus, MockCanDevice>>>
+    usbd_gscan::labels::ControlPipe for TesterPipe<'_, 'a, X>
+{
+    type Error = AnyUsbError;
+
+    fn get_descriptor(
+        &mut self,
+        kind: u8,
+        index: u8,
+        lang_id: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        let length = u16::try_from(buf.len()).unwrap_or(u16::MAX);
+        let data = self
+            .dev
+            .device_get_descriptor(self.cls, kind, index, lang_id, length)?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+#[test]
+#[cfg(feature = "host-tools")]
+fn test_channel_labels_reader() {
+    use usbd_gscan::labels::{self, LabelError};
+
+    TestCtx {
+        channel_labels: &["Chassis bus", "Körper bus"],
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        let mut pipe = TesterPipe {
+            dev: &mut dev,
+            cls: &mut cls,
+        };
+        let mut buf = [0; 256];
+        let first = labels::first_label_index(&mut pipe, 0, &mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(first, 4);
+        assert_eq!(
+            labels::first_label_index(&mut pipe, 1, &mut buf),
+            Err(LabelError::NoInterface)
+        );
+
+        for (channel, label) in [(CHANNEL0, "Chassis bus"), (CHANNEL1, "Körper bus")] {
+            let read = labels::read_label(&mut pipe, first, channel, 0x409, &mut buf).unwrap();
+            assert_eq!(read, label);
+        }
+        // "Körper bus" is 11 bytes of UTF-8.
+        assert_eq!(
+            labels::read_label(&mut pipe, first, CHANNEL1, 0x409, &mut [0; 10]),
+            Err(LabelError::BufferTooSmall)
+        );
+        let channel = Channel::new(2).unwrap();
+        assert!(matches!(
+            labels::read_label(&mut pipe, first, channel, 0x409, &mut buf),
+            Err(LabelError::Pipe(_))
+        ));
+    })
+    .expect("with_usb")
+}
+
+#[test]
+#[cfg(feature = "host-tools")]
+fn test_channel_labels_reader_none() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let mut pipe = TesterPipe {
+                dev: &mut dev,
+                cls: &mut cls,
+            };
+            let first = usbd_gscan::labels::first_label_index(&mut pipe, 0, &mut [0; 256]);
+            assert_eq!(first, Ok(None));
+        })
+        .expect("with_usb")
+}
+
+#[test]
+#[should_panic(expected = "more labels than channels")]
+fn test_channel_labels_too_many() {
+    TestCtx {
+        channel_labels: &["a", "b", "c"],
+        ..Default::default()
+    }
+    .with_usb(|_cls, _dev| {})
+    .ok();
+}
+
+#[test]
+fn test_endpoint_addresses() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let data = dev.device_get_descriptor(&mut cls, 2, 0, 0, 255).unwrap();
+            let endpoints: Vec<(u8, u8, u16)> = split_descriptors(&data)
+                .iter()
+                .filter(|d| d[1] == 5)
+                .map(|d| (d[2], d[3], u16::from_le_bytes([d[4], d[5]])))
+                .collect();
+
+            // as hard-coded in the host drivers, bulk with 64 byte packets.
+            assert_eq!(endpoints, [(0x81, 2, 64), (0x02, 2, 64)]);
+        })
+        .expect("with_usb")
+}
+
+/// Allocates endpoint `0x81` before the class.
+struct TakenEndpointTestCtx {}
+
+impl UsbDeviceCtx for TakenEndpointTestCtx {
+    type C<'c> = GsCan<'c, EmulatedUsbBus, MockCanDevice>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<Self::C<'a>> {
+        let address = EndpointAddress::from_parts(1, UsbDirection::In);
+        alloc
+            .alloc::<In>(Some(address), EndpointType::Interrupt, 8, 1)
+            .unwrap();
+        Ok(GsCan::new(alloc, MockCanDevice::default()))
+    }
+}
+
+#[test]
+#[should_panic(expected = "endpoint address unavailable")]
+fn test_endpoint_address_taken() {
+    TakenEndpointTestCtx {}.with_usb(|_cls, _dev| {}).ok();
+}
+
+/// Allocates the class endpoints with `interval`, as `ep_type` for OUT.
+struct CustomEndpointTestCtx {
+    interval: u8,
+    ep_type: EndpointType,
+}
+
+impl UsbDeviceCtx for CustomEndpointTestCtx {
+    type C<'c> = GsCan<'c, EmulatedUsbBus, MockCanDevice>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<Self::C<'a>> {
+        let (interval, ep_type) = (self.interval, self.ep_type);
+        Ok(GsCan::new_with_endpoints(
+            alloc,
+            MockCanDevice::default(),
+            |alloc| {
+                let address = |index, dir| Some(EndpointAddress::from_parts(index, dir));
+                (
+                    alloc
+                        .alloc(
+                            address(1, UsbDirection::In),
+                            EndpointType::Bulk,
+                            64,
+                            interval,
+                        )
+                        .unwrap(),
+                    alloc
+                        .alloc(address(2, UsbDirection::Out), ep_type, 64, interval)
+                        .unwrap(),
+                )
+            },
+        ))
+    }
+}
+
+#[test]
+fn test_configuration_descriptor_custom_endpoints() {
+    CustomEndpointTestCtx {
+        interval: 4,
+        ep_type: EndpointType::Bulk,
+    }
+    .with_usb(|mut cls, mut dev| {
+        let data = dev.device_get_descriptor(&mut cls, 2, 0, 0, 255).unwrap();
+        assert_eq!(
+            data[18..],
+            [
+                7, 5, 0x81, 2, 64, 0, 4, // write endpoint
+                7, 5, 0x02, 2, 64, 0, 4, // read endpoint
+            ]
+        );
+    })
+    .expect("with_usb")
+}
+
+#[test]
+#[should_panic(expected = "endpoint isn't bulk with 64 byte packets")]
+fn test_custom_endpoints_not_bulk() {
+    CustomEndpointTestCtx {
+        interval: 1,
+        ep_type: EndpointType::Interrupt,
+    }
+    .with_usb(|_cls, _dev| {})
+    .ok();
+}
+
+#[test]
+fn test_configuration_descriptor_iad() {
+    TestCtx {
+        composite: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        let device = dev.device_get_descriptor(&mut cls, 1, 0, 0, 18).unwrap();
+        assert_eq!(device[4..7], [0xef, 0x02, 0x01]);
+
+        let data = dev.device_get_descriptor(&mut cls, 2, 0, 0, 255).unwrap();
+        let descriptors = split_descriptors(&data);
+        let kinds: Vec<u8> = descriptors.iter().map(|d| d[1]).collect();
+        assert_eq!(kinds, [2, 11, 4, 5, 5]);
+
+        // one interface, vendor class.
+        assert_eq!(descriptors[1], [8, 11, 0, 1, 0xff, 0xff, 0xff, 0]);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_control_in_short_length() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            // bit timing const, device config, extended bit timing const, state.
+            #[cfg(feature = "fd")]
+            let requests = [4, 5, 11, 14];
+            #[cfg(not(feature = "fd"))]
+            let requests = [4, 5, 14];
+            for request in requests {
+                let data = dev
+                    .control_read(
+                        &mut cls,
+                        CtrRequestType::to_host().vendor(),
+                        request,
+                        0,
+                        0,
+                        6,
+                    )
+                    .unwrap();
+                assert_eq!(data.len(), 6, "request {}", request);
+            }
+
+            // interface count is the last byte of the first word.
+            let data = dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 5, 0, 0, 4)
+                .unwrap();
+            assert_eq!(data, [0, 0, 0, 1]);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_get_state() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let data = dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 14, 0, 0, 12)
+                .unwrap();
+            let raw = RawDeviceState::read_from(&data[..]).unwrap();
+            assert_eq!(DeviceState::try_from(raw), Ok(cls.device.state(CHANNEL0)));
+        })
+        .expect("with_usb")
+}
+
+/// Read the state of a channel.
+fn get_state<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    channel: u16,
+) -> DeviceState
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    let data = dev
+        .control_read(cls, CtrRequestType::to_host().vendor(), 14, 0, channel, 12)
+        .unwrap();
+    DeviceState::try_from(RawDeviceState::read_from(&data[..]).unwrap()).unwrap()
+}
+
+#[test]
+fn test_get_state_reported() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_mode(&mut dev, &mut cls, 0, 1);
+            cls.report_state(CHANNEL0, DeviceState::passive(130, 5));
+            assert_eq!(
+                get_state(&mut dev, &mut cls, 0),
+                DeviceState::passive(130, 5)
+            );
+
+            // the device answers for channels without a report.
+            assert_eq!(get_state(&mut dev, &mut cls, 1), DeviceState::active(0, 1));
+
+            // until the channel is reset.
+            set_mode(&mut dev, &mut cls, 0, 0);
+            assert_eq!(get_state(&mut dev, &mut cls, 0), DeviceState::active(0, 0));
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_get_state_source() {
+    TestCtx {
+        state_source: StateSource::Device,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        cls.report_state(CHANNEL1, DeviceState::warning(100, 0));
+        assert_eq!(get_state(&mut dev, &mut cls, 1), DeviceState::active(0, 1));
+    })
+    .expect("with_usb");
+
+    TestCtx {
+        state_source: StateSource::ReportedOnly,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert_eq!(get_state(&mut dev, &mut cls, 1), DeviceState::active(0, 0));
+        cls.report_state(CHANNEL1, DeviceState::warning(100, 0));
+        assert_eq!(
+            get_state(&mut dev, &mut cls, 1),
+            DeviceState::warning(100, 0)
+        );
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_deferred_start() {
+    TestCtx {
+        deferred_start: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert_eq!(cls.channel_mode(CHANNEL0), ChannelMode::Stopped);
+        set_mode(&mut dev, &mut cls, 0, 1);
+        assert_eq!(cls.channel_mode(CHANNEL0), ChannelMode::Starting);
+        assert_eq!(get_state(&mut dev, &mut cls, 0).state, CanState::Stopped);
+
+        // the host retries before the device is ready.
+        assert!(try_set_mode(&mut dev, &mut cls, 0, 1, Feature::empty()).is_err());
+        assert_eq!(
+            cls.last_rejection().map(|rejection| rejection.reason),
+            Some(RejectReason::ChannelStarting)
+        );
+        assert_eq!(cls.device.modes, [("start", CHANNEL0)]);
+
+        assert!(cls.start_complete(CHANNEL0));
+        assert!(!cls.start_complete(CHANNEL0));
+        assert_eq!(cls.channel_mode(CHANNEL0), ChannelMode::Started);
+        assert_eq!(get_state(&mut dev, &mut cls, 0).state, CanState::Active);
+
+        // a reset cancels a start.
+        set_mode(&mut dev, &mut cls, 1, 1);
+        set_mode(&mut dev, &mut cls, 1, 0);
+        assert_eq!(cls.channel_mode(CHANNEL1), ChannelMode::Stopped);
+        assert!(!cls.start_complete(CHANNEL1));
+        assert_eq!(
+            cls.device.modes[1..],
+            [("start", CHANNEL1), ("reset", CHANNEL1)]
+        );
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_deferred_stop() {
+    TestCtx {
+        deferred_start: true,
+        deferred_stop: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        set_mode(&mut dev, &mut cls, 0, 1);
+        assert!(cls.start_complete(CHANNEL0));
+        assert!(!cls.stop_complete(CHANNEL0));
+
+        set_mode(&mut dev, &mut cls, 0, 0);
+        assert_eq!(cls.channel_mode(CHANNEL0), ChannelMode::Stopping);
+        assert!(!cls.is_started(CHANNEL0));
+        assert_eq!(get_state(&mut dev, &mut cls, 0).state, CanState::Stopped);
+        assert_eq!(cls.device.modes, [("start", CHANNEL0), ("reset", CHANNEL0)]);
+
+        // the host starts it again before the device has left the bus.
+        assert!(try_set_mode(&mut dev, &mut cls, 0, 1, Feature::empty()).is_err());
+        assert_eq!(
+            cls.last_rejection().map(|rejection| rejection.reason),
+            Some(RejectReason::ChannelStopping)
+        );
+        // a repeated reset leaves it stopping.
+        set_mode(&mut dev, &mut cls, 0, 0);
+        assert_eq!(cls.channel_mode(CHANNEL0), ChannelMode::Stopping);
+        assert_eq!(cls.device.modes.len(), 2);
+
+        assert!(cls.stop_complete(CHANNEL0));
+        assert!(!cls.stop_complete(CHANNEL0));
+        assert_eq!(cls.channel_mode(CHANNEL0), ChannelMode::Stopped);
+        set_mode(&mut dev, &mut cls, 0, 1);
+        assert_eq!(cls.channel_mode(CHANNEL0), ChannelMode::Starting);
+
+        // a channel that was never started stops at once.
+        set_mode(&mut dev, &mut cls, 1, 0);
+        assert_eq!(cls.channel_mode(CHANNEL1), ChannelMode::Stopped);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_channel_fault() {
+    TestCtx {
+        echo_mode: EchoMode::Device,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        set_mode(&mut dev, &mut cls, 0, 1);
+        assert!(host_write(&mut dev, &mut cls, &host_frame_bytes(1)).is_empty());
+
+        cls.channel_fault(CHANNEL0, FaultReason::Thermal);
+        assert_eq!(cls.fault(CHANNEL0), Some(FaultReason::Thermal));
+        assert_eq!(cls.channel_mode(CHANNEL0), ChannelMode::Stopped);
+
+        // the frame in flight is aborted, then the channel is bus-off.
+        cls.kick();
+        let frames = read_frames(&mut dev, &mut cls);
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].echo_id, frames[0].can_id), (7, 1));
+        assert!(frames[0].flags.contains(FrameFlag::OVERFLOW));
+        assert!(frames[1].is_error_frame());
+        assert_eq!(
+            frames[1].raw_id(),
+            IdFlag::ERROR.bits() | ErrorClass::BUS_OFF.bits()
+        );
+        assert_eq!(get_state(&mut dev, &mut cls, 0).state, CanState::BusOff);
+
+        // frames for the stopped channel are dropped.
+        let written = host_exchange(&mut dev, &mut cls, &host_frame_bytes(2));
+        assert!(parse_frame(&written).flags.contains(FrameFlag::OVERFLOW));
+        assert_eq!(cls.device.received.len(), 1);
+        assert_eq!(cls.host_tx_dropped(CHANNEL0), 1);
+
+        // the host restarts the channel.
+        set_mode(&mut dev, &mut cls, 0, 0);
+        assert_eq!(cls.fault(CHANNEL0), Some(FaultReason::Thermal));
+        set_mode(&mut dev, &mut cls, 0, 1);
+        assert_eq!(cls.fault(CHANNEL0), None);
+        assert!(host_write(&mut dev, &mut cls, &host_frame_bytes(3)).is_empty());
+        assert_eq!(cls.device.received.len(), 2);
+        assert_eq!(cls.device.modes, [("start", CHANNEL0), ("start", CHANNEL0)]);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_channel_mode() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_mode(&mut dev, &mut cls, 0, 1);
+            assert_eq!(cls.channel_mode(CHANNEL0), ChannelMode::Started);
+            assert!(!cls.start_complete(CHANNEL0));
+
+            // a repeated start restarts the device.
+            set_mode(&mut dev, &mut cls, 0, 1);
+            assert_eq!(
+                cls.device.modes,
+                [
+                    ("start", CHANNEL0),
+                    ("reset", CHANNEL0),
+                    ("start", CHANNEL0)
+                ]
+            );
+            set_mode(&mut dev, &mut cls, 0, 0);
+            assert_eq!(cls.channel_mode(CHANNEL0), ChannelMode::Stopped);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_capabilities() {
+    TestCtx {
+        capabilities_request: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        let data = dev
+            .control_read(
+                &mut cls,
+                CtrRequestType::to_host().vendor(),
+                REQ_CAPABILITIES,
+                0,
+                0,
+                64,
+            )
+            .unwrap();
+        let capabilities: Vec<_> = data
+            .iter()
+            .map(|&code| Capability::try_from(code).unwrap())
+            .collect();
+        assert_eq!(capabilities, CAPABILITIES);
+
+        // as the features built.
+        for (capability, feature) in [
+            (Capability::HwTimestamp, false),
+            (Capability::Fd, cfg!(feature = "fd")),
+            (Capability::SelfTest, cfg!(feature = "self-test")),
+            (Capability::SharedChannels, cfg!(feature = "shared")),
+            (Capability::PanicFree, cfg!(feature = "panic-free")),
+        ] {
+            assert_eq!(
+                capabilities.contains(&capability),
+                feature,
+                "{capability:?}"
+            );
+        }
+    })
+    .expect("with_usb");
+
+    // an extension, unknown unless enabled.
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            assert!(dev
+                .control_read(
+                    &mut cls,
+                    CtrRequestType::to_host().vendor(),
+                    REQ_CAPABILITIES,
+                    0,
+                    0,
+                    64,
+                )
+                .is_err());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_host_capabilities() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            // before the channel is started, e.g. `ip -details link show`.
+            dev.control_read(&mut cls, CtrRequestType::to_host().vendor(), 14, 0, 0, 12)
+                .unwrap();
+            assert!(dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 6, 0, 0, 4)
+                .is_err());
+            assert_eq!(cls.host_capabilities(), HostCapabilities::default());
+
+            set_mode(&mut dev, &mut cls, 0, 1);
+            dev.control_read(&mut cls, CtrRequestType::to_host().vendor(), 14, 0, 0, 12)
+                .unwrap();
+            assert_eq!(
+                cls.host_capabilities(),
+                HostCapabilities {
+                    polls_state: true,
+                    reads_timestamp: false,
+                }
+            );
+            // unanswered, still noted.
+            assert!(dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 6, 0, 0, 4)
+                .is_err());
+            assert!(cls.host_capabilities().reads_timestamp);
+
+            UsbClass::<EmulatedUsbBus>::reset(&mut cls);
+            assert_eq!(cls.host_capabilities(), HostCapabilities::default());
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_bit_timing_ext_not_advertised() {
+    TestCtx {
+        features: Some(Feature::FD),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert!(dev
+            .control_read(&mut cls, CtrRequestType::to_host().vendor(), 11, 0, 0, 72)
+            .is_err());
+        assert_eq!(
+            cls.last_rejection().map(|r| (r.request, r.reason)),
+            Some((11, RejectReason::UnsupportedFeature))
+        );
+    })
+    .expect("with_usb")
+}
+
+/// Device relying on the default extended bit timing.
+#[cfg(feature = "fd")]
+struct NominalOnlyDevice;
+
+#[cfg(feature = "fd")]
+impl Device for NominalOnlyDevice {
+    fn config(&self) -> DeviceConfig {
+        DeviceConfig::new(1)
+    }
+
+    fn bit_timing(&self) -> DeviceBitTimingConst {
+        DeviceBitTimingConst {
+            features: Feature::BT_CONST_EXT,
+            fclk_can: 80_000_000,
+            timing: TIMING_NOMINAL,
+        }
+    }
+
+    fn reset(&mut self, _channel: Channel) {}
+
+    fn start(
+        &mut self,
+        _channel: Channel,
+        _features: Feature,
+        _nominal: &DeviceBitTiming,
+        _data: Option<&DeviceBitTiming>,
+    ) {
+    }
+
+    fn state(&self, _channel: Channel) -> DeviceState {
+        DeviceState {
+            state: CanState::Active,
+            rx_errors: 0,
+            tx_errors: 0,
+        }
+    }
+
+    fn receive(&mut self, _channel: Channel, _frame: &Frame) -> nb::Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fd")]
+struct NominalOnlyTestCtx {}
+
+#[cfg(feature = "fd")]
+impl UsbDeviceCtx for NominalOnlyTestCtx {
+    type C<'c> = GsCan<'c, EmulatedUsbBus, NominalOnlyDevice>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<Self::C<'a>> {
+        Ok(GsCan::new(alloc, NominalOnlyDevice))
+    }
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_bit_timing_ext_default() {
+    NominalOnlyTestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let nominal = dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 4, 0, 0, 40)
+                .unwrap();
+            let ext = dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 11, 0, 0, 72)
+                .unwrap();
+
+            // the nominal options for both phases.
+            assert_eq!(ext.len(), 72);
+            assert_eq!(ext[..40], nominal);
+            assert_eq!(ext[40..], nominal[8..]);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_software_version() {
+    fn read_version<'a, X>(
+        dev: &mut usbd_class_tester::Device<'a, GsCan<'a, EmulatedUsbBus, MockCanDevice>, X>,
+        cls: &mut GsCan<'a, EmulatedUsbBus, MockCanDevice>,
+    ) -> u32
+    where
+        X: UsbDeviceCtx<C<'a> = GsCan<'a, EmulatedUsbBus, MockCanDevice>>,
+    {
+        let config = dev
+            .control_read(cls, CtrRequestType::to_host().vendor(), 5, 0, 0, 12)
+            .unwrap();
+        DeviceConfig::read_from(&config[..])
+            .unwrap()
+            .software_version
+    }
+
+    TestCtx {
+        features: Some(Feature::ONE_SHOT),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert_eq!(read_version(&mut dev, &mut cls), 2);
+    })
+    .expect("with_usb");
+
+    // advertised timestamps don't raise the version, as the class doesn't
+    // fill them in, the application may still set it.
+    TestCtx {
+        features: Some(Feature::ONE_SHOT | Feature::HW_TIMESTAMP),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert_eq!(read_version(&mut dev, &mut cls), SOFTWARE_VERSION);
+
+        let mut cls = cls.with_software_version(3);
+        assert_eq!(read_version(&mut dev, &mut cls), 3);
+    })
+    .expect("with_usb");
+
+    // nor do timestamps recommended for a known device.
+    TestCtx {
+        features: Some(Feature::ONE_SHOT),
+        known_device: Some(KnownDevice {
+            features: Feature::HW_TIMESTAMP,
+            ..*identifier::lookup(identifier::CANDLELIGHT).unwrap()
+        }),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert_eq!(read_version(&mut dev, &mut cls), 2);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_known_device() {
+    let known = KnownDevice {
+        features: Feature::IDENTIFY,
+        pad_packets: true,
+        max_channels: 2,
+        ..*identifier::lookup(identifier::CANDLELIGHT).unwrap()
+    };
+
+    TestCtx {
+        features: Some(Feature::ONE_SHOT),
+        known_device: Some(known),
+        ..Default::default()
+    }
+    .with_usb(|cls, _dev| {
+        let features = Feature::ONE_SHOT | Feature::IDENTIFY | Feature::PAD_PKTS_TO_MAX_PKT_SIZE;
+        assert_eq!(cls.advertised_features().bits(), features.bits());
+    })
+    .expect("with_usb")
+}
+
+#[test]
+#[should_panic(expected = "more channels than the identifier supports")]
+fn test_known_device_too_many_channels() {
+    TestCtx {
+        known_device: identifier::lookup(identifier::CES_CANEXT_FD).copied(),
+        ..Default::default()
+    }
+    .with_usb(|_cls, _dev| {})
+    .ok();
+}
+
+#[test]
+fn test_advertised_device_info() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(cls.advertised_features().bits(), ALL_FEATURES.bits());
+            assert_eq!(cls.can_clock(), 80_000_000);
+            assert_eq!(cls.device_config().interface_count, 1);
+
+            // matches what the host reads.
+            let data = dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 4, 0, 0, 8)
+                .unwrap();
+            assert_eq!(data[..4], cls.advertised_features().bits().to_le_bytes());
+            assert_eq!(data[4..], cls.can_clock().to_le_bytes());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_set_can_clock() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            assert!(cls.set_can_clock(40_000_000));
+            assert_eq!(cls.can_clock(), 40_000_000);
+
+            #[cfg(feature = "fd")]
+            let requests = [4, 11];
+            #[cfg(not(feature = "fd"))]
+            let requests = [4];
+            for request in requests {
+                let data = dev
+                    .control_read(
+                        &mut cls,
+                        CtrRequestType::to_host().vendor(),
+                        request,
+                        0,
+                        0,
+                        8,
+                    )
+                    .unwrap();
+                assert_eq!(
+                    data[4..],
+                    40_000_000u32.to_le_bytes(),
+                    "request {}",
+                    request
+                );
+            }
+
+            // the host already calculates timings from the clock it read.
+            assert!(!cls.set_can_clock(60_000_000));
+            assert_eq!(cls.can_clock(), 40_000_000);
+
+            // until enumerated again.
+            UsbClass::<EmulatedUsbBus>::reset(&mut cls);
+            assert!(cls.set_can_clock(60_000_000));
+            let data = dev
+                .control_read(&mut cls, CtrRequestType::to_host().vendor(), 4, 0, 0, 8)
+                .unwrap();
+            assert_eq!(data[4..], 60_000_000u32.to_le_bytes());
+        })
+        .expect("with_usb")
+}
+
+/// Send a mode request to the device.
+fn set_mode<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    channel: u16,
+    mode: u32,
+) where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    set_mode_flags(dev, cls, channel, mode, Feature::empty());
+}
+
+/// Send an identify request to the device.
+fn identify<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    channel: u16,
+    mode: u32,
+) -> AnyResult<Vec<u8>>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    dev.control_write(
+        cls,
+        CtrRequestType::to_device().vendor(),
+        7,
+        channel,
+        0,
+        4,
+        &mode.to_le_bytes(),
+    )
+}
+
+/// Send a mode request with feature flags to the device.
+///
+/// Like the Linux driver, the nominal timing is configured before starting.
+fn set_mode_flags<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    channel: u16,
+    mode: u32,
+    flags: Feature,
+) where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    if mode == 1 {
+        set_timing(dev, cls, 1, channel, &NOMINAL_TIMING).unwrap();
+    }
+    try_set_mode(dev, cls, channel, mode, flags).unwrap();
+}
+
+std::thread_local! {
+    /// Mode flags and frame sizes checked by `test_wire_format`.
+    static WIRE_FORMAT: std::cell::Cell<(Feature, usize, usize)> =
+        const { std::cell::Cell::new((Feature::empty(), 0, 0)) };
+}
+
+/// Frame sizes on the wire for each mode the host can start a channel in.
+#[test]
+fn test_wire_format() {
+    // (mode flags, bytes from the host, bytes to the host)
+    let cases = [
+        (Feature::empty(), 20, 20),
+        (Feature::HW_TIMESTAMP, 20, 24),
+        #[cfg(feature = "fd")]
+        (Feature::FD, 76, 76),
+        #[cfg(feature = "fd")]
+        (Feature::FD | Feature::HW_TIMESTAMP, 76, 80),
+    ];
+
+    for case in cases {
+        WIRE_FORMAT.set(case);
+        TestCtx::default()
+            .with_usb(|mut cls, mut dev| {
+                let (flags, out_len, in_len) = WIRE_FORMAT.get();
+                #[cfg(feature = "fd")]
+                set_timing(&mut dev, &mut cls, 10, 0, &DATA_TIMING).unwrap();
+                set_mode_flags(&mut dev, &mut cls, 0, 1, flags);
+
+                // from the host, in two packets when longer than one.
+                let mut frame = classic_frame(1);
+                if flags.contains(Feature::FD) {
+                    frame.flags = FrameFlag::FD;
+                }
+                let mut echo = Vec::new();
+                for packet in frame.as_bytes()[..out_len].chunks(64) {
+                    echo = host_write(&mut dev, &mut cls, packet);
+                }
+                assert_eq!(cls.device.received.len(), 1, "{:?}", flags);
+                assert_eq!(echo.len(), in_len, "echo with {:?}", flags);
+                assert_eq!(parse_frame(&echo).can_id, 1);
+
+                // to the host.
+                cls.transmit(CHANNEL0, &classic_frame(2), FrameFlag::empty());
+                cls.kick();
+                let written = dev.ep_read(&mut cls, 1, 256).unwrap();
+                assert_eq!(written.len(), in_len, "frame with {:?}", flags);
+                assert_eq!(parse_frame(&written).can_id, 2);
+            })
+            .expect("with_usb");
+    }
+}
+
+/// Configure the data phase timing and start channel 0 in FD mode.
+#[cfg(feature = "fd")]
+fn start_fd<'a, C, X>(dev: &mut usbd_class_tester::Device<'a, C, X>, cls: &mut C)
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    set_timing(dev, cls, 10, 0, &DATA_TIMING).unwrap();
+    set_mode_flags(dev, cls, 0, 1, Feature::FD);
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_channel_features() {
+    TestCtx::default()
+        .with_usb(|cls, mut dev| {
+            let classic = Feature::ONE_SHOT | Feature::LISTEN_ONLY;
+            let mut cls = cls.with_channel_features(CHANNEL1, classic);
+            assert_eq!(cls.channel_features(CHANNEL0).bits(), ALL_FEATURES.bits());
+            assert_eq!(cls.channel_features(CHANNEL1).bits(), classic.bits());
+
+            // an FD start on the classic only channel is refused.
+            set_timing(&mut dev, &mut cls, 1, 1, &NOMINAL_TIMING).unwrap();
+            set_timing(&mut dev, &mut cls, 10, 1, &DATA_TIMING).unwrap();
+            assert!(try_set_mode(&mut dev, &mut cls, 1, 1, Feature::FD).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason)),
+                Some((2, RejectReason::UnsupportedFeature))
+            );
+            assert!(!cls.is_started(CHANNEL1));
+
+            try_set_mode(&mut dev, &mut cls, 1, 1, Feature::ONE_SHOT).unwrap();
+            assert!(cls.is_started(CHANNEL1));
+            start_fd(&mut dev, &mut cls);
+            assert!(cls.is_started(CHANNEL0));
+
+            // as is identifying it.
+            assert!(identify(&mut dev, &mut cls, 1, 1).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason)),
+                Some((7, RejectReason::UnsupportedFeature))
+            );
+            identify(&mut dev, &mut cls, 0, 1).unwrap();
+        })
+        .expect("with_usb")
+}
+
+/// Send a set termination request to the device.
+fn set_termination<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    channel: u16,
+    state: u32,
+) -> AnyResult<Vec<u8>>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    dev.control_write(
+        cls,
+        CtrRequestType::to_device().vendor(),
+        12,
+        channel,
+        0,
+        4,
+        &state.to_le_bytes(),
+    )
+}
+
+/// Send a get termination request to the device.
+fn get_termination<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    channel: u16,
+) -> AnyResult<Vec<u8>>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    dev.control_read(cls, CtrRequestType::to_host().vendor(), 13, channel, 0, 4)
+}
+
+#[test]
+fn test_termination() {
+    TestCtx::default()
+        .with_usb(|cls, mut dev| {
+            let mut cls =
+                cls.with_channel_features(CHANNEL1, Feature::all() - Feature::TERMINATION);
+
+            set_termination(&mut dev, &mut cls, 0, 1).unwrap();
+            assert_eq!(cls.device.terminations, [(CHANNEL0, true)]);
+            assert_eq!(
+                get_termination(&mut dev, &mut cls, 0).unwrap(),
+                [1, 0, 0, 0]
+            );
+            set_termination(&mut dev, &mut cls, 0, 0).unwrap();
+            assert_eq!(
+                get_termination(&mut dev, &mut cls, 0).unwrap(),
+                [0, 0, 0, 0]
+            );
+
+            // the channel without termination refuses both.
+            assert!(set_termination(&mut dev, &mut cls, 1, 1).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason)),
+                Some((12, RejectReason::UnsupportedFeature))
+            );
+            assert!(get_termination(&mut dev, &mut cls, 1).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason)),
+                Some((13, RejectReason::UnsupportedFeature))
+            );
+            assert_eq!(cls.device.terminations.len(), 2);
+
+            assert!(set_termination(&mut dev, &mut cls, 2, 1).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| r.reason),
+                Some(RejectReason::InvalidChannel)
+            );
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_termination_not_advertised() {
+    TestCtx {
+        features: Some(ALL_FEATURES - Feature::TERMINATION),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert!(set_termination(&mut dev, &mut cls, 0, 1).is_err());
+        assert!(get_termination(&mut dev, &mut cls, 0).is_err());
+        assert!(cls.device.terminations.is_empty());
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_forced_features() {
+    TestCtx::default()
+        .with_usb(|cls, mut dev| {
+            let forced = Feature::LISTEN_ONLY | Feature::HW_TIMESTAMP;
+            let mut cls = cls
+                .with_forced_features(CHANNEL1, forced)
+                .with_channel_features(CHANNEL1, Feature::all() - Feature::ONE_SHOT);
+            // the frame format is the host's.
+            assert_eq!(
+                cls.forced_features(CHANNEL1).bits(),
+                Feature::LISTEN_ONLY.bits()
+            );
+            assert!(cls.forced_features(CHANNEL0).is_empty());
+
+            // a normal start comes up listen only.
+            set_mode_flags(&mut dev, &mut cls, 1, 1, Feature::empty());
+            assert_eq!(
+                cls.device.start_features.last().map(|f| f.bits()),
+                Some(Feature::LISTEN_ONLY.bits())
+            );
+            assert_eq!(
+                cls.channel_info(CHANNEL1).features.bits(),
+                Feature::LISTEN_ONLY.bits()
+            );
+            set_mode_flags(&mut dev, &mut cls, 0, 1, Feature::empty());
+            assert_eq!(cls.device.start_features.last().map(|f| f.bits()), Some(0));
+
+            // the device sees the forced features when validating.
+            assert!(try_set_mode(&mut dev, &mut cls, 1, 1, Feature::LOOP_BACK).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| r.reason),
+                Some(RejectReason::DeviceRejected)
+            );
+            // and features removed from the channel are refused.
+            assert!(try_set_mode(&mut dev, &mut cls, 1, 1, Feature::ONE_SHOT).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| r.reason),
+                Some(RejectReason::UnsupportedFeature)
+            );
+            assert!(cls.is_started(CHANNEL1));
+        })
+        .expect("with_usb")
+}
+
+/// Read the features of the bit timing constants for a channel.
+fn bt_const_features<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    channel: u16,
+) -> u32
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    let bt_const = dev
+        .control_read(cls, CtrRequestType::to_host().vendor(), 4, channel, 0, 40)
+        .unwrap();
+    u32::from_le_bytes(bt_const[..4].try_into().unwrap())
+}
+
+#[test]
+fn test_forced_off_features() {
+    TestCtx::default()
+        .with_usb(|cls, mut dev| {
+            let mut cls = cls
+                .with_forced_off_features(CHANNEL1, Feature::ONE_SHOT)
+                .with_forced_features(CHANNEL0, Feature::LISTEN_ONLY)
+                .with_forced_off_features(CHANNEL0, Feature::LISTEN_ONLY);
+            assert_eq!(
+                cls.forced_off_features(CHANNEL1).bits(),
+                Feature::ONE_SHOT.bits()
+            );
+
+            // masked from the features read for the channel.
+            let advertised = cls.advertised_features();
+            assert_eq!(
+                bt_const_features(&mut dev, &mut cls, 1),
+                (advertised - Feature::ONE_SHOT).bits()
+            );
+            assert_eq!(
+                bt_const_features(&mut dev, &mut cls, 0),
+                (advertised - Feature::LISTEN_ONLY).bits()
+            );
+            assert_eq!(bt_const_features(&mut dev, &mut cls, 7), advertised.bits());
+
+            // a start requesting a feature forced off is refused.
+            assert!(try_set_mode(&mut dev, &mut cls, 1, 1, Feature::ONE_SHOT).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| r.reason),
+                Some(RejectReason::FeatureForcedOff)
+            );
+            assert!(!cls.is_started(CHANNEL1));
+            set_mode_flags(&mut dev, &mut cls, 1, 1, Feature::LOOP_BACK);
+            assert!(cls.is_started(CHANNEL1));
+
+            // as is every start of a channel forced both on and off.
+            assert!(try_set_mode(&mut dev, &mut cls, 0, 1, Feature::empty()).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| r.reason),
+                Some(RejectReason::FeatureForcedOff)
+            );
+            assert!(!cls.is_started(CHANNEL0));
+            assert_eq!(cls.device.modes, [("start", CHANNEL1)]);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_default_termination() {
+    TestCtx::default()
+        .with_usb(|cls, mut dev| {
+            let mut cls = cls
+                .with_default_termination(CHANNEL0, true)
+                .with_default_termination(CHANNEL1, true);
+
+            set_mode(&mut dev, &mut cls, 0, 1);
+            assert_eq!(cls.device.terminations, [(CHANNEL0, true)]);
+
+            // the host's choice is kept.
+            set_termination(&mut dev, &mut cls, 1, 0).unwrap();
+            set_mode(&mut dev, &mut cls, 1, 1);
+            assert_eq!(
+                cls.device.terminations,
+                [(CHANNEL0, true), (CHANNEL1, false)]
+            );
+
+            // until the bus reset.
+            cls.reset();
+            set_mode(&mut dev, &mut cls, 1, 1);
+            assert_eq!(cls.device.terminations.last(), Some(&(CHANNEL1, true)));
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_default_termination_not_advertised() {
+    TestCtx {
+        features: Some(ALL_FEATURES - Feature::TERMINATION),
+        ..Default::default()
+    }
+    .with_usb(|cls, mut dev| {
+        let mut cls = cls.with_default_termination(CHANNEL0, true);
+        set_mode(&mut dev, &mut cls, 0, 1);
+        assert!(cls.is_started(CHANNEL0));
+        assert!(cls.device.terminations.is_empty());
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_channel_info() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let info = cls.channel_info(CHANNEL1);
+            assert!(!info.started);
+            assert!(info.nominal_timing.is_none());
+            assert!(info.nominal_bitrate.is_none());
+
+            set_mode_flags(&mut dev, &mut cls, 1, 1, Feature::LISTEN_ONLY);
+            let info = cls.channel_info(CHANNEL1);
+            assert!(info.started);
+            assert_eq!(info.features.bits(), Feature::LISTEN_ONLY.bits());
+            assert_eq!(info.nominal_timing, Some(NOMINAL_TIMING));
+            assert_eq!(info.nominal_bitrate, Some(500_000));
+            assert!(info.data_timing.is_none());
+            assert!(info.data_bitrate.is_none());
+
+            #[cfg(feature = "fd")]
+            {
+                start_fd(&mut dev, &mut cls);
+                let info = cls.channel_info(CHANNEL0);
+                assert!(info.started);
+                assert_eq!(info.features.bits(), Feature::FD.bits());
+                assert_eq!(info.data_timing, Some(DATA_TIMING));
+                assert_eq!(info.nominal_bitrate, Some(500_000));
+                assert_eq!(info.data_bitrate, Some(2_000_000));
+            }
+
+            // the timing is kept once reset.
+            set_mode(&mut dev, &mut cls, 1, 0);
+            let info = cls.channel_info(CHANNEL1);
+            assert!(!info.started);
+            assert!(info.features.is_empty());
+            assert_eq!(info.nominal_bitrate, Some(500_000));
+        })
+        .expect("with_usb")
+}
+
+/// Send a nominal (1) or data phase (10) timing request to the device.
+fn set_timing<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    request: u8,
+    channel: u16,
+    timing: &DeviceBitTiming,
+) -> AnyResult<Vec<u8>>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    dev.control_write(
+        cls,
+        CtrRequestType::to_device().vendor(),
+        request,
+        channel,
+        0,
+        20,
+        timing.as_bytes(),
+    )
+}
+
+/// Send a mode request to the device, returning an error if it is rejected.
+fn try_set_mode<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    channel: u16,
+    mode: u32,
+    flags: Feature,
+) -> AnyResult<Vec<u8>>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    let mut data = mode.to_le_bytes().to_vec();
+    data.extend_from_slice(&flags.bits().to_le_bytes());
+    dev.control_write(
+        cls,
+        CtrRequestType::to_device().vendor(),
+        2,
+        channel,
+        0,
+        8,
+        &data,
+    )
+}
+
+/// Replay a setup packet as sent by a host, with the data stage of a write.
+fn replay<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    setup: [u8; 8],
+    data: &[u8],
+) -> AnyResult<Vec<u8>>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    let reqt = CtrRequestType::from(setup[0]);
+    let value = u16::from_le_bytes([setup[2], setup[3]]);
+    let index = u16::from_le_bytes([setup[4], setup[5]]);
+    let length = u16::from_le_bytes([setup[6], setup[7]]);
+    if setup[0] & 0x80 != 0 {
+        dev.control_read(cls, reqt, setup[1], value, index, length)
+    } else {
+        dev.control_write(cls, reqt, setup[1], value, index, length, data)
+    }
+}
+
+/// Start channel 1 and read its state with the requests of a host.
+fn replay_start<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    setup: [[u8; 8]; 4],
+) -> RawDeviceState
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    let mut mode = 1_u32.to_le_bytes().to_vec();
+    mode.extend_from_slice(&0_u32.to_le_bytes());
+
+    replay(dev, cls, setup[0], &0x0000beef_u32.to_le_bytes()).unwrap();
+    replay(dev, cls, setup[1], NOMINAL_TIMING.as_bytes()).unwrap();
+    replay(dev, cls, setup[2], &mode).unwrap();
+    let state = replay(dev, cls, setup[3], &[]).unwrap();
+    RawDeviceState::read_from(&state[..]).unwrap()
+}
+
+/// Setup packets as sent by the Linux driver.
+#[test]
+fn test_requests_linux() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            #[rustfmt::skip]
+            let state = replay_start(&mut dev, &mut cls, [
+                // host format, 1 in wValue and the interface in wIndex.
+                [0x41, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00],
+                // bit timing, mode and state with the channel in wValue.
+                [0x41, 0x01, 0x01, 0x00, 0x00, 0x00, 0x14, 0x00],
+                [0x41, 0x02, 0x01, 0x00, 0x00, 0x00, 0x08, 0x00],
+                [0xc1, 0x0e, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00],
+            ]);
+
+            assert!(cls.is_started(CHANNEL1));
+            assert_eq!(cls.device.modes, [("start", CHANNEL1)]);
+            assert_eq!(state.rx_errors, 1);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_requests_channel_in_index() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            #[rustfmt::skip]
+            let state = replay_start(&mut dev, &mut cls, [
+                // as the Linux driver.
+                [0x41, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00],
+                [0x41, 0x01, 0x01, 0x00, 0x00, 0x00, 0x14, 0x00],
+                [0x41, 0x02, 0x01, 0x00, 0x00, 0x00, 0x08, 0x00],
+                // a tool with the channel in wIndex of a request to the device.
+                [0xc0, 0x0e, 0x00, 0x00, 0x01, 0x00, 0x0c, 0x00],
+            ]);
+
+            assert!(cls.is_started(CHANNEL1));
+            assert_eq!(state.rx_errors, 1);
+
+            // the channel in wValue takes precedence.
+            let state = replay(
+                &mut dev,
+                &mut cls,
+                [0xc0, 0x0e, 0x01, 0x00, 0x02, 0x00, 0x0c, 0x00],
+                &[],
+            )
+            .unwrap();
+            assert_eq!(RawDeviceState::read_from(&state[..]).unwrap().rx_errors, 1);
+
+            // the channel of the original requests is only read from wValue.
+            replay(
+                &mut dev,
+                &mut cls,
+                [0x40, 0x02, 0x00, 0x00, 0x01, 0x00, 0x08, 0x00],
+                &[0; 8],
+            )
+            .unwrap();
+            assert!(cls.is_started(CHANNEL1));
+            assert!(!cls.is_started(CHANNEL0));
+        })
+        .expect("with_usb")
+}
+
+/// Replay a startup handshake on channel 0, with the data each request
+/// carries.
+fn handshake<'a, C, X>(
+    dev: &mut usbd_class_tester::Device<'a, C, X>,
+    cls: &mut C,
+    setup: &[[u8; 8]],
+) where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    let mut mode = 1_u32.to_le_bytes().to_vec();
+    mode.extend_from_slice(&0_u32.to_le_bytes());
+
+    for setup in setup {
+        let data = match setup[1] {
+            0 => 0x0000beef_u32.to_le_bytes().to_vec(),
+            1 => NOMINAL_TIMING.as_bytes().to_vec(),
+            2 => mode.clone(),
+            _ => Vec::new(),
+        };
+        let read = replay(dev, cls, *setup, &data).unwrap();
+        // every read is answered in full, whatever came before.
+        if setup[0] & 0x80 != 0 {
+            assert_eq!(read.len(), usize::from(setup[6]), "request {}", setup[1]);
+        }
+    }
+}
+
+/// The state every handshake ends in.
+fn assert_handshake_state<'a, X>(
+    dev: &mut usbd_class_tester::Device<'a, GsCan<'a, EmulatedUsbBus, MockCanDevice>, X>,
+    cls: &mut GsCan<'a, EmulatedUsbBus, MockCanDevice>,
+) where
+    X: UsbDeviceCtx<C<'a> = GsCan<'a, EmulatedUsbBus, MockCanDevice>>,
+{
+    assert!(cls.is_started(CHANNEL0));
+    assert!(!cls.is_started(CHANNEL1));
+    assert_eq!(cls.device.modes, [("start", CHANNEL0)]);
+    assert_eq!(cls.device.start_timing, [(NOMINAL_TIMING, None)]);
+    assert_eq!(cls.device.start_features.len(), 1);
+    assert!(cls.device.start_features[0].is_empty());
+    assert!(cls.last_rejection().is_none());
+
+    let config = dev
+        .control_read(cls, CtrRequestType::to_host().vendor(), 5, 0, 0, 12)
+        .unwrap();
+    let expected = DeviceConfig::new(2);
+    assert_eq!(config, expected.as_bytes());
+
+    // frames pass in the little endian default.
+    let echo = host_write(dev, cls, &host_frame_bytes(0x123));
+    assert_eq!(parse_frame(&echo).can_id, 0x123);
+    assert_eq!(cls.device.received.len(), 1);
+}
+
+/// Setup packets of the Linux driver: host format, device config, timing
+/// constants, then the bit timing and mode once the channel is opened.
+#[test]
+fn test_handshake_linux() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            #[rustfmt::skip]
+            handshake(&mut dev, &mut cls, &[
+                [0x41, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00],
+                [0xc1, 0x05, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00],
+                [0xc1, 0x04, 0x00, 0x00, 0x00, 0x00, 0x28, 0x00],
+                [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00],
+                [0x41, 0x02, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00],
+            ]);
+            assert_handshake_state(&mut dev, &mut cls);
+        })
+        .expect("with_usb")
+}
+
+/// Setup packets of candle.dll, reading the device config first.
+#[test]
+fn test_handshake_candle() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            #[rustfmt::skip]
+            handshake(&mut dev, &mut cls, &[
+                [0xc1, 0x05, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00],
+                [0xc1, 0x04, 0x00, 0x00, 0x00, 0x00, 0x28, 0x00],
+                [0x41, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00],
+                [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00],
+                [0x41, 0x02, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00],
+            ]);
+            assert_handshake_state(&mut dev, &mut cls);
+        })
+        .expect("with_usb")
+}
+
+/// Setup packets of python-can, which never sends the host format.
+#[test]
+fn test_handshake_python_can() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            #[rustfmt::skip]
+            handshake(&mut dev, &mut cls, &[
+                [0xc1, 0x04, 0x00, 0x00, 0x00, 0x00, 0x28, 0x00],
+                [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00],
+                [0x41, 0x02, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00],
+            ]);
+            assert_handshake_state(&mut dev, &mut cls);
+        })
+        .expect("with_usb")
+}
+
+/// Read every response of the device, asking for `length` bytes or exactly
+/// the length of each if `None`.
+fn read_responses<'a, X>(
+    dev: &mut usbd_class_tester::Device<'a, GsCan<'a, EmulatedUsbBus, MockCanDevice>, X>,
+    cls: &mut GsCan<'a, EmulatedUsbBus, MockCanDevice>,
+    length: Option<u16>,
+) where
+    X: UsbDeviceCtx<C<'a> = GsCan<'a, EmulatedUsbBus, MockCanDevice>>,
+{
+    let expected = [
+        (5, cls.device_config().as_bytes().to_vec()),
+        (4, cls.device.bit_timing().as_bytes().to_vec()),
+        #[cfg(feature = "fd")]
+        (11, cls.device.bit_timing_ext().as_bytes().to_vec()),
+    ];
+    for (request, bytes) in expected {
+        let length = length.unwrap_or(bytes.len() as u16);
+        let read = dev
+            .control_read(
+                cls,
+                CtrRequestType::to_host().vendor(),
+                request,
+                0,
+                0,
+                length,
+            )
+            .unwrap();
+        assert_eq!(read, bytes, "request {}", request);
+    }
+}
+
+/// The largest response, the 72 bytes of extended timing, over control
+/// endpoints down to 8 bytes.
+#[test]
+fn test_control_in_ep0_sizes() {
+    fn check<'a>(
+        mut cls: GsCan<'a, EmulatedUsbBus, MockCanDevice>,
+        mut dev: usbd_class_tester::Device<'a, GsCan<'a, EmulatedUsbBus, MockCanDevice>, TestCtx>,
+    ) {
+        // exactly the length of each response, and more as hosts ask.
+        read_responses(&mut dev, &mut cls, None);
+        read_responses(&mut dev, &mut cls, Some(u16::MAX));
+    }
+
+    for size in [8, 16, 32, 64] {
+        TestCtx {
+            ep0_size: Some(size),
+            ..Default::default()
+        }
+        .with_usb(check)
+        .expect("with_usb");
+    }
+}
+
+#[test]
+fn test_mode_double_start() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_mode(&mut dev, &mut cls, 0, 1);
+            assert!(cls.is_started(CHANNEL0));
+            set_mode(&mut dev, &mut cls, 0, 1);
+            assert!(cls.is_started(CHANNEL0));
+
+            assert_eq!(
+                cls.device.modes,
+                [
+                    ("start", CHANNEL0),
+                    ("reset", CHANNEL0),
+                    ("start", CHANNEL0)
+                ]
+            );
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_mode_spurious_reset() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_mode(&mut dev, &mut cls, 0, 0);
+            assert!(cls.device.modes.is_empty());
+
+            set_mode(&mut dev, &mut cls, 0, 1);
+            set_mode(&mut dev, &mut cls, 0, 0);
+            set_mode(&mut dev, &mut cls, 0, 0);
+            assert!(!cls.is_started(CHANNEL0));
+            assert_eq!(cls.device.modes, [("start", CHANNEL0), ("reset", CHANNEL0)]);
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_reset_mid_frame() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+
+            // first half of an FD frame from the host.
+            let mut fd = Frame::new_raw(0x7, &[0xAA; 64]).unwrap();
+            fd.flags = FrameFlag::FD;
+            host_write(&mut dev, &mut cls, &fd.as_bytes()[..64]);
+
+            // first half of a frame to the host.
+            cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+
+            UsbClass::<EmulatedUsbBus>::reset(&mut cls);
+            assert_eq!(cls.split_frames_dropped(), 2);
+            assert!(!cls.is_started(CHANNEL0));
+
+            // the bus reset flushes the endpoint, the emulated bus doesn't.
+            dev.ep_read(&mut cls, 1, 64).unwrap();
+            dev.setup(&mut cls).unwrap();
+
+            // both directions resume at a frame boundary.
+            let echo = parse_frame(&host_write(&mut dev, &mut cls, &host_frame_bytes(2)));
+            assert_eq!(echo.can_id, 2);
+            assert_eq!(echo.data(), [0x01, 0x02, 0x03, 0x04]);
+
+            let ids: Vec<u32> = cls.device.received.iter().map(|f| f.can_id).collect();
+            assert_eq!(ids, [2]);
+        })
+        .expect("with_usb")
+}
+
+// frames only queue behind a frame split across packets.
+#[cfg(feature = "fd")]
+#[test]
+fn test_idle_to_host() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+            assert!(cls.is_idle());
+
+            cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+            assert!(!cls.is_idle());
+            cls.transmit(CHANNEL0, &classic_frame(2), FrameFlag::empty());
+
+            // first frame split across packets, second queued.
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            assert!(!cls.is_idle());
+
+            // first frame complete, second still queued.
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            assert!(!cls.is_idle());
+
+            // second frame split across packets.
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            assert!(!cls.is_idle());
+
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            assert!(cls.is_idle());
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_transmit_between_packets() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+
+            // transmit only queues, the endpoint is written when polled.
+            cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::empty());
+            assert!(cls.needs_poll());
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+
+            // a CAN interrupt between the packets of the first frame.
+            cls.transmit(CHANNEL0, &classic_frame(2), FrameFlag::empty());
+
+            for _ in 0..4 {
+                UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            }
+            assert!(cls.is_idle());
+            assert!(!cls.needs_poll());
+
+            // each frame is written whole.
+            let written = dev.ep_read(&mut cls, 1, 256).unwrap();
+            assert_eq!(written.len(), 2 * FD_FRAME_LEN);
+            let (first, second) = written.split_at(FD_FRAME_LEN);
+            assert_eq!(parse_frame(first).can_id, 1);
+            assert_eq!(parse_frame(first).data(), [0x01, 0x02, 0x03, 0x04]);
+            assert_eq!(parse_frame(second).can_id, 2);
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_idle_split_from_host() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+
+            let mut fd = Frame::new_raw(0x7, &[0xAA; 64]).unwrap();
+            fd.flags = FrameFlag::FD;
+            host_write(&mut dev, &mut cls, &fd.as_bytes()[..64]);
+            assert!(!cls.is_idle());
+
+            host_write(&mut dev, &mut cls, &fd.as_bytes()[64..]);
+            assert!(cls.is_idle());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_idle_held_from_host() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.device.busy = true;
+            host_write(&mut dev, &mut cls, &host_frame_bytes(1));
+            assert!(!cls.is_idle());
+
+            cls.device.busy = false;
+            cls.retry_receive();
+            UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+            assert_eq!(
+                parse_frame(&dev.ep_read(&mut cls, 1, 128).unwrap()).can_id,
+                1
+            );
+            assert!(cls.is_idle());
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_idle_queued_from_host() {
+    QueuedTestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            host_write(&mut dev, &mut cls, &host_frame_bytes(1));
+            assert!(!cls.is_idle());
+
+            cls.dequeue_host_frame().unwrap();
+            assert!(cls.is_idle());
+        })
+        .expect("with_usb")
+}
+
+/// Classic frame as sent by the Linux driver with the LPC546xx quirk.
+const QUIRK_CLASSIC_FRAME: [u8; 21] = [
+    0x05, 0x00, 0x00, 0x00, // echo_id
+    0x23, 0x01, 0x00, 0x00, // can_id
+    0x02, 0x00, 0x00, 0x00, // can_dlc, channel, flags, reserved
+    0xDE, 0xAD, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // data
+    0x00, // quirk
+];
+
+#[test]
+fn test_lpc546xx_quirk() {
+    TestCtx {
+        features: Some(Feature::REQ_USB_QUIRK_LPC546XX),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        let echo = parse_frame(&host_write(&mut dev, &mut cls, &QUIRK_CLASSIC_FRAME));
+        assert_eq!(echo.can_id, 0x123);
+        assert_eq!(echo.data(), [0xDE, 0xAD]);
+        assert_eq!(cls.device.received.len(), 1);
+
+        // an unpadded frame isn't mistaken for a padded one.
+        assert!(host_write(&mut dev, &mut cls, &QUIRK_CLASSIC_FRAME[..20]).is_empty());
+        assert_eq!(cls.device.received.len(), 1);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_lpc546xx_quirk_not_advertised() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            // a padded frame is oversized without the quirk.
+            assert!(host_write(&mut dev, &mut cls, &QUIRK_CLASSIC_FRAME).is_empty());
+            assert!(cls.device.received.is_empty());
+
+            host_write(&mut dev, &mut cls, &QUIRK_CLASSIC_FRAME[..20]);
+            assert_eq!(cls.device.received.len(), 1);
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_lpc546xx_quirk_fd() {
+    TestCtx {
+        features: Some(Feature::FD | Feature::BT_CONST_EXT | Feature::REQ_USB_QUIRK_LPC546XX),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        start_fd(&mut dev, &mut cls);
+
+        // 12 bytes of data with bit rate switching.
+        let mut transfer = vec![
+            0x05, 0x00, 0x00, 0x00, // echo_id
+            0x56, 0x04, 0x00, 0x00, // can_id
+            0x09, 0x00, 0x06, 0x00, // can_dlc, channel, flags, reserved
+        ];
+        transfer.extend([0x11; 12]);
+        transfer.extend([0x00; 52]);
+        transfer.push(0x00); // quirk
+        assert_eq!(transfer.len(), 77);
+
+        assert!(host_write(&mut dev, &mut cls, &transfer[..64]).is_empty());
+        let echo = parse_frame(&host_write(&mut dev, &mut cls, &transfer[64..]));
+        assert_eq!(echo.can_id, 0x456);
+        assert_eq!(echo.data(), [0x11; 12]);
+        assert!(echo.brs());
+
+        let received = cls.device.received.last().unwrap();
+        assert!(received.as_bytes()[FD_FRAME_LEN..].iter().all(|&b| b == 0));
+    })
+    .expect("with_usb")
+}
+
+/// Send a CAN FD frame to the host, returning it as read by the host.
+#[cfg(feature = "fd")]
+fn transmit_fd<'a, X>(
+    dev: &mut usbd_class_tester::Device<'a, GsCan<'a, EmulatedUsbBus, MockCanDevice>, X>,
+    cls: &mut GsCan<'a, EmulatedUsbBus, MockCanDevice>,
+    esi: Option<bool>,
+) -> Frame
+where
+    X: UsbDeviceCtx<C<'a> = GsCan<'a, EmulatedUsbBus, MockCanDevice>>,
+{
+    cls.transmit_fd(CHANNEL0, &classic_frame(1), FrameFlag::empty(), esi);
+    cls.kick();
+    parse_frame(&dev.ep_read(cls, 1, 256).unwrap())
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_error_passive_esi() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+
+            let received = transmit_fd(&mut dev, &mut cls, None);
+            assert!(received.is_fd());
+            assert!(!received.esi());
+
+            cls.set_error_state(CHANNEL0, CanState::Warning);
+            assert!(!transmit_fd(&mut dev, &mut cls, None).esi());
+
+            cls.set_error_state(CHANNEL0, CanState::Passive);
+            assert!(transmit_fd(&mut dev, &mut cls, None).esi());
+            // the caller overrides the channel state.
+            assert!(!transmit_fd(&mut dev, &mut cls, Some(false)).esi());
+
+            // frames from the host are echoed with the channel state.
+            let mut fd = Frame::new_raw(0x7, &[0xAA; 12]).unwrap();
+            fd.flags = FrameFlag::FD;
+            host_write(&mut dev, &mut cls, &fd.as_bytes()[..64]);
+            let echo = parse_frame(&host_write(&mut dev, &mut cls, &fd.as_bytes()[64..76]));
+            assert!(echo.esi());
+            assert!(!cls.device.received.last().unwrap().esi());
+
+            // the controller restarts error active.
+            start_fd(&mut dev, &mut cls);
+            assert!(!transmit_fd(&mut dev, &mut cls, None).esi());
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_transmit_fd_long() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+
+            let mut frame = Frame::new_raw(0x7, &[0xAA; 64]).unwrap();
+            frame.flags = FrameFlag::FD;
+            let flags = FrameFlag::FD | FrameFlag::BIT_RATE_SWITCH;
+            cls.transmit(CHANNEL0, &frame, flags);
+            cls.kick();
+
+            let received = parse_frame(&dev.ep_read(&mut cls, 1, 256).unwrap());
+            assert_eq!(received.flags.bits(), flags.bits());
+            assert_eq!(received.data(), [0xAA; 64]);
+        })
+        .expect("with_usb")
+}
+
+#[cfg(all(feature = "fd", not(feature = "panic-free")))]
+#[test]
+#[should_panic(expected = "InvalidLength")]
+fn test_transmit_classic_long() {
+    TestCtx::default()
+        .with_usb(|mut cls, _dev| {
+            let mut frame = Frame::new_raw(0x7, &[0xAA; 12]).unwrap();
+            frame.flags = FrameFlag::FD;
+            cls.transmit(CHANNEL0, &frame, FrameFlag::empty());
+        })
+        .ok();
+}
+
+#[cfg(all(feature = "fd", feature = "panic-free"))]
+#[test]
+fn test_transmit_invalid_dropped() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let mut frame = Frame::new_raw(0x7, &[0xAA; 12]).unwrap();
+            frame.flags = FrameFlag::FD;
+            cls.transmit(CHANNEL0, &frame, FrameFlag::empty());
+            cls.transmit(CHANNEL0, &classic_frame(1), FrameFlag::BIT_RATE_SWITCH);
+            assert_eq!(cls.invalid_frames(), 2);
+
+            cls.kick();
+            assert!(read_frames(&mut dev, &mut cls).is_empty());
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_transmit_no_stale_data() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+
+            let mut fd = Frame::new_raw(0x7, &[0xAA; 64]).unwrap();
+            fd.flags = FrameFlag::FD;
+            host_write(&mut dev, &mut cls, &fd.as_bytes()[..64]);
+            host_write(&mut dev, &mut cls, &fd.as_bytes()[64..76]);
+
+            // classic frame with the reserved byte set by the host.
+            let mut classic = classic_frame(2);
+            classic.as_bytes_mut()[11] = 0xAA;
+            let echo = host_write(&mut dev, &mut cls, &classic.as_bytes()[..76]);
+            assert_eq!(echo.len(), FD_FRAME_LEN);
+            assert_eq!(echo[11], 0);
+            assert_eq!(echo[12..16], [0x01, 0x02, 0x03, 0x04]);
+            assert!(echo[16..].iter().all(|&b| b == 0));
+
+            // frames from the device are clean too.
+            cls.transmit_fd(CHANNEL0, &fd, FrameFlag::empty(), None);
+            cls.transmit(CHANNEL0, &classic_frame(3), FrameFlag::empty());
+            cls.kick();
+            let written = dev.ep_read(&mut cls, 1, 256).unwrap();
+            assert_eq!(written.len(), 2 * FD_FRAME_LEN);
+            assert!(written[FD_FRAME_LEN + 16..].iter().all(|&b| b == 0));
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_receive_fd_one_write() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+
+            // both packets read in one callback.
+            let mut fd = Frame::new_raw(0x7, &[0xAA; 64]).unwrap();
+            fd.flags = FrameFlag::FD;
+            let echo = host_write(&mut dev, &mut cls, &fd.as_bytes()[..76]);
+            assert_eq!(echo.len(), FD_FRAME_LEN);
+            assert_eq!(cls.device.received.len(), 1);
+            assert_eq!(cls.device.received[0].data(), [0xAA; 64]);
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_receive_no_stale_data() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+
+            let mut fd = Frame::new_raw(0x7, &[0xAA; 64]).unwrap();
+            fd.flags = FrameFlag::FD;
+            host_write(&mut dev, &mut cls, &fd.as_bytes()[..64]);
+            let echo = host_write(&mut dev, &mut cls, &fd.as_bytes()[64..76]);
+            assert_eq!(parse_frame(&echo).data(), [0xAA; 64]);
+
+            // classic frame in a single short packet.
+            let echo = host_write(&mut dev, &mut cls, &host_frame_bytes(2));
+            assert_eq!(parse_frame(&echo).data(), [0x01, 0x02, 0x03, 0x04]);
+            assert!(!echo.contains(&0xAA));
+
+            let received = cls.device.received.last().unwrap();
+            assert!(received.as_bytes()[16..].iter().all(|&b| b == 0));
+        })
+        .expect("with_usb")
+}
+
+/// A classic frame on channel 0 as python-can's gs_usb backend sends it, in a
+/// CAN FD sized transfer with or without room for a timestamp.
+#[cfg(feature = "fd")]
+fn python_can_frame(len: usize) -> Vec<u8> {
+    #[rustfmt::skip]
+    let mut bytes = vec![
+        0x00, 0x00, 0x00, 0x00, // echo_id
+        0x23, 0x01, 0x00, 0x00, // can_id
+        0x04, 0x00, 0x00, 0x00, // can_dlc, channel, flags, reserved
+        0xde, 0xad, 0xbe, 0xef,
+    ];
+    bytes.resize(len, 0);
+    bytes
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_receive_fd_sized_classic() {
+    TestCtx {
+        fd_sized_classic: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        set_mode(&mut dev, &mut cls, 0, 1);
+
+        for len in [76, 80] {
+            let bytes = python_can_frame(len);
+            host_write(&mut dev, &mut cls, &bytes[..64]);
+            let echo = host_write(&mut dev, &mut cls, &bytes[64..]);
+            assert_eq!(echo.len(), FRAME_LEN);
+            assert_eq!(parse_frame(&echo).data(), [0xde, 0xad, 0xbe, 0xef]);
+        }
+        assert_eq!(cls.device.received.len(), 2);
+        assert_eq!(cls.device.received[1].can_id, 0x123);
+        assert_eq!(cls.device.received[1].data(), [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(cls.normalized_frames(), 2);
+
+        // frames with the FD flag are still refused.
+        let mut bytes = python_can_frame(76);
+        bytes[10] = FrameFlag::FD.bits();
+        host_write(&mut dev, &mut cls, &bytes[..64]);
+        assert!(host_write(&mut dev, &mut cls, &bytes[64..]).is_empty());
+        assert_eq!(cls.device.received.len(), 2);
+        assert_eq!(cls.normalized_frames(), 2);
+        assert_eq!(cls.invalid_host_frames(), 1);
+
+        // classic frames are unaffected.
+        assert_eq!(
+            host_write(&mut dev, &mut cls, &host_frame_bytes(2)).len(),
+            FRAME_LEN
+        );
+        assert_eq!(cls.normalized_frames(), 2);
+    })
+    .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_receive_fd_sized_classic_refused() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_mode(&mut dev, &mut cls, 0, 1);
+
+            let bytes = python_can_frame(76);
+            host_write(&mut dev, &mut cls, &bytes[..64]);
+            assert!(host_write(&mut dev, &mut cls, &bytes[64..]).is_empty());
+            assert!(cls.device.received.is_empty());
+            assert_eq!(cls.invalid_host_frames(), 1);
+            assert_eq!(cls.normalized_frames(), 0);
+
+            // the rest of the transfer isn't taken for a frame.
+            let echo = host_write(&mut dev, &mut cls, &host_frame_bytes(2));
+            assert_eq!(parse_frame(&echo).can_id, 2);
+            assert_eq!(cls.device.received.len(), 1);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_receive_oversized() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_mode(&mut dev, &mut cls, 0, 1);
+
+            // a CAN XL sized transfer, header and 2048 data bytes.
+            let mut bytes = host_frame_bytes(1)[..12].to_vec();
+            bytes.resize(12 + 2048, 0xAA);
+            for chunk in bytes.chunks(1024) {
+                assert!(host_write(&mut dev, &mut cls, chunk).is_empty());
+            }
+            assert_eq!(cls.oversized_transfers(), 1);
+            assert!(cls.device.received.is_empty());
+            assert!(cls.is_idle());
+
+            // none of it is taken for the next frame.
+            let echo = host_write(&mut dev, &mut cls, &host_frame_bytes(2));
+            assert_eq!(parse_frame(&echo).can_id, 2);
+            assert_eq!(cls.device.received.len(), 1);
+            assert_eq!(cls.oversized_transfers(), 1);
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_receive_oversized_fd() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            start_fd(&mut dev, &mut cls);
+
+            // one packet more than an FD frame, ending in a short packet.
+            let mut fd = Frame::new_raw(0x7, &[0xAA; 64]).unwrap();
+            fd.flags = FrameFlag::FD;
+            let mut bytes = fd.as_bytes()[..76].to_vec();
+            bytes.resize(76 + 64, 0xAA);
+            host_write(&mut dev, &mut cls, &bytes[..64]);
+            assert!(host_write(&mut dev, &mut cls, &bytes[64..]).is_empty());
+            assert_eq!(cls.oversized_transfers(), 1);
+            assert!(cls.device.received.is_empty());
+
+            // FD frames are still read whole.
+            host_write(&mut dev, &mut cls, &bytes[..64]);
+            assert!(!host_write(&mut dev, &mut cls, &bytes[64..76]).is_empty());
+            assert_eq!(cls.device.received.len(), 1);
+            assert_eq!(cls.oversized_transfers(), 1);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_receive_length_mismatch() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            // FD frame on a classic channel.
+            let mut fd = Frame::new_raw(0x7, &[0xAA; 8]).unwrap();
+            fd.flags = FrameFlag::FD;
+            assert!(host_write(&mut dev, &mut cls, &fd.as_bytes()[..20]).is_empty());
+
+            // DLC 8 with only 4 data bytes.
+            let mut short = classic_frame(3);
+            short.can_dlc = 8;
+            assert!(host_write(&mut dev, &mut cls, &short.as_bytes()[..16]).is_empty());
+
+            // DLC out of range.
+            let mut invalid = classic_frame(4);
+            invalid.can_dlc = 16;
+            assert!(host_write(&mut dev, &mut cls, &invalid.as_bytes()[..20]).is_empty());
+
+            // bit rate switching on a classic frame.
+            let mut brs = classic_frame(6);
+            brs.flags = FrameFlag::BIT_RATE_SWITCH;
+            assert!(host_write(&mut dev, &mut cls, &brs.as_bytes()[..20]).is_empty());
+
+            assert!(cls.device.received.is_empty());
+
+            // classic DLC above 8 carries 8 bytes.
+            let mut len8 = classic_frame(5);
+            len8.can_dlc = 12;
+            let echo = parse_frame(&host_write(&mut dev, &mut cls, &len8.as_bytes()[..20]));
+            assert_eq!(echo.data().len(), 8);
+            assert_eq!(cls.device.received.len(), 1);
+
+            #[cfg(feature = "fd")]
+            {
+                start_fd(&mut dev, &mut cls);
+
+                // DLC 15 in a classic sized packet.
+                let mut fd = Frame::new_raw(0x7, &[0xAA; 64]).unwrap();
+                fd.flags = FrameFlag::FD;
+                assert!(host_write(&mut dev, &mut cls, &fd.as_bytes()[..20]).is_empty());
+                assert_eq!(cls.device.received.len(), 1);
+            }
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_invalid_host_frames() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let mut invalid_channel = classic_frame(1);
+            invalid_channel.interface = 7;
+            let mut fd = classic_frame(2);
+            fd.flags = FrameFlag::FD;
+            let mut short = classic_frame(3);
+            short.can_dlc = 8;
+
+            for _ in 0..300 {
+                for frame in [&invalid_channel, &fd, &short] {
+                    assert!(host_write(&mut dev, &mut cls, &frame.as_bytes()[..16]).is_empty());
+                }
+            }
+            assert_eq!(cls.invalid_host_frames(), 900);
+            assert!(cls.device.received.is_empty());
+
+            // valid frames aren't counted.
+            assert!(!host_write(&mut dev, &mut cls, &host_frame_bytes(4)).is_empty());
+            assert_eq!(cls.invalid_host_frames(), 900);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_queue_full_dropped() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            for id in 0..64 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            assert_eq!(cls.queue_full_dropped(), 0);
+
+            // every drop is counted, not only those logged.
+            for id in 0..300 {
+                cls.transmit(CHANNEL0, &classic_frame(id), FrameFlag::empty());
+            }
+            assert_eq!(cls.queue_full_dropped(), 300);
+
+            // echoes too.
+            dev.ep_write(&mut cls, READ_EP, &host_frame_bytes(1))
+                .unwrap();
+            assert_eq!(cls.device.received.len(), 1);
+            assert_eq!(cls.queue_full_dropped(), 301);
+
+            cls.kick();
+            assert_eq!(read_frames(&mut dev, &mut cls).len(), 64);
+            assert_eq!(cls.queue_full_dropped(), 301);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_mode_unadvertised_feature() {
+    TestCtx {
+        features: Some(Feature::LISTEN_ONLY | Feature::LOOP_BACK),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert!(try_set_mode(&mut dev, &mut cls, 0, 1, Feature::FD).is_err());
+        assert!(!cls.is_started(CHANNEL0));
+        assert!(cls.device.modes.is_empty());
+
+        // FD frames are still refused.
+        let mut fd = Frame::new_raw(0x7, &[0xAA; 8]).unwrap();
+        fd.flags = FrameFlag::FD;
+        assert!(host_write(&mut dev, &mut cls, &fd.as_bytes()[..20]).is_empty());
+
+        set_mode_flags(&mut dev, &mut cls, 0, 1, Feature::LOOP_BACK);
+        assert!(cls.is_started(CHANNEL0));
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_mode_validate_start() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_mode(&mut dev, &mut cls, 0, 1);
+
+            // rejected by the device, the channel keeps running.
+            let flags = Feature::LISTEN_ONLY | Feature::LOOP_BACK;
+            assert!(try_set_mode(&mut dev, &mut cls, 0, 1, flags).is_err());
+            assert!(cls.is_started(CHANNEL0));
+            assert_eq!(cls.device.modes, [("start", CHANNEL0)]);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_unconfigured_channel() {
+    TestCtx {
+        channels: Some(1),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        // channel 1 exists in the class but not on the device.
+        assert!(try_set_mode(&mut dev, &mut cls, 1, 1, Feature::empty()).is_err());
+        assert_eq!(
+            cls.last_rejection().map(|rejection| rejection.reason),
+            Some(RejectReason::InvalidChannel)
+        );
+
+        let mut frame = classic_frame(1);
+        frame.interface = 1;
+        assert!(host_write(&mut dev, &mut cls, &frame.as_bytes()[..20]).is_empty());
+        assert!(cls.device.received.is_empty());
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_channel_high_byte() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_mode(&mut dev, &mut cls, 0, 1);
+            let timing = DeviceBitTiming {
+                prop_seg: 1,
+                phase_seg1: 2,
+                phase_seg2: 3,
+                sjw: 1,
+                brp: 4,
+            };
+
+            // not channel 0 with flags in the high byte.
+            assert!(try_set_mode(&mut dev, &mut cls, 0x0100, 0, Feature::empty()).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.value, r.reason)),
+                Some((0x0100, RejectReason::InvalidChannel))
+            );
+            assert!(set_timing(&mut dev, &mut cls, 1, 0x0100, &timing).is_err());
+            assert!(identify(&mut dev, &mut cls, 0x0100, 1).is_err());
+            assert!(dev
+                .control_read(
+                    &mut cls,
+                    CtrRequestType::to_host().vendor(),
+                    14,
+                    0x0100,
+                    0,
+                    12
+                )
+                .is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason)),
+                Some((14, RejectReason::InvalidChannel))
+            );
+            assert!(cls.is_started(CHANNEL0));
+            assert_eq!(cls.device.modes, [("start", CHANNEL0)]);
+            assert_eq!(cls.identify(CHANNEL0).poll(0), LedState::Idle);
+
+            // nor interface 0.
+            assert!(dev
+                .control_write(
+                    &mut cls,
+                    CtrRequestType::to_device().vendor().interface(),
+                    2,
+                    0,
+                    0x0100,
+                    8,
+                    &[0; 8],
+                )
+                .is_err());
+            assert!(cls.is_started(CHANNEL0));
+        })
+        .expect("with_usb")
+}
+
+#[test]
+#[cfg_attr(
+    debug_assertions,
+    should_panic(expected = "more channels than supported")
+)]
+fn test_too_many_channels() {
+    TestCtx {
+        channels: Some(4),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        // release builds only report the supported channels.
+        assert_eq!(cls.device_config().interface_count, 2);
+        assert!(try_set_mode(&mut dev, &mut cls, 3, 1, Feature::empty()).is_err());
+        set_mode(&mut dev, &mut cls, 2, 1);
+        assert!(cls.is_started(Channel::new(2).unwrap()));
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_start_unknown_feature() {
+    TestCtx {
+        features: Some(Feature::LOOP_BACK),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        let unknown = Feature::from_bits_retain(1 << 20);
+        set_mode_flags(&mut dev, &mut cls, 0, 1, Feature::LOOP_BACK | unknown);
+        assert!(cls.is_started(CHANNEL0));
+
+        let features = cls.device.start_features[0];
+        assert_eq!(features.raw_bits(), (1 << 20) | Feature::LOOP_BACK.bits());
+        assert_eq!(features.known().bits(), Feature::LOOP_BACK.bits());
+
+        // named features are still checked.
+        assert!(try_set_mode(&mut dev, &mut cls, 1, 1, Feature::ONE_SHOT | unknown).is_err());
+        assert_eq!(
+            cls.last_rejection().unwrap().reason,
+            RejectReason::UnsupportedFeature
+        );
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_last_rejection() {
+    TestCtx {
+        features: Some(Feature::LOOP_BACK),
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        assert!(cls.last_rejection().is_none());
+
+        assert!(try_set_mode(&mut dev, &mut cls, 5, 1, Feature::empty()).is_err());
+        assert_eq!(
+            cls.last_rejection(),
+            Some(Rejection {
+                request: 2,
+                value: 5,
+                reason: RejectReason::InvalidChannel,
+                count: 1,
+            })
+        );
+
+        assert!(try_set_mode(&mut dev, &mut cls, 0, 1, Feature::FD).is_err());
+        assert_eq!(
+            cls.last_rejection(),
+            Some(Rejection {
+                request: 2,
+                value: 0,
+                reason: RejectReason::UnsupportedFeature,
+                count: 2,
+            })
+        );
+
+        // short host format request.
+        assert!(dev
+            .control_write(
+                &mut cls,
+                CtrRequestType::to_device().vendor(),
+                0,
+                0,
+                0,
+                2,
+                &[0xef, 0xbe],
+            )
+            .is_err());
+        let rejection = cls.last_rejection().unwrap();
+        assert_eq!(rejection.reason, RejectReason::InvalidLength);
+        assert_eq!(rejection.count, 3);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_echo_device() {
+    TestCtx {
+        echo_mode: EchoMode::Device,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        // not echoed until the application says so.
+        assert!(host_write(&mut dev, &mut cls, &host_frame_bytes(1)).is_empty());
+        let received = cls.device.received[0];
+        assert_eq!(received.echo_id, 7);
+        assert!(!cls.is_idle());
+
+        assert!(!cls.echo(CHANNEL0, 8));
+        assert!(cls.echo(CHANNEL0, 7));
+        UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+        let echo = parse_frame(&dev.ep_read(&mut cls, 1, 128).unwrap());
+        assert_eq!(echo.echo_id, 7);
+        assert_eq!(echo.can_id, 1);
+        assert!(echo.flags.is_empty());
+
+        // only echoed once.
+        assert!(!cls.echo(CHANNEL0, 7));
+        assert!(cls.is_idle());
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_echo_order() {
+    TestCtx {
+        echo_mode: EchoMode::Device,
+        ordered_echoes: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        // sent on the bus, then a frame received before the echo is reported.
+        host_write(&mut dev, &mut cls, &host_frame_bytes(1));
+        cls.transmit(CHANNEL0, &classic_frame(2), FrameFlag::empty());
+        assert!(!cls.needs_poll());
+        assert!(cls.echo(CHANNEL0, 7));
+        UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+
+        let frames = read_frames(&mut dev, &mut cls);
+        let order: Vec<_> = frames.iter().map(|frame| frame.echo_id).collect();
+        assert_eq!(order, [7, u32::MAX]);
+        assert!(cls.is_idle());
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_echo_order_unordered() {
+    TestCtx {
+        echo_mode: EchoMode::Device,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        host_write(&mut dev, &mut cls, &host_frame_bytes(1));
+        cls.transmit(CHANNEL0, &classic_frame(2), FrameFlag::empty());
+        assert!(cls.echo(CHANNEL0, 7));
+        UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+
+        // echoed in the order reported.
+        let frames = read_frames(&mut dev, &mut cls);
+        let order: Vec<_> = frames.iter().map(|frame| frame.echo_id).collect();
+        assert_eq!(order, [u32::MAX, 7]);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_echo_order_reset() {
+    TestCtx {
+        echo_mode: EchoMode::Device,
+        ordered_echoes: true,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        host_write(&mut dev, &mut cls, &host_frame_bytes(1));
+        set_mode(&mut dev, &mut cls, 0, 0);
+
+        // the place held for the echo is released.
+        assert!(!cls.echo(CHANNEL0, 7));
+        cls.transmit(CHANNEL1, &classic_frame(2), FrameFlag::empty());
+        UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+        let frames = read_frames(&mut dev, &mut cls);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].can_id, 2);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_echo_aborted() {
+    TestCtx {
+        echo_mode: EchoMode::Device,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        host_write(&mut dev, &mut cls, &host_frame_bytes(1));
+        assert!(cls.echo_aborted(CHANNEL0, 7));
+        UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+        let echo = parse_frame(&dev.ep_read(&mut cls, 1, 128).unwrap());
+        assert_eq!(echo.echo_id, 7);
+        assert_eq!(echo.can_id, 1);
+        assert!(echo.flags.contains(FrameFlag::OVERFLOW));
+
+        // the host reuses the echo ID for the next frame.
+        host_write(&mut dev, &mut cls, &host_frame_bytes(2));
+        assert!(cls.echo(CHANNEL0, 7));
+        UsbClass::<EmulatedUsbBus>::poll(&mut cls);
+        let echo = parse_frame(&dev.ep_read(&mut cls, 1, 128).unwrap());
+        assert_eq!(echo.echo_id, 7);
+        assert_eq!(echo.can_id, 2);
+        assert!(echo.flags.is_empty());
+        assert!(!cls.echo_aborted(CHANNEL0, 7));
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_echo_latency() {
+    TestCtx {
+        echo_mode: EchoMode::Device,
+        ..Default::default()
+    }
+    .with_usb(|mut cls, mut dev| {
+        cls.device.now_us = Some(1000);
+        host_write(&mut dev, &mut cls, &host_frame_bytes(1));
+        cls.device.now_us = Some(1250);
+        assert!(cls.echo(CHANNEL0, 7));
+        let stats = cls.echo_latency(CHANNEL0).unwrap();
+        assert_eq!(
+            (stats.min_us, stats.max_us, stats.last_us, stats.count),
+            (250, 250, 250, 1)
+        );
+
+        // across the timer wrapping.
+        cls.device.now_us = Some(u32::MAX - 49);
+        host_write(&mut dev, &mut cls, &host_frame_bytes(2));
+        cls.device.now_us = Some(50);
+        assert!(cls.echo_aborted(CHANNEL0, 7));
+        let stats = cls.echo_latency(CHANNEL0).unwrap();
+        assert_eq!(
+            (stats.min_us, stats.max_us, stats.last_us, stats.count),
+            (100, 250, 100, 2)
+        );
+        assert_eq!(cls.echo_latency(CHANNEL1), None);
+
+        cls.reset_echo_latency(CHANNEL0);
+        assert_eq!(cls.echo_latency(CHANNEL0), None);
+    })
+    .expect("with_usb")
+}
+
+#[test]
+fn test_echo_latency_immediate() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            // without a timer.
+            host_write(&mut dev, &mut cls, &host_frame_bytes(1));
+            assert_eq!(cls.echo_latency(CHANNEL0), None);
+
+            // echoed straight away.
+            cls.device.now_us = Some(10);
+            host_write(&mut dev, &mut cls, &host_frame_bytes(2));
+            let stats = cls.echo_latency(CHANNEL0).unwrap();
+            assert_eq!((stats.last_us, stats.count), (0, 1));
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_fd_start_without_data_timing() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_timing(&mut dev, &mut cls, 1, 0, &NOMINAL_TIMING).unwrap();
+            assert!(try_set_mode(&mut dev, &mut cls, 0, 1, Feature::FD).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|rejection| rejection.reason),
+                Some(RejectReason::MissingDataTiming)
+            );
+            assert!(!cls.is_started(CHANNEL0));
+
+            set_timing(&mut dev, &mut cls, 10, 0, &DATA_TIMING).unwrap();
+            set_mode_flags(&mut dev, &mut cls, 0, 1, Feature::FD);
+            assert!(cls.is_started(CHANNEL0));
+
+            // timing is kept when the host restarts the channel.
+            set_mode(&mut dev, &mut cls, 0, 0);
+            set_mode_flags(&mut dev, &mut cls, 0, 1, Feature::FD);
+            assert!(cls.is_started(CHANNEL0));
+            assert_eq!(
+                cls.device.start_timing,
+                [
+                    (NOMINAL_TIMING, Some(DATA_TIMING)),
+                    (NOMINAL_TIMING, Some(DATA_TIMING))
+                ]
+            );
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_fd_start_default_data_timing() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            cls.device.default_data_timing = true;
+
+            set_mode_flags(&mut dev, &mut cls, 0, 1, Feature::FD);
+            assert!(cls.is_started(CHANNEL0));
+            assert_eq!(
+                cls.device.start_timing,
+                [(NOMINAL_TIMING, Some(DEFAULT_DATA_TIMING))]
+            );
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_timing_any_order() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            // as candle_api, the data phase first.
+            set_timing(&mut dev, &mut cls, 10, 0, &DATA_TIMING).unwrap();
+            set_timing(&mut dev, &mut cls, 1, 0, &NOMINAL_TIMING).unwrap();
+            try_set_mode(&mut dev, &mut cls, 0, 1, Feature::FD).unwrap();
+
+            // only the data phase, the nominal timing is kept.
+            set_mode(&mut dev, &mut cls, 0, 0);
+            set_timing(&mut dev, &mut cls, 10, 0, &DEFAULT_DATA_TIMING).unwrap();
+            try_set_mode(&mut dev, &mut cls, 0, 1, Feature::FD).unwrap();
+            assert_eq!(
+                cls.device.start_timing,
+                [
+                    (NOMINAL_TIMING, Some(DATA_TIMING)),
+                    (NOMINAL_TIMING, Some(DEFAULT_DATA_TIMING))
+                ]
+            );
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_timing_change_started() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            set_timing(&mut dev, &mut cls, 10, 0, &DATA_TIMING).unwrap();
+            set_mode_flags(&mut dev, &mut cls, 0, 1, Feature::FD);
+
+            // repeated timings are accepted, changes rejected.
+            set_timing(&mut dev, &mut cls, 1, 0, &NOMINAL_TIMING).unwrap();
+            set_timing(&mut dev, &mut cls, 10, 0, &DATA_TIMING).unwrap();
+            assert!(cls.last_rejection().is_none());
+            assert!(set_timing(&mut dev, &mut cls, 1, 0, &DATA_TIMING).is_err());
+            assert!(set_timing(&mut dev, &mut cls, 10, 0, &DEFAULT_DATA_TIMING).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|r| (r.request, r.reason, r.count)),
+                Some((10, RejectReason::ChannelStarted, 2))
+            );
+
+            // the channel restarts with the timing it ran with.
+            set_mode(&mut dev, &mut cls, 0, 0);
+            try_set_mode(&mut dev, &mut cls, 0, 1, Feature::FD).unwrap();
+            assert_eq!(
+                cls.device.start_timing,
+                [
+                    (NOMINAL_TIMING, Some(DATA_TIMING)),
+                    (NOMINAL_TIMING, Some(DATA_TIMING))
+                ]
+            );
+        })
+        .expect("with_usb")
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_netlink_up_sequence() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            // start without any timing configured.
+            assert!(try_set_mode(&mut dev, &mut cls, 0, 1, Feature::empty()).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|rejection| rejection.reason),
+                Some(RejectReason::MissingTiming)
+            );
+
+            // ip link set can0 type can bitrate 500000 dbitrate 2000000 fd on
+            set_timing(&mut dev, &mut cls, 1, 0, &NOMINAL_TIMING).unwrap();
+            set_timing(&mut dev, &mut cls, 10, 0, &DATA_TIMING).unwrap();
+            assert!(cls.device.modes.is_empty());
+
+            // ip link set can0 up
+            try_set_mode(&mut dev, &mut cls, 0, 1, Feature::FD).unwrap();
+            assert_eq!(
+                cls.device.start_timing,
+                [(NOMINAL_TIMING, Some(DATA_TIMING))]
+            );
+
+            // a classic start doesn't get the data phase timing.
+            set_mode(&mut dev, &mut cls, 0, 0);
+            try_set_mode(&mut dev, &mut cls, 0, 1, Feature::empty()).unwrap();
+            assert_eq!(cls.device.start_timing[1], (NOMINAL_TIMING, None));
+        })
+        .expect("with_usb")
+}
+
+#[cfg(not(feature = "fd"))]
+#[test]
+fn test_classic_only() {
+    TestCtx::default()
+        .with_usb(|mut cls, mut dev| {
+            let timing = NOMINAL_TIMING;
+            assert!(set_timing(&mut dev, &mut cls, 10, 0, &timing).is_err());
+            assert_eq!(
+                cls.last_rejection().map(|rejection| rejection.reason),
+                Some(RejectReason::UnknownRequest)
+            );
+
+            set_timing(&mut dev, &mut cls, 1, 0, &timing).unwrap();
+            assert!(try_set_mode(&mut dev, &mut cls, 0, 1, Feature::FD).is_err());
+            assert!(!cls.is_started(CHANNEL0));
+        })
+        .expect("with_usb")
+}
+
+#[cfg(not(feature = "fd"))]
+#[test]
+#[should_panic(expected = "CAN FD advertised without the `fd` feature")]
+fn test_classic_only_advertises_fd() {
+    TestCtx {
+        features: Some(Feature::FD),
+        ..Default::default()
+    }
+    .with_usb(|_cls, _dev| {})
+    .ok();
+}
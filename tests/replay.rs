@@ -0,0 +1,184 @@
+//! Sessions of USB transfers replayed against the class, see
+//! `usbd_gscan::session`.
+//!
+//! The fixtures in `tests/sessions` are regression sessions recorded with the
+//! trace hooks from this class on the synthetic bus, driven with requests
+//! scripted after those of a host:
+//!
+//! - `regression_linux_bring_up.bin`: the Linux gs_usb driver probing the
+//!   device, bringing channel 0 up at 2 Mbit/s, sending three frames and
+//!   bringing it down again.
+//! - `regression_python_can.bin`: python-can's gs_usb interface, which resets
+//!   the channel before configuring it, sending extended frames.
+//!
+//! They aren't captures from a real host, so they catch changes in the
+//! traffic of the class, not disagreements with a host. Captures of a host
+//! driving a device replace them: record the bus with usbmon, e.g.
+//! `cat /sys/kernel/debug/usb/usbmon/1u > capture.txt` whilst bringing a
+//! channel up and down, then convert it with `session::from_usbmon` and write
+//! the records to `tests/sessions`.
+
+mod support;
+
+use support::SyntheticBus;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::UsbDirection;
+use usbd_gscan::host::Feature;
+use usbd_gscan::session::{self, Record, RecordKind, Records, SessionError, UsbmonError};
+
+const LINUX_BRING_UP: &str = "tests/sessions/regression_linux_bring_up.bin";
+const PYTHON_CAN: &str = "tests/sessions/regression_python_can.bin";
+
+fn replay_fixture(path: &str) -> usize {
+    let session = std::fs::read(path).unwrap();
+    let alloc = UsbBusAllocator::new(SyntheticBus::default());
+    let (mut class, mut device) = support::class(&alloc, Feature::empty());
+
+    let replayed = support::replay(&mut device, &mut class, &session);
+    assert_eq!(replayed.written, replayed.recorded);
+    class.device.received
+}
+
+#[test]
+fn test_replay_regression_linux_bring_up() {
+    assert_eq!(replay_fixture(LINUX_BRING_UP), 3);
+}
+
+#[test]
+fn test_replay_regression_python_can() {
+    assert_eq!(replay_fixture(PYTHON_CAN), 2);
+}
+
+#[test]
+fn test_records() {
+    let records = [
+        Record {
+            kind: RecordKind::Control,
+            time_us: 1,
+            bytes: &[0x41, 2, 0, 0, 0, 0, 8, 0, 1, 0, 0, 0, 0, 0, 0, 0],
+        },
+        Record {
+            kind: RecordKind::BulkOut,
+            time_us: u32::MAX,
+            bytes: &[],
+        },
+        Record {
+            kind: RecordKind::BulkIn,
+            time_us: 3,
+            bytes: &[0xaa; 20],
+        },
+    ];
+    let mut session = Vec::new();
+    for record in &records {
+        let mut buf = [0; 64];
+        let len = record.write_to(&mut buf).unwrap();
+        assert_eq!(len, record.len());
+        session.extend_from_slice(&buf[..len]);
+    }
+    assert_eq!(&session[..7], [0, 16, 0, 1, 0, 0, 0]);
+
+    let read: Vec<_> = Records::new(&session).map(Result::unwrap).collect();
+    assert_eq!(read, records);
+
+    // too small, or cut short.
+    assert_eq!(records[2].write_to(&mut [0; 26]), None);
+    let mut records = Records::new(&session[..session.len() - 1]);
+    assert!(records.next().unwrap().is_ok());
+    assert!(records.next().unwrap().is_ok());
+    assert_eq!(records.next(), Some(Err(SessionError::Truncated)));
+    assert_eq!(records.next(), None);
+
+    let unknown = [7, 0, 0, 0, 0, 0, 0];
+    assert_eq!(
+        Records::new(&unknown).collect::<Vec<_>>(),
+        [Err(SessionError::UnknownKind(7))]
+    );
+}
+
+#[test]
+fn test_control_bytes() {
+    let req = Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Interface,
+        request: 4,
+        value: 1,
+        index: 0,
+        length: 40,
+    };
+    assert_eq!(session::setup_packet(&req), [0xc1, 4, 1, 0, 0, 0, 40, 0]);
+
+    let req = Request {
+        direction: UsbDirection::Out,
+        length: 2,
+        ..req
+    };
+    let mut buf = [0; 10];
+    assert_eq!(session::control_bytes(&req, &[5, 6], &mut buf), Some(10));
+    assert_eq!(buf, [0x41, 4, 1, 0, 0, 0, 2, 0, 5, 6]);
+    assert_eq!(session::control_bytes(&req, &[5, 6], &mut [0; 9]), None);
+}
+
+#[test]
+fn test_from_usbmon() {
+    // lines of the usbmon text format, with a standard request and a hub
+    // on another address left out.
+    let capture = "\
+ffff9a8b40a6e0c0 2130417411 S Ci:1:005:0 s 80 06 0100 0000 0012 18 <
+ffff9a8b40a6e0c0 2130417533 C Ci:1:005:0 0 18 = 12010002 ff000040 d01d6160 00000102 0301
+ffff9a8b40a6e0c0 2130417602 S Co:1:005:0 s 41 00 0001 0000 0004 4 = efbe0000
+ffff9a8b40a6e0c0 2130417688 C Co:1:005:0 0 4 >
+ffff9a8b40a6e0c0 2130417702 S Ci:1:005:0 s c1 05 0001 0000 000c 12 <
+ffff9a8b40a6e0c0 2130417799 C Ci:1:005:0 0 12 = 00000001 02000000 00000000
+ffff9a8b4c1f2a80 2130417810 S Ii:1:001:1 -115:2048 4 <
+
+ffff9a8b40a6f240 2130418001 S Bo:1:005:2 -115 20 = 00000000 23010000 04000000 deadbeef 00000000
+ffff9a8b40a6fa80 2130418203 C Bi:1:005:1 0 20 = 00000000 23010000 04000000 deadbeef 00000000
+ffff9a8b40a6f240 2130418207 C Bo:1:005:2 0 20 >
+";
+    let mut records = Vec::new();
+    session::from_usbmon(capture, 5, 64, |record| {
+        records.push((record.kind, record.time_us, record.bytes.to_vec()))
+    })
+    .unwrap();
+
+    let frame = [
+        0, 0, 0, 0, 0x23, 1, 0, 0, 4, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 0,
+    ];
+    assert_eq!(
+        records,
+        [
+            (
+                RecordKind::Control,
+                2130417602,
+                vec![0x41, 0, 1, 0, 0, 0, 4, 0, 0xef, 0xbe, 0, 0]
+            ),
+            (
+                RecordKind::Control,
+                2130417702,
+                vec![0xc1, 5, 1, 0, 0, 0, 12, 0]
+            ),
+            (RecordKind::BulkOut, 2130418001, frame.to_vec()),
+            (RecordKind::BulkIn, 2130418203, frame.to_vec()),
+        ]
+    );
+
+    // split into packets as the endpoint reads them.
+    let fd = "ffff9a8b40a6f240 10 C Bi:1:005:1 0 80 = ".to_owned() + &["00000000"; 20].join(" ");
+    let mut packets = Vec::new();
+    session::from_usbmon(&fd, 5, 64, |record| packets.push(record.bytes.len())).unwrap();
+    assert_eq!(packets, [64, 16]);
+
+    // data cut short by usbmon, and a line that isn't an event.
+    let truncated =
+        "ffff9a8b40a6f240 10 S Bo:1:005:2 -115 76 = ".to_owned() + &["00000000"; 8].join(" ");
+    assert_eq!(
+        session::from_usbmon(&truncated, 5, 64, |_| {}),
+        Err(UsbmonError::Truncated(1))
+    );
+    assert_eq!(
+        session::from_usbmon("\nusbmon\n", 5, 64, |_| {}),
+        Err(UsbmonError::Malformed(2))
+    );
+}
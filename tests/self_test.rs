@@ -0,0 +1,222 @@
+//! The host sequence of the self test, run against the emulated bus.
+
+use embedded_can::Frame as _;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{StringDescriptors, UsbDevice, UsbDeviceBuilder};
+use usb_device::endpoint::{EndpointAddress, EndpointType, In};
+use usb_device::UsbDirection;
+use usbd_class_tester::prelude::*;
+use usbd_gscan::{
+    host::{DeviceBitTiming, Feature, Frame, FrameFlag},
+    identifier,
+    self_test::{self, SelfTestDevice},
+    Channel, GsCan,
+};
+use zerocopy::{AsBytes, FromZeroes};
+
+/// 500 kbit/s at 80 MHz.
+const NOMINAL_TIMING: DeviceBitTiming = DeviceBitTiming {
+    prop_seg: 69,
+    phase_seg1: 70,
+    phase_seg2: 20,
+    sjw: 1,
+    brp: 1,
+};
+/// 2 Mbit/s at 80 MHz.
+const DATA_TIMING: DeviceBitTiming = DeviceBitTiming {
+    prop_seg: 15,
+    phase_seg1: 16,
+    phase_seg2: 8,
+    sjw: 1,
+    brp: 1,
+};
+
+/// Bytes of a frame on each channel.
+const CLASSIC_LEN: usize = 20;
+const FD_LEN: usize = 76;
+
+struct SelfTestCtx {}
+
+impl UsbDeviceCtx for SelfTestCtx {
+    type C<'c> = GsCan<'c, EmulatedUsbBus, SelfTestDevice>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<Self::C<'a>> {
+        let class = GsCan::new(alloc, SelfTestDevice::new());
+        // the emulated bus reads back from the IN endpoint with the index of
+        // the OUT endpoint written to.
+        alloc
+            .alloc::<In>(
+                Some(EndpointAddress::from_parts(2, UsbDirection::In)),
+                EndpointType::Bulk,
+                64,
+                0,
+            )
+            .unwrap();
+        Ok(class)
+    }
+
+    fn build_usb_device<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<UsbDevice<'a, EmulatedUsbBus>> {
+        let usb_dev = UsbDeviceBuilder::new(alloc, identifier::CANDLELIGHT)
+            .strings(&[StringDescriptors::default().product("self test")])
+            .unwrap()
+            .build();
+        Ok(usb_dev)
+    }
+}
+
+type Class<'a> = GsCan<'a, EmulatedUsbBus, SelfTestDevice>;
+type TestDevice<'a> = usbd_class_tester::Device<'a, Class<'a>, SelfTestCtx>;
+
+fn vendor_write<'a>(
+    dev: &mut TestDevice<'a>,
+    cls: &mut Class<'a>,
+    request: u8,
+    channel: u16,
+    data: &[u8],
+) {
+    dev.control_write(
+        cls,
+        CtrRequestType::to_device().vendor(),
+        request,
+        channel,
+        0,
+        data.len() as u16,
+        data,
+    )
+    .unwrap();
+}
+
+/// Start the classic channel and the CAN FD channel in FD mode.
+fn start<'a>(dev: &mut TestDevice<'a>, cls: &mut Class<'a>) {
+    vendor_write(dev, cls, 0, 0, &0x0000beef_u32.to_le_bytes());
+    for (channel, flags) in [(0, Feature::empty()), (1, Feature::FD)] {
+        vendor_write(dev, cls, 1, channel, NOMINAL_TIMING.as_bytes());
+        vendor_write(dev, cls, 10, channel, DATA_TIMING.as_bytes());
+        let mut mode = 1_u32.to_le_bytes().to_vec();
+        mode.extend_from_slice(&flags.bits().to_le_bytes());
+        vendor_write(dev, cls, 2, channel, &mode);
+    }
+}
+
+/// Send a frame as the host, in packets of 64 bytes.
+fn host_send<'a>(dev: &mut TestDevice<'a>, cls: &mut Class<'a>, frame: &Frame, len: usize) {
+    for packet in frame.as_bytes()[..len].chunks(64) {
+        dev.ep_write(cls, 2, packet).unwrap();
+    }
+}
+
+/// Frames written to the host.
+fn host_receive<'a>(dev: &mut TestDevice<'a>, cls: &mut Class<'a>) -> Vec<Frame> {
+    let mut written = Vec::new();
+    loop {
+        let read = dev.ep_read(cls, 1, u16::MAX).unwrap();
+        if read.is_empty() {
+            break;
+        }
+        written.extend(read);
+    }
+
+    let mut frames = Vec::new();
+    let mut bytes = &written[..];
+    while !bytes.is_empty() {
+        let mut frame = Frame::new_zeroed();
+        // the interface byte, channel 1 is in FD mode.
+        let len = if bytes[9] == 1 { FD_LEN } else { CLASSIC_LEN };
+        frame.as_bytes_mut()[..len].copy_from_slice(&bytes[..len]);
+        frames.push(frame);
+        bytes = &bytes[len..];
+    }
+    frames
+}
+
+/// Returns `true` if a frame from the device carries the pattern frame.
+fn matches(frame: &Frame, expected: &Frame) -> bool {
+    frame.can_id == expected.can_id
+        && frame.can_dlc == expected.can_dlc
+        && frame.flags.contains(FrameFlag::FD) == expected.flags.contains(FrameFlag::FD)
+        && frame.data() == expected.data()
+}
+
+#[test]
+fn test_self_test_sequence() {
+    SelfTestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            start(&mut dev, &mut cls);
+
+            for n in 0..32 {
+                for (channel, len) in [(self_test::CLASSIC, CLASSIC_LEN), (self_test::FD, FD_LEN)] {
+                    let mut frame = self_test::pattern(channel, n);
+                    frame.echo_id = n;
+                    frame.interface = channel.index();
+                    host_send(&mut dev, &mut cls, &frame, len);
+                    cls.loop_back();
+                    cls.kick();
+
+                    // the echo, then the frame received back.
+                    let frames = host_receive(&mut dev, &mut cls);
+                    assert_eq!(frames.len(), 2, "frame {} on {:?}", n, channel);
+                    assert_ne!(frames[0].echo_id, u32::MAX);
+                    assert_eq!(frames[1].echo_id, u32::MAX);
+                    for received in &frames {
+                        assert_eq!(received.interface, channel.index());
+                        assert!(matches(received, &frame), "frame {} on {:?}", n, channel);
+                    }
+                }
+            }
+
+            assert_eq!(cls.device.matched(), 64);
+            assert_eq!(cls.device.mismatches(), 0);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_self_test_mismatch() {
+    SelfTestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            start(&mut dev, &mut cls);
+
+            // frame 1 sent first.
+            let mut frame = self_test::pattern(self_test::CLASSIC, 1);
+            frame.echo_id = 0;
+            host_send(&mut dev, &mut cls, &frame, CLASSIC_LEN);
+            cls.loop_back();
+            cls.kick();
+
+            // looped back all the same.
+            assert_eq!(host_receive(&mut dev, &mut cls).len(), 2);
+            assert_eq!(cls.device.matched(), 0);
+            assert_eq!(cls.device.mismatches(), 1);
+        })
+        .expect("with_usb")
+}
+
+#[test]
+fn test_self_test_classic_channel() {
+    SelfTestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            vendor_write(&mut dev, &mut cls, 1, 0, NOMINAL_TIMING.as_bytes());
+            vendor_write(&mut dev, &mut cls, 10, 0, DATA_TIMING.as_bytes());
+            let mut mode = 1_u32.to_le_bytes().to_vec();
+            mode.extend_from_slice(&Feature::FD.bits().to_le_bytes());
+            assert!(dev
+                .control_write(
+                    &mut cls,
+                    CtrRequestType::to_device().vendor(),
+                    2,
+                    0,
+                    0,
+                    8,
+                    &mode
+                )
+                .is_err());
+            assert!(!cls.is_started(Channel::new(0).unwrap()));
+        })
+        .expect("with_usb")
+}
@@ -0,0 +1,76 @@
+use embedded_can::{Frame as _, StandardId};
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usbd_gscan::{
+    host::{Feature, Frame},
+    shared::{Shared, SharedDevice},
+    Channel, GsCan, RejectReason,
+};
+use zerocopy::AsBytes;
+
+mod support;
+
+use support::{CountingDevice, SyntheticBus};
+
+const CHANNEL0: Channel = Channel::new(0).unwrap();
+
+#[test]
+fn test_first_start_owns() {
+    let shared = Shared::new(CountingDevice::new(Feature::empty()));
+    let alloc0 = UsbBusAllocator::new(SyntheticBus::default());
+    let alloc1 = UsbBusAllocator::new(SyntheticBus::default());
+    let (mut cls0, mut dev0) = support::class_with(&alloc0, shared.port(0));
+    let (mut cls1, mut dev1) = support::class_with(&alloc1, shared.port(1));
+    assert_eq!(shared.owner(CHANNEL0), None);
+
+    support::start(&mut dev0, &mut cls0, Feature::empty());
+    assert_eq!(shared.owner(CHANNEL0), Some(0));
+
+    // the other host is refused and its reset leaves the channel alone.
+    support::request_start(&mut dev1, &mut cls1, Feature::empty());
+    assert!(!cls1.is_started(CHANNEL0));
+    assert_eq!(
+        cls1.last_rejection().map(|rejection| rejection.reason),
+        Some(RejectReason::DeviceRejected)
+    );
+    support::reset(&mut dev1, &mut cls1);
+    assert_eq!(shared.owner(CHANNEL0), Some(0));
+
+    // released by the owner, the other host can start it.
+    support::reset(&mut dev0, &mut cls0);
+    assert_eq!(shared.owner(CHANNEL0), None);
+    support::start(&mut dev1, &mut cls1, Feature::empty());
+    assert_eq!(shared.owner(CHANNEL0), Some(1));
+
+    // a bus reset releases it too.
+    UsbClass::<SyntheticBus>::reset(&mut cls1);
+    assert_eq!(shared.owner(CHANNEL0), None);
+}
+
+#[test]
+fn test_frames_from_owner_only() {
+    let shared = Shared::new(CountingDevice::new(Feature::empty()));
+    let alloc0 = UsbBusAllocator::new(SyntheticBus::default());
+    let alloc1 = UsbBusAllocator::new(SyntheticBus::default());
+    let (mut cls0, mut dev0) = support::class_with(&alloc0, shared.port(0));
+    let (mut cls1, mut dev1) = support::class_with(&alloc1, shared.port(1));
+    support::start(&mut dev0, &mut cls0, Feature::empty());
+    support::request_start(&mut dev1, &mut cls1, Feature::empty());
+
+    let frame = Frame::new(StandardId::ZERO, &[0xAA; 8]).unwrap();
+    let bytes = &frame.as_bytes()[..20];
+    dev0.bus().send_from_host(&[bytes], 2);
+    support::run(&mut dev0, &mut cls0);
+    dev1.bus().send_from_host(&[bytes], 3);
+    support::run(&mut dev1, &mut cls1);
+
+    assert_eq!(shared.with_device(|device| device.received), 2);
+}
+
+#[test]
+fn test_ports_are_send() {
+    fn assert_send<T: Send>() {}
+
+    // the classes may be polled from different interrupts.
+    assert_send::<GsCan<'static, SyntheticBus, SharedDevice<'static, CountingDevice>>>();
+}
@@ -0,0 +1,206 @@
+use core::convert::Infallible;
+use std::sync::Mutex;
+use usb_device::bus::{PollResult, UsbBus, UsbBusAllocator};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{UsbDirection, UsbError};
+#[cfg(feature = "fd")]
+use usbd_gscan::host::DeviceBitTimingConstExtended;
+use usbd_gscan::{
+    host::{
+        CanBitTimingConst, CanState, DeviceBitTiming, DeviceBitTimingConst, DeviceConfig,
+        DeviceState, Feature, Frame, FrameKind,
+    },
+    Channel, Device, GsCan,
+};
+use zerocopy::AsBytes;
+
+/// Bus that allocates endpoints and otherwise does nothing.
+#[derive(Default)]
+struct NullBus {
+    next_endpoint: u8,
+}
+
+impl UsbBus for NullBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> usb_device::Result<EndpointAddress> {
+        if let Some(addr) = ep_addr {
+            return Ok(addr);
+        }
+
+        let addr = EndpointAddress::from_parts(usize::from(self.next_endpoint), ep_dir);
+        self.next_endpoint += 1;
+        Ok(addr)
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, _ep_addr: EndpointAddress, _buf: &[u8]) -> usb_device::Result<usize> {
+        Err(UsbError::WouldBlock)
+    }
+
+    fn read(&self, _ep_addr: EndpointAddress, _buf: &mut [u8]) -> usb_device::Result<usize> {
+        Err(UsbError::WouldBlock)
+    }
+
+    fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        PollResult::None
+    }
+}
+
+const TIMING: CanBitTimingConst = CanBitTimingConst {
+    tseg1_min: 1,
+    tseg1_max: 16,
+    tseg2_min: 1,
+    tseg2_max: 8,
+    sjw_max: 4,
+    brp_min: 1,
+    brp_max: 1024,
+    brp_inc: 1,
+};
+
+/// The host structs built at compile time, placed in flash on target.
+static CONFIG: DeviceConfig = DeviceConfig::new(1);
+static BT_CONST: DeviceBitTimingConst = DeviceBitTimingConst::classic(
+    Feature::GS_DEFAULT,
+    42_000_000,
+    CanBitTimingConst::new(16, 8, 4, 1024),
+);
+#[cfg(feature = "fd")]
+static BT_CONST_EXT: DeviceBitTimingConstExtended = DeviceBitTimingConstExtended::new(
+    Feature::GS_DEFAULT.union(Feature::CAN_FD),
+    80_000_000,
+    CanBitTimingConst::new(256, 128, 128, 512),
+    CanBitTimingConst {
+        brp_inc: 2,
+        ..CanBitTimingConst::new(32, 16, 16, 32)
+    },
+);
+
+/// Device owning all of its state, as firmware keeping the class in a static
+/// would.
+#[derive(Default)]
+struct OwnedDevice {
+    received: usize,
+}
+
+impl Device for OwnedDevice {
+    fn config(&self) -> DeviceConfig {
+        CONFIG
+    }
+
+    fn bit_timing(&self) -> DeviceBitTimingConst {
+        BT_CONST
+    }
+
+    fn reset(&mut self, _channel: Channel) {}
+
+    fn start(
+        &mut self,
+        _channel: Channel,
+        _features: Feature,
+        _nominal: &DeviceBitTiming,
+        _data: Option<&DeviceBitTiming>,
+    ) {
+    }
+
+    fn state(&self, _channel: Channel) -> DeviceState {
+        DeviceState {
+            state: CanState::Active,
+            rx_errors: 0,
+            tx_errors: 0,
+        }
+    }
+
+    fn receive(&mut self, _channel: Channel, _frame: &Frame) -> nb::Result<(), Infallible> {
+        self.received += 1;
+        Ok(())
+    }
+}
+
+type StaticGsCan = GsCan<'static, NullBus, OwnedDevice>;
+
+/// Shared between the USB interrupt and the CAN interrupt in firmware.
+static CLASS: Mutex<Option<StaticGsCan>> = Mutex::new(None);
+
+fn assert_send<T: Send>() {}
+
+fn assert_send_sync_copy<T: Send + Sync + Copy>() {}
+
+/// Fails to compile unless the class is `Send` for every bus and queue depth
+/// whenever the device is.
+#[allow(dead_code)]
+fn assert_class_send<B: UsbBus + 'static, D: Device + Send, const RX: usize>() {
+    assert_send::<GsCan<'static, B, D, RX>>();
+}
+
+#[test]
+fn test_static_class_is_send() {
+    assert_send::<StaticGsCan>();
+}
+
+#[test]
+fn test_host_values_are_send() {
+    // passed between an interrupt and a thread, e.g. through a channel.
+    assert_send_sync_copy::<Frame>();
+    assert_send_sync_copy::<FrameKind>();
+    assert_send_sync_copy::<DeviceBitTiming>();
+    assert_send_sync_copy::<Channel>();
+}
+
+#[test]
+fn test_static_class() {
+    // stands in for a `StaticCell` on target.
+    let alloc: &'static UsbBusAllocator<NullBus> =
+        Box::leak(Box::new(UsbBusAllocator::new(NullBus::default())));
+
+    let class = GsCan::new(alloc, OwnedDevice::default());
+    *CLASS.lock().unwrap() = Some(class);
+
+    let mut class = CLASS.lock().unwrap();
+    let class = class.as_mut().unwrap();
+    assert!(class.is_idle());
+    assert_eq!(class.device.received, 0);
+}
+
+#[test]
+fn test_static_host_structs() {
+    assert_eq!(CONFIG.as_bytes(), DeviceConfig::new(1).as_bytes());
+
+    let bt_const = DeviceBitTimingConst {
+        features: Feature::LISTEN_ONLY | Feature::LOOP_BACK | Feature::ONE_SHOT,
+        fclk_can: 42_000_000,
+        timing: TIMING,
+    };
+    assert_eq!(BT_CONST.as_bytes(), bt_const.as_bytes());
+
+    #[cfg(feature = "fd")]
+    {
+        assert_eq!(
+            BT_CONST_EXT.features.bits(),
+            (Feature::GS_DEFAULT | Feature::FD | Feature::BT_CONST_EXT).bits()
+        );
+        assert_eq!(BT_CONST_EXT.timing_nominal.tseg1_min, 1);
+        assert_eq!(BT_CONST_EXT.timing_data.tseg1_max, 32);
+        assert_eq!(BT_CONST_EXT.timing_data.brp_inc, 2);
+    }
+}
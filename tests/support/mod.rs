@@ -0,0 +1,498 @@
+//! Synthetic `UsbBus` driving `GsCan` off-target, shared by the integration
+//! tests and the benchmarks.
+//!
+//! The bus plays the host: it accepts the packets the class writes as fast
+//! as the configured [`Acceptance`] allows, repeats a transfer of frames from
+//! the host as many times as asked, and feeds control requests one packet per
+//! poll.
+#![allow(dead_code)]
+
+use core::convert::Infallible;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use usb_device::bus::{PollResult, UsbBus, UsbBusAllocator};
+use usb_device::device::{UsbDevice, UsbDeviceBuilder};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{UsbDirection, UsbError};
+use usbd_gscan::{
+    host::{
+        CanBitTimingConst, CanState, DeviceBitTiming, DeviceBitTimingConst, DeviceConfig,
+        DeviceState, Feature, Frame,
+    },
+    identifier, Channel, Device, GsCan,
+};
+use zerocopy::AsBytes;
+
+/// Index of the bulk IN endpoint.
+const BULK_IN: usize = 1;
+
+/// Index of the bulk OUT endpoint.
+const BULK_OUT: usize = 2;
+
+/// Packets the bulk IN endpoint accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acceptance {
+    /// Every packet, a host always reading.
+    All,
+    /// `n` packets then `WouldBlock` once, a host reading in bursts.
+    Burst(u32),
+    /// None, a host that stopped reading.
+    None,
+}
+
+/// Transfer the host sends repeatedly on the bulk OUT endpoint.
+#[derive(Default)]
+struct HostSource {
+    /// Packets of the transfer
+    packets: Vec<Vec<u8>>,
+    /// Packet to read next
+    next: usize,
+    /// Transfers left to send
+    remaining: usize,
+}
+
+/// Bus playing the host at the other end of the cable.
+pub struct SyntheticBus {
+    acceptance: Mutex<Acceptance>,
+    /// Packets accepted since the last one refused
+    burst: AtomicU32,
+    /// A bulk IN packet was accepted since the last poll
+    in_complete: AtomicBool,
+    /// Packets and bytes accepted by the bulk IN endpoint
+    packets_written: AtomicUsize,
+    bytes_written: AtomicUsize,
+    /// Keep the packets accepted by the bulk IN endpoint
+    record: AtomicBool,
+    written: Mutex<Vec<Vec<u8>>>,
+    /// Control packets waiting to be read, `true` for a setup packet
+    control: Mutex<VecDeque<(Vec<u8>, bool)>>,
+    host: Mutex<HostSource>,
+}
+
+impl SyntheticBus {
+    pub fn new(acceptance: Acceptance) -> Self {
+        Self {
+            acceptance: Mutex::new(acceptance),
+            burst: AtomicU32::new(0),
+            in_complete: AtomicBool::new(false),
+            packets_written: AtomicUsize::new(0),
+            bytes_written: AtomicUsize::new(0),
+            record: AtomicBool::new(false),
+            written: Mutex::new(Vec::new()),
+            control: Mutex::new(VecDeque::new()),
+            host: Mutex::new(HostSource::default()),
+        }
+    }
+
+    /// Change how many packets the bulk IN endpoint accepts.
+    pub fn set_acceptance(&self, acceptance: Acceptance) {
+        *self.acceptance.lock().unwrap() = acceptance;
+        self.burst.store(0, Ordering::Relaxed);
+    }
+
+    /// Keep the packets accepted by the bulk IN endpoint, see
+    /// [`SyntheticBus::written`].
+    pub fn set_record(&self, record: bool) {
+        self.record.store(record, Ordering::Relaxed);
+    }
+
+    /// Packets accepted by the bulk IN endpoint whilst recording.
+    pub fn written(&self) -> Vec<Vec<u8>> {
+        self.written.lock().unwrap().clone()
+    }
+
+    /// Number of packets accepted by the bulk IN endpoint.
+    pub fn packets_written(&self) -> usize {
+        self.packets_written.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes accepted by the bulk IN endpoint.
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Send a transfer of `packets` on the bulk OUT endpoint `count` times,
+    /// replacing any transfers not read yet.
+    pub fn send_from_host(&self, packets: &[&[u8]], count: usize) {
+        *self.host.lock().unwrap() = HostSource {
+            packets: packets.iter().map(|packet| packet.to_vec()).collect(),
+            next: 0,
+            remaining: count,
+        };
+    }
+
+    /// Number of transfers on the bulk OUT endpoint not read completely.
+    pub fn host_remaining(&self) -> usize {
+        self.host.lock().unwrap().remaining
+    }
+
+    /// Queue a vendor request with a data stage for `channel`.
+    pub fn vendor_out(&self, request: u8, channel: u16, data: &[u8]) {
+        let value = channel.to_le_bytes();
+        let len = (data.len() as u16).to_le_bytes();
+        let setup = [0x40, request, value[0], value[1], 0, 0, len[0], len[1]];
+        self.control_request(&setup, data);
+    }
+
+    /// Queue a control request from its setup packet and the data stage of
+    /// an OUT request, empty otherwise.
+    pub fn control_request(&self, setup: &[u8], data: &[u8]) {
+        let mut control = self.control.lock().unwrap();
+        control.push_back((setup.to_vec(), true));
+        if !data.is_empty() {
+            control.push_back((data.to_vec(), false));
+        }
+    }
+
+    /// Returns `true` once the control requests are read.
+    pub fn control_done(&self) -> bool {
+        self.control.lock().unwrap().is_empty()
+    }
+
+    fn accept(&self) -> bool {
+        match *self.acceptance.lock().unwrap() {
+            Acceptance::All => true,
+            Acceptance::Burst(n) => {
+                if self.burst.load(Ordering::Relaxed) >= n {
+                    self.burst.store(0, Ordering::Relaxed);
+                    false
+                } else {
+                    self.burst.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+            }
+            Acceptance::None => false,
+        }
+    }
+}
+
+impl Default for SyntheticBus {
+    fn default() -> Self {
+        Self::new(Acceptance::All)
+    }
+}
+
+impl UsbBus for SyntheticBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> usb_device::Result<EndpointAddress> {
+        Ok(ep_addr.unwrap_or(EndpointAddress::from_parts(0, ep_dir)))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+        if ep_addr.index() != BULK_IN {
+            return Ok(buf.len());
+        }
+
+        if !self.accept() {
+            return Err(UsbError::WouldBlock);
+        }
+
+        self.packets_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(buf.len(), Ordering::Relaxed);
+        if self.record.load(Ordering::Relaxed) {
+            self.written.lock().unwrap().push(buf.to_vec());
+        }
+        self.in_complete.store(true, Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+        let copy = |packet: &[u8], buf: &mut [u8]| {
+            let dest = buf
+                .get_mut(..packet.len())
+                .ok_or(UsbError::BufferOverflow)?;
+            dest.copy_from_slice(packet);
+            Ok(packet.len())
+        };
+
+        match ep_addr.index() {
+            0 => {
+                let mut control = self.control.lock().unwrap();
+                let (packet, _) = control.pop_front().ok_or(UsbError::WouldBlock)?;
+                copy(&packet, buf)
+            }
+            BULK_OUT => {
+                let mut host = self.host.lock().unwrap();
+                if host.remaining == 0 {
+                    return Err(UsbError::WouldBlock);
+                }
+
+                let read = copy(&host.packets[host.next], buf)?;
+                host.next += 1;
+                if host.next == host.packets.len() {
+                    host.next = 0;
+                    host.remaining -= 1;
+                }
+                Ok(read)
+            }
+            _ => Err(UsbError::WouldBlock),
+        }
+    }
+
+    fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let (mut ep_out, mut ep_setup) = (0, 0);
+        match self.control.lock().unwrap().front() {
+            Some((_, true)) => ep_setup |= 1,
+            Some((_, false)) => ep_out |= 1,
+            None => {}
+        }
+        if self.host.lock().unwrap().remaining > 0 {
+            ep_out |= 1 << BULK_OUT;
+        }
+        let ep_in_complete = if self.in_complete.swap(false, Ordering::Relaxed) {
+            1 << BULK_IN
+        } else {
+            0
+        };
+
+        if ep_out | ep_in_complete | ep_setup == 0 {
+            return PollResult::None;
+        }
+        PollResult::Data {
+            ep_out,
+            ep_in_complete,
+            ep_setup,
+        }
+    }
+}
+
+const TIMING: CanBitTimingConst = CanBitTimingConst {
+    tseg1_min: 1,
+    tseg1_max: 255,
+    tseg2_min: 1,
+    tseg2_max: 127,
+    sjw_max: 127,
+    brp_min: 1,
+    brp_max: 511,
+    brp_inc: 1,
+};
+
+pub const BIT_TIMING: DeviceBitTiming = DeviceBitTiming {
+    prop_seg: 15,
+    phase_seg1: 16,
+    phase_seg2: 8,
+    sjw: 1,
+    brp: 1,
+};
+
+/// Device counting the frames from the host and dropping them.
+pub struct CountingDevice {
+    /// Features advertised to the host
+    pub features: Feature,
+    /// Frames from the host
+    pub received: usize,
+    /// Last frame from the host
+    pub last: Option<Frame>,
+}
+
+impl CountingDevice {
+    pub fn new(features: Feature) -> Self {
+        Self {
+            features,
+            received: 0,
+            last: None,
+        }
+    }
+}
+
+impl Device for CountingDevice {
+    fn config(&self) -> DeviceConfig {
+        DeviceConfig::new(1)
+    }
+
+    fn bit_timing(&self) -> DeviceBitTimingConst {
+        DeviceBitTimingConst {
+            features: self.features,
+            fclk_can: 80_000_000,
+            timing: TIMING,
+        }
+    }
+
+    fn reset(&mut self, _channel: Channel) {}
+
+    fn start(
+        &mut self,
+        _channel: Channel,
+        _features: Feature,
+        _nominal: &DeviceBitTiming,
+        _data: Option<&DeviceBitTiming>,
+    ) {
+    }
+
+    fn state(&self, _channel: Channel) -> DeviceState {
+        DeviceState {
+            state: CanState::Active,
+            rx_errors: 0,
+            tx_errors: 0,
+        }
+    }
+
+    fn receive(&mut self, _channel: Channel, frame: &Frame) -> nb::Result<(), Infallible> {
+        self.received += 1;
+        self.last = Some(*frame);
+        Ok(())
+    }
+}
+
+pub type Class<'a> = GsCan<'a, SyntheticBus, CountingDevice>;
+
+/// A configured class on `alloc` advertising `features`.
+pub fn class(
+    alloc: &UsbBusAllocator<SyntheticBus>,
+    features: Feature,
+) -> (Class<'_>, UsbDevice<'_, SyntheticBus>) {
+    class_with(alloc, CountingDevice::new(features))
+}
+
+/// A configured class on `alloc` for `device`.
+pub fn class_with<D: Device>(
+    alloc: &UsbBusAllocator<SyntheticBus>,
+    device: D,
+) -> (GsCan<'_, SyntheticBus, D>, UsbDevice<'_, SyntheticBus>) {
+    let mut class = GsCan::new(alloc, device);
+    // completes the allocation so the endpoints can be used.
+    let device = UsbDeviceBuilder::new(alloc, identifier::CANDLELIGHT).build();
+    // the synthetic bus is never enumerated.
+    class.set_configured(true);
+    (class, device)
+}
+
+/// Start channel 0 with `features`, e.g. `Feature::FD`.
+pub fn start<D: Device>(
+    device: &mut UsbDevice<'_, SyntheticBus>,
+    class: &mut GsCan<'_, SyntheticBus, D>,
+    features: Feature,
+) {
+    request_start(device, class, features);
+    assert!(class.is_started(Channel::new(0).unwrap()));
+}
+
+/// Ask to start channel 0 with `features`, which the class may refuse.
+pub fn request_start<D: Device>(
+    device: &mut UsbDevice<'_, SyntheticBus>,
+    class: &mut GsCan<'_, SyntheticBus, D>,
+    features: Feature,
+) {
+    let bus = device.bus();
+    bus.vendor_out(1, 0, BIT_TIMING.as_bytes());
+    if features.contains(Feature::FD) {
+        bus.vendor_out(10, 0, BIT_TIMING.as_bytes());
+    }
+    let mut mode = 1_u32.to_le_bytes().to_vec();
+    mode.extend_from_slice(&features.bits().to_le_bytes());
+    bus.vendor_out(2, 0, &mode);
+    poll_control(device, class);
+}
+
+/// Reset channel 0.
+pub fn reset<D: Device>(
+    device: &mut UsbDevice<'_, SyntheticBus>,
+    class: &mut GsCan<'_, SyntheticBus, D>,
+) {
+    device.bus().vendor_out(2, 0, &[0; 8]);
+    poll_control(device, class);
+}
+
+/// Poll until the control requests are read.
+pub fn poll_control<D: Device>(
+    device: &mut UsbDevice<'_, SyntheticBus>,
+    class: &mut GsCan<'_, SyntheticBus, D>,
+) {
+    while !device.bus().control_done() {
+        device.poll(&mut [&mut *class]);
+    }
+}
+
+/// Poll until the transfers from the host are read and the frames for the
+/// host written, or the host stops reading.
+pub fn run<D: Device>(
+    device: &mut UsbDevice<'_, SyntheticBus>,
+    class: &mut GsCan<'_, SyntheticBus, D>,
+) {
+    while device.bus().host_remaining() > 0 || !class.is_idle() {
+        if device.poll(&mut [&mut *class]) {
+            continue;
+        }
+
+        // nothing from the bus, the firmware's main loop kicks the class.
+        let written = device.bus().packets_written();
+        class.kick();
+        if device.bus().packets_written() == written {
+            return;
+        }
+    }
+}
+
+/// Packets of the bulk IN endpoint in a replayed session, see [`replay`].
+#[cfg(feature = "host-tools")]
+pub struct Replayed {
+    /// Written by the class during the replay
+    pub written: Vec<Vec<u8>>,
+    /// Written when the session was recorded
+    pub recorded: Vec<Vec<u8>>,
+}
+
+/// Replay the host's side of a recorded session against a class, panicking
+/// on a malformed session.
+///
+/// Control requests and packets from the host are sent in the order of the
+/// session, each handled before the next is sent. Their times are ignored, so
+/// a replay is the same whatever the timing of the recording.
+#[cfg(feature = "host-tools")]
+pub fn replay<D: Device>(
+    device: &mut UsbDevice<'_, SyntheticBus>,
+    class: &mut GsCan<'_, SyntheticBus, D>,
+    session: &[u8],
+) -> Replayed {
+    use usbd_gscan::session::{RecordKind, Records};
+
+    device.bus().set_record(true);
+    let mut recorded = Vec::new();
+    for record in Records::new(session) {
+        let record = record.expect("malformed session");
+        match record.kind {
+            RecordKind::Control => {
+                let (setup, data) = record
+                    .bytes
+                    .split_at_checked(8)
+                    .expect("control record without a setup packet");
+                device.bus().control_request(setup, data);
+                poll_control(device, class);
+            }
+            RecordKind::BulkOut => {
+                device.bus().send_from_host(&[record.bytes], 1);
+                run(device, class);
+            }
+            RecordKind::BulkIn => recorded.push(record.bytes.to_vec()),
+        }
+    }
+    run(device, class);
+
+    Replayed {
+        written: device.bus().written(),
+        recorded,
+    }
+}
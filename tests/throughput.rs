@@ -0,0 +1,81 @@
+use embedded_can::{Frame as _, StandardId};
+use std::time::Instant;
+use usb_device::bus::UsbBusAllocator;
+use usbd_gscan::{
+    host::{Feature, Frame, FrameFlag},
+    Channel,
+};
+use zerocopy::AsBytes;
+
+mod support;
+
+use support::SyntheticBus;
+
+/// Time to pass frames from `transmit` to the endpoint, run with
+/// `cargo test --release --test throughput -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_transmit() {
+    const FRAMES: u32 = 1_000_000;
+    const BURST: u32 = 32;
+
+    let alloc = UsbBusAllocator::new(SyntheticBus::default());
+    let (mut class, mut device) = support::class(&alloc, Feature::empty());
+    let channel = Channel::new(0).unwrap();
+    let frame = Frame::new(StandardId::ZERO, &[0xAA; 8]).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..FRAMES / BURST {
+        for _ in 0..BURST {
+            class.transmit(channel, &frame, FrameFlag::empty());
+        }
+
+        support::run(&mut device, &mut class);
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{} ns per frame",
+        elapsed.as_nanos() / u128::from(FRAMES - FRAMES % BURST)
+    );
+}
+
+#[test]
+fn test_synthetic_bus() {
+    let alloc = UsbBusAllocator::new(SyntheticBus::default());
+    let (mut class, mut device) = support::class(&alloc, Feature::empty());
+    support::start(&mut device, &mut class, Feature::empty());
+    device.bus().set_record(true);
+
+    let frame = Frame::new(StandardId::ZERO, &[0xAA; 8]).unwrap();
+    device.bus().send_from_host(&[&frame.as_bytes()[..20]], 3);
+    support::run(&mut device, &mut class);
+
+    // each frame from the host is echoed.
+    assert_eq!(class.device.received, 3);
+    let written = device.bus().written();
+    assert_eq!(written.len(), 3);
+    assert!(written.iter().all(|packet| packet[12..] == [0xAA; 8]));
+}
+
+#[test]
+fn test_synthetic_bus_acceptance() {
+    let alloc = UsbBusAllocator::new(SyntheticBus::new(support::Acceptance::Burst(2)));
+    let (mut class, mut device) = support::class(&alloc, Feature::empty());
+    let channel = Channel::new(0).unwrap();
+    let frame = Frame::new(StandardId::ZERO, &[0xAA; 8]).unwrap();
+
+    for _ in 0..5 {
+        class.transmit(channel, &frame, FrameFlag::empty());
+    }
+    support::run(&mut device, &mut class);
+    assert!(class.is_idle());
+    assert_eq!(device.bus().packets_written(), 5);
+
+    // a host that stopped reading leaves the frames queued.
+    device.bus().set_acceptance(support::Acceptance::None);
+    class.transmit(channel, &frame, FrameFlag::empty());
+    support::run(&mut device, &mut class);
+    assert!(!class.is_idle());
+    assert_eq!(device.bus().packets_written(), 5);
+}
@@ -0,0 +1,105 @@
+use usbd_gscan::host::DeviceBitTiming;
+
+/// CAN clock of an SJA1000 with a 16 MHz oscillator.
+const FCLK_CAN: u32 = 8_000_000;
+
+/// Bitrate and registers for a 16 MHz SJA1000, sampling at 75% or above.
+const SJA1000_BITRATES: [(u32, u8, u8); 9] = [
+    (1_000_000, 0x00, 0x14),
+    (800_000, 0x00, 0x16),
+    (500_000, 0x00, 0x1C),
+    (250_000, 0x01, 0x1C),
+    (125_000, 0x03, 0x1C),
+    (100_000, 0x04, 0x1C),
+    (50_000, 0x09, 0x1C),
+    (20_000, 0x18, 0x1C),
+    (10_000, 0x31, 0x1C),
+];
+
+fn bitrate(timing: &DeviceBitTiming) -> u32 {
+    let tq = 1 + timing.prop_seg + timing.phase_seg1 + timing.phase_seg2;
+    FCLK_CAN / (timing.brp * tq)
+}
+
+#[test]
+fn test_from_btr() {
+    for (rate, btr0, btr1) in SJA1000_BITRATES {
+        let timing = DeviceBitTiming::from_btr(btr0, btr1);
+        assert_eq!(bitrate(&timing), rate, "{btr0:#04x} {btr1:#04x}");
+        assert_eq!(timing.sjw, 1);
+    }
+}
+
+#[test]
+fn test_bitrate() {
+    for (rate, btr0, btr1) in SJA1000_BITRATES {
+        let timing = DeviceBitTiming::from_btr(btr0, btr1);
+        assert_eq!(timing.bitrate(FCLK_CAN), Some(rate));
+    }
+
+    let mut timing = DeviceBitTiming::from_btr(0x00, 0x1C);
+    timing.brp = 0;
+    assert_eq!(timing.bitrate(FCLK_CAN), None);
+    timing.brp = 1;
+    timing.phase_seg2 = u32::MAX;
+    assert_eq!(timing.bitrate(FCLK_CAN), None);
+}
+
+#[test]
+fn test_to_btr() {
+    for (_, btr0, btr1) in SJA1000_BITRATES {
+        let timing = DeviceBitTiming::from_btr(btr0, btr1);
+        assert_eq!(timing.to_btr(), Some((btr0, btr1)));
+    }
+}
+
+#[test]
+fn test_to_btr_fields() {
+    let timing = DeviceBitTiming {
+        prop_seg: 6,
+        phase_seg1: 7,
+        phase_seg2: 2,
+        sjw: 4,
+        brp: 64,
+    };
+    assert_eq!(timing.to_btr(), Some((0xFF, 0x1C)));
+
+    // sampling bit is ignored.
+    assert_eq!(DeviceBitTiming::from_btr(0xFF, 0x9C), timing);
+}
+
+#[test]
+fn test_to_btr_out_of_range() {
+    let valid = DeviceBitTiming::from_btr(0x00, 0x1C);
+
+    for timing in [
+        DeviceBitTiming { brp: 0, ..valid },
+        DeviceBitTiming { brp: 65, ..valid },
+        DeviceBitTiming { sjw: 0, ..valid },
+        DeviceBitTiming { sjw: 5, ..valid },
+        DeviceBitTiming {
+            prop_seg: 8,
+            phase_seg1: 9,
+            ..valid
+        },
+        DeviceBitTiming {
+            prop_seg: 0,
+            phase_seg1: 0,
+            ..valid
+        },
+        DeviceBitTiming {
+            phase_seg2: 0,
+            ..valid
+        },
+        DeviceBitTiming {
+            phase_seg2: 9,
+            ..valid
+        },
+        DeviceBitTiming {
+            prop_seg: u32::MAX,
+            ..valid
+        },
+    ] {
+        assert_eq!(timing.to_btr(), None, "{timing:?}");
+    }
+}
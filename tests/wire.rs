@@ -0,0 +1,659 @@
+//! Wire format of the host interface structs, checked against the bytes
+//! exchanged with the Linux gs_usb driver on a little endian host.
+
+mod support;
+
+use embedded_can::Frame as _;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use support::SyntheticBus;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::UsbDirection;
+use usbd_gscan::host::{
+    dlc, CanBitTimingConst, CanState, ConfigError, DeviceBitTiming, DeviceBitTimingConst,
+    DeviceBitTimingConstExtended, DeviceConfig, DeviceMode, DeviceState, Feature, Frame, FrameFlag,
+    FrameKind, GsRequest, HostConfig, IdFlag, Mode, RawDeviceState, WireFormat, FRAME_HEADER_LEN,
+};
+use usbd_gscan::Channel;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+const CHANNEL: Channel = Channel::new(0).unwrap();
+
+/// Bytes of a bulk packet.
+const PACKET_LEN: usize = 64;
+
+/// Frames in flight from the Linux driver per channel.
+const GS_MAX_TX_URBS: usize = 10;
+
+const TIMING: CanBitTimingConst = CanBitTimingConst {
+    tseg1_min: 1,
+    tseg1_max: 16,
+    tseg2_min: 1,
+    tseg2_max: 8,
+    sjw_max: 4,
+    brp_min: 1,
+    brp_max: 1024,
+    brp_inc: 1,
+};
+
+#[rustfmt::skip]
+const TIMING_BYTES: [u8; 32] = [
+    0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00,
+    0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x04, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+];
+
+#[test]
+fn test_host_config() {
+    let bytes = [0xef, 0xbe, 0x00, 0x00];
+    let config = HostConfig::read_from(&bytes[..]).unwrap();
+    assert_eq!(config.byte_order, 0x0000beef);
+    assert_eq!(config.as_bytes(), bytes);
+}
+
+#[test]
+fn test_device_config() {
+    #[rustfmt::skip]
+    let bytes = [
+        0x00, 0x00, 0x00, 0x01,
+        0x02, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(DeviceConfig::new(2).as_bytes(), bytes);
+
+    let config = DeviceConfig::read_from(&bytes[..]).unwrap();
+    assert_eq!(config.interface_count, 1);
+    assert_eq!(config.as_bytes(), bytes);
+}
+
+#[test]
+fn test_device_config_try_new() {
+    assert_eq!(
+        DeviceConfig::try_new(0).err(),
+        Some(ConfigError::NoInterfaces)
+    );
+    assert_eq!(
+        DeviceConfig::try_new(4).err(),
+        Some(ConfigError::TooManyInterfaces)
+    );
+    assert_eq!(
+        DeviceConfig::try_new(u8::MAX).err(),
+        Some(ConfigError::TooManyInterfaces)
+    );
+
+    for interfaces in 1..=3 {
+        let config = DeviceConfig::try_new(interfaces).unwrap();
+        assert_eq!(config.interface_count, interfaces - 1);
+        assert_eq!(config.as_bytes(), DeviceConfig::new(interfaces).as_bytes());
+    }
+}
+
+#[test]
+#[should_panic(expected = "device config without interfaces")]
+fn test_device_config_no_interfaces() {
+    DeviceConfig::new(0);
+}
+
+#[test]
+#[should_panic(expected = "more interfaces than supported")]
+fn test_device_config_too_many_interfaces() {
+    DeviceConfig::new(4);
+}
+
+#[test]
+fn test_device_mode() {
+    // start in loop back and one shot mode.
+    let bytes = [0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00];
+    let mode = DeviceMode::read_from(&bytes[..]).unwrap();
+    assert!(matches!(Mode::try_from(mode.mode), Ok(Mode::Start)));
+    assert_eq!(
+        mode.flags.bits(),
+        (Feature::LOOP_BACK | Feature::ONE_SHOT).bits()
+    );
+    assert_eq!(mode.as_bytes(), bytes);
+}
+
+#[test]
+fn test_device_state() {
+    let state = DeviceState {
+        state: CanState::Passive,
+        rx_errors: 0x80,
+        tx_errors: 0x100,
+    };
+    #[rustfmt::skip]
+    let bytes = [
+        0x02, 0x00, 0x00, 0x00,
+        0x80, 0x00, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00,
+    ];
+    assert_eq!(state.as_bytes(), bytes);
+}
+
+#[test]
+fn test_raw_device_state() {
+    #[rustfmt::skip]
+    let bytes = [
+        0x03, 0x00, 0x00, 0x00,
+        0x7f, 0x00, 0x00, 0x00,
+        0x22, 0x01, 0x00, 0x00,
+    ];
+    let raw = RawDeviceState::read_from(&bytes[..]).unwrap();
+    let state = DeviceState::try_from(raw).unwrap();
+    assert_eq!(state, DeviceState::bus_off(34, 127));
+    assert_eq!(RawDeviceState::from(state), raw);
+    assert_eq!(raw.as_bytes(), bytes);
+}
+
+#[test]
+fn test_raw_device_state_invalid() {
+    for state in [6, 0x100, u32::MAX] {
+        let raw = RawDeviceState {
+            state,
+            rx_errors: 0,
+            tx_errors: 0,
+        };
+        assert_eq!(DeviceState::try_from(raw), Err(()), "state {state}");
+        assert_eq!(CanState::try_from(state), Err(()));
+    }
+
+    for state in 0..6 {
+        assert_eq!(CanState::try_from(state).map(u32::from), Ok(state));
+    }
+}
+
+#[test]
+fn test_device_bit_timing() {
+    // 500 kbit/s at 80 MHz, 87.5% sample point.
+    #[rustfmt::skip]
+    let bytes = [
+        0x45, 0x00, 0x00, 0x00,
+        0x46, 0x00, 0x00, 0x00,
+        0x14, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00,
+    ];
+    let timing = DeviceBitTiming::read_from(&bytes[..]).unwrap();
+    assert_eq!(timing.prop_seg, 69);
+    assert_eq!(timing.phase_seg1, 70);
+    assert_eq!(timing.phase_seg2, 20);
+    assert_eq!(timing.sjw, 1);
+    assert_eq!(timing.brp, 1);
+    assert_eq!(timing.as_bytes(), bytes);
+}
+
+#[test]
+fn test_device_bit_timing_const() {
+    let bt_const = DeviceBitTimingConst {
+        features: Feature::LISTEN_ONLY | Feature::GET_STATE,
+        fclk_can: 48_000_000,
+        timing: TIMING,
+    };
+    let mut bytes = vec![0x01, 0x20, 0x00, 0x00, 0x00, 0x6c, 0xdc, 0x02];
+    bytes.extend_from_slice(&TIMING_BYTES);
+    assert_eq!(bt_const.as_bytes(), bytes);
+
+    let bt_const = DeviceBitTimingConst::read_from(&bytes[..]).unwrap();
+    assert_eq!(bt_const.fclk_can, 48_000_000);
+    assert_eq!(bt_const.timing.brp_max, 1024);
+}
+
+#[test]
+fn test_device_bit_timing_const_extended() {
+    let bt_const = DeviceBitTimingConstExtended {
+        features: Feature::FD | Feature::BT_CONST_EXT,
+        fclk_can: 80_000_000,
+        timing_nominal: TIMING,
+        timing_data: CanBitTimingConst {
+            brp_max: 32,
+            ..TIMING
+        },
+    };
+    let mut bytes = vec![0x00, 0x05, 0x00, 0x00, 0x00, 0xb4, 0xc4, 0x04];
+    bytes.extend_from_slice(&TIMING_BYTES);
+    bytes.extend_from_slice(&TIMING_BYTES);
+    bytes[64] = 0x20;
+    bytes[65] = 0x00;
+    assert_eq!(bt_const.as_bytes(), bytes);
+
+    let bt_const = DeviceBitTimingConstExtended::read_from(&bytes[..]).unwrap();
+    assert_eq!(bt_const.timing_nominal.brp_max, 1024);
+    assert_eq!(bt_const.timing_data.brp_max, 32);
+}
+
+#[test]
+fn test_frame_classic() {
+    // echo id 3, standard id 0x123, 4 data bytes.
+    #[rustfmt::skip]
+    let bytes = [
+        0x03, 0x00, 0x00, 0x00,
+        0x23, 0x01, 0x00, 0x00,
+        0x04, 0x00, 0x00, 0x00,
+        0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let mut frame = Frame::new_zeroed();
+    frame.as_bytes_mut()[..bytes.len()].copy_from_slice(&bytes);
+    assert_eq!(frame.echo_id, 3);
+    assert_eq!(frame.raw_id(), 0x123);
+    assert_eq!(frame.data(), [1, 2, 3, 4]);
+    assert_eq!(frame.as_bytes()[..bytes.len()], bytes);
+}
+
+#[cfg(feature = "fd")]
+#[test]
+fn test_frame_fd() {
+    // extended id 0x1234567 on channel 1 with bit rate switching, 64 data bytes.
+    let mut bytes = vec![
+        0xff, 0xff, 0xff, 0xff, 0x67, 0x45, 0x23, 0x81, 0x0f, 0x01, 0x06, 0x00,
+    ];
+    bytes.extend(0..64);
+    bytes.extend_from_slice(&[0x00; 4]);
+
+    let frame = Frame::read_from(&bytes[..]).unwrap();
+    assert_eq!(frame.echo_id, u32::MAX);
+    assert_eq!(frame.interface, 1);
+    assert_eq!(
+        frame.flags.bits(),
+        (FrameFlag::FD | FrameFlag::BIT_RATE_SWITCH).bits()
+    );
+    assert_eq!(frame.data().len(), 64);
+    assert_eq!(frame.data()[63], 63);
+    assert_eq!(frame.as_bytes(), bytes);
+}
+
+#[cfg(not(feature = "fd"))]
+#[test]
+fn test_frame_classic_only() {
+    assert_eq!(core::mem::size_of::<Frame>(), 24);
+    assert!(Frame::new_raw(0x7, &[0xAA; 12]).is_err());
+}
+
+#[test]
+fn test_dlc_inverse() {
+    for dlc in 0..=15 {
+        let len = dlc::fd_dlc_to_len(dlc).unwrap();
+        assert_eq!(dlc::fd_len_to_dlc(len), Some(dlc as u8));
+        assert!(dlc::is_valid_fd_len(len));
+        assert_eq!(dlc::round_len_to_next_dlc(len), (dlc as u8, len));
+    }
+    assert_eq!(dlc::fd_dlc_to_len(16), None);
+
+    for len in 0..=dlc::MAX_FD_LEN + 1 {
+        match dlc::fd_len_to_dlc(len) {
+            Some(dlc) => assert_eq!(dlc::fd_dlc_to_len(dlc.into()), Some(len)),
+            None => assert!(!dlc::is_valid_fd_len(len)),
+        }
+    }
+}
+
+#[test]
+fn test_dlc_round_len() {
+    for len in 0..=dlc::MAX_FD_LEN {
+        let (dlc, padded) = dlc::round_len_to_next_dlc(len);
+        assert!(padded >= len);
+        assert_eq!(dlc::fd_dlc_to_len(dlc.into()), Some(padded));
+        // no shorter length holds it.
+        if dlc > 0 {
+            assert!(dlc::fd_dlc_to_len(usize::from(dlc) - 1).unwrap() < len);
+        }
+    }
+    assert_eq!(dlc::round_len_to_next_dlc(10), (9, 12));
+    assert_eq!(dlc::round_len_to_next_dlc(65), (15, 64));
+}
+
+#[test]
+fn test_device_state_constructors() {
+    #[rustfmt::skip]
+    let cases = [
+        (DeviceState::active(95, 3), [0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x5f, 0x00, 0x00, 0x00]),
+        (DeviceState::warning(0, 96), [0x01, 0x00, 0x00, 0x00, 0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+        (DeviceState::passive(255, 10), [0x02, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00]),
+        (DeviceState::bus_off(34, 127), [0x03, 0x00, 0x00, 0x00, 0x7f, 0x00, 0x00, 0x00, 0x22, 0x01, 0x00, 0x00]),
+    ];
+
+    for (state, bytes) in cases {
+        assert!(state.is_consistent());
+        assert_eq!(state.as_bytes(), bytes, "{state:?}");
+    }
+}
+
+#[test]
+fn test_device_state_inconsistent() {
+    let state = DeviceState {
+        state: CanState::BusOff,
+        rx_errors: 0,
+        tx_errors: 34,
+    };
+    assert!(!state.is_consistent());
+}
+
+#[test]
+#[should_panic(expected = "error counters disagree with the CAN state")]
+fn test_device_state_active_over_threshold() {
+    DeviceState::active(96, 0);
+}
+
+/// A vendor request.
+fn request(recipient: Recipient, request: u8, value: u16, index: u16) -> Request {
+    Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Vendor,
+        recipient,
+        request,
+        value,
+        index,
+        length: 0,
+    }
+}
+
+#[test]
+fn test_gs_request_parse() {
+    let parse =
+        |req, value, index| GsRequest::parse(&request(Recipient::Device, req, value, index));
+
+    assert_eq!(parse(0, 1, 2), Some(GsRequest::HostFormat));
+    assert_eq!(parse(2, 1, 0), Some(GsRequest::Mode { channel: 1 }));
+    assert_eq!(parse(15, 0, 0), None);
+
+    // the original requests only read wValue.
+    assert_eq!(parse(1, 0, 1), Some(GsRequest::BitTiming { channel: 0 }));
+    // wValue takes precedence, then wIndex of a request to the device.
+    assert_eq!(parse(14, 1, 0), Some(GsRequest::GetState { channel: 1 }));
+    assert_eq!(parse(14, 1, 3), Some(GsRequest::GetState { channel: 1 }));
+    assert_eq!(parse(14, 0, 1), Some(GsRequest::GetState { channel: 1 }));
+    assert_eq!(
+        parse(12, 0, 1),
+        Some(GsRequest::SetTermination { channel: 1 })
+    );
+    // wIndex of a request to an interface is the interface number.
+    assert_eq!(
+        GsRequest::parse(&request(Recipient::Interface, 14, 0, 1)),
+        Some(GsRequest::GetState { channel: 0 })
+    );
+
+    assert_eq!(GsRequest::GetState { channel: 1 }.channel(), Some(1));
+    assert_eq!(GsRequest::DeviceConfig.channel(), None);
+}
+
+#[test]
+fn test_frame_kind() {
+    assert_eq!(FrameKind::from_echo_id(0xffff_ffff), FrameKind::Receive);
+    assert_eq!(FrameKind::from_echo_id(0), FrameKind::Echo(0));
+    assert_eq!(FrameKind::Receive.echo_id(), 0xffff_ffff);
+    assert_eq!(FrameKind::Echo(7).echo_id(), 7);
+
+    let mut frame = Frame::new_zeroed();
+    assert_eq!(frame.kind(), FrameKind::Echo(0));
+    frame.set_kind(FrameKind::Receive);
+    assert_eq!(&frame.as_bytes()[..4], [0xff; 4]);
+}
+
+#[test]
+fn test_wire_format() {
+    let classic = WireFormat::new(Feature::empty());
+    assert_eq!((classic.in_len(), classic.out_len()), (20, 20));
+    let timestamp = WireFormat::new(Feature::HW_TIMESTAMP);
+    assert_eq!((timestamp.in_len(), timestamp.out_len()), (24, 20));
+
+    #[cfg(feature = "fd")]
+    {
+        let fd = WireFormat::new(Feature::FD | Feature::HW_TIMESTAMP);
+        assert_eq!((fd.in_len(), fd.out_len()), (80, 76));
+    }
+    #[cfg(not(feature = "fd"))]
+    assert_eq!(WireFormat::new(Feature::FD), classic);
+}
+
+/// A valid frame as sent on the wire.
+#[derive(Debug, Clone)]
+struct WireCase {
+    raw_id: u32,
+    data: Vec<u8>,
+    fd: bool,
+    brs: bool,
+    esi: bool,
+    echo_id: u32,
+}
+
+/// Identifiers of either format.
+fn raw_id() -> impl Strategy<Value = u32> {
+    prop_oneof![
+        0..=0x7FFu32,
+        (0..=0x1FFFFFFFu32).prop_map(|id| id | IdFlag::EXTENDED.bits()),
+    ]
+}
+
+/// Payloads of a length with a DLC.
+fn payload(fd: bool) -> impl Strategy<Value = Vec<u8>> {
+    (0..if fd { 16usize } else { 9 }).prop_flat_map(move |dlc| {
+        let len = if fd {
+            dlc::fd_dlc_to_len(dlc).unwrap()
+        } else {
+            dlc
+        };
+        vec(any::<u8>(), len)
+    })
+}
+
+prop_compose! {
+    /// Frames of either type, echo ids as the Linux driver uses them.
+    fn wire_case()(fd in any::<bool>().prop_map(|fd| cfg!(feature = "fd") && fd))(
+        fd in Just(fd),
+        raw_id in raw_id(),
+        data in payload(fd),
+        brs in any::<bool>(),
+        esi in any::<bool>(),
+        echo_id in 0..GS_MAX_TX_URBS as u32,
+    ) -> WireCase {
+        WireCase {
+            raw_id,
+            data,
+            fd,
+            brs: fd && brs,
+            esi: fd && esi,
+            echo_id,
+        }
+    }
+}
+
+impl WireCase {
+    /// Features the channel is started with.
+    fn features(&self) -> Feature {
+        if self.fd {
+            Feature::FD
+        } else {
+            Feature::empty()
+        }
+    }
+
+    fn frame(&self) -> Frame {
+        let mut frame = Frame::new_raw(self.raw_id, &self.data).unwrap();
+        #[cfg(feature = "fd")]
+        frame.flags.set(FrameFlag::FD, self.fd);
+        frame.set_brs(self.brs).unwrap();
+        frame.set_esi(self.esi).unwrap();
+        frame.echo_id = self.echo_id;
+        frame
+    }
+
+    /// Bytes of the frame from the host.
+    fn encode(&self) -> Vec<u8> {
+        let format = WireFormat::new(self.features());
+        self.frame().as_bytes()[..format.out_len()].to_vec()
+    }
+
+    /// Checks a frame carries the identifier, flags and data of the case.
+    fn check(&self, frame: &Frame) {
+        assert_eq!(frame.raw_id(), self.raw_id, "{self:?}");
+        assert_eq!(frame.interface, 0, "{self:?}");
+        assert_eq!(
+            (frame.is_fd(), frame.brs(), frame.esi()),
+            (self.fd, self.brs, self.esi),
+            "{self:?}"
+        );
+        assert_eq!(frame.data(), self.data, "{self:?}");
+        assert_eq!(frame.data_len(), Some(self.data.len()), "{self:?}");
+    }
+}
+
+/// Reads a frame from wire bytes the way the class does, zero filled.
+fn decode(bytes: &[u8]) -> Frame {
+    let mut frame = Frame::new_zeroed();
+    frame.as_bytes_mut()[..bytes.len()].copy_from_slice(bytes);
+    frame
+}
+
+/// Header fields and payload of a frame.
+fn fields(frame: &Frame) -> (u32, u32, u8, u8, u8, u8, Vec<u8>) {
+    (
+        frame.echo_id,
+        frame.raw_id(),
+        frame.can_dlc,
+        frame.interface,
+        frame.flags.bits(),
+        frame.sequence(),
+        frame.data().to_vec(),
+    )
+}
+
+/// Sends a case through the class on the synthetic bus both ways: to the
+/// host, read back from the bulk IN packets, and from the host, written to
+/// the bulk OUT endpoint and handed to the device, then echoed.
+fn check_round_trip(case: &WireCase) {
+    let alloc = UsbBusAllocator::new(SyntheticBus::default());
+    let (mut class, mut device) = support::class(&alloc, case.features());
+    support::start(&mut device, &mut class, case.features());
+    device.bus().set_record(true);
+    let format = WireFormat::new(case.features());
+
+    let mut flags = FrameFlag::empty();
+    #[cfg(feature = "fd")]
+    flags.set(FrameFlag::FD, case.fd);
+    flags.set(FrameFlag::BIT_RATE_SWITCH, case.brs);
+    flags.set(FrameFlag::ERROR_STATE_INDICATOR, case.esi);
+    class.transmit(CHANNEL, &case.frame(), flags);
+    support::run(&mut device, &mut class);
+    let to_host = device.bus().written().concat();
+    assert_eq!(to_host.len(), format.in_len(), "{case:?}");
+    let frame = decode(&to_host);
+    assert_eq!(frame.kind(), FrameKind::Receive, "{case:?}");
+    case.check(&frame);
+
+    let bytes = case.encode();
+    let packets: Vec<_> = bytes.chunks(PACKET_LEN).collect();
+    device.bus().send_from_host(&packets, 1);
+    support::run(&mut device, &mut class);
+    assert_eq!(class.device.received, 1, "{case:?}");
+    case.check(&class.device.last.unwrap());
+
+    let echo = device.bus().written()[to_host.len().div_ceil(PACKET_LEN)..].concat();
+    assert_eq!(echo.len(), format.in_len(), "{case:?}");
+    let echo = decode(&echo);
+    // echoed at once, as the transfer completing.
+    assert_eq!(echo.kind(), FrameKind::Echo(0), "{case:?}");
+    case.check(&echo);
+}
+
+#[test]
+fn test_frame_round_trip_regressions() {
+    let base = WireCase {
+        raw_id: 0,
+        data: vec![],
+        fd: false,
+        brs: false,
+        esi: false,
+        echo_id: 0,
+    };
+    let mut cases = vec![
+        base.clone(),
+        // largest classic payload.
+        WireCase {
+            data: vec![0xff; 8],
+            ..base.clone()
+        },
+        // largest identifiers of each format.
+        WireCase {
+            raw_id: 0x7FF,
+            ..base.clone()
+        },
+        WireCase {
+            raw_id: 0x1FFFFFFF | IdFlag::EXTENDED.bits(),
+            echo_id: GS_MAX_TX_URBS as u32 - 1,
+            ..base.clone()
+        },
+    ];
+    if cfg!(feature = "fd") {
+        cases.extend([
+            // FD frame with a classic length.
+            WireCase {
+                data: vec![0xaa; 8],
+                fd: true,
+                ..base.clone()
+            },
+            // first FD only length, and the largest with every flag.
+            WireCase {
+                data: vec![1; 12],
+                fd: true,
+                brs: true,
+                ..base.clone()
+            },
+            WireCase {
+                data: (0..64).collect(),
+                fd: true,
+                brs: true,
+                esi: true,
+                ..base.clone()
+            },
+        ]);
+    }
+
+    for case in &cases {
+        check_round_trip(case);
+    }
+}
+
+proptest! {
+    /// Frames cross the class both ways at the size of their wire format.
+    #[test]
+    fn test_frame_round_trip(case in wire_case()) {
+        check_round_trip(&case);
+    }
+
+    /// Every bit of the header is part of a field, so a change to it is
+    /// either seen or makes the frame invalid.
+    #[test]
+    fn test_frame_header_mutation(case in wire_case(), bit in 0..FRAME_HEADER_LEN * 8) {
+        let bytes = case.encode();
+        let original = fields(&decode(&bytes));
+
+        let mut mutated = bytes.clone();
+        mutated[bit / 8] ^= 1 << (bit % 8);
+        let frame = decode(&mutated);
+        prop_assert!(frame.data_len().is_none() || fields(&frame) != original);
+        // a mutated DLC never reads past the frame.
+        prop_assert!(frame.data().len() <= dlc::MAX_FD_LEN);
+    }
+
+    /// The smallest wire format that carries a payload agrees with its DLC.
+    #[test]
+    fn test_frame_sizes(dlc in 0..16usize) {
+        let len = dlc::fd_dlc_to_len(dlc).unwrap();
+        let classic = len <= 8;
+        prop_assume!(classic || cfg!(feature = "fd"));
+
+        let format = WireFormat::new(if classic {
+            Feature::empty()
+        } else {
+            Feature::FD
+        });
+        prop_assert!(len <= format.data_len());
+        prop_assert_eq!(format.out_len(), FRAME_HEADER_LEN + format.data_len());
+        #[cfg_attr(not(feature = "fd"), allow(unused_mut))]
+        let mut frame = Frame::new_raw(0x7, &vec![0; len]).unwrap();
+        #[cfg(feature = "fd")]
+        frame.flags.set(FrameFlag::FD, !classic);
+        prop_assert_eq!(usize::from(frame.can_dlc), dlc);
+        prop_assert_eq!(frame.data_len(), Some(len));
+    }
+}